@@ -0,0 +1,325 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::{
+    gpio::Pin,
+    ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
+    units::FromValueType,
+};
+use esp_idf_sys::{esp, gpio_drive_cap_t_GPIO_DRIVE_CAP_3, gpio_set_drive_capability};
+
+use crate::model::{
+    button_events::{BtnId, UserBtnState},
+    config::DisplayConfig,
+    peripherals::BacklightPeripherals,
+};
+
+// Drives the backlight pin with a PWM signal, the duty cycle sets the brightness
+pub struct Backlight {
+    // The channel is declared first so it stops before the timer it runs on is reset
+    channel: LedcDriver<'static>,
+    _timer: LedcTimerDriver<'static>,
+    percent: u8,
+}
+
+impl Backlight {
+    pub fn new(backlight_peripherals: BacklightPeripherals) -> Self {
+        let pin = backlight_peripherals.pin.pin();
+
+        let timer = LedcTimerDriver::new(
+            backlight_peripherals.timer,
+            &TimerConfig::new().frequency(5.kHz().into()),
+        )
+        .unwrap();
+        let channel = LedcDriver::new(
+            backlight_peripherals.channel,
+            &timer,
+            backlight_peripherals.pin,
+        )
+        .unwrap();
+
+        // The backlight was driven at 40mA when it was switched on as a plain output
+        esp!(unsafe { gpio_set_drive_capability(pin, gpio_drive_cap_t_GPIO_DRIVE_CAP_3) }).unwrap();
+
+        let mut backlight = Self {
+            channel,
+            _timer: timer,
+            percent: 0,
+        };
+        backlight.set_percent(100);
+
+        backlight
+    }
+
+    pub fn set_percent(&mut self, percent: u8) {
+        let percent = percent.min(100);
+
+        if percent != self.percent {
+            let duty = self.channel.get_max_duty() * percent as u32 / 100;
+            self.channel.set_duty(duty).unwrap();
+            self.percent = percent;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BacklightSettings {
+    pub day_percent: u8,
+    pub night_percent: u8,
+    pub night_start_hour: u8,
+    pub night_end_hour: u8,
+    // None keeps the screen on
+    pub screen_timeout: Option<Duration>,
+}
+
+impl From<&DisplayConfig> for BacklightSettings {
+    fn from(display: &DisplayConfig) -> Self {
+        Self {
+            day_percent: display.backlight_percent,
+            night_percent: display.night_backlight_percent,
+            night_start_hour: display.night_start_hour,
+            night_end_hour: display.night_end_hour,
+            screen_timeout: match display.screen_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs as u64)),
+            },
+        }
+    }
+}
+
+// The press that wakes the screen is swallowed up to and including the click it ends with
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WakeButton {
+    None,
+    Down(BtnId),
+    Released(BtnId),
+}
+
+/**
+ * Decides how bright the backlight is.  The day brightness is used outside the night hours and the night brightness
+ * inside them, the night hours may span midnight and are not used until the clock has been set.  When a screen
+ * timeout is set the backlight goes off after that long without a button event, a button or an alert wakes it.
+ */
+pub struct BacklightState {
+    settings: BacklightSettings,
+    // The local hour, None until the clock has been set
+    local_hour: Option<u8>,
+    last_activity: Instant,
+    is_asleep: bool,
+    wake_button: WakeButton,
+}
+
+impl BacklightState {
+    pub fn new(settings: BacklightSettings, now: Instant) -> Self {
+        Self {
+            settings,
+            local_hour: None,
+            last_activity: now,
+            is_asleep: false,
+            wake_button: WakeButton::None,
+        }
+    }
+
+    pub fn update(&mut self, settings: BacklightSettings, local_hour: Option<u8>) {
+        self.settings = settings;
+        self.local_hour = local_hour;
+    }
+
+    pub fn wake(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.is_asleep = false;
+    }
+
+    /**
+     * Every button event keeps the screen on.  Returns false when the gui should not act on the event, i.e. the
+     * press that wakes the screen and the events that follow it until the button is released.
+     */
+    pub fn button_event(&mut self, user_btn_state: UserBtnState, now: Instant) -> bool {
+        self.last_activity = now;
+
+        match self.wake_button {
+            WakeButton::Down(btn) => {
+                if user_btn_state == UserBtnState::Released(btn) {
+                    self.wake_button = WakeButton::Released(btn);
+                }
+                return false;
+            }
+
+            WakeButton::Released(btn) => match user_btn_state {
+                UserBtnState::Clicked(clicked) | UserBtnState::DoubleClicked(clicked)
+                    if clicked == btn =>
+                {
+                    return false;
+                }
+                _ => self.wake_button = WakeButton::None,
+            },
+
+            WakeButton::None => (),
+        }
+
+        if self.is_asleep {
+            self.is_asleep = false;
+
+            if let UserBtnState::Pressed(btn) = user_btn_state {
+                self.wake_button = WakeButton::Down(btn);
+            }
+            return false;
+        }
+
+        true
+    }
+
+    // The brightness to show now, 0 while the screen is asleep
+    pub fn percent(&mut self, now: Instant) -> u8 {
+        if let Some(screen_timeout) = self.settings.screen_timeout {
+            if now.duration_since(self.last_activity) >= screen_timeout {
+                self.is_asleep = true;
+            }
+        }
+
+        if self.is_asleep {
+            return 0;
+        }
+
+        match self.local_hour {
+            Some(hour) if self.is_night(hour) => self.settings.night_percent,
+            _ => self.settings.day_percent,
+        }
+    }
+
+    // The night starts at night_start_hour and ends at night_end_hour, the same hour for both means there is no night
+    fn is_night(&self, hour: u8) -> bool {
+        let (start, end) = (self.settings.night_start_hour, self.settings.night_end_hour);
+
+        if start <= end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(night_start_hour: u8, night_end_hour: u8, timeout_secs: u64) -> BacklightSettings {
+        BacklightSettings {
+            day_percent: 100,
+            night_percent: 20,
+            night_start_hour,
+            night_end_hour,
+            screen_timeout: match timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+    }
+
+    fn percent_at(state: &mut BacklightState, settings: BacklightSettings, hour: Option<u8>) -> u8 {
+        state.update(settings, hour);
+        state.percent(Instant::now())
+    }
+
+    #[test]
+    fn night_window_wraps_past_midnight() {
+        let night = settings(22, 6, 0);
+        let mut state = BacklightState::new(night, Instant::now());
+
+        assert_eq!(percent_at(&mut state, night, Some(21)), 100);
+        assert_eq!(percent_at(&mut state, night, Some(22)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(23)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(0)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(5)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(6)), 100);
+        assert_eq!(percent_at(&mut state, night, Some(12)), 100);
+
+        // The night hours are not used until the clock has been set
+        assert_eq!(percent_at(&mut state, night, None), 100);
+    }
+
+    #[test]
+    fn night_window_within_a_day() {
+        let night = settings(1, 5, 0);
+        let mut state = BacklightState::new(night, Instant::now());
+
+        assert_eq!(percent_at(&mut state, night, Some(0)), 100);
+        assert_eq!(percent_at(&mut state, night, Some(1)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(4)), 20);
+        assert_eq!(percent_at(&mut state, night, Some(5)), 100);
+
+        // The same start and end hour means there is no night
+        let no_night = settings(22, 22, 0);
+        assert_eq!(percent_at(&mut state, no_night, Some(22)), 100);
+        assert_eq!(percent_at(&mut state, no_night, Some(3)), 100);
+    }
+
+    #[test]
+    fn screen_times_out_and_wakes() {
+        let t0 = Instant::now();
+        let mut state = BacklightState::new(settings(22, 6, 60), t0);
+
+        assert_eq!(state.percent(t0 + Duration::from_secs(59)), 100);
+        assert_eq!(state.percent(t0 + Duration::from_secs(60)), 0);
+
+        // An alert wakes the screen and the timeout starts again
+        let t1 = t0 + Duration::from_secs(120);
+        state.wake(t1);
+        assert_eq!(state.percent(t1), 100);
+        assert_eq!(state.percent(t1 + Duration::from_secs(59)), 100);
+        assert_eq!(state.percent(t1 + Duration::from_secs(60)), 0);
+    }
+
+    #[test]
+    fn button_events_keep_the_screen_on() {
+        let t0 = Instant::now();
+        let mut state = BacklightState::new(settings(22, 6, 60), t0);
+
+        let t1 = t0 + Duration::from_secs(50);
+        assert!(state.button_event(UserBtnState::Pressed(BtnId::Btn1), t1));
+        assert!(state.button_event(UserBtnState::Released(BtnId::Btn1), t1));
+        assert!(state.button_event(UserBtnState::Clicked(BtnId::Btn1), t1));
+        assert_eq!(state.percent(t1 + Duration::from_secs(59)), 100);
+
+        // Without a timeout the screen stays on
+        let mut always_on = BacklightState::new(settings(22, 6, 0), t0);
+        assert_eq!(
+            always_on.percent(t0 + Duration::from_secs(24 * 60 * 60)),
+            100
+        );
+    }
+
+    #[test]
+    fn wake_press_is_swallowed() {
+        let t0 = Instant::now();
+        let mut state = BacklightState::new(settings(22, 6, 60), t0);
+
+        let t1 = t0 + Duration::from_secs(60);
+        assert_eq!(state.percent(t1), 0);
+
+        // The press that wakes the screen, its hold and its click do nothing else
+        assert!(!state.button_event(UserBtnState::Pressed(BtnId::Btn2), t1));
+        assert_eq!(state.percent(t1), 100);
+        assert!(!state.button_event(UserBtnState::Held(BtnId::Btn2), t1));
+        assert!(!state.button_event(UserBtnState::Repeated(BtnId::Btn2), t1));
+        assert!(!state.button_event(UserBtnState::Released(BtnId::Btn2), t1));
+        assert!(!state.button_event(UserBtnState::Clicked(BtnId::Btn2), t1));
+
+        // The next press is acted on
+        assert!(state.button_event(UserBtnState::Pressed(BtnId::Btn2), t1));
+        assert!(state.button_event(UserBtnState::Released(BtnId::Btn2), t1));
+        assert!(state.button_event(UserBtnState::Clicked(BtnId::Btn2), t1));
+    }
+
+    #[test]
+    fn other_button_after_the_wake_press_is_acted_on() {
+        let t0 = Instant::now();
+        let mut state = BacklightState::new(settings(22, 6, 60), t0);
+
+        let t1 = t0 + Duration::from_secs(60);
+        state.percent(t1);
+
+        assert!(!state.button_event(UserBtnState::Pressed(BtnId::Btn1), t1));
+        assert!(!state.button_event(UserBtnState::Released(BtnId::Btn1), t1));
+        assert!(state.button_event(UserBtnState::Pressed(BtnId::Btn3), t1));
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::{
+    ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
+    units::FromValueType,
+};
+
+use crate::model::peripherals::SpeakerPeripherals;
+
+// Three short beeps, each on for BEEP_ON and off for BEEP_OFF
+const BEEPS: u32 = 3;
+const BEEP_ON: Duration = Duration::from_millis(150);
+const BEEP_OFF: Duration = Duration::from_millis(150);
+
+/**
+ * Beeps the speaker with a square wave.  The beeps are switched on and off by update, which the gui calls every pass of
+ * its loop, so beeping never holds up the display.
+ */
+pub struct Beeper {
+    // The channel is declared first so it stops before the timer it runs on is reset
+    channel: LedcDriver<'static>,
+    _timer: LedcTimerDriver<'static>,
+    started: Option<Instant>,
+    is_on: bool,
+}
+
+impl Beeper {
+    pub fn new(speaker_peripherals: SpeakerPeripherals) -> Self {
+        let timer = LedcTimerDriver::new(
+            speaker_peripherals.timer,
+            &TimerConfig::new().frequency(2.kHz().into()),
+        )
+        .unwrap();
+        let mut channel =
+            LedcDriver::new(speaker_peripherals.channel, &timer, speaker_peripherals.pin).unwrap();
+        channel.set_duty(0).unwrap();
+
+        Self {
+            channel,
+            _timer: timer,
+            started: None,
+            is_on: false,
+        }
+    }
+
+    pub fn beep(&mut self, now: Instant) {
+        self.started = Some(now);
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        let started = match self.started {
+            Some(started) => started,
+            None => return,
+        };
+
+        let period = BEEP_ON + BEEP_OFF;
+        let elapsed = now.duration_since(started);
+
+        let on = if elapsed >= period * BEEPS {
+            self.started = None;
+            false
+        } else {
+            elapsed.as_millis() % period.as_millis() < BEEP_ON.as_millis()
+        };
+
+        if on != self.is_on {
+            // A square wave is half on and half off
+            let duty = if on {
+                self.channel.get_max_duty() / 2
+            } else {
+                0
+            };
+            self.channel.set_duty(duty).unwrap();
+            self.is_on = on;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::model::{
+    units::{Quantity, Units},
+    weather_station::{Measurement, MeasurementField},
+};
+
+/**
+ * How a label shows the value of a measurement.  A quantity is converted to the units chosen for the display and shown
+ * with their symbol, any other number is shown with a fixed unit and text is shown as it is.  The placeholder is shown
+ * until the first value of the measurement arrives.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Formatter {
+    quantity: Option<Quantity>,
+    // None uses the digits the units show for the quantity, or none for other numbers
+    precision: Option<usize>,
+    unit: &'static str,
+    placeholder: &'static str,
+}
+
+impl Formatter {
+    pub const fn quantity(quantity: Quantity) -> Self {
+        Self {
+            quantity: Some(quantity),
+            precision: None,
+            unit: "",
+            placeholder: "--",
+        }
+    }
+
+    pub const fn unit(unit: &'static str) -> Self {
+        Self {
+            quantity: None,
+            precision: None,
+            unit,
+            placeholder: "--",
+        }
+    }
+
+    pub const fn precision(mut self, precision: usize) -> Self {
+        self.precision = Some(precision);
+        self
+    }
+
+    pub const fn placeholder(mut self, placeholder: &'static str) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    pub fn placeholder_text(&self) -> &'static str {
+        self.placeholder
+    }
+
+    pub fn format(&self, measurement: Option<&Measurement>, units: Units) -> String {
+        let measurement = match measurement {
+            Some(measurement) => measurement,
+            None => return self.placeholder.to_string(),
+        };
+
+        match (measurement, measurement.numeric_value(), self.quantity) {
+            (_, Some(value), Some(quantity)) => format!(
+                "{:.*}{}",
+                self.precision.unwrap_or(units.decimals(quantity)),
+                units.from_imperial(quantity, value),
+                units.symbol(quantity)
+            ),
+            (_, Some(value), None) => {
+                format!("{:.*}{}", self.precision.unwrap_or(0), value, self.unit)
+            }
+            (Measurement::BatteryStatus(text), None, _)
+            | (Measurement::WindDirection(text), None, _) => format!("{}{}", text, self.unit),
+            _ => self.placeholder.to_string(),
+        }
+    }
+}
+
+// A label bound to a measurement field shows the latest value of that field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binding {
+    pub field: MeasurementField,
+    pub formatter: Formatter,
+}
@@ -8,7 +8,7 @@ use display_interface_spi::SPIInterfaceNoCS;
 
 use esp_idf_hal::{
     delay::Ets,
-    gpio::{AnyOutputPin, DriveStrength, Gpio19, Output, PinDriver},
+    gpio::{AnyOutputPin, Gpio19, Output, PinDriver},
     spi::{Dma, SpiConfig, SpiDeviceDriver, SpiDriver, SPI2},
     units::FromValueType,
 };
@@ -30,16 +30,6 @@ impl Display {
     pub fn build_display(
         display_spi_peripherals: DisplaySpiPeripherals<SPI2>,
     ) -> ADisplay {
-        if let Some(backlight) = display_spi_peripherals.control.backlight {
-            //let mut backlight = PinDriver::output(backlight)?;
-            let mut backlight = PinDriver::output(backlight).unwrap();
-
-            backlight.set_drive_strength(DriveStrength::I40mA).unwrap();
-            //backlight.set_high()?;
-            backlight.set_high().unwrap();
-            std::mem::forget(backlight); // TODO: For now
-        }
-
         let mut delay = Ets;
 
         let spi = SpiDeviceDriver::new_single(
@@ -1,69 +1,197 @@
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, Sender};
 use esp_idf_hal::{delay::FreeRtos, spi::SPI2};
 use std::borrow::BorrowMut;
+use std::fmt::Debug;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use time::{OffsetDateTime, PrimitiveDateTime};
 
 use crate::{
     gui::{
+        backlight::{Backlight, BacklightSettings, BacklightState},
+        beeper::Beeper,
         display::{ADisplay, Display},
+        kiosk::Kiosk,
+        menu::MenuValue,
+        menu::{Menu, MenuEvent},
+        settings::{apply_action, apply_setting, settings_menu_items, Setting},
         views::{
-            IndoorValueId, NavigationButtonId, OutdoorValueId, RainValueId, StatusValueId,
-            TimeDateValueId, ViewId, Views, WindValueId,
+            AlertBannerPanel, AlertBannerValueId, MenuValueId, MessageValueId, NavigationButtonId,
+            SetAction, StatusValueId, TimeDateValueId, ViewId, Views, WindRainStatusPanelId,
+            CONTENT_PANEL, MENU_ROWS,
         },
+        widgets::{Panel, SignalIconState},
     },
     model::{
-        peripherals::DisplaySpiPeripherals, scheduler::TimeDate, user_buttons::UserBtnState,
-        weather_station::Measurement,
+        alerts::{Alert, AlertEvent, AlertKind},
+        button_events::{BtnId, UserBtnState},
+        config::{Config, SharedConfig},
+        faults::{Fault, FAULTS},
+        history::Sensor,
+        measurement_cache::MeasurementCache,
+        peripherals::{DisplaySpiPeripherals, SpeakerPeripherals},
+        pressure::HPA_PER_INHG,
+        retry::retry,
+        scheduler::{format_date, format_time, TimeDate},
+        statistics::STATISTICS,
+        supervisor::Heartbeat,
+        time_date_editor::TimeDateEditor,
+        time_sync::is_clock_set,
+        time_zone::TimeZone,
+        units::{Quantity, Units},
+        weather_station::{Measurement, MeasurementField},
     },
+    net::wifi::{signal_bars, LinkState, NetworkEvent, NetworkStatus},
 };
 
+// The backlight schedule, the screen timeout and the kiosk rotation are checked this often, a wake turns the backlight
+// on straight away
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Gui {
     cbc_rx_weather_station_measurements: Receiver<Measurement>,
     cbc_rx_user_btn: Receiver<UserBtnState>,
     cbc_rx_time_date: Receiver<TimeDate>,
+    cbc_rx_network_event: Receiver<NetworkEvent>,
+    cbc_rx_alert_event: Receiver<AlertEvent>,
+    cbc_tx_set_time: Sender<OffsetDateTime>,
+    // The reference reading of the pressure calibration in hPa
+    cbc_tx_pressure_reference: Sender<f32>,
+    config: SharedConfig,
     display: ADisplay,
+    // None when the display has no backlight pin
+    backlight: Option<Backlight>,
+    backlight_state: BacklightState,
+    schedule_checked: Instant,
+    beeper: Beeper,
+    // Drawn over the view showing while any alert is active
+    alert_banner: Panel,
+    // The active alerts, the one raised last is shown in the banner
+    active_alerts: Vec<Alert>,
+    // The peripherals that stopped answering, shown in the banner ahead of the alerts
+    faults: Vec<Fault>,
+    kiosk: Kiosk,
     views: Views,
     view_showing: ViewId,
+    // Set while the time and date are being set on the TimeDate view
+    time_date_editor: Option<TimeDateEditor>,
+    settings_menu: Menu<Setting>,
+    // Set while the buttons drive the settings menu, PREV and NEXT change views again once it is left
+    settings_active: bool,
+    // The sensor heard most recently, offered for pairing in the settings menu
+    last_product_id: Option<u16>,
+    units: Units,
+    // The views render the measurements from here, a view shown later starts with the latest values
+    measurements: MeasurementCache,
+    // When the sensor first reported a low battery, None while it is good
+    battery_low_since: Option<Instant>,
+    battery_text: String,
 }
 
 impl Gui {
     pub fn new(
-        display_spi_peripherals: DisplaySpiPeripherals<SPI2>,
+        mut display_spi_peripherals: DisplaySpiPeripherals<SPI2>,
+        speaker_peripherals: SpeakerPeripherals,
         rx1: Receiver<Measurement>,
         rx2: Receiver<UserBtnState>,
         rx3: Receiver<TimeDate>,
+        rx4: Receiver<NetworkEvent>,
+        rx5: Receiver<AlertEvent>,
+        tx1: Sender<OffsetDateTime>,
+        tx2: Sender<f32>,
+        config: SharedConfig,
+        indoor_sensors: Vec<Sensor>,
     ) -> Self {
+        let (settings_menu, units, backlight_settings, kiosk_config) = {
+            let config_store = config.lock().unwrap();
+            let config = config_store.config();
+
+            (
+                Menu::new("Settings", settings_menu_items(config, None, None)),
+                config.display.units,
+                BacklightSettings::from(&config.display),
+                config.kiosk.clone(),
+            )
+        };
+
+        let views = Views::build_views(&indoor_sensors);
+
+        // The Gui owns the backlight, it is taken before the rest of the display peripherals go to the display driver
+        let backlight = display_spi_peripherals
+            .control
+            .backlight
+            .take()
+            .map(Backlight::new);
+
         Self {
             cbc_rx_weather_station_measurements: rx1,
             cbc_rx_user_btn: rx2,
             cbc_rx_time_date: rx3,
+            cbc_rx_network_event: rx4,
+            cbc_rx_alert_event: rx5,
+            cbc_tx_set_time: tx1,
+            cbc_tx_pressure_reference: tx2,
+            config,
             display: Display::build_display(display_spi_peripherals),
-            views: Views::build_views(),
+            backlight,
+            backlight_state: BacklightState::new(backlight_settings, Instant::now()),
+            schedule_checked: Instant::now(),
+            beeper: Beeper::new(speaker_peripherals),
+            alert_banner: AlertBannerPanel::build(),
+            active_alerts: Vec::new(),
+            faults: Vec::new(),
+            kiosk: Kiosk::new(&kiosk_config, |name| views.kiosk_view(name), Instant::now()),
+            views,
             view_showing: ViewId::IndoorOutdoor,
+            time_date_editor: None,
+            settings_menu,
+            settings_active: false,
+            last_product_id: None,
+            units,
+            measurements: MeasurementCache::default(),
+            battery_low_since: None,
+            battery_text: String::new(),
         }
     }
 
-    pub fn start(mut self) {
-        let _display_thread = std::thread::Builder::new()
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        std::thread::Builder::new()
             .stack_size(7000)
             .spawn(move || loop {
                 println!("Starting Gui Thread");
 
-                self.views
-                    .show_view(self.display.borrow_mut(), ViewId::IndoorOutdoor)
-                    .unwrap();
+                draw(|| {
+                    self.views
+                        .show_view(self.display.borrow_mut(), ViewId::IndoorOutdoor)
+                });
 
                 loop {
+                    heartbeat.beat();
+                    let loop_started = Instant::now();
+
                     self.check_for_time_events();
                     self.check_for_button_events();
                     self.check_for_weather_station_events();
+                    self.check_for_network_events();
+                    self.check_for_alert_events();
+                    self.check_schedules();
+                    self.beeper.update(Instant::now());
+
+                    STATISTICS.gui_loop.record(loop_started.elapsed());
 
                     FreeRtos::delay_ms(30);
                 }
-            });
+            })
+            .unwrap()
     }
 
     fn check_for_time_events(&mut self) {
         if let Ok(time_date) = self.cbc_rx_time_date.try_recv() {
+            // The time being set stays on the display until it is saved
+            if self.time_date_editor.is_some() {
+                return;
+            }
+
             match time_date {
                 TimeDate::Time(time_str) => {
                     self.update_time_date_value(TimeDateValueId::Time as usize, &time_str);
@@ -76,33 +204,165 @@ impl Gui {
         }
     }
 
-    fn check_for_button_events(&mut self) {
-        if let Ok(user_btn_state) = self.cbc_rx_user_btn.try_recv() {
-            match user_btn_state {
-                UserBtnState::Btn1Pressed => {
-                    self.show_button_pressed(NavigationButtonId::Previous as usize)
+    fn check_for_network_events(&mut self) {
+        if let Ok(network_event) = self.cbc_rx_network_event.try_recv() {
+            match network_event {
+                NetworkEvent::Provisioning { ap_ssid, address } => {
+                    self.update_signal_icon(SignalIconState::AccessPoint);
+                    self.update_status_value(
+                        StatusValueId::Ip as usize,
+                        &format!("AP {}", address),
+                    );
+
+                    self.show_message(
+                        "Wi-Fi Setup",
+                        &[
+                            "Join the Wi-Fi network",
+                            &ap_ssid,
+                            "and open the page at",
+                            &format!("http://{}", address),
+                            "Press a button to close",
+                        ],
+                    )
                 }
 
-                UserBtnState::Btn2Pressed => {
-                    self.show_button_pressed(NavigationButtonId::Set as usize)
-                }
+                NetworkEvent::CredentialsSaved { ssid } => self.show_message(
+                    "Wi-Fi Setup",
+                    &["Settings saved.", "Restarting to join", &ssid, "", ""],
+                ),
 
-                UserBtnState::Btn3Pressed => {
-                    self.show_button_pressed(NavigationButtonId::Next as usize)
-                }
+                NetworkEvent::Status(status) => self.update_network_status(&status),
+            };
+        }
+    }
+
+    fn check_for_alert_events(&mut self) {
+        let mut changed = false;
 
-                UserBtnState::Btn1Released => {
-                    self.show_button_released(NavigationButtonId::Previous as usize);
-                    self.show_previous_view();
+        while let Ok(alert_event) = self.cbc_rx_alert_event.try_recv() {
+            match alert_event {
+                AlertEvent::Raised(alert) => {
+                    self.active_alerts
+                        .retain(|active| active.kind != alert.kind);
+                    self.active_alerts.push(alert);
+
+                    self.wake_backlight();
+                    if self.config.lock().unwrap().config().alerts.beep {
+                        self.beeper.beep(Instant::now());
+                    }
+                }
+                AlertEvent::Cleared(kind) => {
+                    self.active_alerts.retain(|active| active.kind != kind)
                 }
-                UserBtnState::Btn2Released => {
-                    self.show_button_released(NavigationButtonId::Set as usize)
+            }
+
+            changed = true;
+        }
+
+        if changed {
+            self.update_alert_banner();
+        }
+    }
+
+    // Faults are raised by the other threads in a static, the banner is redrawn when the active ones change
+    fn check_for_faults(&mut self) {
+        let faults = FAULTS.active();
+
+        if faults != self.faults {
+            self.faults = faults;
+            self.update_alert_banner();
+        }
+    }
+
+    // The banner shows the fault or the alert raised last and how many others are active, e.g. "BME280 fault  +1"
+    fn update_alert_banner(&mut self) {
+        let mut texts: Vec<String> = self
+            .active_alerts
+            .iter()
+            .map(|alert| alert_text(alert, self.units))
+            .collect();
+        texts.extend(
+            self.faults
+                .iter()
+                .map(|fault| format!("{} fault", fault.name())),
+        );
+
+        match texts.pop() {
+            Some(mut text) => {
+                if !texts.is_empty() {
+                    text.push_str(&format!("  +{}", texts.len()));
                 }
 
-                UserBtnState::Btn3Released => {
-                    self.show_button_released(NavigationButtonId::Next as usize);
+                draw(|| {
+                    self.alert_banner.update_value(
+                        self.display.borrow_mut(),
+                        AlertBannerValueId::Text as usize,
+                        &text,
+                    )
+                });
+                draw(|| self.alert_banner.show(self.display.borrow_mut()));
+            }
+
+            // The view is drawn again where the banner was
+            None => draw(|| {
+                self.views
+                    .show_view(self.display.borrow_mut(), self.view_showing)
+            }),
+        }
+    }
+
+    // The banner is drawn again after anything that may have drawn over it
+    fn show_alert_banner(&mut self) {
+        if !self.active_alerts.is_empty() || !self.faults.is_empty() {
+            draw(|| self.alert_banner.show(self.display.borrow_mut()));
+        }
+    }
+
+    fn check_for_button_events(&mut self) {
+        // A release can be followed by a click and a double click, they are all handled in the same pass
+        while let Ok(user_btn_state) = self.cbc_rx_user_btn.try_recv() {
+            self.kiosk.pause(Instant::now());
+
+            // The press that wakes the screen only wakes it
+            if !self
+                .backlight_state
+                .button_event(user_btn_state, Instant::now())
+            {
+                self.set_backlight();
+                continue;
+            }
+
+            // The message view has no navigation, any click closes it and the station carries on without wifi
+            if matches!(self.view_showing, ViewId::Message) {
+                if let UserBtnState::Clicked(_) = user_btn_state {
                     self.show_next_view();
                 }
+                continue;
+            }
+
+            match user_btn_state {
+                UserBtnState::Pressed(btn) => self.show_button_pressed(navigation_button_id(btn)),
+
+                UserBtnState::Released(btn) => self.show_button_released(navigation_button_id(btn)),
+
+                _ if self.time_date_editor.is_some() => self.edit_time_date(user_btn_state),
+
+                _ if self.settings_active => self.use_settings_menu(user_btn_state),
+
+                UserBtnState::Clicked(BtnId::Btn1) => self.show_previous_view(),
+
+                UserBtnState::Clicked(BtnId::Btn2) => {
+                    match self.views.set_action(self.view_showing) {
+                        Some(SetAction::EditTimeDate) => self.start_time_date_edit(),
+                        Some(SetAction::OpenSettingsMenu) => {
+                            self.settings_active = true;
+                            self.show_settings_menu();
+                        }
+                        None => (),
+                    }
+                }
+
+                UserBtnState::Clicked(BtnId::Btn3) => self.show_next_view(),
 
                 _ => (),
             };
@@ -110,200 +370,647 @@ impl Gui {
     }
 
     fn check_for_weather_station_events(&mut self) {
+        // The units are read from the config each pass so a change from the settings menu or the web page shows at once
+        let units = self.config.lock().unwrap().config().display.units;
+        let mut changed = units != self.units;
+        self.units = units;
+
+        let mut refresh_settings = false;
+
         while let Ok(measurement) = self.cbc_rx_weather_station_measurements.try_recv() {
-            match measurement {
-                Measurement::ChannelNumber(channel) => {
-                    let value_str = &format!("{}", channel);
-                    self.update_status_value(StatusValueId::Channel as usize, value_str);
+            match &measurement {
+                Measurement::ProductId(product_id) => self.last_product_id = Some(*product_id),
+                // The calibration starts from the pressure reported last, and shows the offset a calibration saved
+                Measurement::IndoorPressure(_) => refresh_settings = true,
+                Measurement::BatteryStatus(status) => {
+                    self.battery_low_since = match (status.as_str(), self.battery_low_since) {
+                        ("OK", _) => None,
+                        (_, Some(low_since)) => Some(low_since),
+                        (_, None) => Some(Instant::now()),
+                    };
+                    self.update_battery_status();
                 }
+                _ => (),
+            }
 
-                Measurement::ProductId(product_id) => {
-                    let value_str = &format!("{}", product_id);
-                    self.update_status_value(StatusValueId::ProductId as usize, value_str);
-                }
+            self.measurements.update(measurement);
+            changed = true;
+        }
 
-                Measurement::BatteryStatus(battery_status) => {
-                    self.update_status_value(StatusValueId::Battery as usize, &battery_status)
-                }
+        if refresh_settings && self.settings_active && !self.settings_menu.is_editing() {
+            self.refresh_settings_menu();
+        }
 
-                Measurement::CurrentWindSpeedMph(current_wind_speed) => {
-                    let value_str = &format!("{}{}", current_wind_speed, "mph");
-                    self.update_wind_value(WindValueId::CurrentWindSpeed as usize, value_str);
-                }
+        // The other views are rendered from the cache when they are shown
+        if changed {
+            self.update_bindings(self.view_showing);
+            self.show_alert_banner();
+        }
+    }
 
-                Measurement::AverageWindSpeedMph(average_wind_speed) => {
-                    let value_str = &format!("{}{}", average_wind_speed, "mph");
-                    self.update_wind_value(WindValueId::AverageWindSpeed as usize, value_str);
-                }
+    fn update_bindings(&mut self, view_id: ViewId) {
+        draw(|| {
+            self.views.update_bindings(
+                self.display.borrow_mut(),
+                view_id,
+                &self.measurements,
+                self.units,
+            )
+        });
+    }
 
-                Measurement::PeakWindSpeedMph(peak_wind_speed) => {
-                    let value_str = &format!("{}{}", peak_wind_speed, "mph");
-                    self.update_wind_value(WindValueId::PeakWindSpeed as usize, value_str);
-                }
+    /**
+     * While the time is being set PREV and NEXT step the highlighted field down and up, and keep stepping while they
+     * are held.  SET moves on to the next field, holding SET saves the time, date and clock format and pressing PREV
+     * and NEXT together leaves without saving.
+     */
+    fn edit_time_date(&mut self, user_btn_state: UserBtnState) {
+        let editor = match self.time_date_editor.as_mut() {
+            Some(editor) => editor,
+            None => return,
+        };
 
-                Measurement::WindDirection(wind_direction) => {
-                    //println!("Got new wind direction = {}", wind_direction);
-                }
+        match user_btn_state {
+            UserBtnState::Clicked(BtnId::Btn1)
+            | UserBtnState::Held(BtnId::Btn1)
+            | UserBtnState::Repeated(BtnId::Btn1) => editor.decrement(),
 
-                Measurement::DailyRainfall(daily_rainfall) => {
-                    let value_str = &format!("{:.2}{}", daily_rainfall, "in");
-                    self.update_rain_value(RainValueId::DailyRainfall as usize, value_str);
-                }
+            UserBtnState::Clicked(BtnId::Btn3)
+            | UserBtnState::Held(BtnId::Btn3)
+            | UserBtnState::Repeated(BtnId::Btn3) => editor.increment(),
 
-                Measurement::MonthlyRainfall(monthly_rainfall) => {
-                    let value_str = &format!("{:.2}{}", monthly_rainfall, "in");
-                    self.update_rain_value(RainValueId::MonthlyRainfall as usize, value_str);
-                }
+            UserBtnState::Clicked(BtnId::Btn2) => editor.next_field(),
 
-                Measurement::YearlyRainfall(yearly_rainfall) => {
-                    let value_str = &format!("{:.2}{}", yearly_rainfall, "in");
-                    self.update_rain_value(RainValueId::YearlyRainfall as usize, value_str);
-                }
+            UserBtnState::Held(BtnId::Btn2) => {
+                self.save_time_date();
+                return;
+            }
 
-                Measurement::OutdoorTemperature(outdoor_temperature) => {
-                    let value_str = &format!("{}{}", outdoor_temperature, "F");
-                    self.update_outdoor_value(OutdoorValueId::Temperature as usize, value_str);
-                }
+            UserBtnState::Chord(BtnId::Btn1, BtnId::Btn3) => {
+                self.cancel_time_date_edit();
+                return;
+            }
 
-                Measurement::OutdoorHumidity(outdoor_humidity) => {
-                    let value_str = &format!("{}{}", outdoor_humidity, "%");
-                    self.update_outdoor_value(OutdoorValueId::Humidity as usize, value_str);
-                }
+            _ => return,
+        }
 
-                Measurement::OutdoorHeatIndex(outdoor_heat_index) => {
-                    let value_str = &format!("{}{}", outdoor_heat_index, "F");
-                    self.update_outdoor_value(OutdoorValueId::HeatIndex as usize, value_str);
-                }
+        self.show_time_date_editor();
+    }
 
-                Measurement::OutdoorWindchill(outdoor_wind_chill) => {
-                    let value_str = &format!("{}{}", outdoor_wind_chill, "F");
-                    self.update_outdoor_value(OutdoorValueId::WindChill as usize, value_str);
-                }
+    fn start_time_date_edit(&mut self) {
+        let (time_zone, clock_24h) = self.time_settings();
+        let local = time_zone.to_local(OffsetDateTime::now_utc());
+
+        self.time_date_editor = Some(TimeDateEditor::new(
+            PrimitiveDateTime::new(local.date(), local.time()),
+            clock_24h,
+        ));
+
+        self.set_step_buttons("-", "+");
+        self.update_time_date_value(
+            TimeDateValueId::Help as usize,
+            "SET next - hold SET to save",
+        );
+        self.show_time_date_editor();
+    }
 
-                Measurement::OutdoorDewpoint(outdoor_dew_point) => {
-                    let value_str = &format!("{}{}", outdoor_dew_point, "F");
-                    self.update_outdoor_value(OutdoorValueId::DewPoint as usize, value_str);
-                }
+    fn show_time_date_editor(&mut self) {
+        if let Some(editor) = &self.time_date_editor {
+            let time_str = editor.time_text();
+            let date_str = editor.date_text();
+            let format_str = editor.format_text();
 
-                Measurement::IndoorTemperature(indoor_temperature) => {
-                    let value_str = &format!("{}{}", indoor_temperature, "F");
-                    self.update_indoor_value(IndoorValueId::Temperature as usize, value_str);
-                }
+            self.update_time_date_value(TimeDateValueId::Time as usize, &time_str);
+            self.update_time_date_value(TimeDateValueId::Date as usize, &date_str);
+            self.update_time_date_value(TimeDateValueId::ClockFormat as usize, &format_str);
+        }
+    }
 
-                Measurement::IndoorHumidity(indoor_humidity) => {
-                    let value_str = &format!("{}{}", indoor_humidity, "%");
-                    self.update_indoor_value(IndoorValueId::Humidity as usize, value_str);
-                }
+    // The local time is converted to UTC for the clocks, the clock format is saved in the config
+    fn save_time_date(&mut self) {
+        let editor = match self.time_date_editor.take() {
+            Some(editor) => editor,
+            None => return,
+        };
+
+        let (time_zone, clock_24h) = self.time_settings();
+        let utc = time_zone.from_local(editor.date_time());
+
+        if let Err(e) = self.cbc_tx_set_time.try_send(utc) {
+            println!("Failed to set the time {:?}", e);
+        }
+
+        if editor.clock_24h() != clock_24h {
+            let mut config_store = self.config.lock().unwrap();
+            let mut config = config_store.config().clone();
+            config.station.clock_24h = editor.clock_24h();
+
+            if let Err(e) = config_store.update(config) {
+                println!("Failed to save the clock format {}", e);
+            }
+        }
+
+        self.end_time_date_edit(editor.date_time(), editor.clock_24h());
+    }
+
+    // Setting the time is given up on PREV and NEXT together or when a message takes over the display, the time shown
+    // goes back to the clock
+    fn cancel_time_date_edit(&mut self) {
+        if self.time_date_editor.take().is_some() {
+            let (time_zone, clock_24h) = self.time_settings();
+            let local = time_zone.to_local(OffsetDateTime::now_utc());
+
+            self.end_time_date_edit(
+                PrimitiveDateTime::new(local.date(), local.time()),
+                clock_24h,
+            );
+        }
+    }
+
+    fn end_time_date_edit(&mut self, local: PrimitiveDateTime, clock_24h: bool) {
+        let time_str = format_time(local, clock_24h);
+        let date_str = format_date(local);
+
+        self.update_time_date_value(TimeDateValueId::Time as usize, &time_str);
+        self.update_time_date_value(TimeDateValueId::Date as usize, &date_str);
+        self.update_time_date_value(TimeDateValueId::ClockFormat as usize, "");
+        self.update_time_date_value(TimeDateValueId::Help as usize, "");
+        self.set_step_buttons("PREV", "NEXT");
+    }
+
+    fn time_settings(&self) -> (TimeZone, bool) {
+        let config = self.config.lock().unwrap();
+        let station = &config.config().station;
+
+        (
+            TimeZone::parse(&station.time_zone).unwrap_or_else(TimeZone::utc),
+            station.clock_24h,
+        )
+    }
 
-                Measurement::IndoorPressure(indoor_pressure) => {
-                    let value_str = &format!("{:.2}{}", indoor_pressure, " inHg");
-                    self.update_indoor_value(IndoorValueId::Pressure as usize, value_str);
+    /**
+     * While the settings menu is in use PREV and NEXT move through the list and SET opens the item, while a number or a
+     * choice is being changed PREV and NEXT step it and SET keeps it.  PREV and NEXT pressed together cancel the change
+     * or go back up a level.  Every change is saved as soon as it is made, a value the config does not allow is not
+     * saved and the menu goes back to the saved value.
+     */
+    fn use_settings_menu(&mut self, user_btn_state: UserBtnState) {
+        let menu_event = match user_btn_state {
+            UserBtnState::Clicked(BtnId::Btn1)
+            | UserBtnState::Held(BtnId::Btn1)
+            | UserBtnState::Repeated(BtnId::Btn1) => {
+                self.settings_menu.previous();
+                MenuEvent::None
+            }
+
+            UserBtnState::Clicked(BtnId::Btn3)
+            | UserBtnState::Held(BtnId::Btn3)
+            | UserBtnState::Repeated(BtnId::Btn3) => {
+                self.settings_menu.next();
+                MenuEvent::None
+            }
+
+            UserBtnState::Clicked(BtnId::Btn2) => self.settings_menu.select(),
+
+            UserBtnState::Chord(BtnId::Btn1, BtnId::Btn3) => self.settings_menu.back(),
+
+            _ => return,
+        };
+
+        let saved = match menu_event {
+            MenuEvent::None => true,
+
+            MenuEvent::Changed(Setting::PressureReference, MenuValue::Number(reference)) => {
+                let reference_hpa =
+                    self.units.to_imperial(Quantity::Pressure, reference) * HPA_PER_INHG;
+                self.cbc_tx_pressure_reference
+                    .try_send(reference_hpa)
+                    .is_ok()
+            }
+
+            MenuEvent::Changed(setting, value) => {
+                self.update_settings(|config| apply_setting(config, setting, value))
+            }
+
+            MenuEvent::Action(setting) => {
+                let last_product_id = self.last_product_id;
+                self.update_settings(|config| apply_action(config, setting, last_product_id))
+            }
+
+            MenuEvent::Exit => {
+                self.settings_active = false;
+                true
+            }
+        };
+
+        self.show_settings_menu();
+
+        if !saved {
+            self.update_settings_value(MenuValueId::Help as usize, "Not saved - value not allowed");
+        }
+    }
+
+    // Save a change to the config, the menu is built again from the saved config either way
+    fn update_settings<F>(&mut self, change: F) -> bool
+    where
+        F: FnOnce(&mut Config),
+    {
+        let pressure = self.reported_pressure();
+        let mut config_store = self.config.lock().unwrap();
+        let mut config = config_store.config().clone();
+        change(&mut config);
+
+        let saved = match config_store.update(config) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Failed to save the settings {}", e);
+                false
+            }
+        };
+
+        self.settings_menu.replace_items(settings_menu_items(
+            config_store.config(),
+            self.last_product_id,
+            pressure,
+        ));
+
+        saved
+    }
+
+    // A message taking over the display leaves the settings menu at the top level, a change not kept is given up
+    fn leave_settings_menu(&mut self) {
+        if self.settings_active {
+            while self.settings_menu.back() != MenuEvent::Exit {}
+            self.settings_active = false;
+            self.show_settings_menu();
+        }
+    }
+
+    fn refresh_settings_menu(&mut self) {
+        let items = settings_menu_items(
+            self.config.lock().unwrap().config(),
+            self.last_product_id,
+            self.reported_pressure(),
+        );
+        self.settings_menu.replace_items(items);
+        self.show_settings_menu();
+    }
+
+    fn reported_pressure(&self) -> Option<f32> {
+        self.measurements
+            .get(MeasurementField::IndoorPressure)
+            .and_then(Measurement::numeric_value)
+    }
+
+    // The list scrolls to keep the selected item on the display
+    fn show_settings_menu(&mut self) {
+        let menu = &self.settings_menu;
+
+        let help = if !self.settings_active {
+            "Press SET to change settings"
+        } else if menu.is_editing() {
+            "SET keep  PREV+NEXT cancel"
+        } else {
+            "SET select  PREV+NEXT back"
+        };
+
+        let first = (menu.selected() + 1).saturating_sub(MENU_ROWS);
+        let rows: Vec<(String, String)> = (first..first + MENU_ROWS)
+            .map(|index| match menu.items().get(index) {
+                Some(item) if self.settings_active && index == menu.selected() => {
+                    let value = if menu.is_editing() {
+                        format!("[{}]", item.value_text())
+                    } else {
+                        item.value_text()
+                    };
+
+                    (format!("> {}", item.label), value)
                 }
-            };
+                Some(item) => (item.label.clone(), item.value_text()),
+                None => (String::new(), String::new()),
+            })
+            .collect();
+        let title = menu.title().to_string();
+
+        self.update_settings_value(MenuValueId::Title as usize, &title);
+        self.update_settings_value(MenuValueId::Help as usize, help);
+
+        for (row, (name, value)) in rows.iter().enumerate() {
+            let name_id = MenuValueId::FirstRow as usize + 2 * row;
+            self.update_settings_value(name_id, name);
+            self.update_settings_value(name_id + 1, value);
         }
+
+        let (previous, next) = if !self.settings_active {
+            ("PREV", "NEXT")
+        } else if self.settings_menu.is_editing() {
+            ("-", "+")
+        } else {
+            ("UP", "DOWN")
+        };
+        self.set_step_buttons(previous, next);
     }
 
-    fn update_status_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .wind_rain_status_view
-            .status_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+    fn set_step_buttons(&mut self, previous: &str, next: &str) {
+        let navigation_panel = match self.views.navigation_panel(self.view_showing) {
+            Some(navigation_panel) => navigation_panel,
+            None => return,
+        };
+
+        draw(|| {
+            navigation_panel.set_button_text(
+                self.display.borrow_mut(),
+                NavigationButtonId::Previous as usize,
+                previous,
+            )
+        });
+        draw(|| {
+            navigation_panel.set_button_text(
+                self.display.borrow_mut(),
+                NavigationButtonId::Next as usize,
+                next,
+            )
+        });
+    }
+
+    fn check_schedules(&mut self) {
+        if self.schedule_checked.elapsed() < SCHEDULE_CHECK_INTERVAL {
+            return;
+        }
+        self.schedule_checked = Instant::now();
+
+        let settings = {
+            let config_store = self.config.lock().unwrap();
+            let config = config_store.config();
+
+            self.kiosk
+                .update(&config.kiosk, |name| self.views.kiosk_view(name));
+            BacklightSettings::from(&config.display)
+        };
+        let local_hour = if is_clock_set() {
+            let (time_zone, _) = self.time_settings();
+            Some(time_zone.to_local(OffsetDateTime::now_utc()).hour())
+        } else {
+            None
+        };
+
+        self.backlight_state.update(settings, local_hour);
+        self.set_backlight();
+
+        self.rotate_kiosk_view();
+        self.update_battery_status();
+        self.check_for_faults();
+    }
+
+    // The battery value counts up how long the battery has been low, the label is only redrawn when the text changes
+    fn update_battery_status(&mut self) {
+        let battery_text = match self.battery_low_since {
+            Some(low_since) => format!("Low {}", low_duration(low_since.elapsed())),
+            None if self
+                .measurements
+                .get(MeasurementField::BatteryStatus)
+                .is_some() =>
+            {
+                "OK".to_string()
+            }
+            None => "--".to_string(),
+        };
+
+        if battery_text != self.battery_text {
+            self.update_status_value(StatusValueId::Battery as usize, &battery_text);
+            self.battery_text = battery_text;
+        }
+    }
+
+    // The rotation waits while the time or the settings are being changed and while a message is showing
+    fn rotate_kiosk_view(&mut self) {
+        if self.time_date_editor.is_some()
+            || self.settings_active
+            || matches!(self.view_showing, ViewId::Message)
+        {
+            return;
+        }
+
+        if let Some(view_id) = self.kiosk.next_view(self.view_showing, Instant::now()) {
+            self.show_view(view_id);
+        }
+    }
+
+    // Alerts and messages wake the screen
+    fn wake_backlight(&mut self) {
+        self.backlight_state.wake(Instant::now());
+        self.set_backlight();
     }
 
-    fn update_wind_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .wind_rain_status_view
-            .wind_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+    fn set_backlight(&mut self) {
+        let percent = self.backlight_state.percent(Instant::now());
+
+        if let Some(backlight) = self.backlight.as_mut() {
+            backlight.set_percent(percent);
+        }
     }
 
-    fn update_rain_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .wind_rain_status_view
-            .rain_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+    fn update_network_status(&mut self, status: &NetworkStatus) {
+        let icon_state = match (status.state, status.rssi) {
+            (LinkState::Connected, Some(rssi)) => SignalIconState::Connected(signal_bars(rssi)),
+            (LinkState::Connected, None) => SignalIconState::Connecting,
+            (LinkState::Reconnecting, _) => SignalIconState::Disconnected,
+        };
+        self.update_signal_icon(icon_state);
+
+        let ip_str = match (status.state, status.ip) {
+            (LinkState::Connected, Some(ip)) => format!("IP {}", ip),
+            (LinkState::Connected, None) => "Connecting".to_string(),
+            (LinkState::Reconnecting, _) => "Reconnecting".to_string(),
+        };
+        self.update_status_value(StatusValueId::Ip as usize, &ip_str);
+
+        let rssi_str = match status.rssi {
+            Some(rssi) => format!("{}dBm", rssi),
+            None => "--".to_string(),
+        };
+        self.update_status_value(StatusValueId::Rssi as usize, &rssi_str);
     }
 
-    fn update_outdoor_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .indoor_outdoor_view
-            .outdoor_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+    // Every view has its own navigation panel, all of them are kept up to date so the icon is right after a view change
+    fn update_signal_icon(&mut self, state: SignalIconState) {
+        for navigation_panel in self.views.navigation_panels() {
+            draw(|| navigation_panel.update_signal_icon(self.display.borrow_mut(), state));
+        }
     }
 
-    fn update_indoor_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .indoor_outdoor_view
-            .indoor_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+    fn update_status_value(&mut self, value_id: usize, value: &str) {
+        self.update_view_value(
+            ViewId::WindRainStatus,
+            WindRainStatusPanelId::Status as usize,
+            value_id,
+            value,
+        );
     }
 
     fn update_time_date_value(&mut self, value_id: usize, value: &str) {
-        self.views
-            .time_date_view
-            .time_date_panel
-            .update_value(self.display.borrow_mut(), value_id, value)
-            .unwrap();
+        self.update_view_value(ViewId::TimeDate, CONTENT_PANEL, value_id, value);
     }
 
-    fn show_next_view(&mut self) {
-        self.views
-            .hide_view(self.display.borrow_mut(), self.view_showing)
-            .unwrap();
+    fn update_settings_value(&mut self, value_id: usize, value: &str) {
+        self.update_view_value(ViewId::Settings, CONTENT_PANEL, value_id, value);
+    }
+
+    fn update_view_value(&mut self, view_id: ViewId, panel: usize, value_id: usize, value: &str) {
+        draw(|| {
+            self.views.panel(view_id, panel).update_value(
+                self.display.borrow_mut(),
+                value_id,
+                value,
+            )
+        });
+
+        if view_id == self.view_showing {
+            self.show_alert_banner();
+        }
+    }
 
-        match self.view_showing {
-            ViewId::IndoorOutdoor => self.view_showing = ViewId::WindRainStatus,
-            ViewId::WindRainStatus => self.view_showing = ViewId::TimeDate,
-            ViewId::TimeDate => self.view_showing = ViewId::IndoorOutdoor,
+    fn show_message(&mut self, title: &str, lines: &[&str; 5]) {
+        self.cancel_time_date_edit();
+        self.leave_settings_menu();
+        self.wake_backlight();
+
+        let message_panel = self.views.panel(ViewId::Message, CONTENT_PANEL);
+
+        draw(|| {
+            message_panel.update_value(
+                self.display.borrow_mut(),
+                MessageValueId::Title as usize,
+                title,
+            )
+        });
+        for (line, text) in lines.iter().enumerate() {
+            draw(|| {
+                message_panel.update_value(
+                    self.display.borrow_mut(),
+                    MessageValueId::Line1 as usize + line,
+                    text,
+                )
+            });
         }
 
-        self.views
-            .show_view(self.display.borrow_mut(), self.view_showing)
-            .unwrap();
+        if !matches!(self.view_showing, ViewId::Message) {
+            draw(|| {
+                self.views
+                    .hide_view(self.display.borrow_mut(), self.view_showing)
+            });
+            self.view_showing = ViewId::Message;
+            draw(|| {
+                self.views
+                    .show_view(self.display.borrow_mut(), self.view_showing)
+            });
+            self.show_alert_banner();
+        }
+    }
+
+    fn show_next_view(&mut self) {
+        let view_id = self.views.next_view(self.view_showing);
+        self.show_view(view_id);
     }
 
     fn show_previous_view(&mut self) {
-        self.views
-            .hide_view(self.display.borrow_mut(), self.view_showing)
-            .unwrap();
+        let view_id = self.views.previous_view(self.view_showing);
+        self.show_view(view_id);
+    }
 
-        match self.view_showing {
-            ViewId::IndoorOutdoor => self.view_showing = ViewId::TimeDate,
-            ViewId::WindRainStatus => self.view_showing = ViewId::IndoorOutdoor,
-            ViewId::TimeDate => self.view_showing = ViewId::WindRainStatus,
+    fn show_view(&mut self, view_id: ViewId) {
+        draw(|| {
+            self.views
+                .hide_view(self.display.borrow_mut(), self.view_showing)
+        });
+
+        self.view_showing = view_id;
+
+        // The settings may have been changed from the web page since the menu was last shown
+        if matches!(self.view_showing, ViewId::Settings) {
+            self.refresh_settings_menu();
         }
 
-        self.views
-            .show_view(self.display.borrow_mut(), self.view_showing)
-            .unwrap();
+        // Rendered while the view is hidden so it is drawn once, with the latest values
+        self.update_bindings(self.view_showing);
+
+        draw(|| {
+            self.views
+                .show_view(self.display.borrow_mut(), self.view_showing)
+        });
+        self.show_alert_banner();
+        self.kiosk.view_shown(Instant::now());
     }
 
     fn show_button_pressed(&mut self, button_id: usize) {
-        let navigation_panel = match self.view_showing {
-            ViewId::IndoorOutdoor => &mut self.views.indoor_outdoor_view.navigation_panel,
-            ViewId::WindRainStatus => &mut self.views.wind_rain_status_view.navigation_panel,
-            ViewId::TimeDate => &mut self.views.time_date_view.navigation_panel,
+        let navigation_panel = match self.views.navigation_panel(self.view_showing) {
+            Some(navigation_panel) => navigation_panel,
+            None => return,
         };
 
-        navigation_panel
-            .show_button_pressed(self.display.borrow_mut(), button_id)
-            .unwrap();
+        draw(|| navigation_panel.show_button_pressed(self.display.borrow_mut(), button_id));
     }
 
     fn show_button_released(&mut self, button_id: usize) {
-        let navigation_panel = match self.view_showing {
-            ViewId::IndoorOutdoor => &mut self.views.indoor_outdoor_view.navigation_panel,
-            ViewId::WindRainStatus => &mut self.views.wind_rain_status_view.navigation_panel,
-            ViewId::TimeDate => &mut self.views.time_date_view.navigation_panel,
+        let navigation_panel = match self.views.navigation_panel(self.view_showing) {
+            Some(navigation_panel) => navigation_panel,
+            None => return,
         };
 
-        navigation_panel
-            .show_button_released(self.display.borrow_mut(), button_id)
-            .unwrap();
+        draw(|| navigation_panel.show_button_released(self.display.borrow_mut(), button_id));
+    }
+}
+
+// Draw on the display, retrying a failed SPI transfer.  A draw that still fails raises the display fault and is
+// skipped, the next change of the same value draws it again
+fn draw<E: Debug>(operation: impl FnMut() -> Result<(), E>) {
+    match retry(operation) {
+        Ok(_) => {
+            if FAULTS.clear(Fault::Display) {
+                println!("The display is answering again");
+            }
+        }
+        Err(e) => {
+            if FAULTS.raise(Fault::Display) {
+                println!("Failed to draw on the display {:?}", e);
+            }
+        }
+    }
+}
+
+fn navigation_button_id(btn: BtnId) -> usize {
+    match btn {
+        BtnId::Btn1 => NavigationButtonId::Previous as usize,
+        BtnId::Btn2 => NavigationButtonId::Set as usize,
+        BtnId::Btn3 => NavigationButtonId::Next as usize,
+    }
+}
+
+// The time the battery has been low in its largest unit, short enough for the status panel column
+fn low_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        0..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+// The banner text of an alert, e.g. "Frost 30F" or "Rain rate 1.20in/h"
+fn alert_text(alert: &Alert, units: Units) -> String {
+    match alert.kind {
+        AlertKind::IndoorHumidityLow | AlertKind::IndoorHumidityHigh => {
+            format!("{} {}%", alert.kind.title(), alert.value)
+        }
+        AlertKind::RainRate => format!(
+            "{} {}/h",
+            alert.kind.title(),
+            units.format(Quantity::Rainfall, alert.value)
+        ),
+        kind => match kind.quantity() {
+            Some(quantity) => format!("{} {}", kind.title(), units.format(quantity, alert.value)),
+            None => kind.title().to_string(),
+        },
     }
 }
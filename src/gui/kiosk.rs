@@ -0,0 +1,239 @@
+use std::time::{Duration, Instant};
+
+use crate::{gui::views::ViewId, model::config::KioskConfig};
+
+/**
+ * Rotates through the kiosk views.  The view showing stays up for its dwell time and is then replaced by the next one
+ * in the list.  A button pauses the rotation, once the station has been left alone for the resume time the view
+ * showing gets a full dwell time again, a view that is not part of the rotation is replaced straight away.
+ *
+ * The views are listed by name in the config, kiosk_view finds them in the view registry.  A name that is not a view
+ * of the station is left out of the rotation.
+ */
+pub struct Kiosk {
+    config: KioskConfig,
+    enabled: bool,
+    views: Vec<(ViewId, Duration)>,
+    resume_after: Duration,
+    shown_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Kiosk {
+    pub fn new<F>(kiosk_config: &KioskConfig, kiosk_view: F, now: Instant) -> Self
+    where
+        F: Fn(&str) -> Option<ViewId>,
+    {
+        let mut kiosk = Self {
+            config: KioskConfig::default(),
+            enabled: false,
+            views: Vec::new(),
+            resume_after: Duration::ZERO,
+            shown_at: now,
+            paused_until: None,
+        };
+        kiosk.set_config(kiosk_config, kiosk_view);
+
+        kiosk
+    }
+
+    // Called with the config each time the schedule is checked, the views are only looked up again when it changed
+    pub fn update<F>(&mut self, kiosk_config: &KioskConfig, kiosk_view: F)
+    where
+        F: Fn(&str) -> Option<ViewId>,
+    {
+        if *kiosk_config != self.config {
+            self.set_config(kiosk_config, kiosk_view);
+        }
+    }
+
+    fn set_config<F>(&mut self, kiosk_config: &KioskConfig, kiosk_view: F)
+    where
+        F: Fn(&str) -> Option<ViewId>,
+    {
+        self.config = kiosk_config.clone();
+        self.enabled = kiosk_config.enabled;
+        self.views = kiosk_config
+            .views
+            .iter()
+            .filter_map(|view_config| match kiosk_view(&view_config.view) {
+                Some(view_id) => {
+                    Some((view_id, Duration::from_secs(view_config.dwell_secs as u64)))
+                }
+                None => {
+                    println!(
+                        "Kiosk view {} is not shown on this station",
+                        view_config.view
+                    );
+                    None
+                }
+            })
+            .collect();
+        self.resume_after = Duration::from_secs(kiosk_config.resume_after_secs as u64);
+    }
+
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_until = Some(now + self.resume_after);
+    }
+
+    // Called whenever a view is shown, by the rotation or by hand
+    pub fn view_shown(&mut self, now: Instant) {
+        self.shown_at = now;
+    }
+
+    // The view to show next, None while the view showing should stay
+    pub fn next_view(&mut self, showing: ViewId, now: Instant) -> Option<ViewId> {
+        if !self.enabled || self.views.is_empty() {
+            return None;
+        }
+
+        if let Some(paused_until) = self.paused_until {
+            if now < paused_until {
+                return None;
+            }
+
+            self.paused_until = None;
+            self.shown_at = now;
+        }
+
+        match self.views.iter().position(|(view, _)| *view == showing) {
+            Some(index) => {
+                let (_, dwell) = self.views[index];
+                let (next, _) = self.views[(index + 1) % self.views.len()];
+
+                if now.duration_since(self.shown_at) >= dwell && next != showing {
+                    Some(next)
+                } else {
+                    None
+                }
+            }
+            None => Some(self.views[0].0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::config::KioskViewConfig;
+
+    fn kiosk_view(name: &str) -> Option<ViewId> {
+        match name {
+            "indoor_outdoor" => Some(ViewId::IndoorOutdoor),
+            "wind_rain_status" => Some(ViewId::WindRainStatus),
+            "time_date" => Some(ViewId::TimeDate),
+            _ => None,
+        }
+    }
+
+    fn config(views: &[(&str, u32)]) -> KioskConfig {
+        KioskConfig {
+            enabled: true,
+            views: views
+                .iter()
+                .map(|(view, dwell_secs)| KioskViewConfig {
+                    view: view.to_string(),
+                    dwell_secs: *dwell_secs,
+                })
+                .collect(),
+            resume_after_secs: 60,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn each_view_stays_up_for_its_dwell_time() {
+        let t0 = Instant::now();
+        let mut kiosk = Kiosk::new(
+            &config(&[("indoor_outdoor", 10), ("time_date", 30)]),
+            kiosk_view,
+            t0,
+        );
+
+        assert_eq!(kiosk.next_view(ViewId::IndoorOutdoor, t0 + secs(9)), None);
+        assert_eq!(
+            kiosk.next_view(ViewId::IndoorOutdoor, t0 + secs(10)),
+            Some(ViewId::TimeDate)
+        );
+
+        let t1 = t0 + secs(10);
+        kiosk.view_shown(t1);
+        assert_eq!(kiosk.next_view(ViewId::TimeDate, t1 + secs(29)), None);
+        assert_eq!(
+            kiosk.next_view(ViewId::TimeDate, t1 + secs(30)),
+            Some(ViewId::IndoorOutdoor)
+        );
+    }
+
+    #[test]
+    fn a_button_pauses_the_rotation_until_the_resume_time() {
+        let t0 = Instant::now();
+        let mut kiosk = Kiosk::new(
+            &config(&[("indoor_outdoor", 10), ("time_date", 10)]),
+            kiosk_view,
+            t0,
+        );
+
+        kiosk.pause(t0 + secs(5));
+        assert_eq!(kiosk.next_view(ViewId::IndoorOutdoor, t0 + secs(64)), None);
+
+        // The view showing gets a full dwell time once the rotation resumes
+        let resumed = t0 + secs(65);
+        assert_eq!(kiosk.next_view(ViewId::IndoorOutdoor, resumed), None);
+        assert_eq!(
+            kiosk.next_view(ViewId::IndoorOutdoor, resumed + secs(9)),
+            None
+        );
+        assert_eq!(
+            kiosk.next_view(ViewId::IndoorOutdoor, resumed + secs(10)),
+            Some(ViewId::TimeDate)
+        );
+    }
+
+    #[test]
+    fn a_view_outside_the_rotation_is_replaced_by_the_first_view() {
+        let t0 = Instant::now();
+        let mut kiosk = Kiosk::new(
+            &config(&[("time_date", 10), ("indoor_outdoor", 10)]),
+            kiosk_view,
+            t0,
+        );
+
+        assert_eq!(
+            kiosk.next_view(ViewId::Settings, t0),
+            Some(ViewId::TimeDate)
+        );
+    }
+
+    #[test]
+    fn unknown_views_are_left_out_and_a_single_view_stays() {
+        let t0 = Instant::now();
+        let mut kiosk = Kiosk::new(&config(&[("time_date", 10), ("radar", 10)]), kiosk_view, t0);
+
+        assert_eq!(kiosk.next_view(ViewId::TimeDate, t0 + secs(60)), None);
+    }
+
+    #[test]
+    fn a_disabled_kiosk_shows_nothing() {
+        let t0 = Instant::now();
+        let mut kiosk = Kiosk::new(
+            &KioskConfig {
+                enabled: false,
+                ..config(&[("time_date", 10)])
+            },
+            kiosk_view,
+            t0,
+        );
+
+        assert_eq!(kiosk.next_view(ViewId::Settings, t0 + secs(60)), None);
+
+        kiosk.update(&config(&[("time_date", 10)]), kiosk_view);
+        assert_eq!(
+            kiosk.next_view(ViewId::Settings, t0 + secs(60)),
+            Some(ViewId::TimeDate)
+        );
+    }
+}
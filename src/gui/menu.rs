@@ -0,0 +1,520 @@
+// A number stepped up and down between min and max
+pub struct Spinner {
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+    pub decimals: usize,
+    pub unit: String,
+}
+
+impl Spinner {
+    fn step_by(&mut self, steps: f32) {
+        let value = (self.value + steps * self.step).clamp(self.min, self.max);
+
+        // Keep the value on the steps shown so repeated steps of 0.1 do not drift
+        let scale = 10f32.powi(self.decimals as i32);
+        self.value = (value * scale).round() / scale;
+    }
+}
+
+// One of a list of choices
+pub struct Picker {
+    pub options: Vec<String>,
+    pub selected: usize,
+}
+
+pub enum ItemKind<S> {
+    Submenu(Vec<MenuItem<S>>),
+    Toggle(bool),
+    Spinner(Spinner),
+    Picker(Picker),
+    // An action shows the text given as its value
+    Action(String),
+    // Goes up a level, or leaves the menu from the top level
+    Back,
+}
+
+pub struct MenuItem<S> {
+    pub setting: S,
+    pub label: String,
+    pub kind: ItemKind<S>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuValue {
+    Bool(bool),
+    Number(f32),
+    Choice(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MenuEvent<S> {
+    // Nothing to save, the menu only needs to be drawn again
+    None,
+    Changed(S, MenuValue),
+    Action(S),
+    Exit,
+}
+
+impl<S: Copy> MenuItem<S> {
+    pub fn new(setting: S, label: &str, kind: ItemKind<S>) -> Self {
+        Self {
+            setting,
+            label: label.to_string(),
+            kind,
+        }
+    }
+
+    pub fn value_text(&self) -> String {
+        match &self.kind {
+            ItemKind::Submenu(_) => ">".to_string(),
+            ItemKind::Toggle(true) => "On".to_string(),
+            ItemKind::Toggle(false) => "Off".to_string(),
+            ItemKind::Spinner(spinner) => {
+                format!("{:.*}{}", spinner.decimals, spinner.value, spinner.unit)
+            }
+            ItemKind::Picker(picker) => picker
+                .options
+                .get(picker.selected)
+                .cloned()
+                .unwrap_or_default(),
+            ItemKind::Action(text) => text.clone(),
+            ItemKind::Back => String::new(),
+        }
+    }
+
+    fn value(&self) -> Option<MenuValue> {
+        match &self.kind {
+            ItemKind::Toggle(on) => Some(MenuValue::Bool(*on)),
+            ItemKind::Spinner(spinner) => Some(MenuValue::Number(spinner.value)),
+            ItemKind::Picker(picker) => Some(MenuValue::Choice(picker.selected)),
+            _ => None,
+        }
+    }
+
+    fn set_value(&mut self, value: MenuValue) {
+        match (&mut self.kind, value) {
+            (ItemKind::Toggle(on), MenuValue::Bool(value)) => *on = value,
+            (ItemKind::Spinner(spinner), MenuValue::Number(value)) => spinner.value = value,
+            (ItemKind::Picker(picker), MenuValue::Choice(value)) => picker.selected = value,
+            _ => (),
+        }
+    }
+
+    fn step(&mut self, steps: i32) {
+        match &mut self.kind {
+            ItemKind::Spinner(spinner) => spinner.step_by(steps as f32),
+            ItemKind::Picker(picker) if !picker.options.is_empty() => {
+                let count = picker.options.len() as i32;
+                picker.selected = (picker.selected as i32 + steps).rem_euclid(count) as usize;
+            }
+            _ => (),
+        }
+    }
+}
+
+/**
+ * A menu driven by the three buttons.  A menu is a list of items, an item opens a list of its own, switches a setting
+ * on or off, steps a number, picks one of a list of choices or runs an action.
+ *
+ * PREV and NEXT move through the list, SET opens the item.  While a number or a choice is being changed PREV and NEXT
+ * change it and SET keeps it, the value the item had before is restored when the change is cancelled.
+ */
+pub struct Menu<S> {
+    title: String,
+    items: Vec<MenuItem<S>>,
+    // The submenus that have been opened, from the top level down
+    path: Vec<usize>,
+    selected: usize,
+    // The value of the item being changed before the change started
+    editing: Option<MenuValue>,
+}
+
+impl<S: Copy> Menu<S> {
+    pub fn new(title: &str, items: Vec<MenuItem<S>>) -> Self {
+        Self {
+            title: title.to_string(),
+            items,
+            path: Vec::new(),
+            selected: 0,
+            editing: None,
+        }
+    }
+
+    // The title of the list showing, the label of the submenu or the menu title at the top level
+    pub fn title(&self) -> &str {
+        match self.path.last() {
+            Some(_) => {
+                let (parent, index) = self.parent_level();
+                &parent[index].label
+            }
+            None => &self.title,
+        }
+    }
+
+    pub fn items(&self) -> &[MenuItem<S>] {
+        let mut items = &self.items;
+
+        for index in &self.path {
+            if let ItemKind::Submenu(sub_items) = &items[*index].kind {
+                items = sub_items;
+            }
+        }
+
+        items
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn is_editing(&self) -> bool {
+        self.editing.is_some()
+    }
+
+    pub fn previous(&mut self) {
+        self.move_by(-1);
+    }
+
+    pub fn next(&mut self) {
+        self.move_by(1);
+    }
+
+    // Move through the list, or change the value while an item is being changed
+    fn move_by(&mut self, steps: i32) {
+        if self.is_editing() {
+            if let Some(item) = self.selected_item_mut() {
+                item.step(steps);
+            }
+        } else {
+            let count = self.items().len() as i32;
+            if count > 0 {
+                self.selected = (self.selected as i32 + steps).rem_euclid(count) as usize;
+            }
+        }
+    }
+
+    // SET opens the selected item, or keeps the value of the item being changed
+    pub fn select(&mut self) -> MenuEvent<S> {
+        let selected = self.selected;
+        let editing = self.editing.take();
+
+        let item = match self.selected_item_mut() {
+            Some(item) => item,
+            None => return MenuEvent::None,
+        };
+        let setting = item.setting;
+        let value = item.value();
+
+        if editing.is_some() {
+            return match value {
+                Some(value) => MenuEvent::Changed(setting, value),
+                None => MenuEvent::None,
+            };
+        }
+
+        match &mut item.kind {
+            ItemKind::Toggle(on) => {
+                *on = !*on;
+                MenuEvent::Changed(setting, MenuValue::Bool(*on))
+            }
+
+            ItemKind::Submenu(_) => {
+                self.path.push(selected);
+                self.selected = 0;
+                MenuEvent::None
+            }
+
+            ItemKind::Spinner(_) | ItemKind::Picker(_) => {
+                self.editing = value;
+                MenuEvent::None
+            }
+
+            ItemKind::Action(_) => MenuEvent::Action(setting),
+
+            ItemKind::Back => self.back(),
+        }
+    }
+
+    // Cancel the change of a value, otherwise go up a level or leave the menu from the top level
+    pub fn back(&mut self) -> MenuEvent<S> {
+        if let Some(value) = self.editing.take() {
+            if let Some(item) = self.selected_item_mut() {
+                item.set_value(value);
+            }
+            return MenuEvent::None;
+        }
+
+        match self.path.pop() {
+            Some(index) => {
+                self.selected = index;
+                MenuEvent::None
+            }
+            None => {
+                self.selected = 0;
+                MenuEvent::Exit
+            }
+        }
+    }
+
+    /**
+     * Replace the items with a fresh set, e.g. built again from the saved config, keeping the place in the menu.  When
+     * the new items no longer have a submenu that is open the menu goes back up to the deepest one they still have.
+     */
+    pub fn replace_items(&mut self, items: Vec<MenuItem<S>>) {
+        self.items = items;
+        self.editing = None;
+
+        let depth = self.open_depth();
+        if depth < self.path.len() {
+            self.selected = self.path[depth];
+            self.path.truncate(depth);
+        }
+
+        let count = self.items().len();
+        if self.selected >= count {
+            self.selected = count.saturating_sub(1);
+        }
+    }
+
+    // How many of the submenus on the path are still submenus of the items
+    fn open_depth(&self) -> usize {
+        let mut items = &self.items[..];
+
+        for (depth, index) in self.path.iter().enumerate() {
+            match items.get(*index).map(|item| &item.kind) {
+                Some(ItemKind::Submenu(sub_items)) => items = sub_items,
+                _ => return depth,
+            }
+        }
+
+        self.path.len()
+    }
+
+    fn selected_item_mut(&mut self) -> Option<&mut MenuItem<S>> {
+        let mut items = &mut self.items;
+
+        for index in &self.path {
+            items = match &mut items.get_mut(*index)?.kind {
+                ItemKind::Submenu(sub_items) => sub_items,
+                _ => return None,
+            };
+        }
+
+        items.get_mut(self.selected)
+    }
+
+    fn parent_level(&self) -> (&[MenuItem<S>], usize) {
+        let mut items = &self.items[..];
+        let (last, parents) = self.path.split_last().unwrap();
+
+        for index in parents {
+            if let ItemKind::Submenu(sub_items) = &items[*index].kind {
+                items = sub_items;
+            }
+        }
+
+        (items, *last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Setting {
+        Submenu,
+        Toggle,
+        Spinner,
+        Picker,
+        Action,
+        Back,
+    }
+
+    fn spinner(value: f32) -> ItemKind<Setting> {
+        ItemKind::Spinner(Spinner {
+            value,
+            min: -1.0,
+            max: 1.0,
+            step: 0.1,
+            decimals: 1,
+            unit: "F".to_string(),
+        })
+    }
+
+    fn items(on: bool) -> Vec<MenuItem<Setting>> {
+        vec![
+            MenuItem::new(
+                Setting::Submenu,
+                "Calibration",
+                ItemKind::Submenu(vec![
+                    MenuItem::new(Setting::Spinner, "Offset", spinner(0.0)),
+                    MenuItem::new(Setting::Back, "Back", ItemKind::Back),
+                ]),
+            ),
+            MenuItem::new(Setting::Toggle, "Alerts", ItemKind::Toggle(on)),
+            MenuItem::new(
+                Setting::Picker,
+                "Units",
+                ItemKind::Picker(Picker {
+                    options: vec!["Imperial".to_string(), "Metric".to_string()],
+                    selected: 0,
+                }),
+            ),
+            MenuItem::new(
+                Setting::Action,
+                "Pair",
+                ItemKind::Action("1234".to_string()),
+            ),
+            MenuItem::new(Setting::Back, "Exit", ItemKind::Back),
+        ]
+    }
+
+    fn menu() -> Menu<Setting> {
+        Menu::new("Settings", items(false))
+    }
+
+    #[test]
+    fn moving_wraps_around_the_list() {
+        let mut menu = menu();
+
+        menu.previous();
+        assert_eq!(menu.selected(), 4);
+        menu.next();
+        assert_eq!(menu.selected(), 0);
+        menu.next();
+        assert_eq!(menu.selected(), 1);
+    }
+
+    #[test]
+    fn a_submenu_is_entered_and_left() {
+        let mut menu = menu();
+
+        assert_eq!(menu.select(), MenuEvent::None);
+        assert_eq!(menu.title(), "Calibration");
+        assert_eq!(menu.items().len(), 2);
+        assert_eq!(menu.selected(), 0);
+
+        // Back returns to the item the submenu was opened from, and back again leaves the menu
+        menu.next();
+        assert_eq!(menu.select(), MenuEvent::None);
+        assert_eq!(menu.title(), "Settings");
+        assert_eq!(menu.selected(), 0);
+        assert_eq!(menu.back(), MenuEvent::Exit);
+    }
+
+    #[test]
+    fn a_toggle_is_changed_straight_away() {
+        let mut menu = menu();
+        menu.next();
+
+        assert_eq!(
+            menu.select(),
+            MenuEvent::Changed(Setting::Toggle, MenuValue::Bool(true))
+        );
+        assert_eq!(menu.items()[1].value_text(), "On");
+        assert!(!menu.is_editing());
+    }
+
+    #[test]
+    fn a_choice_is_kept_with_set() {
+        let mut menu = menu();
+        menu.next();
+        menu.next();
+
+        menu.select();
+        assert!(menu.is_editing());
+        menu.next();
+        assert_eq!(
+            menu.select(),
+            MenuEvent::Changed(Setting::Picker, MenuValue::Choice(1))
+        );
+        assert_eq!(menu.items()[2].value_text(), "Metric");
+    }
+
+    #[test]
+    fn cancelling_a_change_restores_the_value() {
+        let mut menu = menu();
+        menu.select();
+
+        menu.select();
+        menu.next();
+        menu.next();
+        assert_eq!(menu.items()[0].value_text(), "0.2F");
+
+        assert_eq!(menu.back(), MenuEvent::None);
+        assert!(!menu.is_editing());
+        assert_eq!(menu.items()[0].value_text(), "0.0F");
+        assert_eq!(menu.title(), "Calibration");
+    }
+
+    #[test]
+    fn a_spinner_is_clamped_and_stays_on_its_steps() {
+        let mut menu = menu();
+        menu.select();
+        menu.select();
+
+        for _ in 0..7 {
+            menu.next();
+        }
+        assert_eq!(
+            menu.select(),
+            MenuEvent::Changed(Setting::Spinner, MenuValue::Number(0.7))
+        );
+
+        menu.select();
+        for _ in 0..5 {
+            menu.next();
+        }
+        assert_eq!(
+            menu.select(),
+            MenuEvent::Changed(Setting::Spinner, MenuValue::Number(1.0))
+        );
+    }
+
+    #[test]
+    fn an_action_is_reported() {
+        let mut menu = menu();
+        menu.previous();
+        menu.previous();
+
+        assert_eq!(menu.select(), MenuEvent::Action(Setting::Action));
+    }
+
+    #[test]
+    fn replaced_items_keep_the_place_in_the_menu() {
+        let mut menu = menu();
+        menu.select();
+        menu.select();
+
+        menu.replace_items(items(true));
+        assert!(!menu.is_editing());
+        assert_eq!(menu.title(), "Calibration");
+        assert_eq!(menu.selected(), 0);
+        assert_eq!(menu.items()[0].value_text(), "0.0F");
+    }
+
+    #[test]
+    fn replaced_items_without_the_open_submenu_go_back_up() {
+        let mut menu = menu();
+        menu.select();
+        menu.next();
+
+        menu.replace_items(vec![
+            MenuItem::new(Setting::Toggle, "Alerts", ItemKind::Toggle(false)),
+            MenuItem::new(Setting::Back, "Exit", ItemKind::Back),
+        ]);
+        assert_eq!(menu.title(), "Settings");
+        assert_eq!(menu.selected(), 0);
+        assert_eq!(
+            menu.select(),
+            MenuEvent::Changed(Setting::Toggle, MenuValue::Bool(true))
+        );
+
+        // An empty list has nothing to select
+        menu.replace_items(Vec::new());
+        assert_eq!(menu.select(), MenuEvent::None);
+    }
+}
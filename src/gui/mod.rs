@@ -1,4 +1,10 @@
+pub mod backlight;
+pub mod beeper;
+pub mod bindings;
 pub mod display;
 pub mod gui;
+pub mod kiosk;
+pub mod menu;
+pub mod settings;
 pub mod views;
 pub mod widgets;
\ No newline at end of file
@@ -0,0 +1,572 @@
+use crate::{
+    gui::menu::{ItemKind, MenuItem, MenuValue, Picker, Spinner},
+    model::{
+        config::Config,
+        pressure::{PressureMode, HPA_PER_INHG},
+        time_zone::zone_names,
+        units::{Quantity, Units},
+    },
+};
+
+const STANDARD_PRESSURE_INHG: f32 = 29.92;
+
+// The config setting each item of the settings menu changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    Display,
+    Units,
+    Clock24h,
+    Backlight,
+    NightBacklight,
+    ScreenTimeout,
+    Kiosk,
+    Altitude,
+    TimeZone,
+    Calibration,
+    PressureMode,
+    PressureReference,
+    PressureOffset,
+    IndoorTemperatureOffset,
+    IndoorHumidityOffset,
+    OutdoorTemperatureOffset,
+    OutdoorHumidityOffset,
+    Alerts,
+    AlertsEnabled,
+    HighTemperature,
+    LowTemperature,
+    HighWind,
+    DailyRainfall,
+    AlertBeep,
+    Sensor,
+    PairSensor,
+    UnpairSensor,
+    Back,
+}
+
+/**
+ * Build the settings menu from the config.  The alert thresholds are stored in the units the station measures in and
+ * are shown and stepped in the units chosen for the display.  last_product_id is the sensor heard most recently, it is
+ * the one that is paired.  pressure is the pressure reported last in inHg, the calibration starts from it.
+ */
+pub fn settings_menu_items(
+    config: &Config,
+    last_product_id: Option<u16>,
+    pressure: Option<f32>,
+) -> Vec<MenuItem<Setting>> {
+    let units = config.display.units;
+
+    vec![
+        MenuItem::new(
+            Setting::Display,
+            "Display",
+            ItemKind::Submenu(vec![
+                MenuItem::new(
+                    Setting::Units,
+                    "Units",
+                    ItemKind::Picker(Picker {
+                        options: Units::ALL
+                            .iter()
+                            .map(|units| units.name().to_string())
+                            .collect(),
+                        selected: Units::ALL
+                            .iter()
+                            .position(|u| *u == units)
+                            .unwrap_or_default(),
+                    }),
+                ),
+                MenuItem::new(
+                    Setting::Clock24h,
+                    "24 hour clock",
+                    ItemKind::Toggle(config.station.clock_24h),
+                ),
+                MenuItem::new(
+                    Setting::Backlight,
+                    "Backlight",
+                    ItemKind::Spinner(Spinner {
+                        value: config.display.backlight_percent as f32,
+                        min: 10.0,
+                        max: 100.0,
+                        step: 10.0,
+                        decimals: 0,
+                        unit: "%".to_string(),
+                    }),
+                ),
+                MenuItem::new(
+                    Setting::NightBacklight,
+                    "Night backlight",
+                    ItemKind::Spinner(Spinner {
+                        value: config.display.night_backlight_percent as f32,
+                        min: 10.0,
+                        max: 100.0,
+                        step: 10.0,
+                        decimals: 0,
+                        unit: "%".to_string(),
+                    }),
+                ),
+                // 0 keeps the screen on
+                MenuItem::new(
+                    Setting::ScreenTimeout,
+                    "Screen off after",
+                    ItemKind::Spinner(Spinner {
+                        value: config.display.screen_timeout_secs as f32,
+                        min: 0.0,
+                        max: 3600.0,
+                        step: 30.0,
+                        decimals: 0,
+                        unit: "s".to_string(),
+                    }),
+                ),
+                MenuItem::new(
+                    Setting::Kiosk,
+                    "Cycle views",
+                    ItemKind::Toggle(config.kiosk.enabled),
+                ),
+                MenuItem::new(Setting::Back, "Back", ItemKind::Back),
+            ]),
+        ),
+        MenuItem::new(
+            Setting::Altitude,
+            "Altitude",
+            ItemKind::Spinner(Spinner {
+                value: config.station.altitude_meters.round(),
+                min: -500.0,
+                max: 9000.0,
+                step: 10.0,
+                decimals: 0,
+                unit: "m".to_string(),
+            }),
+        ),
+        MenuItem::new(
+            Setting::TimeZone,
+            "Time zone",
+            ItemKind::Picker(Picker {
+                selected: time_zone_options(config)
+                    .iter()
+                    .position(|zone| *zone == config.station.time_zone)
+                    .unwrap_or_default(),
+                options: time_zone_options(config),
+            }),
+        ),
+        MenuItem::new(
+            Setting::Calibration,
+            "Calibration",
+            ItemKind::Submenu(vec![
+                MenuItem::new(
+                    Setting::PressureMode,
+                    "Pressure",
+                    ItemKind::Picker(Picker {
+                        options: PressureMode::ALL
+                            .iter()
+                            .map(|mode| mode.name().to_string())
+                            .collect(),
+                        selected: PressureMode::ALL
+                            .iter()
+                            .position(|mode| *mode == config.calibration.pressure_mode)
+                            .unwrap_or_default(),
+                    }),
+                ),
+                // Set to the reading of a reference barometer the offset is worked out by the weather station
+                MenuItem::new(
+                    Setting::PressureReference,
+                    "Reference",
+                    pressure_reference(units, pressure),
+                ),
+                MenuItem::new(
+                    Setting::PressureOffset,
+                    "Pressure offset",
+                    pressure_offset(units, config.calibration.pressure_offset_hpa),
+                ),
+                MenuItem::new(
+                    Setting::IndoorTemperatureOffset,
+                    "Indoor temp",
+                    temperature_offset(units, config.calibration.indoor_temperature_offset_f),
+                ),
+                MenuItem::new(
+                    Setting::IndoorHumidityOffset,
+                    "Indoor humidity",
+                    humidity_offset(config.calibration.indoor_humidity_offset),
+                ),
+                MenuItem::new(
+                    Setting::OutdoorTemperatureOffset,
+                    "Outdoor temp",
+                    temperature_offset(units, config.calibration.outdoor_temperature_offset_f),
+                ),
+                MenuItem::new(
+                    Setting::OutdoorHumidityOffset,
+                    "Outdoor humidity",
+                    humidity_offset(config.calibration.outdoor_humidity_offset),
+                ),
+                MenuItem::new(Setting::Back, "Back", ItemKind::Back),
+            ]),
+        ),
+        MenuItem::new(
+            Setting::Alerts,
+            "Alerts",
+            ItemKind::Submenu(vec![
+                MenuItem::new(
+                    Setting::AlertsEnabled,
+                    "Alerts",
+                    ItemKind::Toggle(config.alerts.enabled),
+                ),
+                MenuItem::new(
+                    Setting::HighTemperature,
+                    "High temp",
+                    threshold(
+                        units,
+                        Quantity::Temperature,
+                        config.alerts.high_temperature_f,
+                    ),
+                ),
+                MenuItem::new(
+                    Setting::LowTemperature,
+                    "Low temp",
+                    threshold(
+                        units,
+                        Quantity::Temperature,
+                        config.alerts.low_temperature_f,
+                    ),
+                ),
+                MenuItem::new(
+                    Setting::HighWind,
+                    "High wind",
+                    threshold(units, Quantity::WindSpeed, config.alerts.high_wind_mph),
+                ),
+                MenuItem::new(
+                    Setting::DailyRainfall,
+                    "Daily rain",
+                    threshold(units, Quantity::Rainfall, config.alerts.daily_rainfall_in),
+                ),
+                MenuItem::new(
+                    Setting::AlertBeep,
+                    "Beep",
+                    ItemKind::Toggle(config.alerts.beep),
+                ),
+                MenuItem::new(Setting::Back, "Back", ItemKind::Back),
+            ]),
+        ),
+        MenuItem::new(
+            Setting::Sensor,
+            "Sensor",
+            ItemKind::Submenu(vec![
+                MenuItem::new(
+                    Setting::PairSensor,
+                    "Pair with",
+                    ItemKind::Action(match last_product_id {
+                        Some(product_id) => format!("{}", product_id),
+                        None => "--".to_string(),
+                    }),
+                ),
+                MenuItem::new(
+                    Setting::UnpairSensor,
+                    "Unpair",
+                    ItemKind::Action(match config.sensor.product_id {
+                        Some(product_id) => format!("{}", product_id),
+                        None => "Any".to_string(),
+                    }),
+                ),
+                MenuItem::new(Setting::Back, "Back", ItemKind::Back),
+            ]),
+        ),
+        MenuItem::new(Setting::Back, "Exit", ItemKind::Back),
+    ]
+}
+
+// Apply a change made in the settings menu to the config, the caller validates and saves it.  The pressure reference
+// is not a setting, the gui sends it to the weather station which saves the offset it works out.
+pub fn apply_setting(config: &mut Config, setting: Setting, value: MenuValue) {
+    let units = config.display.units;
+
+    match (setting, value) {
+        (Setting::Units, MenuValue::Choice(choice)) => {
+            config.display.units = Units::ALL[choice.min(Units::ALL.len() - 1)]
+        }
+        (Setting::Clock24h, MenuValue::Bool(on)) => config.station.clock_24h = on,
+        (Setting::Backlight, MenuValue::Number(percent)) => {
+            config.display.backlight_percent = percent as u8
+        }
+        (Setting::NightBacklight, MenuValue::Number(percent)) => {
+            config.display.night_backlight_percent = percent as u8
+        }
+        (Setting::ScreenTimeout, MenuValue::Number(secs)) => {
+            config.display.screen_timeout_secs = secs as u32
+        }
+        (Setting::Kiosk, MenuValue::Bool(on)) => config.kiosk.enabled = on,
+
+        (Setting::Altitude, MenuValue::Number(meters)) => config.station.altitude_meters = meters,
+        (Setting::TimeZone, MenuValue::Choice(choice)) => {
+            if let Some(zone) = time_zone_options(config).get(choice) {
+                config.station.time_zone = zone.clone();
+            }
+        }
+
+        (Setting::PressureMode, MenuValue::Choice(choice)) => {
+            config.calibration.pressure_mode =
+                PressureMode::ALL[choice.min(PressureMode::ALL.len() - 1)]
+        }
+        (Setting::PressureOffset, MenuValue::Number(value)) => {
+            config.calibration.pressure_offset_hpa = match units {
+                Units::Imperial => value * HPA_PER_INHG,
+                Units::Metric => value,
+            }
+        }
+        (Setting::IndoorTemperatureOffset, MenuValue::Number(value)) => {
+            config.calibration.indoor_temperature_offset_f = temperature_difference_f(units, value)
+        }
+        (Setting::IndoorHumidityOffset, MenuValue::Number(value)) => {
+            config.calibration.indoor_humidity_offset = value
+        }
+        (Setting::OutdoorTemperatureOffset, MenuValue::Number(value)) => {
+            config.calibration.outdoor_temperature_offset_f = temperature_difference_f(units, value)
+        }
+        (Setting::OutdoorHumidityOffset, MenuValue::Number(value)) => {
+            config.calibration.outdoor_humidity_offset = value
+        }
+
+        (Setting::AlertsEnabled, MenuValue::Bool(on)) => config.alerts.enabled = on,
+        (Setting::HighTemperature, MenuValue::Number(value)) => {
+            config.alerts.high_temperature_f = units.to_imperial(Quantity::Temperature, value)
+        }
+        (Setting::LowTemperature, MenuValue::Number(value)) => {
+            config.alerts.low_temperature_f = units.to_imperial(Quantity::Temperature, value)
+        }
+        (Setting::HighWind, MenuValue::Number(value)) => {
+            config.alerts.high_wind_mph = units.to_imperial(Quantity::WindSpeed, value)
+        }
+        (Setting::DailyRainfall, MenuValue::Number(value)) => {
+            config.alerts.daily_rainfall_in = units.to_imperial(Quantity::Rainfall, value)
+        }
+        (Setting::AlertBeep, MenuValue::Bool(on)) => config.alerts.beep = on,
+
+        _ => (),
+    }
+}
+
+// Run an action of the settings menu on the config, the caller validates and saves it
+pub fn apply_action(config: &mut Config, setting: Setting, last_product_id: Option<u16>) {
+    match setting {
+        Setting::PairSensor => {
+            if last_product_id.is_some() {
+                config.sensor.product_id = last_product_id;
+            }
+        }
+        Setting::UnpairSensor => config.sensor.product_id = None,
+
+        _ => (),
+    }
+}
+
+// The zones the station knows by name, a POSIX TZ string that is not one of them is offered first so it can be kept
+fn time_zone_options(config: &Config) -> Vec<String> {
+    let mut options: Vec<String> = zone_names().map(|name| name.to_string()).collect();
+
+    if !options.contains(&config.station.time_zone) {
+        options.insert(0, config.station.time_zone.clone());
+    }
+
+    options
+}
+
+fn threshold(units: Units, quantity: Quantity, value: f32) -> ItemKind<Setting> {
+    let (min, max, step) = match (units, quantity) {
+        (Units::Imperial, Quantity::Temperature) => (-40.0, 140.0, 1.0),
+        (Units::Metric, Quantity::Temperature) => (-40.0, 60.0, 1.0),
+        (Units::Imperial, Quantity::WindSpeed) => (1.0, 150.0, 1.0),
+        (Units::Metric, Quantity::WindSpeed) => (2.0, 240.0, 1.0),
+        (Units::Imperial, Quantity::Rainfall) => (0.1, 20.0, 0.1),
+        (Units::Metric, Quantity::Rainfall) => (3.0, 500.0, 1.0),
+        (_, Quantity::Pressure) => (0.0, 0.0, 0.0),
+    };
+    let decimals = if step < 1.0 { 1 } else { 0 };
+
+    // Rounded to the step so the first step lands on a whole value
+    let scale = 10f32.powi(decimals as i32);
+    let value = (units.from_imperial(quantity, value) * scale).round() / scale;
+
+    ItemKind::Spinner(Spinner {
+        value: value.clamp(min, max),
+        min,
+        max,
+        step,
+        decimals,
+        unit: units.symbol(quantity).trim().to_string(),
+    })
+}
+
+/**
+ * The reference reading of the calibration starts at the pressure reported last so it only has to be stepped by the
+ * difference.  It is in the pressure mode the station reports in.
+ */
+fn pressure_reference(units: Units, pressure: Option<f32>) -> ItemKind<Setting> {
+    let (min, max, step, decimals) = match units {
+        Units::Imperial => (15.0, 32.5, 0.01, 2),
+        Units::Metric => (500.0, 1100.0, 0.1, 1),
+    };
+
+    let scale = 10f32.powi(decimals as i32);
+    let value = (units.from_imperial(
+        Quantity::Pressure,
+        pressure.unwrap_or(STANDARD_PRESSURE_INHG),
+    ) * scale)
+        .round()
+        / scale;
+
+    ItemKind::Spinner(Spinner {
+        value: value.clamp(min, max),
+        min,
+        max,
+        step,
+        decimals,
+        unit: units.symbol(Quantity::Pressure).trim().to_string(),
+    })
+}
+
+// The pressure offset is stored in hPa and shown in the units chosen for the display
+fn pressure_offset(units: Units, offset_hpa: f32) -> ItemKind<Setting> {
+    match units {
+        Units::Imperial => offset_spinner(offset_hpa / HPA_PER_INHG, 1.45, 0.01, 2, "inHg"),
+        Units::Metric => offset_spinner(offset_hpa, 50.0, 0.1, 1, "hPa"),
+    }
+}
+
+// A temperature offset is a difference so it is converted without the 32F between the scales
+fn temperature_offset(units: Units, offset_f: f32) -> ItemKind<Setting> {
+    match units {
+        Units::Imperial => offset_spinner(offset_f, 20.0, 0.1, 1, "F"),
+        Units::Metric => offset_spinner(offset_f * 5.0 / 9.0, 11.0, 0.1, 1, "C"),
+    }
+}
+
+fn temperature_difference_f(units: Units, offset: f32) -> f32 {
+    match units {
+        Units::Imperial => offset,
+        Units::Metric => offset * 9.0 / 5.0,
+    }
+}
+
+fn humidity_offset(offset: f32) -> ItemKind<Setting> {
+    offset_spinner(offset, 30.0, 1.0, 0, "%")
+}
+
+fn offset_spinner(
+    value: f32,
+    limit: f32,
+    step: f32,
+    decimals: usize,
+    unit: &str,
+) -> ItemKind<Setting> {
+    let scale = 10f32.powi(decimals as i32);
+
+    ItemKind::Spinner(Spinner {
+        value: ((value * scale).round() / scale).clamp(-limit, limit),
+        min: -limit,
+        max: limit,
+        step,
+        decimals,
+        unit: unit.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric_config() -> Config {
+        let mut config = Config::default();
+        config.display.units = Units::Metric;
+        config
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() < 0.001,
+            "{} != {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn metric_thresholds_are_stored_in_imperial_units() {
+        let mut config = metric_config();
+
+        apply_setting(
+            &mut config,
+            Setting::HighTemperature,
+            MenuValue::Number(40.0),
+        );
+        apply_setting(
+            &mut config,
+            Setting::LowTemperature,
+            MenuValue::Number(-5.0),
+        );
+        apply_setting(&mut config, Setting::HighWind, MenuValue::Number(80.0));
+        apply_setting(&mut config, Setting::DailyRainfall, MenuValue::Number(50.8));
+
+        assert_near(config.alerts.high_temperature_f, 104.0);
+        assert_near(config.alerts.low_temperature_f, 23.0);
+        assert_near(config.alerts.high_wind_mph, 49.709695);
+        assert_near(config.alerts.daily_rainfall_in, 2.0);
+    }
+
+    #[test]
+    fn metric_offsets_are_stored_in_hpa_and_fahrenheit() {
+        let mut config = metric_config();
+
+        apply_setting(
+            &mut config,
+            Setting::PressureOffset,
+            MenuValue::Number(-1.5),
+        );
+        apply_setting(
+            &mut config,
+            Setting::IndoorTemperatureOffset,
+            MenuValue::Number(-2.0),
+        );
+        apply_setting(
+            &mut config,
+            Setting::OutdoorHumidityOffset,
+            MenuValue::Number(3.0),
+        );
+
+        assert_near(config.calibration.pressure_offset_hpa, -1.5);
+        assert_near(config.calibration.indoor_temperature_offset_f, -3.6);
+        assert_near(config.calibration.outdoor_humidity_offset, 3.0);
+    }
+
+    #[test]
+    fn imperial_pressure_offset_is_stored_in_hpa() {
+        let mut config = Config::default();
+
+        apply_setting(&mut config, Setting::PressureOffset, MenuValue::Number(0.1));
+
+        assert_near(config.calibration.pressure_offset_hpa, 3.386389);
+    }
+
+    #[test]
+    fn offsets_are_shown_in_the_display_units() {
+        let mut config = metric_config();
+        config.calibration.indoor_temperature_offset_f = -3.6;
+
+        let items = settings_menu_items(&config, None, None);
+        let calibration = match &items[3].kind {
+            ItemKind::Submenu(items) => items,
+            _ => panic!("calibration is a submenu"),
+        };
+
+        assert_eq!(calibration[3].value_text(), "-2.0C");
+    }
+
+    #[test]
+    fn pairing_needs_a_sensor_that_was_heard() {
+        let mut config = Config::default();
+
+        apply_action(&mut config, Setting::PairSensor, None);
+        assert_eq!(config.sensor.product_id, None);
+
+        apply_action(&mut config, Setting::PairSensor, Some(1234));
+        assert_eq!(config.sensor.product_id, Some(1234));
+
+        apply_action(&mut config, Setting::UnpairSensor, Some(1234));
+        assert_eq!(config.sensor.product_id, None);
+    }
+}
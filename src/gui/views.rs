@@ -1,4 +1,4 @@
-use u8g2_fonts::{fonts, Error, FontRenderer};
+use u8g2_fonts::{fonts, types::HorizontalAlignment, Error, FontRenderer};
 
 use embedded_graphics::{
     pixelcolor::Rgb565,
@@ -6,30 +6,93 @@ use embedded_graphics::{
     primitives::{Rectangle, RoundedRectangle},
 };
 
-use crate::gui::widgets::{Label, Panel, RoundedButton, NavigationPanel};
+use crate::{
+    gui::{
+        bindings::Formatter,
+        widgets::{Label, NavigationPanel, Panel, RoundedButton, SignalIcon},
+    },
+    model::{
+        history::Sensor,
+        measurement_cache::MeasurementCache,
+        units::{Quantity, Units},
+        weather_station::MeasurementField,
+    },
+};
 
 const FONT_LUBS12: FontRenderer = FontRenderer::new::<fonts::u8g2_font_luBS12_tr>();
 const FONT_LUBS24: FontRenderer = FontRenderer::new::<fonts::u8g2_font_luBS24_tr>();
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ViewId {
     IndoorOutdoor,
+    IndoorAir,
     WindRainStatus,
     TimeDate,
+    Settings,
+    Message,
+}
+
+// What SET does on a view
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetAction {
+    EditTimeDate,
+    OpenSettingsMenu,
+}
+
+/**
+ * A screen of the display.  A view is made of panels that are shown and hidden together, most views have a
+ * navigation panel at the bottom for the three buttons.  The measurements a view shows are the fields its labels are
+ * bound to.
+ */
+pub trait View: Send {
+    fn id(&self) -> ViewId;
+
+    // The name the view is listed by in kiosk.views
+    fn name(&self) -> &'static str;
+
+    // The content panels, drawn in order before the navigation panel
+    fn panels(&mut self) -> Vec<&mut Panel>;
+
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        None
+    }
+
+    // PREV and NEXT always change the view, a view that does something with SET shows the SET button
+    fn set_action(&self) -> Option<SetAction> {
+        None
+    }
+
+    // Views that PREV and NEXT move between, the others are only shown when the gui has something to say
+    fn is_navigable(&self) -> bool {
+        true
+    }
+
+    // Views the kiosk rotation may show
+    fn is_kiosk_view(&self) -> bool {
+        self.is_navigable()
+    }
 }
 
+/**
+ * The registry of views.  PREV and NEXT move through the navigable views in the order they are registered, so a new
+ * view is added by implementing View and registering it in build_views.
+ */
 pub struct Views {
-    pub indoor_outdoor_view: IndoorOutdoorView,
-    pub wind_rain_status_view: WindRainStatusView,
-    pub time_date_view: TimeDateView,
+    views: Vec<Box<dyn View>>,
 }
 
 impl Views {
-    pub fn build_views() -> Self {
+    // The indoor sensors are the ones found on the I2C bus at boot, they decide what the indoor air view shows
+    pub fn build_views(indoor_sensors: &[Sensor]) -> Self {
         Self {
-            indoor_outdoor_view: IndoorOutdoorView::build(),
-            wind_rain_status_view: WindRainStatusView::build(),
-            time_date_view: TimeDateView::bulid(),
+            views: vec![
+                Box::new(IndoorOutdoorView::build()),
+                Box::new(IndoorAirView::build(indoor_sensors)),
+                Box::new(WindRainStatusView::build()),
+                Box::new(TimeDateView::bulid()),
+                Box::new(SettingsView::build()),
+                Box::new(MessageView::build()),
+            ],
         }
     }
 
@@ -37,10 +100,13 @@ impl Views {
     where
         D: DrawTarget<Color = Rgb565>,
     {
-        match view_id {
-            ViewId::IndoorOutdoor => self.indoor_outdoor_view.show(display)?,
-            ViewId::WindRainStatus => self.wind_rain_status_view.show(display)?,
-            ViewId::TimeDate => self.time_date_view.show(display)?,
+        let view = self.view(view_id);
+
+        for panel in view.panels() {
+            panel.show(display)?;
+        }
+        if let Some(navigation_panel) = view.navigation_panel() {
+            navigation_panel.show(display)?;
         }
 
         Ok(())
@@ -50,20 +116,116 @@ impl Views {
     where
         D: DrawTarget<Color = Rgb565>,
     {
-        match view_id {
-            ViewId::IndoorOutdoor => self.indoor_outdoor_view.hide(display)?,
-            ViewId::WindRainStatus => self.wind_rain_status_view.hide(display)?,
-            ViewId::TimeDate => self.time_date_view.hide(display)?,
+        let view = self.view(view_id);
+
+        for panel in view.panels() {
+            panel.hide(display)?;
+        }
+        if let Some(navigation_panel) = view.navigation_panel() {
+            navigation_panel.hide(display)?;
         }
 
         Ok(())
     }
+
+    // The navigable view after view_id, from a view that is not navigable it is the first one
+    pub fn next_view(&self, view_id: ViewId) -> ViewId {
+        let navigable = self.navigable_views();
+
+        match navigable.iter().position(|id| *id == view_id) {
+            Some(index) => navigable[(index + 1) % navigable.len()],
+            None => navigable[0],
+        }
+    }
+
+    // The navigable view before view_id, from a view that is not navigable it is the first one
+    pub fn previous_view(&self, view_id: ViewId) -> ViewId {
+        let navigable = self.navigable_views();
+
+        match navigable.iter().position(|id| *id == view_id) {
+            Some(index) => navigable[(index + navigable.len() - 1) % navigable.len()],
+            None => navigable[0],
+        }
+    }
+
+    pub fn set_action(&mut self, view_id: ViewId) -> Option<SetAction> {
+        self.view(view_id).set_action()
+    }
+
+    // The view kiosk.views lists by name, None for a name that is unknown or a view the station does not show
+    pub fn kiosk_view(&self, name: &str) -> Option<ViewId> {
+        self.views
+            .iter()
+            .find(|view| view.name() == name && view.is_kiosk_view())
+            .map(|view| view.id())
+    }
+
+    pub fn panel(&mut self, view_id: ViewId, panel: usize) -> &mut Panel {
+        self.view(view_id).panels().swap_remove(panel)
+    }
+
+    pub fn navigation_panel(&mut self, view_id: ViewId) -> Option<&mut NavigationPanel> {
+        self.view(view_id).navigation_panel()
+    }
+
+    pub fn navigation_panels(&mut self) -> impl Iterator<Item = &mut NavigationPanel> {
+        self.views
+            .iter_mut()
+            .filter_map(|view| view.navigation_panel())
+    }
+
+    // Render the labels of a view that are bound to measurements from their latest values
+    pub fn update_bindings<D>(
+        &mut self,
+        display: &mut D,
+        view_id: ViewId,
+        measurements: &MeasurementCache,
+        units: Units,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        for panel in self.view(view_id).panels() {
+            panel.update_bindings(display, measurements, units)?;
+        }
+
+        Ok(())
+    }
+
+    fn navigable_views(&self) -> Vec<ViewId> {
+        self.views
+            .iter()
+            .filter(|view| view.is_navigable())
+            .map(|view| view.id())
+            .collect()
+    }
+
+    fn view(&mut self, view_id: ViewId) -> &mut dyn View {
+        self.views
+            .iter_mut()
+            .find(|view| view.id() == view_id)
+            .map(|view| view.as_mut())
+            .unwrap()
+    }
+}
+
+// The content panel of a view that has only one
+pub const CONTENT_PANEL: usize = 0;
+
+// Every view with navigation has the same navigation panel at the bottom of the display
+fn navigation_panel(show_set_button: bool) -> NavigationPanel {
+    NavPanel::build(
+        Point::new(0, 201),
+        Size::new(320, 39),
+        Rgb565::CSS_DARK_SLATE_BLUE,
+        show_set_button,
+    )
 }
 
 pub struct IndoorOutdoorView {
-    pub indoor_panel: Panel,
-    pub outdoor_panel: Panel,
-    pub navigation_panel: NavigationPanel,
+    indoor_panel: Panel,
+    outdoor_panel: Panel,
+    navigation_panel: NavigationPanel,
 }
 
 impl IndoorOutdoorView {
@@ -79,43 +241,90 @@ impl IndoorOutdoorView {
                 Size::new(320, 117),
                 Rgb565::CSS_LIGHT_GREEN,
             ),
-            navigation_panel: NavPanel::build(
-                Point::new(0, 201),
-                Size::new(320, 39),
-                Rgb565::CSS_DARK_SLATE_BLUE,
-                false,
+            navigation_panel: navigation_panel(false),
+        }
+    }
+}
+
+impl View for IndoorOutdoorView {
+    fn id(&self) -> ViewId {
+        ViewId::IndoorOutdoor
+    }
+
+    fn name(&self) -> &'static str {
+        "indoor_outdoor"
+    }
+
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![&mut self.indoor_panel, &mut self.outdoor_panel]
+    }
+
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        Some(&mut self.navigation_panel)
+    }
+}
+
+/**
+ * The readings of the indoor air that only some sensors measure, the CO2 of an SCD4x and the gas resistance and air
+ * quality of a BME680.  The view is left out of PREV and NEXT when none of them were found.
+ */
+pub struct IndoorAirView {
+    indoor_air_panel: Panel,
+    navigation_panel: NavigationPanel,
+    has_rows: bool,
+}
+
+impl IndoorAirView {
+    pub fn build(indoor_sensors: &[Sensor]) -> Self {
+        let rows = IndoorAirPanel::rows(indoor_sensors);
+
+        Self {
+            indoor_air_panel: IndoorAirPanel::build(
+                Point::new(0, 0),
+                Size::new(320, 199),
+                Rgb565::CSS_LIGHT_GREEN,
+                &rows,
+                indoor_sensors,
             ),
+            navigation_panel: navigation_panel(false),
+            has_rows: !rows.is_empty(),
         }
     }
+}
 
-    pub fn show<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.indoor_panel.show(display)?;
-        self.outdoor_panel.show(display)?;
-        self.navigation_panel.show(display)?;
+impl View for IndoorAirView {
+    fn id(&self) -> ViewId {
+        ViewId::IndoorAir
+    }
 
-        Ok(())
+    fn name(&self) -> &'static str {
+        "indoor_air"
     }
 
-    pub fn hide<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.indoor_panel.hide(display)?;
-        self.outdoor_panel.hide(display)?;
-        self.navigation_panel.hide(display)?;
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![&mut self.indoor_air_panel]
+    }
 
-        Ok(())
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        Some(&mut self.navigation_panel)
+    }
+
+    fn is_navigable(&self) -> bool {
+        self.has_rows
     }
 }
 
+pub enum WindRainStatusPanelId {
+    Wind,
+    Rain,
+    Status,
+}
+
 pub struct WindRainStatusView {
-    pub wind_panel: Panel,
-    pub rain_panel: Panel,
-    pub status_panel: Panel,
-    pub navigation_panel: NavigationPanel,
+    wind_panel: Panel,
+    rain_panel: Panel,
+    status_panel: Panel,
+    navigation_panel: NavigationPanel,
 }
 
 impl WindRainStatusView {
@@ -136,43 +345,36 @@ impl WindRainStatusView {
                 Size::new(320, 65),
                 Rgb565::CSS_DARK_KHAKI,
             ),
-            navigation_panel: NavPanel::build(
-                Point::new(0, 201),
-                Size::new(320, 39),
-                Rgb565::CSS_DARK_SLATE_BLUE,
-                false,
-            ),
+            navigation_panel: navigation_panel(false),
         }
     }
+}
 
-    pub fn show<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.wind_panel.show(display)?;
-        self.rain_panel.show(display)?;
-        self.status_panel.show(display)?;
-        self.navigation_panel.show(display)?;
+impl View for WindRainStatusView {
+    fn id(&self) -> ViewId {
+        ViewId::WindRainStatus
+    }
 
-        Ok(())
+    fn name(&self) -> &'static str {
+        "wind_rain_status"
     }
 
-    pub fn hide<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.wind_panel.hide(display)?;
-        self.rain_panel.hide(display)?;
-        self.status_panel.hide(display)?;
-        self.navigation_panel.hide(display)?;
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![
+            &mut self.wind_panel,
+            &mut self.rain_panel,
+            &mut self.status_panel,
+        ]
+    }
 
-        Ok(())
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        Some(&mut self.navigation_panel)
     }
 }
 
 pub struct TimeDateView {
-    pub time_date_panel: Panel,
-    pub navigation_panel: NavigationPanel,
+    time_date_panel: Panel,
+    navigation_panel: NavigationPanel,
 }
 
 impl TimeDateView {
@@ -183,40 +385,107 @@ impl TimeDateView {
                 Size::new(320, 200),
                 Rgb565::BLACK,
             ),
-            navigation_panel: NavPanel::build(
-                Point::new(0, 201),
-                Size::new(320, 39),
-                Rgb565::CSS_DARK_SLATE_BLUE,
-                true,
-            ),
+            navigation_panel: navigation_panel(true),
         }
     }
+}
 
-    pub fn show<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.time_date_panel.show(display)?;
-        self.navigation_panel.show(display)?;
+impl View for TimeDateView {
+    fn id(&self) -> ViewId {
+        ViewId::TimeDate
+    }
 
-        Ok(())
+    fn name(&self) -> &'static str {
+        "time_date"
     }
 
-    pub fn hide<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
-    where
-        D: DrawTarget<Color = Rgb565>,
-    {
-        self.time_date_panel.hide(display)?;
-        self.navigation_panel.hide(display)?;
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![&mut self.time_date_panel]
+    }
 
-        Ok(())
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        Some(&mut self.navigation_panel)
+    }
+
+    fn set_action(&self) -> Option<SetAction> {
+        Some(SetAction::EditTimeDate)
     }
 }
 
-pub enum WindValueId {
-    CurrentWindSpeed = 2,
-    AverageWindSpeed = 4,
-    PeakWindSpeed = 6,
+pub struct SettingsView {
+    menu_panel: Panel,
+    navigation_panel: NavigationPanel,
+}
+
+impl SettingsView {
+    pub fn build() -> Self {
+        Self {
+            menu_panel: MenuPanel::build(Point::new(0, 0), Size::new(320, 200), Rgb565::BLACK),
+            navigation_panel: navigation_panel(true),
+        }
+    }
+}
+
+impl View for SettingsView {
+    fn id(&self) -> ViewId {
+        ViewId::Settings
+    }
+
+    fn name(&self) -> &'static str {
+        "settings"
+    }
+
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![&mut self.menu_panel]
+    }
+
+    fn navigation_panel(&mut self) -> Option<&mut NavigationPanel> {
+        Some(&mut self.navigation_panel)
+    }
+
+    fn set_action(&self) -> Option<SetAction> {
+        Some(SetAction::OpenSettingsMenu)
+    }
+
+    // The settings menu is only opened by hand
+    fn is_kiosk_view(&self) -> bool {
+        false
+    }
+}
+
+// A full screen message without navigation, used while the station can not show its measurements
+pub struct MessageView {
+    message_panel: Panel,
+}
+
+impl MessageView {
+    pub fn build() -> Self {
+        Self {
+            message_panel: MessagePanel::build(
+                Point::new(0, 0),
+                Size::new(320, 240),
+                Rgb565::CSS_DARK_SLATE_BLUE,
+            ),
+        }
+    }
+}
+
+impl View for MessageView {
+    fn id(&self) -> ViewId {
+        ViewId::Message
+    }
+
+    fn name(&self) -> &'static str {
+        "message"
+    }
+
+    fn panels(&mut self) -> Vec<&mut Panel> {
+        vec![&mut self.message_panel]
+    }
+
+    fn is_navigable(&self) -> bool {
+        false
+    }
 }
 
 struct WindPanel {}
@@ -256,7 +525,10 @@ impl WindPanel {
 
         // Value - Current WinndSpeed
         let mut value_label = Label::new()
-            .text("--")
+            .bind(
+                MeasurementField::CurrentWindSpeed,
+                Formatter::quantity(Quantity::WindSpeed),
+            )
             .font_color(Rgb565::BLUE)
             .backgound(background)
             .position(top_left + Point::new(55, 52));
@@ -270,7 +542,10 @@ impl WindPanel {
 
         // Value - Average WindSpeed
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::AverageWindSpeed,
+                Formatter::quantity(Quantity::WindSpeed),
+            )
             .position(top_left + Point::new(160, 52));
         panel_labels.push(value_label.clone());
 
@@ -282,7 +557,10 @@ impl WindPanel {
 
         // Value - Peak WindSpeed
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::PeakWindSpeed,
+                Formatter::quantity(Quantity::WindSpeed),
+            )
             .position(top_left + Point::new(270, 52));
         panel_labels.push(value_label);
 
@@ -290,11 +568,6 @@ impl WindPanel {
     }
 }
 
-pub enum RainValueId {
-    DailyRainfall = 2,
-    MonthlyRainfall = 4,
-    YearlyRainfall = 6,
-}
 struct RainPanel {}
 
 /**
@@ -330,7 +603,10 @@ impl RainPanel {
         panel_labels.push(title_label.clone());
 
         let mut value_label = Label::new()
-            .text("--")
+            .bind(
+                MeasurementField::DailyRainfall,
+                Formatter::quantity(Quantity::Rainfall),
+            )
             .font_color(Rgb565::BLUE)
             .backgound(background)
             .position(top_left + Point::new(55, 52));
@@ -342,7 +618,10 @@ impl RainPanel {
         panel_labels.push(title_label.clone());
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::MonthlyRainfall,
+                Formatter::quantity(Quantity::Rainfall),
+            )
             .position(top_left + Point::new(160, 52));
         panel_labels.push(value_label.clone());
 
@@ -352,7 +631,10 @@ impl RainPanel {
         panel_labels.push(title_label);
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::YearlyRainfall,
+                Formatter::quantity(Quantity::Rainfall),
+            )
             .position(top_left + Point::new(270, 52));
         panel_labels.push(value_label);
 
@@ -362,8 +644,8 @@ impl RainPanel {
 
 pub enum StatusValueId {
     Battery = 2,
-    Channel = 4,
-    ProductId = 6,
+    Rssi = 8,
+    Ip = 9,
 }
 struct StatusPanel {}
 
@@ -375,66 +657,83 @@ struct StatusPanel {}
  *  --------------------------------------------------
  *          0           Header
  *          1           Battery title
- *          2           Battery value
+ *          2           Battery value, OK or how long the battery has been low
  *          3           Channel title
  *          4           Channel value
  *          5           Product title
  *          6           Prodcut value
+ *          7           Wifi RSSI title
+ *          8           Wifi RSSI value
+ *          9           Wifi IP address value, next to the header
  */
 
 impl StatusPanel {
     pub fn build(top_left: Point, size: Size, background: Rgb565) -> Panel {
-        let mut panel_labels = Vec::with_capacity(7);
+        let mut panel_labels = Vec::with_capacity(10);
 
         let header_label = Label::new()
             .text("Status")
             .font(FONT_LUBS12)
             .font_color(Rgb565::CSS_DARK_RED)
             .backgound(background)
-            .position(top_left + Point::new(160, 16));
+            .position(top_left + Point::new(55, 16));
         panel_labels.push(header_label);
 
         let mut title_label = Label::new()
             .text("Battery")
-            .position(top_left + Point::new(55, 36));
+            .position(top_left + Point::new(42, 36));
         panel_labels.push(title_label.clone());
 
-        let mut value_label = Label::new()
+        // The battery and wifi values are not measurements, the gui sets them
+        let network_label = Label::new()
             .text("--")
             .font_color(Rgb565::BLUE)
-            .backgound(background)
-            .position(top_left + Point::new(55, 52));
-        panel_labels.push(value_label.clone());
+            .backgound(background);
+
+        panel_labels.push(
+            network_label
+                .clone()
+                .position(top_left + Point::new(42, 52)),
+        );
 
         title_label = title_label
             .text("Channel")
-            .position(top_left + Point::new(160, 36));
+            .position(top_left + Point::new(121, 36));
         panel_labels.push(title_label.clone());
 
-        value_label = value_label
-            .text("--")
-            .position(top_left + Point::new(160, 52));
+        let mut value_label = network_label
+            .clone()
+            .bind(MeasurementField::ChannelNumber, Formatter::unit(""))
+            .position(top_left + Point::new(121, 52));
         panel_labels.push(value_label.clone());
 
         title_label = title_label
             .text("Product")
-            .position(top_left + Point::new(270, 36));
-        panel_labels.push(title_label);
+            .position(top_left + Point::new(200, 36));
+        panel_labels.push(title_label.clone());
 
         value_label = value_label
-            .text("--")
-            .position(top_left + Point::new(270, 52));
+            .bind(MeasurementField::ProductId, Formatter::unit(""))
+            .position(top_left + Point::new(200, 52));
         panel_labels.push(value_label);
 
+        title_label = title_label
+            .text("RSSI")
+            .position(top_left + Point::new(279, 36));
+        panel_labels.push(title_label);
+
+        panel_labels.push(
+            network_label
+                .clone()
+                .position(top_left + Point::new(279, 52)),
+        );
+
+        panel_labels.push(network_label.position(top_left + Point::new(220, 16)));
+
         Panel::new(top_left, size, background, panel_labels)
     }
 }
 
-pub enum IndoorValueId {
-    Pressure = 2,
-    Temperature = 4,
-    Humidity = 6,
-}
 struct IndoorPanel {}
 
 /**
@@ -470,7 +769,10 @@ impl IndoorPanel {
         panel_labels.push(title_label.clone());
 
         let mut value_label = Label::new()
-            .text("--")
+            .bind(
+                MeasurementField::IndoorPressure,
+                Formatter::quantity(Quantity::Pressure),
+            )
             .font_color(Rgb565::BLUE)
             .backgound(background)
             .position(top_left + Point::new(55, 62));
@@ -482,7 +784,10 @@ impl IndoorPanel {
         panel_labels.push(title_label.clone());
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::IndoorTemperature,
+                Formatter::quantity(Quantity::Temperature),
+            )
             .position(top_left + Point::new(160, 61));
         panel_labels.push(value_label.clone());
 
@@ -492,7 +797,7 @@ impl IndoorPanel {
         panel_labels.push(title_label);
 
         value_label = value_label
-            .text("--")
+            .bind(MeasurementField::IndoorHumidity, Formatter::unit("%"))
             .position(top_left + Point::new(265, 62));
         panel_labels.push(value_label);
 
@@ -500,13 +805,109 @@ impl IndoorPanel {
     }
 }
 
-pub enum OutdoorValueId {
-    Humidity = 2,
-    Temperature = 4,
-    DewPoint = 6,
-    HeatIndex = 8,
-    WindChill = 10,
+struct IndoorAirPanel {}
+
+// A row of the indoor air panel, the title and how the value of its field is shown
+struct IndoorAirRow {
+    title: &'static str,
+    field: MeasurementField,
+    formatter: Formatter,
+}
+
+/**
+ * Build indoor air panel
+ *
+ *           Panel Labels Vector Layout
+ *        Element             Element Name
+ *  --------------------------------------------------
+ *          0           Header
+ *          1           Sensors found, below the rows
+ *        2 + 2n        Row n title
+ *        3 + 2n        Row n value
+ *
+ * The rows are CO2 when an SCD4x was found and Gas and Air Quality when a BME680 was found.
+ */
+
+impl IndoorAirPanel {
+    fn rows(indoor_sensors: &[Sensor]) -> Vec<IndoorAirRow> {
+        let mut rows = Vec::with_capacity(3);
+
+        if indoor_sensors.contains(&Sensor::Scd4x) {
+            rows.push(IndoorAirRow {
+                title: "CO2",
+                field: MeasurementField::IndoorCo2,
+                formatter: Formatter::unit(" ppm"),
+            });
+        }
+
+        if indoor_sensors.contains(&Sensor::Bme680) {
+            rows.push(IndoorAirRow {
+                title: "Gas",
+                field: MeasurementField::IndoorGasResistance,
+                formatter: Formatter::unit(" kOhm").precision(1),
+            });
+            rows.push(IndoorAirRow {
+                title: "Air Quality",
+                field: MeasurementField::IndoorAirQuality,
+                formatter: Formatter::unit("%"),
+            });
+        }
+
+        rows
+    }
+
+    pub fn build(
+        top_left: Point,
+        size: Size,
+        background: Rgb565,
+        rows: &[IndoorAirRow],
+        indoor_sensors: &[Sensor],
+    ) -> Panel {
+        let mut panel_labels = Vec::with_capacity(2 + 2 * rows.len());
+
+        let header_label = Label::new()
+            .text("Indoor Air")
+            .font(FONT_LUBS12)
+            .font_color(Rgb565::CSS_DARK_RED)
+            .backgound(background)
+            .position(top_left + Point::new(160, 16));
+        panel_labels.push(header_label);
+
+        let sensor_names: Vec<&str> = indoor_sensors.iter().map(|sensor| sensor.name()).collect();
+        let sensors_label = Label::new()
+            .text(&if sensor_names.is_empty() {
+                "No indoor sensors".to_string()
+            } else {
+                format!("Sensors: {}", sensor_names.join(", "))
+            })
+            .backgound(background)
+            .position(top_left + Point::new(160, 186));
+        panel_labels.push(sensors_label);
+
+        for (index, row) in rows.iter().enumerate() {
+            let y = 60 + 40 * index as i32;
+
+            panel_labels.push(
+                Label::new()
+                    .text(row.title)
+                    .backgound(background)
+                    .position(top_left + Point::new(90, y)),
+            );
+
+            panel_labels.push(
+                Label::new()
+                    .bind(row.field, row.formatter)
+                    .font(FONT_LUBS24)
+                    .font_color(Rgb565::BLUE)
+                    .backgound(background)
+                    .position(top_left + Point::new(220, y)),
+            );
+        }
+
+        Panel::new(top_left, size, background, panel_labels)
+    }
 }
+
 struct OutdoorPanel {}
 
 /**
@@ -547,7 +948,7 @@ impl OutdoorPanel {
         panel_labels.push(title_label.clone());
 
         let mut value_label = Label::new()
-            .text("--")
+            .bind(MeasurementField::OutdoorHumidity, Formatter::unit("%"))
             .font_color(Rgb565::BLUE)
             .backgound(background)
             .position(top_left + Point::new(55, 60));
@@ -559,7 +960,10 @@ impl OutdoorPanel {
         panel_labels.push(title_label.clone());
 
         let temp_label = Label::new()
-            .text("--")
+            .bind(
+                MeasurementField::OutdoorTemperature,
+                Formatter::quantity(Quantity::Temperature),
+            )
             .font(FONT_LUBS24)
             .font_color(Rgb565::BLUE)
             .backgound(background)
@@ -572,7 +976,10 @@ impl OutdoorPanel {
         panel_labels.push(title_label.clone());
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::OutdoorDewpoint,
+                Formatter::quantity(Quantity::Temperature),
+            )
             .position(top_left + Point::new(265, 60));
         panel_labels.push(value_label.clone());
 
@@ -582,7 +989,10 @@ impl OutdoorPanel {
         panel_labels.push(title_label.clone());
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::OutdoorHeatIndex,
+                Formatter::quantity(Quantity::Temperature),
+            )
             .position(top_left + Point::new(55, 98));
         panel_labels.push(value_label.clone());
 
@@ -592,7 +1002,10 @@ impl OutdoorPanel {
         panel_labels.push(title_label);
 
         value_label = value_label
-            .text("--")
+            .bind(
+                MeasurementField::OutdoorWindchill,
+                Formatter::quantity(Quantity::Temperature),
+            )
             .position(top_left + Point::new(265, 98));
         panel_labels.push(value_label);
 
@@ -603,13 +1016,26 @@ impl OutdoorPanel {
 pub enum TimeDateValueId {
     Time,
     Date,
+    ClockFormat,
+    Help,
 }
 
 struct TimeDatePanel {}
 
+/**
+ * Build time date panel
+ *
+ *           Panel Labels Vector Layout
+ *        Element             Element Name
+ *  --------------------------------------------------
+ *          0           Time
+ *          1           Date
+ *          2           Clock format, only while setting the time
+ *          3           Help, only while setting the time
+ */
 impl TimeDatePanel {
     pub fn build(top_left: Point, size: Size, background: Rgb565) -> Panel {
-        let mut panel_labels = Vec::with_capacity(2);
+        let mut panel_labels = Vec::with_capacity(4);
 
         let mut value_label = Label::new()
             .text("--")
@@ -623,12 +1049,132 @@ impl TimeDatePanel {
             .font(FONT_LUBS12)
             .text("--")
             .position(top_left + Point::new(160, 120));
+        panel_labels.push(value_label.clone());
+
+        value_label = value_label
+            .text("")
+            .position(top_left + Point::new(160, 155));
+        panel_labels.push(value_label.clone());
+
+        value_label = value_label
+            .font_color(Rgb565::WHITE)
+            .position(top_left + Point::new(160, 185));
         panel_labels.push(value_label);
 
         Panel::new(top_left, size, background, panel_labels)
     }
 }
 
+pub enum MessageValueId {
+    Title,
+    Line1,
+    Line2,
+    Line3,
+    Line4,
+    Line5,
+}
+
+struct MessagePanel {}
+
+/**
+ * Build message panel
+ *
+ *           Panel Labels Vector Layout
+ *        Element             Element Name
+ *  --------------------------------------------------
+ *          0           Title
+ *          1 - 5       Message lines
+ */
+impl MessagePanel {
+    pub fn build(top_left: Point, size: Size, background: Rgb565) -> Panel {
+        let mut panel_labels = Vec::with_capacity(6);
+
+        let title_label = Label::new()
+            .text("")
+            .font(FONT_LUBS12)
+            .font_color(Rgb565::YELLOW)
+            .backgound(background)
+            .position(top_left + Point::new(160, 30));
+        panel_labels.push(title_label);
+
+        let mut line_label = Label::new()
+            .text("")
+            .font_color(Rgb565::WHITE)
+            .backgound(background);
+
+        for line in 0..5 {
+            line_label = line_label.position(top_left + Point::new(160, 75 + line * 30));
+            panel_labels.push(line_label.clone());
+        }
+
+        Panel::new(top_left, size, background, panel_labels)
+    }
+}
+
+pub enum MenuValueId {
+    Title,
+    Help,
+    // The rows follow as a name label and a value label each
+    FirstRow,
+}
+
+pub const MENU_ROWS: usize = 6;
+
+struct MenuPanel {}
+
+/**
+ * Build menu panel
+ *
+ *           Panel Labels Vector Layout
+ *        Element             Element Name
+ *  --------------------------------------------------
+ *          0           Title
+ *          1           Help
+ *          2, 4 .. 12  Row names
+ *          3, 5 .. 13  Row values
+ */
+impl MenuPanel {
+    pub fn build(top_left: Point, size: Size, background: Rgb565) -> Panel {
+        let mut panel_labels = Vec::with_capacity(2 + 2 * MENU_ROWS);
+
+        let title_label = Label::new()
+            .text("Settings")
+            .font(FONT_LUBS12)
+            .font_color(Rgb565::YELLOW)
+            .backgound(background)
+            .position(top_left + Point::new(160, 18));
+        panel_labels.push(title_label);
+
+        let help_label = Label::new()
+            .text("")
+            .font_color(Rgb565::CSS_LIGHT_GRAY)
+            .backgound(background)
+            .position(top_left + Point::new(160, 188));
+        panel_labels.push(help_label);
+
+        let mut name_label = Label::new()
+            .text("")
+            .font(FONT_LUBS12)
+            .font_color(Rgb565::WHITE)
+            .backgound(background)
+            .horizontal_alignment(HorizontalAlignment::Left);
+        let mut value_label = name_label
+            .clone()
+            .font_color(Rgb565::CSS_LIGHT_GREEN)
+            .horizontal_alignment(HorizontalAlignment::Right);
+
+        for row in 0..MENU_ROWS as i32 {
+            name_label = name_label.position(top_left + Point::new(10, 48 + row * 24));
+            panel_labels.push(name_label.clone());
+
+            value_label = value_label.position(top_left + Point::new(310, 48 + row * 24));
+            panel_labels.push(value_label.clone());
+        }
+
+        Panel::new(top_left, size, background, panel_labels)
+    }
+}
+
 pub enum NavigationButtonId {
     Previous,
     Set,
@@ -670,6 +1216,37 @@ impl NavPanel {
             ),
         ));
 
-        NavigationPanel::new(top_left, size, background, panel_buttons)
+        let signal_icon = SignalIcon::new(top_left + Point::new(288, 8), background);
+
+        NavigationPanel::new(top_left, size, background, panel_buttons, signal_icon)
+    }
+}
+
+pub enum AlertBannerValueId {
+    Text,
+}
+
+pub struct AlertBannerPanel {}
+
+/**
+ * Build alert banner panel, it is drawn over the top of whichever view is showing
+ *
+ *           Panel Labels Vector Layout
+ *        Element             Element Name
+ *  --------------------------------------------------
+ *          0           Alert text
+ */
+impl AlertBannerPanel {
+    pub fn build() -> Panel {
+        let top_left = Point::new(0, 0);
+        let background = Rgb565::CSS_DARK_RED;
+
+        let text_label = Label::new()
+            .text("")
+            .font_color(Rgb565::WHITE)
+            .backgound(background)
+            .position(top_left + Point::new(160, 13));
+
+        Panel::new(top_left, Size::new(320, 26), background, vec![text_label])
     }
 }
@@ -6,10 +6,15 @@ use u8g2_fonts::{
     FontRenderer,
 };
 
+use crate::{
+    gui::bindings::{Binding, Formatter},
+    model::{measurement_cache::MeasurementCache, units::Units, weather_station::MeasurementField},
+};
+
 use embedded_graphics::{
     pixelcolor::Rgb565,
     prelude::*,
-    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, RoundedRectangle},
+    primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, RoundedRectangle},
 };
 
 #[derive(Clone)]
@@ -142,6 +147,7 @@ pub struct Label {
     vertical_position: VerticalPosition,
     horizontal_alignment: HorizontalAlignment,
     text_bounding_box: Rectangle,
+    binding: Option<Binding>,
 }
 
 #[allow(dead_code)]
@@ -185,6 +191,13 @@ impl Label {
         self
     }
 
+    // Show the latest value of a measurement, the placeholder is shown until a value arrives
+    pub fn bind(mut self, field: MeasurementField, formatter: Formatter) -> Self {
+        self.text = formatter.placeholder_text().to_string();
+        self.binding = Some(Binding { field, formatter });
+        self
+    }
+
     pub fn set_text(&mut self, text: &str) {
         self.text = text.to_string();
     }
@@ -232,6 +245,7 @@ impl Default for Label {
             vertical_position: VerticalPosition::Center,
             horizontal_alignment: HorizontalAlignment::Center,
             text_bounding_box: Rectangle::zero(),
+            binding: None,
         }
     }
 }
@@ -306,12 +320,43 @@ impl Panel {
 
         Ok(())
     }
+
+    // Render the labels bound to measurements from their latest values, only the labels whose text changed are drawn
+    pub fn update_bindings<D>(
+        &mut self,
+        display: &mut D,
+        measurements: &MeasurementCache,
+        units: Units,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        for label in &mut self.panel_labels {
+            if let Some(binding) = label.binding {
+                let text = binding
+                    .formatter
+                    .format(measurements.get(binding.field), units);
+
+                if text != label.text {
+                    label.set_text(&text);
+
+                    if self.is_showing {
+                        label.erase_old_text(display)?;
+                        label.show(display)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub struct NavigationPanel {
     panel: Rectangle,
     background: Rgb565,
     buttons: Vec<RoundedButton>,
+    signal_icon: SignalIcon,
     is_showing: bool,
 }
 
@@ -321,11 +366,13 @@ impl NavigationPanel {
         size: Size,
         background: Rgb565,
         buttons: Vec<RoundedButton>,
+        signal_icon: SignalIcon,
     ) -> Self {
         Self {
             panel: Rectangle::new(top_left, size),
             background,
             buttons,
+            signal_icon,
             is_showing: false,
         }
     }
@@ -345,6 +392,45 @@ impl NavigationPanel {
             self.show_button_released(display, button)?;
         }
 
+        self.signal_icon.show(display)?;
+
+        Ok(())
+    }
+
+    pub fn update_signal_icon<D>(
+        &mut self,
+        display: &mut D,
+        state: SignalIconState,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        if self.signal_icon.state != state {
+            self.signal_icon.state = state;
+
+            if self.is_showing {
+                self.signal_icon.show(display)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_button_text<D>(
+        &mut self,
+        display: &mut D,
+        button_id: usize,
+        text: &str,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        self.buttons[button_id].text = text.to_string();
+
+        if self.is_showing {
+            self.show_button_released(display, button_id)?;
+        }
+
         Ok(())
     }
 
@@ -386,6 +472,8 @@ impl NavigationPanel {
     where
         D: DrawTarget<Color = Rgb565>,
     {
+        self.is_showing = false;
+
         self.panel
             .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
             .draw(display)
@@ -394,3 +482,96 @@ impl NavigationPanel {
         Ok(())
     }
 }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SignalIconState {
+    Connecting,
+    Connected(u8),
+    Disconnected,
+    AccessPoint,
+}
+
+/**
+ * Wifi signal strength as four bars of increasing height.  The bars are grey while connecting, crossed out in red
+ * when the link is lost and replaced by "AP" while the setup access point is up.
+ */
+pub struct SignalIcon {
+    area: Rectangle,
+    background: Rgb565,
+    pub state: SignalIconState,
+}
+
+impl SignalIcon {
+    pub fn new(top_left: Point, background: Rgb565) -> Self {
+        Self {
+            area: Rectangle::new(top_left, Size::new(27, 24)),
+            background,
+            state: SignalIconState::Connecting,
+        }
+    }
+
+    pub fn show<D>(&mut self, display: &mut D) -> Result<(), Error<D::Error>>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        self.area
+            .into_styled(PrimitiveStyle::with_fill(self.background))
+            .draw(display)
+            .map_err(|e| DisplayError(e))?;
+
+        if self.state == SignalIconState::AccessPoint {
+            FontRenderer::new::<fonts::u8g2_font_9x15B_tr>().render_aligned(
+                "AP",
+                self.area.center(),
+                VerticalPosition::Center,
+                HorizontalAlignment::Center,
+                FontColor::Transparent(Rgb565::YELLOW),
+                display,
+            )?;
+
+            return Ok(());
+        }
+
+        let bars = match self.state {
+            SignalIconState::Connected(bars) => bars,
+            _ => 0,
+        };
+
+        let top_left = self.area.top_left;
+        for bar in 0..4u8 {
+            let height = 6 * (bar as u32 + 1);
+            let color = if bar < bars {
+                Rgb565::WHITE
+            } else {
+                Rgb565::CSS_GRAY
+            };
+
+            Rectangle::new(
+                top_left + Point::new(bar as i32 * 7, 24 - height as i32),
+                Size::new(5, height),
+            )
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(display)
+            .map_err(|e| DisplayError(e))?;
+        }
+
+        if self.state == SignalIconState::Disconnected {
+            let style = PrimitiveStyle::with_stroke(Rgb565::RED, 3);
+            let bottom_right = self.area.bottom_right().unwrap_or(top_left);
+
+            Line::new(top_left, bottom_right)
+                .into_styled(style)
+                .draw(display)
+                .map_err(|e| DisplayError(e))?;
+            Line::new(
+                Point::new(top_left.x, bottom_right.y),
+                Point::new(bottom_right.x, top_left.y),
+            )
+            .into_styled(style)
+            .draw(display)
+            .map_err(|e| DisplayError(e))?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,23 +1,16 @@
 mod gui;
 mod model;
+mod net;
 
-use log::*;
-
-use anyhow::{bail, Ok, Result};
 use std::time::Duration;
 
-//use ds323x::Ds323x;
-use embedded_svc::wifi::Wifi;
-use esp_idf_hal::{delay::FreeRtos, i2c::I2cDriver, peripheral};
+use anyhow::{Ok, Result};
+
+use ds323x::Ds323x;
+use esp_idf_hal::{delay::FreeRtos, i2c::I2cDriver};
 use esp_idf_sys::{self as _}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
-use esp_idf_svc::{
-    eventloop::{self, EspSystemEventLoop},
-    netif::{EspNetif, EspNetifWait},
-    nvs::EspDefaultNvsPartition,
-    sntp,
-    wifi::{EspWifi, WifiWait},
-};
+use esp_idf_svc::{eventloop, nvs::EspDefaultNvsPartition};
 
 use crossbeam_channel::bounded;
 
@@ -25,15 +18,37 @@ use crate::{
     gui::gui::Gui,
     model::{
         acurite5n1::Acurite5n1,
+        battery_log::NvsBatteryLog,
+        button_events::ButtonTiming,
+        config::{ConfigStore, NvsConfigBackend},
+        history::{HistoryStore, Sensor},
+        indoor_sensors,
         peripherals::{SystemPeripherals, RMT_RX_BUF_SIZE},
+        real_time_clock::RealTimeClock,
         scheduler::Scheduler,
+        supervisor::{Heartbeat, RebootLog, Supervisor},
+        time_sync::TimeSync,
         user_buttons::UserButtons,
         weather_station::WeatherStation,
     },
+    net::{
+        aprs::AprsReporter,
+        http_server::HttpServer,
+        influxdb::InfluxDbExporter,
+        mqtt::MqttPublisher,
+        provisioning::CaptivePortal,
+        pws::PwsUploader,
+        wifi::{self, WifiSupervisor},
+    },
 };
 
-const WIFI_SSID: &str = env!("WIFI_SSID");
-const WIFI_PASSWORD: &str = env!("WIFI_PASSWORD");
+// How long each thread may go without beating its heartbeat before the supervisor reboots the station.  The Acurite5n1
+// receiver waits 17 s after each message and the network threads block on connects and http requests.
+const UI_MAX_SILENCE: Duration = Duration::from_secs(30);
+const WEATHER_STATION_MAX_SILENCE: Duration = Duration::from_secs(60);
+const RECEIVER_MAX_SILENCE: Duration = Duration::from_secs(60);
+const TIME_SYNC_MAX_SILENCE: Duration = Duration::from_secs(60);
+const NETWORK_MAX_SILENCE: Duration = Duration::from_secs(3 * 60);
 
 fn main() -> Result<()> {
     // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
@@ -45,103 +60,218 @@ fn main() -> Result<()> {
 
     let peripherals = SystemPeripherals::take();
     let sysloop = eventloop::EspSystemEventLoop::take()?;
-    let _wifi = wifi(peripherals.modem, sysloop.clone())?;
+    let nvs = EspDefaultNvsPartition::take()?;
 
-    let sntp = sntp::EspSntp::new_default()?;
-    while sntp.get_sync_status() != sntp::SyncStatus::Completed {
-        FreeRtos::delay_ms(100);
-    }
-    info!("SNTP initialized");
+    // Report why the station rebooted, the supervisor records its own reason before it reboots
+    let mut reboot_log = RebootLog::new(nvs.clone())?;
+    let last_reboot = reboot_log.take_last_reason();
+    println!("Last reboot: {}", last_reboot);
+
+    // The supervisor owns every thread started below and watches their heartbeats
+    let mut supervisor = Supervisor::new(reboot_log);
+
+    // Load the runtime config from nvs, it is shared with the threads that use it and the http server
+    let config = ConfigStore::new_shared(Box::new(NvsConfigBackend::new(nvs.clone())?));
+    let config_at_boot = config.lock().unwrap().config().clone();
 
     // Create Crossbeam channels
     let (tx1, rx1) = bounded(5); // tx = Acurite5n1,     rx = WeatherStation
     let (tx2, rx2) = bounded(40); // tx = WeatherStation rx = Gui
-    let (tx3, rx3) = bounded(5); // tx = UserButtons     rx = Gui
+    let (tx3, rx3) = bounded(10); // tx = UserButtons    rx = Gui
     let (tx4, rx4) = bounded(2); // tx = Scheduler       rx = Gui
     let (tx5, rx5) = bounded(5); // tx = Scheduler       rx = WeatherStation
+    let (tx6, rx6) = bounded(40); // tx = WeatherStation rx = MqttPublisher
+    let (tx7, rx7) = bounded(5); // tx = CaptivePortal, WifiSupervisor rx = Gui
+    let (tx8, rx8) = bounded(2); // tx = Gui             rx = TimeSync
+    let (tx9, rx9) = bounded(10); // tx = WeatherStation rx = Gui
+    let (tx10, rx10) = bounded(1); // tx = Gui           rx = WeatherStation
 
-    // Create the I2C devices
-    //let mut rtc = RealTimeClock::new(Ds323x::new_ds3231(i2c_bus_manager.acquire_i2c()));
+    // Create the history store shared by the weather station and the http server, the low battery periods are kept
+    // in nvs
+    let history = HistoryStore::new_shared(Box::new(NvsBatteryLog::new(nvs.clone())?));
 
+    // Create the I2C devices
     use std::result::Result::Ok;
     let i2c_bus_manager: &'static _ =
         shared_bus::new_std!(I2cDriver = peripherals.i2c0_driver).unwrap();
-    let i2c0_proxy_1 = i2c_bus_manager.acquire_i2c();
+    let mut rtc = RealTimeClock::new(Ds323x::new_ds3231(i2c_bus_manager.acquire_i2c()));
+
+    // Start with the time kept by the DS3231 so the station has the right time without a network
+    if let Err(e) = rtc.set_system_clock() {
+        println!("System clock not set from RTC: {}", e);
+    }
+
+    // Scan the I2C bus for the indoor sensors, the station runs without any
+    let indoor_sensors = indoor_sensors::detect(i2c_bus_manager);
+    let indoor_sensor_kinds: Vec<Sensor> = indoor_sensors
+        .iter()
+        .map(|indoor_sensor| indoor_sensor.driver.sensor())
+        .collect();
 
     // Create user buttons
-    let user_buttons = UserButtons::new(peripherals.buttons, tx3);
+    let user_buttons = UserButtons::new(peripherals.buttons, ButtonTiming::default(), tx3);
 
     // Create the Acurite5n1 weather sensor
     let acurite5n1 = Acurite5n1::new(tx1, peripherals.rx_rmt_driver, RMT_RX_BUF_SIZE);
 
     // Create the weather station
-    let weather_station = WeatherStation::new(i2c0_proxy_1, rx1, rx5, tx2);
+    let weather_station = WeatherStation::new(
+        indoor_sensors,
+        rx1,
+        rx5,
+        rx10,
+        tx2,
+        tx6,
+        tx9,
+        history.clone(),
+        config.clone(),
+    );
 
     // Create the Gui
-    let gui = Gui::new(peripherals.display, rx2, rx3, rx4);
+    let gui = Gui::new(
+        peripherals.display,
+        peripherals.speaker,
+        rx2,
+        rx3,
+        rx4,
+        rx7,
+        rx9,
+        tx8,
+        tx10,
+        config.clone(),
+        indoor_sensor_kinds,
+    );
 
     // Create the Scheduler
-    let scheduler = Scheduler::new(tx4, tx5);
-
-    // Start the threads
-    user_buttons.start();
-    weather_station.start();
-    gui.start();
+    let scheduler = Scheduler::new(tx4, tx5, config.clone());
+
+    // Start the threads, the station shows the local sensors whether or not the network comes up
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "user_buttons",
+        UI_MAX_SILENCE,
+        heartbeat.clone(),
+        user_buttons.start(heartbeat),
+    );
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "weather_station",
+        WEATHER_STATION_MAX_SILENCE,
+        heartbeat.clone(),
+        weather_station.start(heartbeat),
+    );
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "gui",
+        UI_MAX_SILENCE,
+        heartbeat.clone(),
+        gui.start(heartbeat),
+    );
     FreeRtos::delay_ms(30);
-    acurite5n1.start();
-    scheduler.start();
-
-    //#[cfg_attr(link_section = ".rtc.data.rtc_memory" )]
-    //static mut YOUR_RTC_DATA_STRUCT: rtc_data = rtc_data::new();
-
-    loop {
-        FreeRtos::delay_ms(1000);
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "acurite5n1",
+        RECEIVER_MAX_SILENCE,
+        heartbeat.clone(),
+        acurite5n1.start(heartbeat),
+    );
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "scheduler",
+        UI_MAX_SILENCE,
+        heartbeat.clone(),
+        scheduler.start(heartbeat),
+    );
+
+    let mut esp_wifi = wifi::create(peripherals.modem, sysloop.clone(), nvs)?;
+
+    // SNTP synchronizes in the background whenever the network is up and the time is written back into the RTC, a time
+    // set by hand from the Gui is written to both clocks
+    let heartbeat = Heartbeat::new();
+    supervisor.supervise(
+        "time_sync",
+        TIME_SYNC_MAX_SILENCE,
+        heartbeat.clone(),
+        TimeSync::new(rtc, rx8).start(heartbeat),
+    );
+
+    // Without a network the setup portal is started, the stored network is still retried in the background
+    let portal = match wifi::connect(&mut esp_wifi, &sysloop, &config_at_boot.wifi) {
+        Ok(()) => None,
+        Err(e) => {
+            println!("{:?}, starting the wifi setup portal", e);
+            Some(
+                CaptivePortal::new(config.clone(), tx7.clone())
+                    .start(&mut esp_wifi, &config_at_boot.wifi)?,
+            )
+        }
+    };
+
+    // Connect or reconnect and show the link state on the display
+    let heartbeat = Heartbeat::new();
+    let wifi_supervisor_thread =
+        WifiSupervisor::new(esp_wifi, sysloop, tx7).start(heartbeat.clone())?;
+    supervisor.supervise(
+        "wifi_supervisor",
+        NETWORK_MAX_SILENCE,
+        heartbeat,
+        wifi_supervisor_thread,
+    );
+
+    // Start publishing to the mqtt broker if one is configured.  It is started before the network is up so that it
+    // reads the weather station's channel from the start, it drops what arrives until it has connected.  The network
+    // services use the config they were started with, a change to their settings applies after a restart.  They only
+    // need the history and their config, so the supervisor starts them again if they stop.
+    if MqttPublisher::is_configured(&config_at_boot.mqtt) {
+        let history = history.clone();
+        let mqtt_config = config_at_boot.mqtt.clone();
+        supervisor.supervise_restartable("mqtt_publisher", NETWORK_MAX_SILENCE, move |heartbeat| {
+            MqttPublisher::new(rx6.clone(), history.clone(), mqtt_config.clone()).start(heartbeat)
+        });
+    } else {
+        // Drop the receiver so the weather station does not count a full channel as a send failure
+        drop(rx6);
     }
-}
 
-fn wifi(
-    modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-) -> Result<Box<EspWifi<'static>>> {
-    use std::net::Ipv4Addr;
-    let nvs = EspDefaultNvsPartition::take().unwrap();
-    let mut esp_wifi = Box::new(EspWifi::new(modem, sysloop.clone(), Some(nvs))?);
-
-    esp_wifi.set_configuration(&embedded_svc::wifi::Configuration::Client(
-        embedded_svc::wifi::ClientConfiguration {
-            ssid: WIFI_SSID.into(),
-            password: WIFI_PASSWORD.into(),
-            ..Default::default()
-        },
-    ))?;
-
-    esp_wifi.start().unwrap();
-    info!("Starting wifi");
-
-    if !WifiWait::new(&sysloop)
-        .unwrap()
-        .wait_with_timeout(Duration::from_secs(20), || esp_wifi.is_started().unwrap())
-    {
-        bail!("Wifi did not start");
-    }
+    // The other network services need the network, they start once the stored network is connected
+    supervisor.run_until(wifi::is_link_up);
+    drop(portal);
 
-    info!("Connecting wifi ...");
+    // Start the http server, the server stops if it is dropped
+    let _http_server = HttpServer::new(history.clone(), config, last_reboot).start()?;
 
-    esp_wifi.connect().unwrap();
+    // Start uploading to Weather Underground, PWSweather and WOW if any of them are configured
+    if PwsUploader::is_configured(&config_at_boot.pws) {
+        let history = history.clone();
+        let pws_config = config_at_boot.pws.clone();
+        supervisor.supervise_restartable("pws_uploader", NETWORK_MAX_SILENCE, move |heartbeat| {
+            PwsUploader::new(history.clone(), &pws_config).start(heartbeat)
+        });
+    }
 
-    if !EspNetifWait::new::<EspNetif>(esp_wifi.sta_netif(), &sysloop)?.wait_with_timeout(
-        Duration::from_secs(45),
-        || {
-            esp_wifi.is_connected().unwrap()
-                && esp_wifi.sta_netif().get_ip_info().unwrap().ip != Ipv4Addr::new(0, 0, 0, 0)
-        },
-    ) {
-        bail!("Wifi did not connect or did not receive a DHCP lease");
+    // Start sending APRS weather reports to CWOP if a callsign is configured
+    if AprsReporter::is_configured(&config_at_boot.aprs) {
+        let history = history.clone();
+        let aprs_config = config_at_boot.aprs.clone();
+        supervisor.supervise_restartable("aprs_reporter", NETWORK_MAX_SILENCE, move |heartbeat| {
+            AprsReporter::new(history.clone(), aprs_config.clone()).start(heartbeat)
+        });
     }
 
-    let ip_info = esp_wifi.sta_netif().get_ip_info()?;
+    // Start pushing the history points to InfluxDB if a server is configured
+    if InfluxDbExporter::is_configured(&config_at_boot.influxdb) {
+        let influxdb_config = config_at_boot.influxdb.clone();
+        supervisor.supervise_restartable(
+            "influxdb_exporter",
+            NETWORK_MAX_SILENCE,
+            move |heartbeat| {
+                InfluxDbExporter::new(history.clone(), &influxdb_config).start(heartbeat)
+            },
+        );
+    }
 
-    info!("Wifi DHCP info: {:?}", ip_info);
+    //#[cfg_attr(link_section = ".rtc.data.rtc_memory" )]
+    //static mut YOUR_RTC_DATA_STRUCT: rtc_data = rtc_data::new();
 
-    Ok(esp_wifi)
+    supervisor.run();
 }
@@ -1,11 +1,20 @@
 use log::*;
-use std::thread;
+use std::fmt;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use esp_idf_hal::delay::{FreeRtos, BLOCK};
+use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::rmt::{PinState, Pulse, Receive, RxRmtDriver};
+use esp_idf_sys::{configTICK_RATE_HZ, EspError};
 
 use crossbeam_channel::Sender;
 
+use crate::model::faults::{Fault, FAULTS};
+use crate::model::retry::retry;
+use crate::model::statistics::{increment, STATISTICS};
+use crate::model::supervisor::Heartbeat;
+use crate::net::backoff::Backoff;
+
 const SYNC_PULSE: u16 = 620;
 const SYNC_MARGIN: u16 = 60;
 const SHORT_PULSE: u16 = 210;
@@ -13,6 +22,33 @@ const SHORT_MARGIN: u16 = 100;
 const LONG_PULSE: u16 = 420;
 const LONG_MARGIN: u16 = 90;
 
+// A receiver that keeps failing is restarted after a delay that doubles up to a minute
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+// The receiver gives up waiting for pulses after a while so the thread keeps beating its heartbeat while the
+// Acurite5n1 is not heard, a transmitter that went quiet is reported by the sensor offline alert
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum ReceiverError {
+    Start(EspError),
+    Stop(EspError),
+    Receive(EspError),
+}
+
+impl fmt::Display for ReceiverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiverError::Start(e) => write!(f, "failed to start the RMT receiver: {}", e),
+            ReceiverError::Stop(e) => write!(f, "failed to stop the RMT receiver: {}", e),
+            ReceiverError::Receive(e) => write!(f, "failed to receive RMT pulses: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReceiverError {}
+
 pub enum Acurite5n1Message {
     Type1(MessageType1),
     Type8(MessageType8),
@@ -22,7 +58,31 @@ pub struct MessageHeader {
     pub channel_number: u8,
     pub report_number: u8,
     pub product_id: u16,
-    pub status: u8,
+    pub status: StatusFlags,
+}
+
+/**
+ * The status nibble of the header, the upper nibble of the byte that holds the message type
+ *
+ *      bit 3       parity of the byte, already checked by the decoder
+ *      bit 2       battery, 1 while the battery is good and 0 when it needs replacing
+ *      bits 1-0    upper bits of the message type, always 0b11 (the message types are 0x31 and 0x38)
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusFlags {
+    pub parity: bool,
+    pub battery_low: bool,
+    pub message_type_msb: u8,
+}
+
+impl From<u8> for StatusFlags {
+    fn from(status: u8) -> Self {
+        Self {
+            parity: status & 0b1000 != 0,
+            battery_low: status & 0b0100 == 0,
+            message_type_msb: status & 0b0011,
+        }
+    }
 }
 
 pub struct MessageType1 {
@@ -80,28 +140,79 @@ impl Acurite5n1 {
         }
     }
 
-    pub fn start(mut self) {
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
         println!("Starting Acurite5n1 Thread");
 
-        let _acurite5n1_thread = thread::spawn(move || {
+        thread::spawn(move || {
             let mut pulses = vec![(Pulse::zero(), Pulse::zero()); self.rmt_rx_buf_size];
             let mut message_pulses: Vec<u16> = Vec::new();
-
-            self.rmt_rx.start().unwrap();
+            let mut backoff = Backoff::new(MIN_RESTART_DELAY, MAX_RESTART_DELAY);
+            let mut started = false;
 
             loop {
-                // Block until rmt items are available
-                let rmt_items = self.rmt_rx.receive(&mut pulses, BLOCK).unwrap();
+                heartbeat.beat();
+
+                let result = if started {
+                    self.receive(&mut pulses, &mut message_pulses)
+                } else {
+                    self.start_receiver()
+                };
 
-                match rmt_items {
-                    Receive::Read(length) => {
-                        self.parse_pulse_stream(&mut pulses, length, &mut message_pulses)
+                match result {
+                    Ok(_) => {
+                        started = true;
+                        backoff.reset();
+                        if FAULTS.clear(Fault::Receiver) {
+                            println!("Receiver restarted");
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}, restarting the receiver", e);
+                        FAULTS.raise(Fault::Receiver);
+
+                        // The receiver may already be stopped, it is started again on the next pass
+                        let _ = self.rmt_rx.stop();
+                        started = false;
+                        message_pulses.clear();
+                        self.clear_decoder();
+                        heartbeat.sleep(backoff.next_delay());
                     }
-                    Receive::Overflow(len) => println!("pulses buffer overflowed by {}", len),
-                    Receive::Timeout => println!("Receiver timeout"),
                 }
             }
-        });
+        })
+    }
+
+    fn start_receiver(&mut self) -> Result<(), ReceiverError> {
+        retry(|| self.rmt_rx.start()).map_err(ReceiverError::Start)
+    }
+
+    fn stop_receiver(&mut self) -> Result<(), ReceiverError> {
+        retry(|| self.rmt_rx.stop()).map_err(ReceiverError::Stop)
+    }
+
+    fn receive(
+        &mut self,
+        pulses: &mut Vec<(Pulse, Pulse)>,
+        message_pulses: &mut Vec<u16>,
+    ) -> Result<(), ReceiverError> {
+        // Wait until rmt items are available or the receive times out
+        let timeout_ticks = RECEIVE_TIMEOUT.as_millis() as u32 * configTICK_RATE_HZ / 1000;
+        let rmt_items = self
+            .rmt_rx
+            .receive(pulses, timeout_ticks)
+            .map_err(ReceiverError::Receive)?;
+
+        match rmt_items {
+            Receive::Read(length) => self.parse_pulse_stream(pulses, length, message_pulses)?,
+            Receive::Overflow(len) => {
+                increment(&STATISTICS.rmt_overflows);
+                println!("pulses buffer overflowed by {}", len)
+            }
+            // Nothing was heard, the thread beats its heartbeat and waits again
+            Receive::Timeout => increment(&STATISTICS.rmt_timeouts),
+        }
+
+        Ok(())
     }
 
     fn parse_pulse_stream(
@@ -109,7 +220,7 @@ impl Acurite5n1 {
         the_pulses: &Vec<(Pulse, Pulse)>,
         length: usize,
         message_pulses: &mut Vec<u16>,
-    ) {
+    ) -> Result<(), ReceiverError> {
         // A packet of 3 messages from the Acurite5n1 is typically 206 pulse-pairs
         if length > 0 {
             let pulses = &the_pulses[..length];
@@ -193,7 +304,7 @@ impl Acurite5n1 {
 
             if self.decoder.found_message {
                 warn!("Stopping RMT");
-                self.rmt_rx.stop().unwrap();
+                self.stop_receiver()?;
 
                 if self.found_good_message(message_pulses) {
                     self.process_message()
@@ -208,11 +319,13 @@ impl Acurite5n1 {
                 FreeRtos::delay_ms(17000);
 
                 info!("################  Starting RMT #################");
-                self.rmt_rx.start().unwrap();
+                self.start_receiver()?;
             }
 
             self.clear_decoder();
         }
+
+        Ok(())
     }
 
     fn clear_decoder(&mut self) {
@@ -239,6 +352,7 @@ impl Acurite5n1 {
             if bit_count == 64 {
                 // We only need one good message since all the messages in message_pulses have same measurement values
                 if self.is_message_integrity_good(self.decoder.decoded_message) {
+                    increment(&STATISTICS.frames_decoded);
                     warn!("good message = {:#017x}", self.decoder.decoded_message);
                     good_message_found = true;
                     break 'outer;
@@ -267,7 +381,7 @@ impl Acurite5n1 {
             channel_number,
             product_id,
             report_number,
-            status,
+            status: StatusFlags::from(status),
         };
 
         if message_type == 1 {
@@ -283,9 +397,13 @@ impl Acurite5n1 {
                 wind_direction,
                 rain_bucket_tips,
             };
-            self.tx1
+            if self
+                .tx1
                 .send(Acurite5n1Message::Type1(message_type_1))
-                .unwrap();
+                .is_err()
+            {
+                increment(&STATISTICS.channel_send_failures);
+            }
         } else {
             // Message Type 8
             let temp_msb: u16 = self.read_bit_field(self.decoder.decoded_message, 27, 24) as u16;
@@ -299,9 +417,13 @@ impl Acurite5n1 {
                 temperature,
                 humidity,
             };
-            self.tx1
+            if self
+                .tx1
                 .send(Acurite5n1Message::Type8(message_type_8))
-                .unwrap();
+                .is_err()
+            {
+                increment(&STATISTICS.channel_send_failures);
+            }
         }
     }
 
@@ -345,13 +467,26 @@ impl Acurite5n1 {
         has_even_parity
     }
 
-    // Check message if message type is valid message and messsage is valid
+    // Check message if message type is valid message and messsage is valid, failures are counted in the statistics
     fn is_message_integrity_good(&self, message: u64) -> bool {
         let message_type = self.read_bit_field(message, 43, 40);
 
-        (message_type == 1 || message_type == 8)
-            && self.is_crc_valid(message)
-            && self.is_parity_valid(message)
+        if message_type != 1 && message_type != 8 {
+            increment(&STATISTICS.invalid_message_types);
+            return false;
+        }
+
+        if !self.is_crc_valid(message) {
+            increment(&STATISTICS.crc_failures);
+            return false;
+        }
+
+        if !self.is_parity_valid(message) {
+            increment(&STATISTICS.parity_failures);
+            return false;
+        }
+
+        true
     }
 
     // Read a bit field (note: The maximum bits in bit field is 8)
@@ -367,3 +502,28 @@ impl Acurite5n1 {
         bit_field as u8
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_flags_from_the_status_nibble() {
+        assert_eq!(
+            StatusFlags::from(0x7),
+            StatusFlags {
+                parity: false,
+                battery_low: false,
+                message_type_msb: 0b11,
+            }
+        );
+        assert_eq!(
+            StatusFlags::from(0x3),
+            StatusFlags {
+                parity: false,
+                battery_low: true,
+                message_type_msb: 0b11,
+            }
+        );
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::model::{config::AlertsConfig, units::Quantity, weather_station::Measurement};
+
+// An alert clears once the value is back past its threshold by this much, so a value hovering at the threshold does not
+// raise it over and over
+const TEMPERATURE_HYSTERESIS_F: f32 = 2.0;
+const WIND_HYSTERESIS_MPH: f32 = 5.0;
+const RAIN_RATE_HYSTERESIS_IN: f32 = 0.1;
+const HUMIDITY_HYSTERESIS: f32 = 3.0;
+
+// The rain rate is the rain that fell in the last hour
+const RAIN_RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertKind {
+    Frost,
+    HighTemperature,
+    HeatIndex,
+    HighWind,
+    RainRate,
+    DailyRainfall,
+    IndoorHumidityLow,
+    IndoorHumidityHigh,
+    BatteryLow,
+    SensorOffline,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 10] = [
+        AlertKind::Frost,
+        AlertKind::HighTemperature,
+        AlertKind::HeatIndex,
+        AlertKind::HighWind,
+        AlertKind::RainRate,
+        AlertKind::DailyRainfall,
+        AlertKind::IndoorHumidityLow,
+        AlertKind::IndoorHumidityHigh,
+        AlertKind::BatteryLow,
+        AlertKind::SensorOffline,
+    ];
+
+    // The name used for this alert in the json api and any other exporters
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertKind::Frost => "frost",
+            AlertKind::HighTemperature => "high_temperature",
+            AlertKind::HeatIndex => "heat_index",
+            AlertKind::HighWind => "high_wind",
+            AlertKind::RainRate => "rain_rate",
+            AlertKind::DailyRainfall => "daily_rainfall",
+            AlertKind::IndoorHumidityLow => "indoor_humidity_low",
+            AlertKind::IndoorHumidityHigh => "indoor_humidity_high",
+            AlertKind::BatteryLow => "battery_low",
+            AlertKind::SensorOffline => "sensor_offline",
+        }
+    }
+
+    // The text shown on the display, followed by the value
+    pub fn title(&self) -> &'static str {
+        match self {
+            AlertKind::Frost => "Frost",
+            AlertKind::HighTemperature => "Heat",
+            AlertKind::HeatIndex => "Heat index",
+            AlertKind::HighWind => "Gust",
+            AlertKind::RainRate => "Rain rate",
+            AlertKind::DailyRainfall => "Daily rain",
+            AlertKind::IndoorHumidityLow => "Indoor dry",
+            AlertKind::IndoorHumidityHigh => "Indoor humid",
+            AlertKind::BatteryLow => "Sensor battery low",
+            AlertKind::SensorOffline => "Sensor offline",
+        }
+    }
+
+    // The quantity of the value, None for the humidity alerts and the alerts that have no value to show
+    pub fn quantity(&self) -> Option<Quantity> {
+        match self {
+            AlertKind::Frost | AlertKind::HighTemperature | AlertKind::HeatIndex => {
+                Some(Quantity::Temperature)
+            }
+            AlertKind::HighWind => Some(Quantity::WindSpeed),
+            AlertKind::RainRate | AlertKind::DailyRainfall => Some(Quantity::Rainfall),
+            _ => None,
+        }
+    }
+}
+
+// The value is in the units the station measures in, the rain rate is in inches per hour
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub value: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertEvent {
+    Raised(Alert),
+    Cleared(AlertKind),
+}
+
+// A rule is broken while the value is above the threshold, or below it for a low limit
+struct Rule {
+    threshold: f32,
+    hysteresis: f32,
+    above: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct AlertState {
+    value: Option<f32>,
+    active: bool,
+    last_raised: Option<Instant>,
+}
+
+/**
+ * Evaluates the alert rules on the measurements.  An alert is raised when its value goes past the threshold and
+ * cleared when the value is back past the threshold by the hysteresis.  Once raised an alert is not raised again until
+ * the cooldown has passed, however often it clears in between.
+ *
+ * The rules that depend on time, the rain rate and the sensor going offline, are evaluated by check which is called
+ * every pass of the weather station loop.
+ */
+pub struct AlertsEngine {
+    states: Vec<AlertState>,
+    rain: VecDeque<(Instant, f32)>,
+    // The station starts counting the daily rainfall from 0 when it boots
+    last_daily_rainfall: f32,
+    sensor_last_seen: Instant,
+}
+
+impl AlertsEngine {
+    // The sensor is given until the offline time from now to be heard from
+    pub fn new(now: Instant) -> Self {
+        Self {
+            states: vec![AlertState::default(); AlertKind::ALL.len()],
+            rain: VecDeque::new(),
+            last_daily_rainfall: 0.0,
+            sensor_last_seen: now,
+        }
+    }
+
+    pub fn sensor_seen(&mut self, now: Instant) {
+        self.sensor_last_seen = now;
+    }
+
+    pub fn measurement(
+        &mut self,
+        config: &AlertsConfig,
+        measurement: &Measurement,
+        now: Instant,
+    ) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+
+        match measurement {
+            Measurement::OutdoorTemperature(temperature) => {
+                self.set_value(AlertKind::Frost, *temperature as f32);
+                self.set_value(AlertKind::HighTemperature, *temperature as f32);
+            }
+            Measurement::OutdoorHeatIndex(heat_index) => {
+                self.set_value(AlertKind::HeatIndex, *heat_index as f32)
+            }
+            Measurement::WindGustMph(wind_gust) => {
+                self.set_value(AlertKind::HighWind, *wind_gust as f32)
+            }
+            Measurement::DailyRainfall(rainfall) => {
+                // The total goes back to 0 at the start of a day, only the rain that fell is counted for the rate
+                if *rainfall > self.last_daily_rainfall {
+                    self.rain
+                        .push_back((now, rainfall - self.last_daily_rainfall));
+                }
+                self.last_daily_rainfall = *rainfall;
+                self.set_value(AlertKind::DailyRainfall, *rainfall);
+                self.update_rain_rate(now);
+            }
+            Measurement::IndoorHumidity(humidity) => {
+                self.set_value(AlertKind::IndoorHumidityLow, *humidity as f32);
+                self.set_value(AlertKind::IndoorHumidityHigh, *humidity as f32);
+            }
+            Measurement::BatteryStatus(status) => self.set_value(
+                AlertKind::BatteryLow,
+                if status == "OK" { 0.0 } else { 1.0 },
+            ),
+            _ => return events,
+        }
+
+        self.evaluate(config, now, &mut events);
+
+        events
+    }
+
+    pub fn check(&mut self, config: &AlertsConfig, now: Instant) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+
+        self.update_rain_rate(now);
+        self.set_value(
+            AlertKind::SensorOffline,
+            now.duration_since(self.sensor_last_seen).as_secs() as f32,
+        );
+
+        self.evaluate(config, now, &mut events);
+
+        events
+    }
+
+    fn set_value(&mut self, kind: AlertKind, value: f32) {
+        self.states[kind as usize].value = Some(value);
+    }
+
+    fn update_rain_rate(&mut self, now: Instant) {
+        while let Some((fell_at, _)) = self.rain.front() {
+            if now.duration_since(*fell_at) < RAIN_RATE_WINDOW {
+                break;
+            }
+            self.rain.pop_front();
+        }
+
+        let rain_rate = self.rain.iter().map(|(_, rainfall)| rainfall).sum();
+        self.set_value(AlertKind::RainRate, rain_rate);
+    }
+
+    fn evaluate(&mut self, config: &AlertsConfig, now: Instant, events: &mut Vec<AlertEvent>) {
+        let cooldown = Duration::from_secs(config.cooldown_secs as u64);
+
+        for kind in AlertKind::ALL {
+            let state = &mut self.states[kind as usize];
+
+            let (rule, value) = match (rule(config, kind), state.value) {
+                (Some(rule), Some(value)) => (rule, value),
+                // A rule that has been switched off clears its alert
+                _ => {
+                    if state.active {
+                        state.active = false;
+                        events.push(AlertEvent::Cleared(kind));
+                    }
+                    continue;
+                }
+            };
+
+            if state.active {
+                let cleared = if rule.above {
+                    value < rule.threshold - rule.hysteresis
+                } else {
+                    value > rule.threshold + rule.hysteresis
+                };
+
+                if cleared {
+                    state.active = false;
+                    events.push(AlertEvent::Cleared(kind));
+                }
+            } else {
+                let broken = if rule.above {
+                    value > rule.threshold
+                } else {
+                    value < rule.threshold
+                };
+                let cooled_down = state.last_raised.map_or(true, |last_raised| {
+                    now.duration_since(last_raised) >= cooldown
+                });
+
+                if broken && cooled_down {
+                    state.active = true;
+                    state.last_raised = Some(now);
+                    events.push(AlertEvent::Raised(Alert { kind, value }));
+                }
+            }
+        }
+    }
+}
+
+// The rule of an alert, None while alerts or that alert are switched off
+fn rule(config: &AlertsConfig, kind: AlertKind) -> Option<Rule> {
+    if !config.enabled {
+        return None;
+    }
+
+    let (threshold, hysteresis, above) = match kind {
+        AlertKind::Frost => (config.low_temperature_f, TEMPERATURE_HYSTERESIS_F, false),
+        AlertKind::HighTemperature => (config.high_temperature_f, TEMPERATURE_HYSTERESIS_F, true),
+        AlertKind::HeatIndex => (config.high_heat_index_f, TEMPERATURE_HYSTERESIS_F, true),
+        AlertKind::HighWind => (config.high_wind_mph, WIND_HYSTERESIS_MPH, true),
+        AlertKind::RainRate => (config.rain_rate_in_per_hour, RAIN_RATE_HYSTERESIS_IN, true),
+        // The daily total only goes down at the start of a day
+        AlertKind::DailyRainfall => (config.daily_rainfall_in, 0.0, true),
+        AlertKind::IndoorHumidityLow => (config.indoor_humidity_min, HUMIDITY_HYSTERESIS, false),
+        AlertKind::IndoorHumidityHigh => (config.indoor_humidity_max, HUMIDITY_HYSTERESIS, true),
+        AlertKind::BatteryLow if config.battery_low => (0.5, 0.0, true),
+        AlertKind::SensorOffline if config.sensor_offline_secs > 0 => {
+            (config.sensor_offline_secs as f32, 0.0, true)
+        }
+        AlertKind::BatteryLow | AlertKind::SensorOffline => return None,
+    };
+
+    Some(Rule {
+        threshold,
+        hysteresis,
+        above,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AlertsConfig {
+        AlertsConfig {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn raised(kind: AlertKind, value: f32) -> AlertEvent {
+        AlertEvent::Raised(Alert { kind, value })
+    }
+
+    #[test]
+    fn high_limit_clears_past_the_hysteresis() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = config();
+
+        let temperature = Measurement::OutdoorTemperature;
+        assert!(engine
+            .measurement(&config, &temperature(100), t0)
+            .is_empty());
+        assert_eq!(
+            engine.measurement(&config, &temperature(101), t0),
+            vec![raised(AlertKind::HighTemperature, 101.0)]
+        );
+        assert!(engine
+            .measurement(&config, &temperature(102), t0)
+            .is_empty());
+
+        // Back under the threshold but within the hysteresis
+        assert!(engine.measurement(&config, &temperature(99), t0).is_empty());
+        assert!(engine.measurement(&config, &temperature(98), t0).is_empty());
+        assert_eq!(
+            engine.measurement(&config, &temperature(97), t0),
+            vec![AlertEvent::Cleared(AlertKind::HighTemperature)]
+        );
+    }
+
+    #[test]
+    fn low_limit_clears_past_the_hysteresis() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = config();
+
+        let temperature = Measurement::OutdoorTemperature;
+        assert_eq!(
+            engine.measurement(&config, &temperature(31), t0),
+            vec![raised(AlertKind::Frost, 31.0)]
+        );
+        assert!(engine.measurement(&config, &temperature(34), t0).is_empty());
+        assert_eq!(
+            engine.measurement(&config, &temperature(35), t0),
+            vec![AlertEvent::Cleared(AlertKind::Frost)]
+        );
+    }
+
+    #[test]
+    fn alert_is_not_raised_again_within_the_cooldown() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = config();
+
+        let gust = Measurement::WindGustMph;
+        assert_eq!(
+            engine.measurement(&config, &gust(45), t0),
+            vec![raised(AlertKind::HighWind, 45.0)]
+        );
+        assert_eq!(
+            engine.measurement(&config, &gust(20), t0 + secs(60)),
+            vec![AlertEvent::Cleared(AlertKind::HighWind)]
+        );
+
+        // The cooldown runs from when the alert was raised, whether or not it cleared since
+        assert!(engine
+            .measurement(&config, &gust(45), t0 + secs(1799))
+            .is_empty());
+        assert_eq!(
+            engine.measurement(&config, &gust(46), t0 + secs(1800)),
+            vec![raised(AlertKind::HighWind, 46.0)]
+        );
+    }
+
+    #[test]
+    fn rain_rate_counts_the_last_hour() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = AlertsConfig {
+            sensor_offline_secs: 0,
+            ..config()
+        };
+
+        let daily = Measurement::DailyRainfall;
+        assert!(engine.measurement(&config, &daily(0.5), t0).is_empty());
+        assert_eq!(
+            engine.measurement(&config, &daily(1.1), t0 + secs(20 * 60)),
+            vec![raised(AlertKind::RainRate, 1.1)]
+        );
+
+        // The first half inch leaves the window an hour after it fell
+        assert!(engine.check(&config, t0 + secs(60 * 60 - 1)).is_empty());
+        assert_eq!(
+            engine.check(&config, t0 + secs(60 * 60)),
+            vec![AlertEvent::Cleared(AlertKind::RainRate)]
+        );
+    }
+
+    #[test]
+    fn rain_rate_carries_over_the_start_of_a_day() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = config();
+
+        let daily = Measurement::DailyRainfall;
+        assert!(engine.measurement(&config, &daily(0.8), t0).is_empty());
+
+        // The daily total going back to 0 is not counted as rain, the rain that fell before it still is
+        assert!(engine
+            .measurement(&config, &daily(0.0), t0 + secs(60))
+            .is_empty());
+        assert_eq!(
+            engine.measurement(&config, &daily(0.3), t0 + secs(120)),
+            vec![raised(AlertKind::RainRate, 1.1)]
+        );
+    }
+
+    #[test]
+    fn sensor_offline() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let config = config();
+
+        assert!(engine.check(&config, t0 + secs(600)).is_empty());
+        assert_eq!(
+            engine.check(&config, t0 + secs(601)),
+            vec![raised(AlertKind::SensorOffline, 601.0)]
+        );
+
+        engine.sensor_seen(t0 + secs(700));
+        assert_eq!(
+            engine.check(&config, t0 + secs(700)),
+            vec![AlertEvent::Cleared(AlertKind::SensorOffline)]
+        );
+    }
+
+    #[test]
+    fn switched_off_rules_clear_their_alerts() {
+        let t0 = Instant::now();
+        let mut engine = AlertsEngine::new(t0);
+        let mut config = config();
+
+        engine.measurement(&config, &Measurement::BatteryStatus("LOW".to_string()), t0);
+        engine.measurement(&config, &Measurement::IndoorHumidity(80), t0);
+        engine.check(&config, t0 + secs(601));
+
+        config.battery_low = false;
+        assert_eq!(
+            engine.check(&config, t0 + secs(602)),
+            vec![AlertEvent::Cleared(AlertKind::BatteryLow)]
+        );
+
+        config.sensor_offline_secs = 0;
+        assert_eq!(
+            engine.check(&config, t0 + secs(603)),
+            vec![AlertEvent::Cleared(AlertKind::SensorOffline)]
+        );
+
+        config.enabled = false;
+        assert_eq!(
+            engine.check(&config, t0 + secs(604)),
+            vec![AlertEvent::Cleared(AlertKind::IndoorHumidityHigh)]
+        );
+
+        // Nothing is raised while alerts are off
+        assert!(engine
+            .measurement(
+                &config,
+                &Measurement::OutdoorTemperature(120),
+                t0 + secs(605)
+            )
+            .is_empty());
+    }
+}
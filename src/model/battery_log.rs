@@ -0,0 +1,48 @@
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_sys::EspError;
+
+use crate::model::history::BatteryLogBackend;
+
+const NVS_NAMESPACE: &str = "battery";
+const NVS_KEY: &str = "low_periods";
+// 16 periods of two timestamps as json
+const NVS_MAX_LOG_SIZE: usize = 1024;
+
+// Keeps the low battery periods of the Acurite5n1 in nvs so the battery history survives a reboot
+pub struct NvsBatteryLog {
+    nvs: EspDefaultNvs,
+}
+
+impl NvsBatteryLog {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        Ok(Self { nvs })
+    }
+}
+
+impl BatteryLogBackend for NvsBatteryLog {
+    fn load(&mut self) -> Option<String> {
+        let mut buf = vec![0u8; NVS_MAX_LOG_SIZE];
+
+        match self.nvs.get_raw(NVS_KEY, &mut buf) {
+            Ok(bytes) => bytes.map(|bytes| String::from_utf8_lossy(bytes).into_owned()),
+            Err(e) => {
+                println!("Failed to read the battery log {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn save(&mut self, json: &str) {
+        if json.len() > NVS_MAX_LOG_SIZE {
+            println!("Battery log too large");
+            return;
+        }
+
+        if let Err(e) = self.nvs.set_raw(NVS_KEY, json.as_bytes()) {
+            println!("Failed to save the battery log {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BtnId {
+    Btn1,
+    Btn2,
+    Btn3,
+}
+
+const BUTTONS: [BtnId; 3] = [BtnId::Btn1, BtnId::Btn2, BtnId::Btn3];
+
+/**
+ * Button events sent to the Gui.  Pressed and Released follow the button itself and are meant for drawing it, the
+ * others say what the user did:
+ * - Clicked - released before the hold time, also sent for the second click of a double click
+ * - DoubleClicked - a second click within the double click time, sent after its Clicked
+ * - Held - kept down for the hold time, sent once while the button is still down
+ * - Repeated - sent at the repeat interval while a held button stays down
+ * - Chord - a second button pressed while the first is down, neither button then clicks, holds or repeats
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserBtnState {
+    Pressed(BtnId),
+    Released(BtnId),
+    Clicked(BtnId),
+    DoubleClicked(BtnId),
+    Held(BtnId),
+    Repeated(BtnId),
+    Chord(BtnId, BtnId),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ButtonTiming {
+    pub hold_time: Duration,
+    pub repeat_interval: Duration,
+    pub double_click_time: Duration,
+}
+
+impl Default for ButtonTiming {
+    fn default() -> Self {
+        Self {
+            hold_time: Duration::from_millis(1000),
+            repeat_interval: Duration::from_millis(200),
+            double_click_time: Duration::from_millis(400),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ButtonTrack {
+    is_down: bool,
+    pressed_at: Instant,
+    is_held: bool,
+    next_repeat: Instant,
+    in_chord: bool,
+    last_click: Option<Instant>,
+}
+
+/**
+ * Turns the debounced presses and releases of the three buttons into user events.  The time is passed in with every
+ * call rather than read from the clock, so the state machine runs the same on the device and in a test.
+ */
+pub struct ButtonEvents {
+    timing: ButtonTiming,
+    buttons: [ButtonTrack; 3],
+}
+
+impl ButtonEvents {
+    pub fn new(timing: ButtonTiming, now: Instant) -> Self {
+        let track = ButtonTrack {
+            is_down: false,
+            pressed_at: now,
+            is_held: false,
+            next_repeat: now,
+            in_chord: false,
+            last_click: None,
+        };
+
+        Self {
+            timing,
+            buttons: [track; 3],
+        }
+    }
+
+    pub fn press(&mut self, btn: BtnId, now: Instant) -> Vec<UserBtnState> {
+        let mut events = vec![UserBtnState::Pressed(btn)];
+
+        let track = &mut self.buttons[btn as usize];
+        track.is_down = true;
+        track.pressed_at = now;
+        track.is_held = false;
+        track.in_chord = false;
+
+        // Only a button that is down and has not done anything else yet can start a chord
+        let partner = BUTTONS.iter().copied().find(|other| {
+            let other_track = &self.buttons[*other as usize];
+            *other != btn && other_track.is_down && !other_track.is_held && !other_track.in_chord
+        });
+
+        if let Some(other) = partner {
+            self.buttons[btn as usize].in_chord = true;
+            self.buttons[other as usize].in_chord = true;
+
+            if (other as usize) < (btn as usize) {
+                events.push(UserBtnState::Chord(other, btn));
+            } else {
+                events.push(UserBtnState::Chord(btn, other));
+            }
+        }
+
+        events
+    }
+
+    pub fn release(&mut self, btn: BtnId, now: Instant) -> Vec<UserBtnState> {
+        let mut events = vec![UserBtnState::Released(btn)];
+
+        let double_click_time = self.timing.double_click_time;
+        let track = &mut self.buttons[btn as usize];
+        track.is_down = false;
+
+        if track.is_held || track.in_chord {
+            track.last_click = None;
+            return events;
+        }
+
+        events.push(UserBtnState::Clicked(btn));
+
+        match track.last_click {
+            Some(last_click) if now.duration_since(last_click) <= double_click_time => {
+                events.push(UserBtnState::DoubleClicked(btn));
+                track.last_click = None;
+            }
+            _ => track.last_click = Some(now),
+        }
+
+        events
+    }
+
+    // Called between presses and releases to send the held and repeated events of buttons that stay down
+    pub fn tick(&mut self, now: Instant) -> Vec<UserBtnState> {
+        let mut events = Vec::new();
+
+        for btn in BUTTONS {
+            let track = &mut self.buttons[btn as usize];
+
+            if !track.is_down || track.in_chord {
+                continue;
+            }
+
+            if !track.is_held {
+                if now.duration_since(track.pressed_at) >= self.timing.hold_time {
+                    track.is_held = true;
+                    track.next_repeat = now + self.timing.repeat_interval;
+                    events.push(UserBtnState::Held(btn));
+                }
+            } else if now >= track.next_repeat {
+                // A late tick sends one repeat rather than catching up
+                track.next_repeat = now + self.timing.repeat_interval;
+                events.push(UserBtnState::Repeated(btn));
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use UserBtnState::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn events() -> (ButtonEvents, Instant) {
+        let start = Instant::now();
+        (ButtonEvents::new(ButtonTiming::default(), start), start)
+    }
+
+    #[test]
+    fn short_press_clicks() {
+        let (mut buttons, t0) = events();
+
+        assert_eq!(buttons.press(BtnId::Btn1, t0), vec![Pressed(BtnId::Btn1)]);
+        assert!(buttons.tick(t0 + ms(500)).is_empty());
+        assert_eq!(
+            buttons.release(BtnId::Btn1, t0 + ms(600)),
+            vec![Released(BtnId::Btn1), Clicked(BtnId::Btn1)]
+        );
+    }
+
+    #[test]
+    fn long_press_holds_and_repeats() {
+        let (mut buttons, t0) = events();
+
+        buttons.press(BtnId::Btn2, t0);
+        assert!(buttons.tick(t0 + ms(999)).is_empty());
+        assert_eq!(buttons.tick(t0 + ms(1000)), vec![Held(BtnId::Btn2)]);
+
+        // Repeats follow at the repeat interval after the hold
+        assert!(buttons.tick(t0 + ms(1100)).is_empty());
+        assert_eq!(buttons.tick(t0 + ms(1200)), vec![Repeated(BtnId::Btn2)]);
+        assert!(buttons.tick(t0 + ms(1300)).is_empty());
+        assert_eq!(buttons.tick(t0 + ms(1400)), vec![Repeated(BtnId::Btn2)]);
+
+        // A late tick sends a single repeat
+        assert_eq!(buttons.tick(t0 + ms(2500)), vec![Repeated(BtnId::Btn2)]);
+        assert!(buttons.tick(t0 + ms(2600)).is_empty());
+
+        // A held button does not click when it is released
+        assert_eq!(
+            buttons.release(BtnId::Btn2, t0 + ms(2650)),
+            vec![Released(BtnId::Btn2)]
+        );
+        assert!(buttons.tick(t0 + ms(3000)).is_empty());
+    }
+
+    #[test]
+    fn double_click() {
+        let (mut buttons, t0) = events();
+
+        buttons.press(BtnId::Btn3, t0);
+        buttons.release(BtnId::Btn3, t0 + ms(100));
+        buttons.press(BtnId::Btn3, t0 + ms(300));
+        assert_eq!(
+            buttons.release(BtnId::Btn3, t0 + ms(450)),
+            vec![
+                Released(BtnId::Btn3),
+                Clicked(BtnId::Btn3),
+                DoubleClicked(BtnId::Btn3)
+            ]
+        );
+
+        // A third click starts over rather than double clicking again
+        buttons.press(BtnId::Btn3, t0 + ms(600));
+        assert_eq!(
+            buttons.release(BtnId::Btn3, t0 + ms(700)),
+            vec![Released(BtnId::Btn3), Clicked(BtnId::Btn3)]
+        );
+    }
+
+    #[test]
+    fn slow_second_click_is_not_a_double_click() {
+        let (mut buttons, t0) = events();
+
+        buttons.press(BtnId::Btn1, t0);
+        buttons.release(BtnId::Btn1, t0 + ms(100));
+        buttons.press(BtnId::Btn1, t0 + ms(450));
+        assert_eq!(
+            buttons.release(BtnId::Btn1, t0 + ms(550)),
+            vec![Released(BtnId::Btn1), Clicked(BtnId::Btn1)]
+        );
+
+        // A hold in between also breaks up a double click
+        buttons.press(BtnId::Btn1, t0 + ms(600));
+        buttons.tick(t0 + ms(1600));
+        buttons.release(BtnId::Btn1, t0 + ms(1700));
+        buttons.press(BtnId::Btn1, t0 + ms(1800));
+        assert_eq!(
+            buttons.release(BtnId::Btn1, t0 + ms(1900)),
+            vec![Released(BtnId::Btn1), Clicked(BtnId::Btn1)]
+        );
+    }
+
+    #[test]
+    fn chord() {
+        let (mut buttons, t0) = events();
+
+        buttons.press(BtnId::Btn3, t0);
+        assert_eq!(
+            buttons.press(BtnId::Btn1, t0 + ms(50)),
+            vec![Pressed(BtnId::Btn1), Chord(BtnId::Btn1, BtnId::Btn3)]
+        );
+
+        // Neither button holds, repeats or clicks
+        assert!(buttons.tick(t0 + ms(2000)).is_empty());
+        assert_eq!(
+            buttons.release(BtnId::Btn3, t0 + ms(2100)),
+            vec![Released(BtnId::Btn3)]
+        );
+        assert_eq!(
+            buttons.release(BtnId::Btn1, t0 + ms(2150)),
+            vec![Released(BtnId::Btn1)]
+        );
+
+        // The buttons click again after the chord
+        buttons.press(BtnId::Btn1, t0 + ms(2500));
+        assert_eq!(
+            buttons.release(BtnId::Btn1, t0 + ms(2600)),
+            vec![Released(BtnId::Btn1), Clicked(BtnId::Btn1)]
+        );
+    }
+
+    #[test]
+    fn held_button_does_not_start_a_chord() {
+        let (mut buttons, t0) = events();
+
+        buttons.press(BtnId::Btn1, t0);
+        assert_eq!(buttons.tick(t0 + ms(1000)), vec![Held(BtnId::Btn1)]);
+        assert_eq!(
+            buttons.press(BtnId::Btn2, t0 + ms(1050)),
+            vec![Pressed(BtnId::Btn2)]
+        );
+        assert_eq!(
+            buttons.release(BtnId::Btn2, t0 + ms(1150)),
+            vec![Released(BtnId::Btn2), Clicked(BtnId::Btn2)]
+        );
+        assert_eq!(buttons.tick(t0 + ms(1200)), vec![Repeated(BtnId::Btn1)]);
+    }
+}
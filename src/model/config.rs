@@ -0,0 +1,991 @@
+use std::fmt;
+#[cfg(not(target_os = "espidf"))]
+use std::fs;
+#[cfg(not(target_os = "espidf"))]
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::model::{
+    pressure::PressureMode,
+    time_zone::{posix_for_utc_offset, TimeZone},
+    units::Units,
+};
+
+// Bump this and add a step to migrate() whenever a change to Config would not load from an older stored config
+pub const CONFIG_VERSION: u32 = 2;
+
+// Secrets are never sent back to network clients, a client that posts the mask back keeps the stored secret
+pub const SECRET_MASK: &str = "********";
+
+const NVS_NAMESPACE: &str = "config";
+const NVS_KEY: &str = "config";
+const NVS_MAX_CONFIG_SIZE: usize = 4096;
+
+pub type SharedConfig = Arc<Mutex<ConfigStore>>;
+
+/**
+ * The runtime configuration of the station.  It is stored as json, every section uses serde defaults so a stored
+ * config that is missing a setting loads with the default for that setting.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+    pub wifi: WifiConfig,
+    pub station: StationConfig,
+    pub http: HttpConfig,
+    pub display: DisplayConfig,
+    pub kiosk: KioskConfig,
+    pub sensor: SensorConfig,
+    pub calibration: CalibrationConfig,
+    pub alerts: AlertsConfig,
+    pub mqtt: MqttConfig,
+    pub pws: PwsConfig,
+    pub aprs: AprsConfig,
+    pub influxdb: InfluxDbConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WifiConfig {
+    pub ssid: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StationConfig {
+    pub altitude_meters: f32,
+    // An IANA zone name like "America/Denver" or a POSIX TZ string like "MST7MDT,M3.2.0,M11.1.0"
+    pub time_zone: String,
+    // Show the time as 19:45 rather than 7:45 PM
+    pub clock_24h: bool,
+}
+
+// POST /api/config and /api/restart need the admin token, without one nothing can be changed over the network
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub admin_token: String,
+}
+
+// The backlight is dimmed to night_backlight_percent from night_start_hour to night_end_hour local time, the same hour
+// for both turns the night brightness off.  A screen_timeout_secs of 0 keeps the screen on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub units: Units,
+    pub backlight_percent: u8,
+    pub night_backlight_percent: u8,
+    pub night_start_hour: u8,
+    pub night_end_hour: u8,
+    pub screen_timeout_secs: u32,
+}
+
+/**
+ * Kiosk mode shows the views in turn, each for its dwell time, in the order they are listed.  A button pauses the
+ * rotation, it carries on after resume_after_secs without a button being pressed.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KioskConfig {
+    pub enabled: bool,
+    pub views: Vec<KioskViewConfig>,
+    pub resume_after_secs: u32,
+}
+
+// The view is named as in the view registry of the gui, e.g. "indoor_outdoor"
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KioskViewConfig {
+    pub view: String,
+    pub dwell_secs: u32,
+}
+
+// Only messages from the paired Acurite5n1 are used, any sensor that is heard is used while none is paired
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorConfig {
+    pub product_id: Option<u16>,
+}
+
+/**
+ * The offsets are added to what the sensors read.  The pressure offset is in hPa, the unit the pressure sensors measure
+ * in, and is added to the pressure at the station before it is reduced as pressure_mode asks.  The temperature offsets
+ * are in F and the humidity offsets in %.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CalibrationConfig {
+    pub pressure_mode: PressureMode,
+    pub pressure_offset_hpa: f32,
+    pub indoor_temperature_offset_f: f32,
+    pub indoor_humidity_offset: f32,
+    pub outdoor_temperature_offset_f: f32,
+    pub outdoor_humidity_offset: f32,
+}
+
+/**
+ * The thresholds are in the units the station measures in, whatever units the display shows.  The low temperature is
+ * the frost alert and the high wind is checked against every gust.  A sensor_offline_secs of 0 turns the offline alert
+ * off, an alert is not raised again within cooldown_secs of being raised.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    pub high_temperature_f: f32,
+    pub low_temperature_f: f32,
+    pub high_heat_index_f: f32,
+    pub high_wind_mph: f32,
+    pub rain_rate_in_per_hour: f32,
+    pub daily_rainfall_in: f32,
+    pub indoor_humidity_min: f32,
+    pub indoor_humidity_max: f32,
+    pub battery_low: bool,
+    pub sensor_offline_secs: u32,
+    pub cooldown_secs: u32,
+    // Beep the speaker when an alert is raised
+    pub beep: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub broker_url: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PwsConfig {
+    pub wu_station_id: String,
+    pub wu_station_key: String,
+    pub pwsweather_station_id: String,
+    pub pwsweather_station_key: String,
+    pub wow_site_id: String,
+    pub wow_site_key: String,
+    pub interval_secs: u32,
+    pub url_override: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AprsConfig {
+    pub callsign: String,
+    pub passcode: String,
+    pub server: String,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub interval_secs: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InfluxDbConfig {
+    pub url: String,
+    pub database: String,
+    pub username: String,
+    pub password: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub location: String,
+    pub interval_secs: u32,
+}
+
+// The wifi credentials given at build time (if any) are only used until a config has been stored
+impl Default for WifiConfig {
+    fn default() -> Self {
+        Self {
+            ssid: option_env!("WIFI_SSID").unwrap_or_default().to_string(),
+            password: option_env!("WIFI_PASSWORD").unwrap_or_default().to_string(),
+        }
+    }
+}
+
+// The altitude and whole hour utc offset given at build time (if any) are only used until a config has been stored
+impl Default for StationConfig {
+    fn default() -> Self {
+        Self {
+            altitude_meters: option_env!("MY_ALTITUDE_METERS")
+                .and_then(|altitude| altitude.trim().parse().ok())
+                .unwrap_or(0.0),
+            time_zone: option_env!("UTC_OFFSET")
+                .and_then(|utc_offset| utc_offset.trim().parse().ok())
+                .map_or("UTC".to_string(), posix_for_utc_offset),
+            clock_24h: false,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            admin_token: option_env!("ADMIN_TOKEN").unwrap_or_default().to_string(),
+        }
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            units: Units::Imperial,
+            backlight_percent: 100,
+            night_backlight_percent: 30,
+            night_start_hour: 22,
+            night_end_hour: 7,
+            screen_timeout_secs: 0,
+        }
+    }
+}
+
+impl Default for KioskConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            views: ["indoor_outdoor", "wind_rain_status", "time_date"]
+                .iter()
+                .map(|view| KioskViewConfig {
+                    view: view.to_string(),
+                    dwell_secs: 10,
+                })
+                .collect(),
+            resume_after_secs: 60,
+        }
+    }
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            high_temperature_f: 100.0,
+            low_temperature_f: 32.0,
+            high_heat_index_f: 105.0,
+            high_wind_mph: 40.0,
+            rain_rate_in_per_hour: 1.0,
+            daily_rainfall_in: 2.0,
+            indoor_humidity_min: 20.0,
+            indoor_humidity_max: 70.0,
+            battery_low: true,
+            sensor_offline_secs: 600,
+            cooldown_secs: 1800,
+            beep: true,
+        }
+    }
+}
+
+impl Default for PwsConfig {
+    fn default() -> Self {
+        Self {
+            wu_station_id: String::new(),
+            wu_station_key: String::new(),
+            pwsweather_station_id: String::new(),
+            pwsweather_station_key: String::new(),
+            wow_site_id: String::new(),
+            wow_site_key: String::new(),
+            interval_secs: 300,
+            url_override: String::new(),
+        }
+    }
+}
+
+impl Default for AprsConfig {
+    fn default() -> Self {
+        Self {
+            callsign: String::new(),
+            passcode: String::new(),
+            server: "cwop.aprs.net:14580".to_string(),
+            latitude: None,
+            longitude: None,
+            interval_secs: 600,
+        }
+    }
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            database: "weather".to_string(),
+            username: String::new(),
+            password: String::new(),
+            org: String::new(),
+            bucket: String::new(),
+            token: String::new(),
+            location: String::new(),
+            interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Invalid {
+        setting: &'static str,
+        reason: &'static str,
+    },
+    Parse(String),
+    UnsupportedVersion(u32),
+    Storage(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Invalid { setting, reason } => write!(f, "{} {}", setting, reason),
+            ConfigError::Parse(message) => write!(f, "invalid json: {}", message),
+            ConfigError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "config version {} is not supported by this firmware",
+                    version
+                )
+            }
+            ConfigError::Storage(message) => write!(f, "storage error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(setting: &'static str, reason: &'static str) -> ConfigError {
+    ConfigError::Invalid { setting, reason }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.wifi.ssid.len() > 32 {
+            return Err(invalid("wifi.ssid", "must be at most 32 bytes"));
+        }
+        if !self.wifi.password.is_empty()
+            && (self.wifi.password.len() < 8 || self.wifi.password.len() > 64)
+        {
+            return Err(invalid("wifi.password", "must be empty or 8 to 64 bytes"));
+        }
+
+        if !(-500.0..=9000.0).contains(&self.station.altitude_meters) {
+            return Err(invalid("station.altitude_meters", "must be -500 to 9000"));
+        }
+        if TimeZone::parse(&self.station.time_zone).is_none() {
+            return Err(invalid(
+                "station.time_zone",
+                "must be an IANA zone name or a POSIX TZ string",
+            ));
+        }
+
+        if !self.http.admin_token.is_empty()
+            && (self.http.admin_token.len() < 8 || self.http.admin_token.len() > 64)
+        {
+            return Err(invalid(
+                "http.admin_token",
+                "must be empty or 8 to 64 bytes",
+            ));
+        }
+
+        if !(10..=100).contains(&self.display.backlight_percent) {
+            return Err(invalid("display.backlight_percent", "must be 10 to 100"));
+        }
+        if !(10..=100).contains(&self.display.night_backlight_percent) {
+            return Err(invalid(
+                "display.night_backlight_percent",
+                "must be 10 to 100",
+            ));
+        }
+        if self.display.night_start_hour > 23 || self.display.night_end_hour > 23 {
+            return Err(invalid(
+                "display.night_start_hour",
+                "night hours must be 0 to 23",
+            ));
+        }
+        if self.display.screen_timeout_secs > 3600 {
+            return Err(invalid("display.screen_timeout_secs", "must be 0 to 3600"));
+        }
+
+        if self.kiosk.enabled && self.kiosk.views.is_empty() {
+            return Err(invalid(
+                "kiosk.views",
+                "must list a view when kiosk mode is enabled",
+            ));
+        }
+        for (index, kiosk_view) in self.kiosk.views.iter().enumerate() {
+            if self.kiosk.views[..index]
+                .iter()
+                .any(|earlier| earlier.view == kiosk_view.view)
+            {
+                return Err(invalid("kiosk.views", "must not list a view twice"));
+            }
+            if !(2..=3600).contains(&kiosk_view.dwell_secs) {
+                return Err(invalid("kiosk.views.dwell_secs", "must be 2 to 3600"));
+            }
+        }
+        if !(5..=3600).contains(&self.kiosk.resume_after_secs) {
+            return Err(invalid("kiosk.resume_after_secs", "must be 5 to 3600"));
+        }
+
+        if !(-50.0..=50.0).contains(&self.calibration.pressure_offset_hpa) {
+            return Err(invalid(
+                "calibration.pressure_offset_hpa",
+                "must be -50 to 50",
+            ));
+        }
+        if !(-20.0..=20.0).contains(&self.calibration.indoor_temperature_offset_f)
+            || !(-20.0..=20.0).contains(&self.calibration.outdoor_temperature_offset_f)
+        {
+            return Err(invalid(
+                "calibration.indoor_temperature_offset_f",
+                "temperature offsets must be -20 to 20",
+            ));
+        }
+        if !(-30.0..=30.0).contains(&self.calibration.indoor_humidity_offset)
+            || !(-30.0..=30.0).contains(&self.calibration.outdoor_humidity_offset)
+        {
+            return Err(invalid(
+                "calibration.indoor_humidity_offset",
+                "humidity offsets must be -30 to 30",
+            ));
+        }
+
+        if self.alerts.low_temperature_f >= self.alerts.high_temperature_f {
+            return Err(invalid(
+                "alerts.low_temperature_f",
+                "must be below alerts.high_temperature_f",
+            ));
+        }
+        if !(1.0..=150.0).contains(&self.alerts.high_wind_mph) {
+            return Err(invalid("alerts.high_wind_mph", "must be 1 to 150"));
+        }
+        if !(0.1..=20.0).contains(&self.alerts.daily_rainfall_in) {
+            return Err(invalid("alerts.daily_rainfall_in", "must be 0.1 to 20"));
+        }
+        if !(60.0..=160.0).contains(&self.alerts.high_heat_index_f) {
+            return Err(invalid("alerts.high_heat_index_f", "must be 60 to 160"));
+        }
+        if !(0.1..=10.0).contains(&self.alerts.rain_rate_in_per_hour) {
+            return Err(invalid("alerts.rain_rate_in_per_hour", "must be 0.1 to 10"));
+        }
+        if self.alerts.indoor_humidity_min >= self.alerts.indoor_humidity_max
+            || self.alerts.indoor_humidity_min < 0.0
+            || self.alerts.indoor_humidity_max > 100.0
+        {
+            return Err(invalid(
+                "alerts.indoor_humidity_min",
+                "must be below alerts.indoor_humidity_max, both 0 to 100",
+            ));
+        }
+        // The sensor sends a packet every 18 seconds
+        if self.alerts.sensor_offline_secs != 0
+            && !(60..=86400).contains(&self.alerts.sensor_offline_secs)
+        {
+            return Err(invalid(
+                "alerts.sensor_offline_secs",
+                "must be 0 or 60 to 86400",
+            ));
+        }
+        if self.alerts.cooldown_secs > 86400 {
+            return Err(invalid("alerts.cooldown_secs", "must be 0 to 86400"));
+        }
+
+        if !self.mqtt.broker_url.is_empty()
+            && !has_scheme(
+                &self.mqtt.broker_url,
+                &["mqtt://", "mqtts://", "ws://", "wss://"],
+            )
+        {
+            return Err(invalid(
+                "mqtt.broker_url",
+                "must start with mqtt:// or mqtts://",
+            ));
+        }
+
+        if self.pws.interval_secs < 60 {
+            return Err(invalid("pws.interval_secs", "must be at least 60"));
+        }
+        if !self.pws.url_override.is_empty() && !has_scheme(&self.pws.url_override, HTTP_SCHEMES) {
+            return Err(invalid(
+                "pws.url_override",
+                "must start with http:// or https://",
+            ));
+        }
+
+        // CWOP asks stations not to report more often than every 5 minutes
+        if self.aprs.interval_secs < 300 {
+            return Err(invalid("aprs.interval_secs", "must be at least 300"));
+        }
+        if !self.aprs.callsign.is_empty() && !self.aprs.server.contains(':') {
+            return Err(invalid("aprs.server", "must be host:port"));
+        }
+        if self.aprs.latitude.is_some() != self.aprs.longitude.is_some() {
+            return Err(invalid(
+                "aprs.latitude",
+                "and longitude must be set together",
+            ));
+        }
+        if let Some(latitude) = self.aprs.latitude {
+            if !(-90.0..=90.0).contains(&latitude) {
+                return Err(invalid("aprs.latitude", "must be -90 to 90"));
+            }
+        }
+        if let Some(longitude) = self.aprs.longitude {
+            if !(-180.0..=180.0).contains(&longitude) {
+                return Err(invalid("aprs.longitude", "must be -180 to 180"));
+            }
+        }
+
+        if !self.influxdb.url.is_empty() && !has_scheme(&self.influxdb.url, HTTP_SCHEMES) {
+            return Err(invalid(
+                "influxdb.url",
+                "must start with http:// or https://",
+            ));
+        }
+        if self.influxdb.interval_secs < 10 {
+            return Err(invalid("influxdb.interval_secs", "must be at least 10"));
+        }
+
+        Ok(())
+    }
+
+    // A copy that is safe to send to network clients, every secret that is set is replaced by the mask
+    pub fn redacted(&self) -> Config {
+        let mut redacted = self.clone();
+
+        for secret in redacted.secrets_mut() {
+            if !secret.is_empty() {
+                *secret = SECRET_MASK.to_string();
+            }
+        }
+
+        redacted
+    }
+
+    // Replace every secret that a client posted back as the mask with the stored secret
+    pub fn keep_masked_secrets(&mut self, stored: &Config) {
+        let mut stored = stored.clone();
+
+        for (secret, stored_secret) in self.secrets_mut().into_iter().zip(stored.secrets_mut()) {
+            if secret == SECRET_MASK {
+                *secret = stored_secret.clone();
+            }
+        }
+    }
+
+    fn secrets_mut(&mut self) -> [&mut String; 9] {
+        [
+            &mut self.wifi.password,
+            &mut self.http.admin_token,
+            &mut self.mqtt.password,
+            &mut self.pws.wu_station_key,
+            &mut self.pws.pwsweather_station_key,
+            &mut self.pws.wow_site_key,
+            &mut self.aprs.passcode,
+            &mut self.influxdb.password,
+            &mut self.influxdb.token,
+        ]
+    }
+}
+
+const HTTP_SCHEMES: &[&str] = &["http://", "https://"];
+
+fn has_scheme(url: &str, schemes: &[&str]) -> bool {
+    schemes.iter().any(|scheme| url.starts_with(scheme))
+}
+
+// Parse a stored config, migrating it to the current version
+pub fn parse_config(json: &str) -> Result<Config, ConfigError> {
+    let value: Value = serde_json::from_str(json).map_err(|e| ConfigError::Parse(e.to_string()))?;
+
+    let value = migrate(value)?;
+
+    serde_json::from_value(value).map_err(|e| ConfigError::Parse(e.to_string()))
+}
+
+/**
+ * Apply a posted config over the stored one.  Only the sections and settings that were posted change, e.g.
+ * {"station":{"altitude_meters":1500}} changes the altitude and keeps every other setting.  A list such as
+ * kiosk.views is replaced as a whole.
+ */
+pub fn merge_config(stored: &Config, json: &str) -> Result<Config, ConfigError> {
+    let posted: Value =
+        serde_json::from_str(json).map_err(|e| ConfigError::Parse(e.to_string()))?;
+
+    if !posted.is_object() {
+        return Err(ConfigError::Parse(
+            "the config must be an object".to_string(),
+        ));
+    }
+
+    let mut value = serde_json::to_value(stored).map_err(|e| ConfigError::Parse(e.to_string()))?;
+    merge_value(&mut value, migrate(posted)?);
+
+    serde_json::from_value(value).map_err(|e| ConfigError::Parse(e.to_string()))
+}
+
+fn merge_value(value: &mut Value, posted: Value) {
+    match (value, posted) {
+        (Value::Object(object), Value::Object(posted)) => {
+            for (key, posted_value) in posted {
+                merge_value(object.entry(key).or_insert(Value::Null), posted_value);
+            }
+        }
+        (value, posted) => *value = posted,
+    }
+}
+
+/**
+ * Migrate a stored config to the current version one version at a time.  A config without a version, e.g. one posted
+ * by a client that left it out, is taken to be the current version.
+ *
+ *  1 -> 2  The whole hour station.utc_offset_hours is replaced by station.time_zone, -7 becomes "<-07>7"
+ */
+pub fn migrate(mut value: Value) -> Result<Value, ConfigError> {
+    let mut version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or(CONFIG_VERSION as u64) as u32;
+
+    if version == 0 || version > CONFIG_VERSION {
+        return Err(ConfigError::UnsupportedVersion(version));
+    }
+
+    while version < CONFIG_VERSION {
+        value = match version {
+            1 => {
+                let utc_offset = value
+                    .pointer("/station/utc_offset_hours")
+                    .and_then(Value::as_i64)
+                    .unwrap_or_default();
+
+                if let Some(station) = value.get_mut("station").and_then(Value::as_object_mut) {
+                    station.remove("utc_offset_hours");
+                }
+                if !value.get("station").map_or(false, Value::is_object) {
+                    value["station"] = json!({});
+                }
+                value["station"]["time_zone"] = json!(posix_for_utc_offset(utc_offset));
+                value["version"] = json!(2);
+
+                value
+            }
+            _ => unreachable!(),
+        };
+
+        version += 1;
+    }
+
+    Ok(value)
+}
+
+fn stored_version(json: &str) -> u32 {
+    serde_json::from_str::<Value>(json)
+        .ok()
+        .and_then(|value| value.get("version").and_then(Value::as_u64))
+        .unwrap_or(CONFIG_VERSION as u64) as u32
+}
+
+pub fn to_json(config: &Config) -> String {
+    serde_json::to_string(config).unwrap()
+}
+
+// Where the config is stored, NVS on the station and a file when running on the host
+pub trait ConfigBackend: Send {
+    fn load(&mut self) -> Result<Option<String>, ConfigError>;
+    fn save(&mut self, json: &str) -> Result<(), ConfigError>;
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub struct FileConfigBackend {
+    path: PathBuf,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl FileConfigBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl ConfigBackend for FileConfigBackend {
+    fn load(&mut self) -> Result<Option<String>, ConfigError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        fs::read_to_string(&self.path)
+            .map(Some)
+            .map_err(|e| ConfigError::Storage(e.to_string()))
+    }
+
+    // Write to a temporary file and rename it so a crash never leaves a half written config
+    fn save(&mut self, json: &str) -> Result<(), ConfigError> {
+        let temporary_path = self.path.with_extension("tmp");
+
+        fs::write(&temporary_path, json)
+            .and_then(|_| fs::rename(&temporary_path, &self.path))
+            .map_err(|e| ConfigError::Storage(e.to_string()))
+    }
+}
+
+// A config backend kept in memory for the tests
+#[cfg(test)]
+pub struct MemoryConfigBackend(pub Option<String>);
+
+#[cfg(test)]
+impl ConfigBackend for MemoryConfigBackend {
+    fn load(&mut self) -> Result<Option<String>, ConfigError> {
+        Ok(self.0.clone())
+    }
+
+    fn save(&mut self, json: &str) -> Result<(), ConfigError> {
+        self.0 = Some(json.to_string());
+        Ok(())
+    }
+}
+
+pub struct NvsConfigBackend {
+    nvs: EspDefaultNvs,
+}
+
+impl NvsConfigBackend {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, ConfigError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)
+            .map_err(|e| ConfigError::Storage(e.to_string()))?;
+
+        Ok(Self { nvs })
+    }
+}
+
+impl ConfigBackend for NvsConfigBackend {
+    fn load(&mut self) -> Result<Option<String>, ConfigError> {
+        let mut buf = vec![0u8; NVS_MAX_CONFIG_SIZE];
+
+        match self.nvs.get_raw(NVS_KEY, &mut buf) {
+            Ok(Some(bytes)) => Ok(Some(String::from_utf8_lossy(bytes).into_owned())),
+            Ok(None) => Ok(None),
+            Err(e) => Err(ConfigError::Storage(e.to_string())),
+        }
+    }
+
+    fn save(&mut self, json: &str) -> Result<(), ConfigError> {
+        if json.len() > NVS_MAX_CONFIG_SIZE {
+            return Err(ConfigError::Storage("config too large".to_string()));
+        }
+
+        self.nvs
+            .set_raw(NVS_KEY, json.as_bytes())
+            .map_err(|e| ConfigError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+pub struct ConfigStore {
+    config: Config,
+    backend: Box<dyn ConfigBackend>,
+}
+
+impl ConfigStore {
+    /**
+     * Load the stored config.  A config from an older version is migrated and saved back, a missing, unreadable or
+     * invalid config is replaced by the defaults so that the station always boots.
+     */
+    pub fn load(mut backend: Box<dyn ConfigBackend>) -> Self {
+        let mut migrated = false;
+
+        let config = match backend.load() {
+            Ok(Some(json)) => match parse_config(&json).and_then(|config| {
+                config.validate()?;
+                Ok(config)
+            }) {
+                Ok(config) => {
+                    migrated = stored_version(&json) != CONFIG_VERSION;
+                    config
+                }
+                Err(e) => {
+                    println!("Stored config rejected ({}), using defaults", e);
+                    Config::new()
+                }
+            },
+            Ok(None) => {
+                println!("No stored config, using defaults");
+                Config::new()
+            }
+            Err(e) => {
+                println!("Failed to read config ({}), using defaults", e);
+                Config::new()
+            }
+        };
+
+        let mut store = Self { config, backend };
+
+        if migrated {
+            println!("Config migrated to version {}", CONFIG_VERSION);
+            if let Err(e) = store.save() {
+                println!("Failed to save migrated config ({})", e);
+            }
+        }
+
+        store
+    }
+
+    pub fn new_shared(backend: Box<dyn ConfigBackend>) -> SharedConfig {
+        Arc::new(Mutex::new(ConfigStore::load(backend)))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Validate and store a new config, the current config is kept if the new one is rejected
+    pub fn update(&mut self, mut config: Config) -> Result<(), ConfigError> {
+        config.version = CONFIG_VERSION;
+        config.validate()?;
+
+        let previous = std::mem::replace(&mut self.config, config);
+
+        if let Err(e) = self.save() {
+            self.config = previous;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), ConfigError> {
+        self.backend.save(&to_json(&self.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_utc_offset_becomes_a_time_zone() {
+        let config = parse_config(
+            r#"{"version":1,"station":{"altitude_meters":780.0,"utc_offset_hours":-7}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.station.time_zone, "<-07>7");
+        assert_eq!(config.station.altitude_meters, 780.0);
+    }
+
+    #[test]
+    fn config_without_a_version_is_the_current_version() {
+        let config = parse_config(r#"{"station":{"time_zone":"Europe/London"}}"#).unwrap();
+
+        assert_eq!(config.station.time_zone, "Europe/London");
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert_eq!(
+            parse_config(r#"{"version":0}"#),
+            Err(ConfigError::UnsupportedVersion(0))
+        );
+        assert_eq!(
+            parse_config(r#"{"version":99}"#),
+            Err(ConfigError::UnsupportedVersion(99))
+        );
+    }
+
+    #[test]
+    fn admin_token_is_a_secret() {
+        let mut config = Config::new();
+        config.http.admin_token = "correct horse".to_string();
+
+        let mut posted = config.redacted();
+        assert_eq!(posted.http.admin_token, SECRET_MASK);
+
+        posted.keep_masked_secrets(&config);
+        assert_eq!(posted.http.admin_token, "correct horse");
+    }
+
+    #[test]
+    fn posted_sections_are_merged_over_the_stored_config() {
+        let mut stored = Config::new();
+        stored.wifi.ssid = "home".to_string();
+        stored.http.admin_token = "correct horse".to_string();
+        stored.station.time_zone = "Europe/London".to_string();
+
+        let config = merge_config(&stored, r#"{"station":{"altitude_meters":1500}}"#).unwrap();
+
+        assert_eq!(config.station.altitude_meters, 1500.0);
+        assert_eq!(config.station.time_zone, "Europe/London");
+        assert_eq!(config.wifi.ssid, "home");
+        assert_eq!(config.http.admin_token, "correct horse");
+        assert_eq!(config.kiosk, stored.kiosk);
+    }
+
+    #[test]
+    fn posted_lists_and_nulls_replace_the_stored_values() {
+        let mut stored = Config::new();
+        stored.sensor.product_id = Some(1234);
+
+        let config = merge_config(
+            &stored,
+            r#"{"sensor":{"product_id":null},"kiosk":{"views":[{"view":"time_date","dwell_secs":5}]}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.sensor.product_id, None);
+        assert_eq!(config.kiosk.views.len(), 1);
+        assert_eq!(config.kiosk.views[0].view, "time_date");
+        assert_eq!(
+            config.kiosk.resume_after_secs,
+            stored.kiosk.resume_after_secs
+        );
+    }
+
+    #[test]
+    fn posted_config_must_be_an_object() {
+        assert!(matches!(
+            merge_config(&Config::new(), "[]"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            merge_config(&Config::new(), r#"{"station":{"altitude_meters":"high"}}"#),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[cfg(not(target_os = "espidf"))]
+    #[test]
+    fn file_backend_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "weather_station_config_{}.json",
+            std::process::id()
+        ));
+        let mut backend = FileConfigBackend::new(&path);
+
+        assert_eq!(backend.load(), Ok(None));
+
+        backend.save(r#"{"version":2}"#).unwrap();
+        assert_eq!(backend.load(), Ok(Some(r#"{"version":2}"#.to_string())));
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn short_admin_token_is_rejected() {
+        let mut config = Config::new();
+        config.http.admin_token = "short".to_string();
+
+        assert!(config.validate().is_err());
+    }
+}
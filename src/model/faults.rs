@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::model::history::Sensor;
+
+/**
+ * A peripheral that stopped answering.  A fault is raised by the thread that owns the peripheral when an operation
+ * still fails after its retries, and cleared by the next operation that succeeds.  The gui shows the active faults
+ * in the alert banner and the exporters report them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    Receiver,
+    Rtc,
+    Display,
+    IndoorSensor(Sensor),
+}
+
+impl Fault {
+    pub fn all() -> Vec<Fault> {
+        let mut faults = vec![Fault::Receiver, Fault::Rtc, Fault::Display];
+
+        faults.extend(
+            Sensor::ALL
+                .iter()
+                .filter(|sensor| **sensor != Sensor::Acurite5n1)
+                .map(|sensor| Fault::IndoorSensor(*sensor)),
+        );
+
+        faults
+    }
+
+    // receiver, rtc, display, sensor_bme280
+    pub fn id(&self) -> String {
+        match self {
+            Fault::Receiver => "receiver".to_string(),
+            Fault::Rtc => "rtc".to_string(),
+            Fault::Display => "display".to_string(),
+            Fault::IndoorSensor(sensor) => format!("sensor_{}", sensor.id()),
+        }
+    }
+
+    // Receiver, RTC, Display, BME280
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::Receiver => "Receiver",
+            Fault::Rtc => "RTC",
+            Fault::Display => "Display",
+            Fault::IndoorSensor(sensor) => sensor.name(),
+        }
+    }
+
+    fn bit(&self) -> u32 {
+        let index = match self {
+            Fault::Receiver => 0,
+            Fault::Rtc => 1,
+            Fault::Display => 2,
+            Fault::IndoorSensor(sensor) => 3 + *sensor as u32,
+        };
+
+        1 << index
+    }
+}
+
+/**
+ * The active faults as a bitmask in a static, like the statistics, so that any thread can raise and clear a fault
+ * without a channel or a lock.
+ */
+pub struct Faults {
+    active: AtomicU32,
+}
+
+pub static FAULTS: Faults = Faults {
+    active: AtomicU32::new(0),
+};
+
+impl Faults {
+    // Returns true when the fault was not already active
+    pub fn raise(&self, fault: Fault) -> bool {
+        let previous = self.active.fetch_or(fault.bit(), Ordering::Relaxed);
+        previous & fault.bit() == 0
+    }
+
+    // Returns true when the fault was active
+    pub fn clear(&self, fault: Fault) -> bool {
+        let previous = self.active.fetch_and(!fault.bit(), Ordering::Relaxed);
+        previous & fault.bit() != 0
+    }
+
+    pub fn active(&self) -> Vec<Fault> {
+        let active = self.active.load(Ordering::Relaxed);
+
+        Fault::all()
+            .into_iter()
+            .filter(|fault| active & fault.bit() != 0)
+            .collect()
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::model::{
+    alerts::{Alert, AlertKind},
+    weather_station::{Measurement, MeasurementField},
+};
+
+// Each history point is 16 bytes so 2048 points is about 32K of heap.  With the Acurite5n1 sending a packet every
+// 18 seconds and the indoor sensors read every 2 minutes this holds several hours of changes.
+pub const HISTORY_CAPACITY: usize = 2048;
+
+// A battery lasts months so a handful of low battery periods covers years.  They are kept across reboots.
+pub const BATTERY_HISTORY_CAPACITY: usize = 16;
+
+pub type SharedHistory = Arc<Mutex<HistoryStore>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sensor {
+    Acurite5n1,
+    Bme280,
+    Bme680,
+    Bmp388,
+    Sht3x,
+    Sht4x,
+    Scd4x,
+}
+
+impl Sensor {
+    pub const ALL: [Sensor; 7] = [
+        Sensor::Acurite5n1,
+        Sensor::Bme280,
+        Sensor::Bme680,
+        Sensor::Bmp388,
+        Sensor::Sht3x,
+        Sensor::Sht4x,
+        Sensor::Scd4x,
+    ];
+
+    // The id used for this sensor in the json api and any other exporters
+    pub fn id(&self) -> &'static str {
+        match self {
+            Sensor::Acurite5n1 => "acurite5n1",
+            Sensor::Bme280 => "bme280",
+            Sensor::Bme680 => "bme680",
+            Sensor::Bmp388 => "bmp388",
+            Sensor::Sht3x => "sht3x",
+            Sensor::Sht4x => "sht4x",
+            Sensor::Scd4x => "scd4x",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sensor::Acurite5n1 => "Acurite 5n1",
+            Sensor::Bme280 => "BME280",
+            Sensor::Bme680 => "BME680",
+            Sensor::Bmp388 => "BMP388",
+            Sensor::Sht3x => "SHT3x",
+            Sensor::Sht4x => "SHT4x",
+            Sensor::Scd4x => "SCD4x",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LatestMeasurement {
+    pub timestamp: i64,
+    pub measurement: Measurement,
+}
+
+// An alert that has been raised and not cleared, since is when it was raised
+#[derive(Clone, Copy, Debug)]
+pub struct ActiveAlert {
+    pub alert: Alert,
+    pub since: i64,
+}
+
+// A period the sensor battery was low, replaced is when the sensor reported a good battery again
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatteryLowPeriod {
+    pub low_since: i64,
+    pub replaced: Option<i64>,
+}
+
+// Where the low battery periods are kept across reboots as json, NVS on the station
+pub trait BatteryLogBackend: Send {
+    fn load(&mut self) -> Option<String>;
+    fn save(&mut self, json: &str);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryPoint {
+    pub timestamp: i64,
+    pub field: MeasurementField,
+    pub value: f32,
+}
+
+/**
+ * The history store keeps the latest value of every measurement field and a ring buffer of the numeric
+ * measurements so that network clients can ask for ranges.  It also holds the alerts that are active so the exporters
+ * can publish them, the periods the sensor battery was low and which sensor measures each field.  All timestamps are
+ * unix seconds (UTC).
+ */
+pub struct HistoryStore {
+    latest: Vec<Option<LatestMeasurement>>,
+    points: VecDeque<HistoryPoint>,
+    points_recorded: u64,
+    last_seen: Vec<Option<i64>>,
+    // The indoor sensors found on the I2C bus at boot and the sensor each field comes from
+    indoor_sensors: Vec<Sensor>,
+    field_sensors: Vec<Option<Sensor>>,
+    active_alerts: Vec<ActiveAlert>,
+    battery_low_periods: VecDeque<BatteryLowPeriod>,
+    battery_log: Option<Box<dyn BatteryLogBackend>>,
+}
+
+impl Default for HistoryStore {
+    fn default() -> Self {
+        Self {
+            latest: vec![None; MeasurementField::ALL.len()],
+            points: VecDeque::with_capacity(HISTORY_CAPACITY),
+            points_recorded: 0,
+            last_seen: vec![None; Sensor::ALL.len()],
+            indoor_sensors: Vec::new(),
+            field_sensors: MeasurementField::ALL
+                .iter()
+                .map(|field| (!field.is_indoor()).then_some(Sensor::Acurite5n1))
+                .collect(),
+            active_alerts: Vec::new(),
+            battery_low_periods: VecDeque::with_capacity(BATTERY_HISTORY_CAPACITY),
+            battery_log: None,
+        }
+    }
+}
+
+impl HistoryStore {
+    // The low battery periods recorded before the station rebooted are loaded from the battery log
+    pub fn new_shared(battery_log: Box<dyn BatteryLogBackend>) -> SharedHistory {
+        Arc::new(Mutex::new(HistoryStore::with_battery_log(battery_log)))
+    }
+
+    pub fn with_battery_log(mut battery_log: Box<dyn BatteryLogBackend>) -> Self {
+        let periods = battery_log.load().and_then(|json| {
+            match serde_json::from_str::<VecDeque<BatteryLowPeriod>>(&json) {
+                Ok(periods) => Some(periods),
+                Err(e) => {
+                    println!("Battery log not loaded {}", e);
+                    None
+                }
+            }
+        });
+
+        let mut history = HistoryStore::default();
+        if let Some(mut periods) = periods {
+            while periods.len() > BATTERY_HISTORY_CAPACITY {
+                periods.pop_front();
+            }
+            history.battery_low_periods = periods;
+        }
+        history.battery_log = Some(battery_log);
+
+        history
+    }
+
+    pub fn record(&mut self, measurement: &Measurement) {
+        self.record_at(now_timestamp(), measurement);
+    }
+
+    pub fn record_at(&mut self, timestamp: i64, measurement: &Measurement) {
+        let field = measurement.field();
+
+        self.latest[field as usize] = Some(LatestMeasurement {
+            timestamp,
+            measurement: measurement.clone(),
+        });
+
+        if let Some(value) = measurement.numeric_value() {
+            if self.points.len() == HISTORY_CAPACITY {
+                self.points.pop_front();
+            }
+
+            self.points.push_back(HistoryPoint {
+                timestamp,
+                field,
+                value,
+            });
+            self.points_recorded += 1;
+        }
+    }
+
+    pub fn sensor_seen(&mut self, sensor: Sensor) {
+        self.last_seen[sensor as usize] = Some(now_timestamp());
+    }
+
+    pub fn last_seen(&self, sensor: Sensor) -> Option<i64> {
+        self.last_seen[sensor as usize]
+    }
+
+    // Record an indoor sensor found on the I2C bus and the fields it was given
+    pub fn sensor_detected(&mut self, sensor: Sensor, fields: &[MeasurementField]) {
+        self.indoor_sensors.push(sensor);
+
+        for field in fields {
+            self.field_sensors[*field as usize] = Some(sensor);
+
+            // The sea level pressure is worked out from the pressure the sensor measures
+            if *field == MeasurementField::IndoorPressure {
+                self.field_sensors[MeasurementField::SeaLevelPressure as usize] = Some(sensor);
+            }
+        }
+    }
+
+    // The Acurite5n1 followed by the indoor sensors that were found
+    pub fn sensors(&self) -> Vec<Sensor> {
+        let mut sensors = vec![Sensor::Acurite5n1];
+        sensors.extend(self.indoor_sensors.iter().copied());
+        sensors
+    }
+
+    // The sensor a field comes from, None for an indoor field no sensor that was found measures
+    pub fn field_sensor(&self, field: MeasurementField) -> Option<Sensor> {
+        self.field_sensors[field as usize]
+    }
+
+    pub fn alert_raised(&mut self, alert: Alert) {
+        self.alert_cleared(alert.kind);
+        self.active_alerts.push(ActiveAlert {
+            alert,
+            since: now_timestamp(),
+        });
+    }
+
+    pub fn alert_cleared(&mut self, kind: AlertKind) {
+        self.active_alerts
+            .retain(|active_alert| active_alert.alert.kind != kind);
+    }
+
+    pub fn active_alerts(&self) -> &[ActiveAlert] {
+        &self.active_alerts
+    }
+
+    // Starts a low battery period when the battery goes low and ends it when the battery is good again
+    pub fn battery_status(&mut self, low: bool) {
+        self.battery_status_at(now_timestamp(), low);
+    }
+
+    pub fn battery_status_at(&mut self, timestamp: i64, low: bool) {
+        match (low, self.battery_low_since()) {
+            (true, None) => {
+                if self.battery_low_periods.len() == BATTERY_HISTORY_CAPACITY {
+                    self.battery_low_periods.pop_front();
+                }
+
+                self.battery_low_periods.push_back(BatteryLowPeriod {
+                    low_since: timestamp,
+                    replaced: None,
+                });
+            }
+            (false, Some(_)) => {
+                if let Some(period) = self.battery_low_periods.back_mut() {
+                    period.replaced = Some(timestamp);
+                }
+            }
+            _ => return,
+        }
+
+        if let Some(battery_log) = self.battery_log.as_mut() {
+            battery_log.save(&serde_json::to_string(&self.battery_low_periods).unwrap());
+        }
+    }
+
+    // When the battery went low, None while the battery is good
+    pub fn battery_low_since(&self) -> Option<i64> {
+        self.battery_low_periods
+            .back()
+            .filter(|period| period.replaced.is_none())
+            .map(|period| period.low_since)
+    }
+
+    // The low battery periods, oldest first
+    pub fn battery_low_periods(&self) -> Vec<BatteryLowPeriod> {
+        self.battery_low_periods.iter().copied().collect()
+    }
+
+    pub fn latest(&self, field: MeasurementField) -> Option<&LatestMeasurement> {
+        self.latest[field as usize].as_ref()
+    }
+
+    // Returns the points of a field with a timestamp between from and to inclusive, oldest first
+    pub fn range(&self, field: MeasurementField, from: i64, to: i64) -> Vec<HistoryPoint> {
+        self.points
+            .iter()
+            .filter(|point| {
+                point.field == field && point.timestamp >= from && point.timestamp <= to
+            })
+            .copied()
+            .collect()
+    }
+
+    // The sequence number the next recorded point will get, points are numbered from 0 in the order they are recorded
+    pub fn next_sequence(&self) -> u64 {
+        self.points_recorded
+    }
+
+    /**
+     * Returns up to limit points starting at the given sequence number, oldest first, and the sequence number to ask
+     * for next.  If the ring buffer has dropped points that were asked for the oldest points still held are returned
+     * instead.  Exporters use this to push the points they missed while they were offline.
+     */
+    pub fn points_from(&self, sequence: u64, limit: usize) -> (Vec<HistoryPoint>, u64) {
+        let oldest_sequence = self.points_recorded - self.points.len() as u64;
+        let start = sequence.clamp(oldest_sequence, self.points_recorded);
+
+        let points: Vec<HistoryPoint> = self
+            .points
+            .iter()
+            .skip((start - oldest_sequence) as usize)
+            .take(limit)
+            .copied()
+            .collect();
+
+        let next = start + points.len() as u64;
+
+        (points, next)
+    }
+
+    // Returns the value a field had at the given time, that is the value of the last point at or before timestamp
+    pub fn value_at(&self, field: MeasurementField, timestamp: i64) -> Option<f32> {
+        self.points
+            .iter()
+            .rev()
+            .find(|point| point.field == field && point.timestamp <= timestamp)
+            .map(|point| point.value)
+    }
+
+    /**
+     * Returns the rainfall in inches since the given time.  The yearly rainfall total only goes up (apart from the
+     * reset on new year) so the rainfall is the current yearly total minus the yearly total at the given time.  The
+     * totals start at zero when the station boots, so if there is no point that old and the ring buffer has not
+     * wrapped the baseline is zero.
+     */
+    pub fn rainfall_since(&self, timestamp: i64) -> f32 {
+        let current = match self.latest(MeasurementField::YearlyRainfall) {
+            Some(latest) => latest.measurement.numeric_value().unwrap_or_default(),
+            None => return 0.0,
+        };
+
+        let baseline = match self.value_at(MeasurementField::YearlyRainfall, timestamp) {
+            Some(value) => value,
+            None if self.points.len() < HISTORY_CAPACITY => 0.0,
+            None => self
+                .points
+                .iter()
+                .find(|point| point.field == MeasurementField::YearlyRainfall)
+                .map(|point| point.value)
+                .unwrap_or(current),
+        };
+
+        // The yearly total was reset since the given time
+        if current < baseline {
+            return current;
+        }
+
+        current - baseline
+    }
+}
+
+pub fn now_timestamp() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A battery log kept in memory, shared with the test so it can see what was saved
+    struct MemoryBatteryLog(Arc<Mutex<Option<String>>>);
+
+    impl BatteryLogBackend for MemoryBatteryLog {
+        fn load(&mut self) -> Option<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn save(&mut self, json: &str) {
+            *self.0.lock().unwrap() = Some(json.to_string());
+        }
+    }
+
+    #[test]
+    fn battery_low_periods_survive_a_reboot() {
+        let stored = Arc::new(Mutex::new(None));
+
+        let mut history =
+            HistoryStore::with_battery_log(Box::new(MemoryBatteryLog(stored.clone())));
+        history.battery_status_at(1000, true);
+        history.battery_status_at(2000, false);
+        history.battery_status_at(3000, true);
+        // A repeated status changes nothing
+        history.battery_status_at(3100, true);
+
+        let history = HistoryStore::with_battery_log(Box::new(MemoryBatteryLog(stored)));
+        assert_eq!(
+            history.battery_low_periods(),
+            vec![
+                BatteryLowPeriod {
+                    low_since: 1000,
+                    replaced: Some(2000),
+                },
+                BatteryLowPeriod {
+                    low_since: 3000,
+                    replaced: None,
+                },
+            ]
+        );
+        assert_eq!(history.battery_low_since(), Some(3000));
+    }
+
+    #[test]
+    fn battery_log_keeps_the_latest_periods() {
+        let stored = Arc::new(Mutex::new(None));
+
+        let mut history =
+            HistoryStore::with_battery_log(Box::new(MemoryBatteryLog(stored.clone())));
+        for period in 0..BATTERY_HISTORY_CAPACITY as i64 + 4 {
+            history.battery_status_at(period * 100, true);
+            history.battery_status_at(period * 100 + 50, false);
+        }
+
+        let history = HistoryStore::with_battery_log(Box::new(MemoryBatteryLog(stored)));
+        let periods = history.battery_low_periods();
+        assert_eq!(periods.len(), BATTERY_HISTORY_CAPACITY);
+        assert_eq!(periods[0].low_since, 400);
+        assert_eq!(history.battery_low_since(), None);
+    }
+
+    #[test]
+    fn unreadable_battery_log_starts_empty() {
+        let stored = Arc::new(Mutex::new(Some("not json".to_string())));
+
+        let history = HistoryStore::with_battery_log(Box::new(MemoryBatteryLog(stored)));
+        assert!(history.battery_low_periods().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use bme280_rs::{Bme280 as Bme280Driver, Configuration, Oversampling, SensorMode};
+use esp_idf_hal::delay;
+
+use crate::model::{
+    history::Sensor,
+    indoor_sensors::{read_register, I2c, I2cBus, IndoorSample, IndoorSensor, SensorError},
+    weather_station::MeasurementField,
+};
+
+// The bme280-rs driver only talks to the primary address
+const ADDRESS: u8 = 0x76;
+const CHIP_ID_REGISTER: u8 = 0xD0;
+const CHIP_ID: u8 = 0x60;
+
+const FIELDS: [MeasurementField; 3] = [
+    MeasurementField::IndoorTemperature,
+    MeasurementField::IndoorHumidity,
+    MeasurementField::IndoorPressure,
+];
+
+pub struct Bme280 {
+    bme280: Bme280Driver<I2c, delay::Ets>,
+}
+
+pub fn detect(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    if !addresses.contains(&ADDRESS) {
+        return None;
+    }
+
+    let mut i2c = bus.acquire_i2c();
+    let mut chip_id = [0u8; 1];
+
+    if read_register(&mut i2c, ADDRESS, CHIP_ID_REGISTER, &mut chip_id).is_err()
+        || chip_id[0] != CHIP_ID
+    {
+        return None;
+    }
+
+    let mut bme280 = Bme280Driver::new(i2c, delay::Ets);
+
+    match configure(&mut bme280) {
+        Ok(_) => Some(Box::new(Bme280 { bme280 })),
+        Err(e) => {
+            println!("Failed to initialize the BME280 {}", e);
+            None
+        }
+    }
+}
+
+// init soft resets the BME280 and reads its calibration, the sampling configuration is lost with the reset
+fn configure(bme280: &mut Bme280Driver<I2c, delay::Ets>) -> Result<(), SensorError> {
+    bme280
+        .init()
+        .map_err(|e| SensorError::Driver(format!("{:?}", e)))?;
+
+    bme280
+        .set_sampling_configuration(
+            Configuration::default()
+                .with_temperature_oversampling(Oversampling::Oversample1)
+                .with_pressure_oversampling(Oversampling::Oversample8)
+                .with_humidity_oversampling(Oversampling::Oversample1)
+                .with_sensor_mode(SensorMode::Normal),
+        )
+        .map_err(|e| SensorError::Driver(format!("{:?}", e)))
+}
+
+impl IndoorSensor for Bme280 {
+    fn sensor(&self) -> Sensor {
+        Sensor::Bme280
+    }
+
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        match self
+            .bme280
+            .read_sample()
+            .map_err(|e| SensorError::Driver(format!("{:?}", e)))?
+        {
+            (Some(t), Some(p), Some(h)) => Ok(Some(IndoorSample {
+                temperature_c: Some(t),
+                humidity: Some(h),
+                pressure_pa: Some(p),
+                ..Default::default()
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        configure(&mut self.bme280)
+    }
+}
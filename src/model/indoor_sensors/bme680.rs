@@ -0,0 +1,500 @@
+use esp_idf_hal::delay::FreeRtos;
+
+use crate::model::{
+    history::Sensor,
+    indoor_sensors::{
+        read_register, write_register, I2c, I2cBus, IndoorSample, IndoorSensor, SensorError,
+    },
+    weather_station::MeasurementField,
+};
+
+const ADDRESSES: [u8; 2] = [0x76, 0x77];
+const CHIP_ID_REGISTER: u8 = 0xD0;
+const CHIP_ID: u8 = 0x61;
+
+const RESET_REGISTER: u8 = 0xE0;
+const RESET_COMMAND: u8 = 0xB6;
+
+// The calibration is read in two blocks, the indexes below are into both blocks one after the other
+const CALIBRATION_1_REGISTER: u8 = 0x89;
+const CALIBRATION_1_LENGTH: usize = 25;
+const CALIBRATION_2_REGISTER: u8 = 0xE1;
+const CALIBRATION_2_LENGTH: usize = 16;
+const RES_HEAT_VAL_REGISTER: u8 = 0x00;
+const RES_HEAT_RANGE_REGISTER: u8 = 0x02;
+const RANGE_SW_ERR_REGISTER: u8 = 0x04;
+
+const RES_HEAT_0_REGISTER: u8 = 0x5A;
+const GAS_WAIT_0_REGISTER: u8 = 0x64;
+const CTRL_GAS_1_REGISTER: u8 = 0x71;
+const CTRL_HUM_REGISTER: u8 = 0x72;
+const CTRL_MEAS_REGISTER: u8 = 0x74;
+
+// The status, pressure, temperature, humidity and gas registers are read in one go from 0x1D to 0x2B
+const DATA_REGISTER: u8 = 0x1D;
+const DATA_LENGTH: usize = 15;
+
+const NEW_DATA: u8 = 0x80;
+const GAS_VALID: u8 = 0x20;
+const HEAT_STABLE: u8 = 0x10;
+const RUN_GAS: u8 = 0x10;
+
+// Humidity oversampling x1, temperature x2, pressure x16 and a forced measurement
+const CTRL_HUM: u8 = 0x01;
+const CTRL_MEAS_FORCED: u8 = (0x02 << 5) | (0x05 << 2) | 0x01;
+
+// The hot plate is heated to 320C for 150 ms, as the Bosch examples do for the indoor air
+const HEATER_TEMPERATURE_C: f32 = 320.0;
+const HEATER_DURATION_MS: u16 = 150;
+
+// Long enough for the oversampled measurement and the heater, then the status is polled
+const MEASUREMENT_MS: u32 = 200;
+const STATUS_POLLS: usize = 5;
+const STATUS_POLL_MS: u32 = 10;
+
+// The air quality weighs the humidity against its ideal at 25% and the gas resistance against its baseline at 75%
+const IDEAL_HUMIDITY: f32 = 40.0;
+const HUMIDITY_WEIGHT: f32 = 25.0;
+const GAS_WEIGHT: f32 = 75.0;
+
+// The corrections of the gas resistance for each of its 16 ranges from the Bosch BME680 driver
+const GAS_RANGE_K1: [f32; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, -0.8, 0.0, 0.0, -0.2, -0.5, 0.0, -1.0, 0.0, 0.0,
+];
+const GAS_RANGE_K2: [f32; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.1, 0.7, 0.0, -0.8, -0.1, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+];
+
+const FIELDS: [MeasurementField; 5] = [
+    MeasurementField::IndoorTemperature,
+    MeasurementField::IndoorHumidity,
+    MeasurementField::IndoorPressure,
+    MeasurementField::IndoorGasResistance,
+    MeasurementField::IndoorAirQuality,
+];
+
+struct Calibration {
+    t1: f32,
+    t2: f32,
+    t3: f32,
+    p1: f32,
+    p2: f32,
+    p3: f32,
+    p4: f32,
+    p5: f32,
+    p6: f32,
+    p7: f32,
+    p8: f32,
+    p9: f32,
+    p10: f32,
+    h1: f32,
+    h2: f32,
+    h3: f32,
+    h4: f32,
+    h5: f32,
+    h6: f32,
+    h7: f32,
+    gh1: f32,
+    gh2: f32,
+    gh3: f32,
+    res_heat_range: f32,
+    res_heat_val: f32,
+    range_sw_err: f32,
+}
+
+impl Calibration {
+    // The calibration from the two blocks of registers and the three heater registers
+    fn from_registers(
+        c: &[u8; CALIBRATION_1_LENGTH + CALIBRATION_2_LENGTH],
+        res_heat_val: u8,
+        res_heat_range: u8,
+        range_sw_err: u8,
+    ) -> Self {
+        let u16_at = |lsb: usize| u16::from_le_bytes([c[lsb], c[lsb + 1]]) as f32;
+        let i16_at = |lsb: usize| i16::from_le_bytes([c[lsb], c[lsb + 1]]) as f32;
+        let i8_at = |index: usize| c[index] as i8 as f32;
+
+        Self {
+            t1: u16_at(33),
+            t2: i16_at(1),
+            t3: i8_at(3),
+            p1: u16_at(5),
+            p2: i16_at(7),
+            p3: i8_at(9),
+            p4: i16_at(11),
+            p5: i16_at(13),
+            p6: i8_at(16),
+            p7: i8_at(15),
+            p8: i16_at(19),
+            p9: i16_at(21),
+            p10: c[23] as f32,
+            // The humidity calibration shares a byte, h1 takes its low nibble and h2 its high nibble
+            h1: (((c[27] as u16) << 4) | (c[26] as u16 & 0x0F)) as f32,
+            h2: (((c[25] as u16) << 4) | (c[26] as u16 >> 4)) as f32,
+            h3: i8_at(28),
+            h4: i8_at(29),
+            h5: i8_at(30),
+            h6: c[31] as f32,
+            h7: i8_at(32),
+            gh1: i8_at(37),
+            gh2: i16_at(35),
+            gh3: i8_at(38),
+            res_heat_range: ((res_heat_range & 0x30) >> 4) as f32,
+            res_heat_val: res_heat_val as i8 as f32,
+            range_sw_err: ((range_sw_err & 0xF0) as i8 / 16) as f32,
+        }
+    }
+
+    // The heater resistance that heats the hot plate to the temperature, from the Bosch float driver
+    fn heater_resistance(&self, target_c: f32, ambient_c: f32) -> u8 {
+        let var1 = self.gh1 / 16.0 + 49.0;
+        let var2 = (self.gh2 / 32768.0) * 0.0005 + 0.00235;
+        let var3 = self.gh3 / 1024.0;
+        let var4 = var1 * (1.0 + var2 * target_c);
+        let var5 = var4 + var3 * ambient_c;
+
+        (3.4 * (var5
+            * (4.0 / (4.0 + self.res_heat_range))
+            * (1.0 / (1.0 + self.res_heat_val * 0.002))
+            - 25.0)) as u8
+    }
+
+    // Returns the temperature in C and t_fine that the pressure and humidity are compensated with
+    fn temperature(&self, adc: f32) -> (f32, f32) {
+        let var1 = (adc / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc / 131072.0 - self.t1 / 8192.0)
+            * (adc / 131072.0 - self.t1 / 8192.0)
+            * self.t3
+            * 16.0;
+        let t_fine = var1 + var2;
+
+        (t_fine / 5120.0, t_fine)
+    }
+
+    // The pressure in Pa
+    fn pressure(&self, adc: f32, t_fine: f32) -> f32 {
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * (self.p6 / 131072.0);
+        var2 += var1 * self.p5 * 2.0;
+        var2 = var2 / 4.0 + self.p4 * 65536.0;
+        var1 = (self.p3 * var1 * var1 / 16384.0 + self.p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1;
+
+        if var1 == 0.0 {
+            return 0.0;
+        }
+
+        let mut pressure = 1048576.0 - adc;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = self.p9 * pressure * pressure / 2147483648.0;
+        let var2 = pressure * (self.p8 / 32768.0);
+        let var3 = (pressure / 256.0).powi(3) * (self.p10 / 131072.0);
+
+        pressure + (var1 + var2 + var3 + self.p7 * 128.0) / 16.0
+    }
+
+    fn humidity(&self, adc: f32, temperature_c: f32) -> f32 {
+        let var1 = adc - (self.h1 * 16.0 + (self.h3 / 2.0) * temperature_c);
+        let var2 = var1
+            * ((self.h2 / 262144.0)
+                * (1.0
+                    + (self.h4 / 16384.0) * temperature_c
+                    + (self.h5 / 1048576.0) * temperature_c * temperature_c));
+        let var3 = self.h6 / 16384.0;
+        let var4 = self.h7 / 2097152.0;
+
+        (var2 + (var3 + var4 * temperature_c) * var2 * var2).clamp(0.0, 100.0)
+    }
+
+    fn gas_resistance(&self, adc: f32, range: usize) -> f32 {
+        let var1 = 1340.0 + 5.0 * self.range_sw_err;
+        let var2 = var1 * (1.0 + GAS_RANGE_K1[range] / 100.0);
+        let var3 = 1.0 + GAS_RANGE_K2[range] / 100.0;
+
+        1.0 / (var3 * 0.000000125 * (1u32 << range) as f32 * ((adc - 512.0) / var2 + 1.0))
+    }
+}
+
+pub struct Bme680 {
+    i2c: I2c,
+    address: u8,
+    calibration: Calibration,
+    // The heater resistance depends on the room temperature, it is worked out again with the last temperature
+    ambient_temperature_c: f32,
+    // The highest gas resistance seen since boot is taken as clean air
+    gas_baseline_ohms: f32,
+}
+
+pub fn detect(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    for address in ADDRESSES
+        .iter()
+        .filter(|address| addresses.contains(address))
+    {
+        let mut i2c = bus.acquire_i2c();
+        let mut chip_id = [0u8; 1];
+
+        if read_register(&mut i2c, *address, CHIP_ID_REGISTER, &mut chip_id).is_err()
+            || chip_id[0] != CHIP_ID
+        {
+            continue;
+        }
+
+        match Bme680::new(i2c, *address) {
+            Ok(bme680) => return Some(Box::new(bme680)),
+            Err(e) => println!("Failed to initialize the BME680 {}", e),
+        }
+    }
+
+    None
+}
+
+impl Bme680 {
+    fn new(mut i2c: I2c, address: u8) -> Result<Self, SensorError> {
+        let calibration = Self::init(&mut i2c, address)?;
+
+        Ok(Self {
+            i2c,
+            address,
+            calibration,
+            ambient_temperature_c: 25.0,
+            gas_baseline_ohms: 0.0,
+        })
+    }
+
+    // Soft reset, read the calibration and set up the oversampling and the heater, which the reset cleared
+    fn init(i2c: &mut I2c, address: u8) -> Result<Calibration, SensorError> {
+        write_register(i2c, address, RESET_REGISTER, RESET_COMMAND)?;
+        FreeRtos::delay_ms(10);
+
+        let calibration = Self::read_calibration(i2c, address)?;
+
+        write_register(i2c, address, CTRL_HUM_REGISTER, CTRL_HUM)?;
+        write_register(
+            i2c,
+            address,
+            GAS_WAIT_0_REGISTER,
+            gas_wait(HEATER_DURATION_MS),
+        )?;
+        write_register(i2c, address, CTRL_GAS_1_REGISTER, RUN_GAS)?;
+
+        Ok(calibration)
+    }
+
+    fn read_calibration(i2c: &mut I2c, address: u8) -> Result<Calibration, SensorError> {
+        let mut c = [0u8; CALIBRATION_1_LENGTH + CALIBRATION_2_LENGTH];
+        read_register(
+            i2c,
+            address,
+            CALIBRATION_1_REGISTER,
+            &mut c[..CALIBRATION_1_LENGTH],
+        )?;
+        read_register(
+            i2c,
+            address,
+            CALIBRATION_2_REGISTER,
+            &mut c[CALIBRATION_1_LENGTH..],
+        )?;
+
+        let mut res_heat_val = [0u8; 1];
+        let mut res_heat_range = [0u8; 1];
+        let mut range_sw_err = [0u8; 1];
+        read_register(i2c, address, RES_HEAT_VAL_REGISTER, &mut res_heat_val)?;
+        read_register(i2c, address, RES_HEAT_RANGE_REGISTER, &mut res_heat_range)?;
+        read_register(i2c, address, RANGE_SW_ERR_REGISTER, &mut range_sw_err)?;
+
+        Ok(Calibration::from_registers(
+            &c,
+            res_heat_val[0],
+            res_heat_range[0],
+            range_sw_err[0],
+        ))
+    }
+
+    /**
+     * The air quality in %, 100% is clean air at the ideal humidity.  The humidity scores up to 25% and falls off away
+     * from 40%, the gas resistance scores up to 75% against the highest resistance seen which is taken as clean air.
+     * The score settles as the sensor burns in over its first hours.
+     */
+    fn air_quality(&mut self, humidity: f32, gas_resistance_ohms: f32) -> f32 {
+        self.gas_baseline_ohms = self.gas_baseline_ohms.max(gas_resistance_ohms);
+
+        let humidity_score = if humidity < IDEAL_HUMIDITY {
+            HUMIDITY_WEIGHT * humidity / IDEAL_HUMIDITY
+        } else {
+            HUMIDITY_WEIGHT * (100.0 - humidity) / (100.0 - IDEAL_HUMIDITY)
+        };
+
+        let gas_score = GAS_WEIGHT * gas_resistance_ohms / self.gas_baseline_ohms;
+
+        (humidity_score + gas_score).clamp(0.0, 100.0)
+    }
+}
+
+impl IndoorSensor for Bme680 {
+    fn sensor(&self) -> Sensor {
+        Sensor::Bme680
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        let res_heat = self
+            .calibration
+            .heater_resistance(HEATER_TEMPERATURE_C, self.ambient_temperature_c);
+        write_register(&mut self.i2c, self.address, RES_HEAT_0_REGISTER, res_heat)?;
+        write_register(
+            &mut self.i2c,
+            self.address,
+            CTRL_MEAS_REGISTER,
+            CTRL_MEAS_FORCED,
+        )?;
+
+        FreeRtos::delay_ms(MEASUREMENT_MS);
+
+        let mut data = [0u8; DATA_LENGTH];
+        for _ in 0..STATUS_POLLS {
+            read_register(&mut self.i2c, self.address, DATA_REGISTER, &mut data)?;
+            if data[0] & NEW_DATA != 0 {
+                break;
+            }
+            FreeRtos::delay_ms(STATUS_POLL_MS);
+        }
+
+        if data[0] & NEW_DATA == 0 {
+            return Ok(None);
+        }
+
+        let adc_pressure =
+            ((data[2] as u32) << 12 | (data[3] as u32) << 4 | (data[4] as u32) >> 4) as f32;
+        let adc_temperature =
+            ((data[5] as u32) << 12 | (data[6] as u32) << 4 | (data[7] as u32) >> 4) as f32;
+        let adc_humidity = ((data[8] as u32) << 8 | data[9] as u32) as f32;
+        let adc_gas = ((data[13] as u32) << 2 | (data[14] as u32) >> 6) as f32;
+        let gas_range = (data[14] & 0x0F) as usize;
+
+        let (temperature_c, t_fine) = self.calibration.temperature(adc_temperature);
+        let pressure_pa = self.calibration.pressure(adc_pressure, t_fine);
+        let humidity = self.calibration.humidity(adc_humidity, temperature_c);
+
+        self.ambient_temperature_c = temperature_c;
+
+        // The gas reading is only good when the hot plate reached its temperature
+        let (gas_resistance_ohms, air_quality) =
+            if data[14] & GAS_VALID != 0 && data[14] & HEAT_STABLE != 0 {
+                let gas_resistance_ohms = self.calibration.gas_resistance(adc_gas, gas_range);
+                (
+                    Some(gas_resistance_ohms),
+                    Some(self.air_quality(humidity, gas_resistance_ohms)),
+                )
+            } else {
+                (None, None)
+            };
+
+        Ok(Some(IndoorSample {
+            temperature_c: Some(temperature_c),
+            humidity: Some(humidity),
+            pressure_pa: Some(pressure_pa),
+            gas_resistance_ohms,
+            air_quality,
+            ..Default::default()
+        }))
+    }
+
+    // The gas baseline is kept, the hot plate is the same after a reset
+    fn reset(&mut self) -> Result<(), SensorError> {
+        self.calibration = Self::init(&mut self.i2c, self.address)?;
+        Ok(())
+    }
+}
+
+// The heater duration takes 6 bits and a multiplier of 1, 4, 16 or 64 in the top 2 bits
+fn gas_wait(mut duration_ms: u16) -> u8 {
+    if duration_ms >= 0xFC0 {
+        return 0xFF;
+    }
+
+    let mut factor: u8 = 0;
+    while duration_ms > 0x3F {
+        duration_ms /= 4;
+        factor += 1;
+    }
+
+    duration_ms as u8 + factor * 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The calibration registers of a BME680, the values are worked out with the Bosch float driver
+    const CALIBRATION: [u8; CALIBRATION_1_LENGTH + CALIBRATION_2_LENGTH] = [
+        0x00, 0x36, 0x67, 0x03, 0x00, 0x49, 0x8E, 0x44, 0xD7, 0x58, 0x00, 0xFE, 0x1B, 0x9A, 0xFF,
+        0x2E, 0x1E, 0x00, 0x00, 0x39, 0xF4, 0xA5, 0xF6, 0x1E, 0x00, 0x3F, 0x7E, 0x30, 0x00, 0x2D,
+        0x14, 0x78, 0x9C, 0x2F, 0x66, 0xF9, 0xCD, 0xE2, 0x12, 0x00, 0x00,
+    ];
+
+    fn calibration() -> Calibration {
+        Calibration::from_registers(&CALIBRATION, 42, 0x10, 0x20)
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn calibration_registers() {
+        let c = calibration();
+
+        assert_eq!((c.t1, c.t2, c.t3), (26159.0, 26422.0, 3.0));
+        assert_eq!((c.p1, c.p2, c.p3, c.p4), (36425.0, -10428.0, 88.0, 7166.0));
+        assert_eq!((c.p5, c.p6, c.p7, c.p8), (-102.0, 30.0, 46.0, -3015.0));
+        assert_eq!((c.p9, c.p10), (-2395.0, 30.0));
+        assert_eq!((c.h1, c.h2, c.h3, c.h4), (782.0, 1015.0, 0.0, 45.0));
+        assert_eq!((c.h5, c.h6, c.h7), (20.0, 120.0, -100.0));
+        assert_eq!((c.gh1, c.gh2, c.gh3), (-30.0, -12807.0, 18.0));
+        assert_eq!(
+            (c.res_heat_val, c.res_heat_range, c.range_sw_err),
+            (42.0, 1.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn compensation() {
+        let c = calibration();
+
+        let (temperature_c, t_fine) = c.temperature(500000.0);
+        assert_close(temperature_c, 25.66, 0.01);
+        assert_close(c.pressure(360000.0, t_fine), 98521.5, 1.0);
+        assert_close(c.humidity(20000.0, temperature_c), 37.42, 0.01);
+    }
+
+    #[test]
+    fn gas_resistance() {
+        let c = calibration();
+
+        assert_close(c.gas_resistance(512.0, 0), 8_000_000.0, 1.0);
+        assert_close(c.gas_resistance(400.0, 5), 270_970.0, 1.0);
+        assert_close(c.gas_resistance(700.0, 10), 6_855.8, 0.1);
+    }
+
+    #[test]
+    fn heater() {
+        assert_eq!(calibration().heater_resistance(320.0, 25.0), 115);
+
+        // 100 ms is 25 x 4 as the datasheet shows
+        assert_eq!(gas_wait(100), 0x59);
+        assert_eq!(gas_wait(HEATER_DURATION_MS), 0x65);
+        assert_eq!(gas_wait(5000), 0xFF);
+    }
+}
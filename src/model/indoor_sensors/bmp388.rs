@@ -0,0 +1,210 @@
+use esp_idf_hal::delay::FreeRtos;
+
+use crate::model::{
+    history::Sensor,
+    indoor_sensors::{
+        read_register, write_register, I2c, I2cBus, IndoorSample, IndoorSensor, SensorError,
+    },
+    weather_station::MeasurementField,
+};
+
+const ADDRESSES: [u8; 2] = [0x76, 0x77];
+const CHIP_ID_REGISTER: u8 = 0x00;
+// The BMP390 is register compatible with the BMP388
+const CHIP_IDS: [u8; 2] = [0x50, 0x60];
+
+const COMMAND_REGISTER: u8 = 0x7E;
+const RESET_COMMAND: u8 = 0xB6;
+
+const CALIBRATION_REGISTER: u8 = 0x31;
+const CALIBRATION_LENGTH: usize = 21;
+
+const STATUS_REGISTER: u8 = 0x03;
+const DATA_REGISTER: u8 = 0x04;
+const PWR_CTRL_REGISTER: u8 = 0x1B;
+const OSR_REGISTER: u8 = 0x1C;
+
+const DATA_READY: u8 = 0x60;
+
+// Pressure and temperature enabled and a forced measurement, pressure oversampling x8 and temperature x1
+const PWR_CTRL_FORCED: u8 = 0x13;
+const OSR: u8 = 0x03;
+
+// The oversampled measurement takes about 20 ms, then the status is polled
+const MEASUREMENT_MS: u32 = 25;
+const STATUS_POLLS: usize = 5;
+const STATUS_POLL_MS: u32 = 5;
+
+const FIELDS: [MeasurementField; 2] = [
+    MeasurementField::IndoorPressure,
+    MeasurementField::IndoorTemperature,
+];
+
+// The calibration scaled to floats as the datasheet does in section 9.1
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    p4: f64,
+    p5: f64,
+    p6: f64,
+    p7: f64,
+    p8: f64,
+    p9: f64,
+    p10: f64,
+    p11: f64,
+}
+
+pub struct Bmp388 {
+    i2c: I2c,
+    address: u8,
+    calibration: Calibration,
+}
+
+pub fn detect(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    for address in ADDRESSES
+        .iter()
+        .filter(|address| addresses.contains(address))
+    {
+        let mut i2c = bus.acquire_i2c();
+        let mut chip_id = [0u8; 1];
+
+        if read_register(&mut i2c, *address, CHIP_ID_REGISTER, &mut chip_id).is_err()
+            || !CHIP_IDS.contains(&chip_id[0])
+        {
+            continue;
+        }
+
+        match Bmp388::new(i2c, *address) {
+            Ok(bmp388) => return Some(Box::new(bmp388)),
+            Err(e) => println!("Failed to initialize the BMP388 {}", e),
+        }
+    }
+
+    None
+}
+
+impl Bmp388 {
+    fn new(mut i2c: I2c, address: u8) -> Result<Self, SensorError> {
+        let calibration = Self::init(&mut i2c, address)?;
+
+        Ok(Self {
+            i2c,
+            address,
+            calibration,
+        })
+    }
+
+    // Soft reset, read the calibration and set the oversampling, which the reset cleared
+    fn init(i2c: &mut I2c, address: u8) -> Result<Calibration, SensorError> {
+        write_register(i2c, address, COMMAND_REGISTER, RESET_COMMAND)?;
+        FreeRtos::delay_ms(10);
+
+        let mut c = [0u8; CALIBRATION_LENGTH];
+        read_register(i2c, address, CALIBRATION_REGISTER, &mut c)?;
+
+        write_register(i2c, address, OSR_REGISTER, OSR)?;
+
+        let u16_at = |lsb: usize| u16::from_le_bytes([c[lsb], c[lsb + 1]]) as f64;
+        let i16_at = |lsb: usize| i16::from_le_bytes([c[lsb], c[lsb + 1]]) as f64;
+        let i8_at = |index: usize| c[index] as i8 as f64;
+
+        Ok(Calibration {
+            t1: u16_at(0) * 2f64.powi(8),
+            t2: u16_at(2) / 2f64.powi(30),
+            t3: i8_at(4) / 2f64.powi(48),
+            p1: (i16_at(5) - 2f64.powi(14)) / 2f64.powi(20),
+            p2: (i16_at(7) - 2f64.powi(14)) / 2f64.powi(29),
+            p3: i8_at(9) / 2f64.powi(32),
+            p4: i8_at(10) / 2f64.powi(37),
+            p5: u16_at(11) * 2f64.powi(3),
+            p6: u16_at(13) / 2f64.powi(6),
+            p7: i8_at(15) / 2f64.powi(8),
+            p8: i8_at(16) / 2f64.powi(15),
+            p9: i16_at(17) / 2f64.powi(48),
+            p10: i8_at(19) / 2f64.powi(48),
+            p11: i8_at(20) / 2f64.powi(65),
+        })
+    }
+
+    fn temperature(&self, adc: f64) -> f64 {
+        let c = &self.calibration;
+
+        let partial_1 = adc - c.t1;
+        let partial_2 = partial_1 * c.t2;
+
+        partial_2 + partial_1 * partial_1 * c.t3
+    }
+
+    // The pressure in Pa
+    fn pressure(&self, adc: f64, t: f64) -> f64 {
+        let c = &self.calibration;
+
+        let out_1 = c.p5 + c.p6 * t + c.p7 * t * t + c.p8 * t * t * t;
+        let out_2 = adc * (c.p1 + c.p2 * t + c.p3 * t * t + c.p4 * t * t * t);
+        let out_3 = adc * adc * (c.p9 + c.p10 * t) + adc * adc * adc * c.p11;
+
+        out_1 + out_2 + out_3
+    }
+}
+
+impl IndoorSensor for Bmp388 {
+    fn sensor(&self) -> Sensor {
+        Sensor::Bmp388
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        write_register(
+            &mut self.i2c,
+            self.address,
+            PWR_CTRL_REGISTER,
+            PWR_CTRL_FORCED,
+        )?;
+
+        FreeRtos::delay_ms(MEASUREMENT_MS);
+
+        let mut status = [0u8; 1];
+        for _ in 0..STATUS_POLLS {
+            read_register(&mut self.i2c, self.address, STATUS_REGISTER, &mut status)?;
+            if status[0] & DATA_READY == DATA_READY {
+                break;
+            }
+            FreeRtos::delay_ms(STATUS_POLL_MS);
+        }
+
+        if status[0] & DATA_READY != DATA_READY {
+            return Ok(None);
+        }
+
+        let mut data = [0u8; 6];
+        read_register(&mut self.i2c, self.address, DATA_REGISTER, &mut data)?;
+
+        let adc_pressure = u32::from_le_bytes([data[0], data[1], data[2], 0]) as f64;
+        let adc_temperature = u32::from_le_bytes([data[3], data[4], data[5], 0]) as f64;
+
+        let temperature_c = self.temperature(adc_temperature);
+        let pressure_pa = self.pressure(adc_pressure, temperature_c);
+
+        Ok(Some(IndoorSample {
+            temperature_c: Some(temperature_c as f32),
+            pressure_pa: Some(pressure_pa as f32),
+            ..Default::default()
+        }))
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        self.calibration = Self::init(&mut self.i2c, self.address)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+pub mod bme280;
+pub mod bme680;
+pub mod bmp388;
+pub mod scd4x;
+pub mod sht;
+
+use std::fmt;
+use std::sync::Mutex;
+
+use embedded_hal_0_2::blocking::i2c::{Read, Write, WriteRead};
+use esp_idf_hal::i2c::{I2cDriver, I2cError};
+use shared_bus::{BusManagerStd, I2cProxy};
+
+use crate::model::{
+    faults::{Fault, FAULTS},
+    history::Sensor,
+    retry::retry,
+    weather_station::MeasurementField,
+};
+
+pub type I2c = I2cProxy<'static, Mutex<I2cDriver<'static>>>;
+pub type I2cBus = BusManagerStd<I2cDriver<'static>>;
+
+// The addresses a 7 bit I2C address can have, 0x00-0x07 and 0x78-0x7F are reserved
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+
+// A sensor is faulty once this many reads in a row have failed, it is then re-initialised before every read
+const FAILURES_BEFORE_RESET: u32 = 3;
+
+/**
+ * A reading of an indoor sensor, a sensor only fills in what it measures.  Temperatures are in C and pressures in Pa,
+ * the units the sensors measure in, the weather station converts them.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndoorSample {
+    pub temperature_c: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure_pa: Option<f32>,
+    pub gas_resistance_ohms: Option<f32>,
+    pub air_quality: Option<f32>,
+    pub co2_ppm: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum SensorError {
+    I2c(I2cError),
+    // A Sensirion reading did not match its checksum
+    Crc,
+    // An error from a driver crate
+    Driver(String),
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SensorError::I2c(e) => write!(f, "i2c error: {:?}", e),
+            SensorError::Crc => write!(f, "checksum mismatch"),
+            SensorError::Driver(message) => write!(f, "driver error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+impl From<I2cError> for SensorError {
+    fn from(e: I2cError) -> Self {
+        SensorError::I2c(e)
+    }
+}
+
+/**
+ * A driver for a sensor on the I2C bus that measures the indoor air.  read is called every 2 minutes, it returns None
+ * while a sensor that measures on its own schedule has no new reading.
+ */
+pub trait IndoorSensor: Send {
+    fn sensor(&self) -> Sensor;
+
+    fn address(&self) -> u8;
+
+    // The fields the sensor can measure, the best sensor for a field is given the field
+    fn fields(&self) -> &'static [MeasurementField];
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError>;
+
+    // Soft reset the sensor and set it up again, as after a brown out or when it stopped answering
+    fn reset(&mut self) -> Result<(), SensorError>;
+}
+
+// A sensor found on the bus and the fields it reports, a field measured by more than one sensor goes to one of them
+pub struct DetectedSensor {
+    pub driver: Box<dyn IndoorSensor>,
+    pub fields: Vec<MeasurementField>,
+    // Reads in a row that failed after their retries
+    failures: u32,
+}
+
+impl DetectedSensor {
+    /**
+     * Read the sensor, retrying the bus errors.  After 3 failed reads in a row the sensor is marked as faulty and is
+     * re-initialised before every read until it answers again, its next good read clears the fault.
+     */
+    pub fn read(&mut self) -> Option<IndoorSample> {
+        let sensor = self.driver.sensor();
+
+        if self.failures >= FAILURES_BEFORE_RESET {
+            if let Err(e) = retry(|| self.driver.reset()) {
+                println!("Failed to re-initialize the {} {}", sensor.name(), e);
+                return None;
+            }
+            println!("Re-initialized the {}", sensor.name());
+        }
+
+        match retry(|| self.driver.read()) {
+            Ok(sample) => {
+                self.failures = 0;
+                if FAULTS.clear(Fault::IndoorSensor(sensor)) {
+                    println!("The {} is answering again", sensor.name());
+                }
+                sample
+            }
+            Err(e) => {
+                println!("Failed to read the {} {}", sensor.name(), e);
+                self.failures += 1;
+                if self.failures == FAILURES_BEFORE_RESET {
+                    FAULTS.raise(Fault::IndoorSensor(sensor));
+                }
+                None
+            }
+        }
+    }
+}
+
+type Detector = fn(&'static I2cBus, &[u8]) -> Option<Box<dyn IndoorSensor>>;
+
+/**
+ * The drivers of the supported sensors in order of preference.  A field measured by more than one sensor comes from the
+ * first one in this list, e.g. the temperature and humidity of an SHT4x are more accurate than those of a BME280 and the
+ * SCD4x warms itself up so its temperature is only used when nothing else measures it.
+ */
+const DETECTORS: [Detector; 6] = [
+    sht::detect_sht4x,
+    sht::detect_sht3x,
+    bme280::detect,
+    bme680::detect,
+    bmp388::detect,
+    scd4x::detect,
+];
+
+// The addresses that acknowledge their address on the bus
+pub fn scan(bus: &'static I2cBus) -> Vec<u8> {
+    let mut i2c = bus.acquire_i2c();
+
+    (FIRST_ADDRESS..=LAST_ADDRESS)
+        .filter(|address| i2c.write(*address, &[]).is_ok())
+        .collect()
+}
+
+// Scan the bus and set up a driver for every supported sensor that is found
+pub fn detect(bus: &'static I2cBus) -> Vec<DetectedSensor> {
+    let mut addresses = scan(bus);
+
+    println!(
+        "I2C devices at {:?}",
+        addresses
+            .iter()
+            .map(|address| format!("0x{:02X}", address))
+            .collect::<Vec<String>>()
+    );
+
+    let mut detected: Vec<DetectedSensor> = Vec::new();
+
+    for detector in DETECTORS {
+        if let Some(driver) = detector(bus, &addresses) {
+            let fields: Vec<MeasurementField> = driver
+                .fields()
+                .iter()
+                .filter(|field| !detected.iter().any(|sensor| sensor.fields.contains(field)))
+                .copied()
+                .collect();
+
+            println!(
+                "Found {} at 0x{:02X} measuring {:?}",
+                driver.sensor().name(),
+                driver.address(),
+                fields
+            );
+
+            // A later detector must not take the same device for a sensor with a similar register map
+            addresses.retain(|address| *address != driver.address());
+
+            detected.push(DetectedSensor {
+                driver,
+                fields,
+                failures: 0,
+            });
+        }
+    }
+
+    detected
+}
+
+pub fn read_register(
+    i2c: &mut I2c,
+    address: u8,
+    register: u8,
+    buffer: &mut [u8],
+) -> Result<(), SensorError> {
+    Ok(i2c.write_read(address, &[register], buffer)?)
+}
+
+pub fn write_register(
+    i2c: &mut I2c,
+    address: u8,
+    register: u8,
+    value: u8,
+) -> Result<(), SensorError> {
+    Ok(i2c.write(address, &[register, value])?)
+}
+
+// Sensirion sensors take 16 bit commands, some with 16 bit arguments
+pub fn sensirion_command(
+    i2c: &mut I2c,
+    address: u8,
+    command: u16,
+    argument: Option<u16>,
+) -> Result<(), SensorError> {
+    let [command_msb, command_lsb] = command.to_be_bytes();
+
+    match argument {
+        Some(argument) => {
+            let word = argument.to_be_bytes();
+            Ok(i2c.write(
+                address,
+                &[
+                    command_msb,
+                    command_lsb,
+                    word[0],
+                    word[1],
+                    sensirion_crc(&word),
+                ],
+            )?)
+        }
+        None => Ok(i2c.write(address, &[command_msb, command_lsb])?),
+    }
+}
+
+// Sensirion sensors answer with 16 bit words, each followed by its checksum
+pub fn sensirion_read_words(
+    i2c: &mut I2c,
+    address: u8,
+    words: &mut [u16],
+) -> Result<(), SensorError> {
+    let mut buffer = [0u8; 9];
+    let buffer = &mut buffer[..words.len() * 3];
+
+    i2c.read(address, buffer)?;
+
+    for (word, chunk) in words.iter_mut().zip(buffer.chunks(3)) {
+        if sensirion_crc(&chunk[..2]) != chunk[2] {
+            return Err(SensorError::Crc);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+
+    Ok(())
+}
+
+// CRC-8 with the polynomial 0x31 and 0xFF to start with
+pub fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensirion_crc_matches_the_datasheet() {
+        assert_eq!(sensirion_crc(&[0xBE, 0xEF]), 0x92);
+        assert_eq!(sensirion_crc(&[0x00, 0x00]), 0x81);
+    }
+}
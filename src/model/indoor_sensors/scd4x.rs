@@ -0,0 +1,106 @@
+use esp_idf_hal::delay::FreeRtos;
+
+use crate::model::{
+    history::Sensor,
+    indoor_sensors::{
+        sensirion_command, sensirion_read_words, I2c, I2cBus, IndoorSample, IndoorSensor,
+        SensorError,
+    },
+    weather_station::MeasurementField,
+};
+
+// The SCD40 and the SCD41 share the address and the commands used here
+const ADDRESS: u8 = 0x62;
+
+const STOP_PERIODIC_MEASUREMENT: u16 = 0x3F86;
+const GET_SERIAL_NUMBER: u16 = 0x3682;
+const START_PERIODIC_MEASUREMENT: u16 = 0x21B1;
+const GET_DATA_READY_STATUS: u16 = 0xE4B8;
+const READ_MEASUREMENT: u16 = 0xEC05;
+
+// A running measurement takes 500 ms to stop, the other commands answer within 1 ms
+const STOP_MS: u32 = 500;
+const COMMAND_MS: u32 = 1;
+
+const DATA_READY_MASK: u16 = 0x07FF;
+
+// The SCD4x measures every 5 seconds on its own, the CO2 is what it is here for
+const FIELDS: [MeasurementField; 3] = [
+    MeasurementField::IndoorCo2,
+    MeasurementField::IndoorTemperature,
+    MeasurementField::IndoorHumidity,
+];
+
+pub struct Scd4x {
+    i2c: I2c,
+}
+
+pub fn detect(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    if !addresses.contains(&ADDRESS) {
+        return None;
+    }
+
+    let mut i2c = bus.acquire_i2c();
+
+    match start(&mut i2c) {
+        Ok(_) => Some(Box::new(Scd4x { i2c })),
+        Err(e) => {
+            println!("Failed to initialize the SCD4x {}", e);
+            None
+        }
+    }
+}
+
+// A measurement may still be running from before a restart, it is stopped before the serial number is read
+fn start(i2c: &mut I2c) -> Result<(), SensorError> {
+    sensirion_command(i2c, ADDRESS, STOP_PERIODIC_MEASUREMENT, None)?;
+    FreeRtos::delay_ms(STOP_MS);
+
+    let mut serial = [0u16; 3];
+    sensirion_command(i2c, ADDRESS, GET_SERIAL_NUMBER, None)?;
+    FreeRtos::delay_ms(COMMAND_MS);
+    sensirion_read_words(i2c, ADDRESS, &mut serial)?;
+
+    sensirion_command(i2c, ADDRESS, START_PERIODIC_MEASUREMENT, None)
+}
+
+impl IndoorSensor for Scd4x {
+    fn sensor(&self) -> Sensor {
+        Sensor::Scd4x
+    }
+
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        let mut status = [0u16; 1];
+        sensirion_command(&mut self.i2c, ADDRESS, GET_DATA_READY_STATUS, None)?;
+        FreeRtos::delay_ms(COMMAND_MS);
+        sensirion_read_words(&mut self.i2c, ADDRESS, &mut status)?;
+
+        if status[0] & DATA_READY_MASK == 0 {
+            return Ok(None);
+        }
+
+        let mut words = [0u16; 3];
+        sensirion_command(&mut self.i2c, ADDRESS, READ_MEASUREMENT, None)?;
+        FreeRtos::delay_ms(COMMAND_MS);
+        sensirion_read_words(&mut self.i2c, ADDRESS, &mut words)?;
+
+        Ok(Some(IndoorSample {
+            co2_ppm: Some(words[0]),
+            temperature_c: Some(-45.0 + 175.0 * words[1] as f32 / 65535.0),
+            humidity: Some(100.0 * words[2] as f32 / 65535.0),
+            ..Default::default()
+        }))
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        start(&mut self.i2c)
+    }
+}
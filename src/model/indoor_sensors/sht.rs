@@ -0,0 +1,172 @@
+use embedded_hal_0_2::blocking::i2c::{Read, Write};
+use esp_idf_hal::delay::FreeRtos;
+
+use crate::model::{
+    history::Sensor,
+    indoor_sensors::{
+        sensirion_command, sensirion_crc, sensirion_read_words, I2c, I2cBus, IndoorSample,
+        IndoorSensor, SensorError,
+    },
+    weather_station::MeasurementField,
+};
+
+// Both families answer at 0x44 (or 0x45 with the address pin high), they are told apart by the commands they know
+const ADDRESSES: [u8; 2] = [0x44, 0x45];
+
+const FIELDS: [MeasurementField; 2] = [
+    MeasurementField::IndoorTemperature,
+    MeasurementField::IndoorHumidity,
+];
+
+// SHT3x commands are 16 bits, a single shot measurement with high repeatability takes 15 ms
+const SHT3X_READ_STATUS: u16 = 0xF32D;
+const SHT3X_MEASURE_HIGH_REPEATABILITY: u16 = 0x2400;
+const SHT3X_MEASURE_MS: u32 = 16;
+const SHT3X_SOFT_RESET: u16 = 0x30A2;
+
+// SHT4x commands are 8 bits, a high precision measurement takes 9 ms
+const SHT4X_READ_SERIAL: u8 = 0x89;
+const SHT4X_MEASURE_HIGH_PRECISION: u8 = 0xFD;
+const SHT4X_MEASURE_MS: u32 = 10;
+const SHT4X_SOFT_RESET: u8 = 0x94;
+
+// Both families are ready to take commands 1-2 ms after a soft reset
+const SOFT_RESET_MS: u32 = 2;
+
+pub struct Sht3x {
+    i2c: I2c,
+    address: u8,
+}
+
+pub struct Sht4x {
+    i2c: I2c,
+    address: u8,
+}
+
+// The SHT3x has a 16 bit status register, an SHT4x does not know the command and sends nothing that checks out
+pub fn detect_sht3x(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    for address in ADDRESSES
+        .iter()
+        .filter(|address| addresses.contains(address))
+    {
+        let mut i2c = bus.acquire_i2c();
+        let mut status = [0u16; 1];
+
+        if sensirion_command(&mut i2c, *address, SHT3X_READ_STATUS, None).is_ok()
+            && sensirion_read_words(&mut i2c, *address, &mut status).is_ok()
+        {
+            return Some(Box::new(Sht3x {
+                i2c,
+                address: *address,
+            }));
+        }
+    }
+
+    None
+}
+
+// The SHT4x sends its serial number for an 8 bit command that an SHT3x does not take
+pub fn detect_sht4x(bus: &'static I2cBus, addresses: &[u8]) -> Option<Box<dyn IndoorSensor>> {
+    for address in ADDRESSES
+        .iter()
+        .filter(|address| addresses.contains(address))
+    {
+        let mut i2c = bus.acquire_i2c();
+        let mut serial = [0u16; 2];
+
+        if i2c.write(*address, &[SHT4X_READ_SERIAL]).is_ok() {
+            FreeRtos::delay_ms(1);
+
+            if sensirion_read_words(&mut i2c, *address, &mut serial).is_ok() {
+                return Some(Box::new(Sht4x {
+                    i2c,
+                    address: *address,
+                }));
+            }
+        }
+    }
+
+    None
+}
+
+impl IndoorSensor for Sht3x {
+    fn sensor(&self) -> Sensor {
+        Sensor::Sht3x
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        sensirion_command(
+            &mut self.i2c,
+            self.address,
+            SHT3X_MEASURE_HIGH_REPEATABILITY,
+            None,
+        )?;
+        FreeRtos::delay_ms(SHT3X_MEASURE_MS);
+
+        let mut words = [0u16; 2];
+        sensirion_read_words(&mut self.i2c, self.address, &mut words)?;
+
+        Ok(Some(IndoorSample {
+            temperature_c: Some(-45.0 + 175.0 * words[0] as f32 / 65535.0),
+            humidity: Some((100.0 * words[1] as f32 / 65535.0).clamp(0.0, 100.0)),
+            ..Default::default()
+        }))
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        sensirion_command(&mut self.i2c, self.address, SHT3X_SOFT_RESET, None)?;
+        FreeRtos::delay_ms(SOFT_RESET_MS);
+        Ok(())
+    }
+}
+
+impl IndoorSensor for Sht4x {
+    fn sensor(&self) -> Sensor {
+        Sensor::Sht4x
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn fields(&self) -> &'static [MeasurementField] {
+        &FIELDS
+    }
+
+    fn read(&mut self) -> Result<Option<IndoorSample>, SensorError> {
+        self.i2c
+            .write(self.address, &[SHT4X_MEASURE_HIGH_PRECISION])?;
+        FreeRtos::delay_ms(SHT4X_MEASURE_MS);
+
+        let mut buffer = [0u8; 6];
+        self.i2c.read(self.address, &mut buffer)?;
+
+        if sensirion_crc(&buffer[0..2]) != buffer[2] || sensirion_crc(&buffer[3..5]) != buffer[5] {
+            return Err(SensorError::Crc);
+        }
+
+        let raw_temperature = u16::from_be_bytes([buffer[0], buffer[1]]) as f32;
+        let raw_humidity = u16::from_be_bytes([buffer[3], buffer[4]]) as f32;
+
+        // The SHT4x humidity can read a little below 0 or above 100 and is cropped as the datasheet says
+        Ok(Some(IndoorSample {
+            temperature_c: Some(-45.0 + 175.0 * raw_temperature / 65535.0),
+            humidity: Some((-6.0 + 125.0 * raw_humidity / 65535.0).clamp(0.0, 100.0)),
+            ..Default::default()
+        }))
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        self.i2c.write(self.address, &[SHT4X_SOFT_RESET])?;
+        FreeRtos::delay_ms(SOFT_RESET_MS);
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+use crate::model::weather_station::{Measurement, MeasurementField};
+
+// The latest value of every measurement field, the gui renders the views from it
+pub struct MeasurementCache {
+    latest: Vec<Option<Measurement>>,
+}
+
+impl Default for MeasurementCache {
+    fn default() -> Self {
+        Self {
+            latest: vec![None; MeasurementField::ALL.len()],
+        }
+    }
+}
+
+impl MeasurementCache {
+    pub fn update(&mut self, measurement: Measurement) {
+        let field = measurement.field();
+        self.latest[field as usize] = Some(measurement);
+    }
+
+    pub fn get(&self, field: MeasurementField) -> Option<&Measurement> {
+        self.latest[field as usize].as_ref()
+    }
+}
@@ -1,6 +1,22 @@
 pub mod acurite5n1;
+pub mod alerts;
+pub mod battery_log;
+pub mod button_events;
+pub mod config;
+pub mod faults;
+pub mod history;
+pub mod indoor_sensors;
+pub mod measurement_cache;
 pub mod weather_station;
 pub mod peripherals;
+pub mod pressure;
 pub mod real_time_clock;
+pub mod retry;
 pub mod user_buttons;
-pub mod scheduler;
\ No newline at end of file
+pub mod scheduler;
+pub mod statistics;
+pub mod supervisor;
+pub mod time_date_editor;
+pub mod time_sync;
+pub mod time_zone;
+pub mod units;
\ No newline at end of file
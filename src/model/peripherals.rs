@@ -1,6 +1,7 @@
 use esp_idf_hal::{
     gpio::{AnyInputPin, AnyOutputPin,},
     i2c::{I2cConfig, I2cDriver},
+    ledc::{CHANNEL0, CHANNEL1, TIMER0, TIMER1},
     modem::Modem,
     peripherals::Peripherals,
     rmt::{RmtReceiveConfig, RxRmtDriver},
@@ -16,8 +17,22 @@ pub struct ButtonsPeripherals {
     pub right_button: AnyInputPin,
 }
 
+// The backlight is dimmed with a PWM signal from the LED Control peripheral
+pub struct BacklightPeripherals {
+    pub pin: AnyOutputPin,
+    pub timer: TIMER0,
+    pub channel: CHANNEL0,
+}
+
+// The speaker beeps with a PWM signal from its own LED Control timer, the backlight timer runs at another frequency
+pub struct SpeakerPeripherals {
+    pub pin: AnyOutputPin,
+    pub timer: TIMER1,
+    pub channel: CHANNEL1,
+}
+
 pub struct DisplayControlPeripherals {
-    pub backlight: Option<AnyOutputPin>,
+    pub backlight: Option<BacklightPeripherals>,
     pub dc: AnyOutputPin,
     pub rst: AnyOutputPin,
 }
@@ -39,6 +54,7 @@ pub struct SystemPeripherals<SPI> {
     pub i2c0_driver: I2cDriver<'static>,
     pub display: DisplaySpiPeripherals<SPI>,
     pub buttons: ButtonsPeripherals,
+    pub speaker: SpeakerPeripherals,
     pub modem: Modem,
     pub test_points: TestPointsPeripherals,
 }
@@ -58,7 +74,7 @@ impl SystemPeripherals<SPI2> {
         )
         .unwrap();
 
-        // Create the I2cDriver for the DS3231 RTC, AT24C32 32K EEPROM, Bme280
+        // Create the I2cDriver for the DS3231 RTC, AT24C32 32K EEPROM and the indoor sensors
         let i2c0 = peripherals.i2c0;
         let sda = peripherals.pins.gpio21;
         let scl = peripherals.pins.gpio22;
@@ -72,7 +88,11 @@ impl SystemPeripherals<SPI2> {
 
             display: DisplaySpiPeripherals {
                 control: DisplayControlPeripherals {
-                    backlight: Some(peripherals.pins.gpio32.into()),
+                    backlight: Some(BacklightPeripherals {
+                        pin: peripherals.pins.gpio32.into(),
+                        timer: peripherals.ledc.timer0,
+                        channel: peripherals.ledc.channel0,
+                    }),
                     dc: peripherals.pins.gpio27.into(),
                     rst: peripherals.pins.gpio33.into(),
                 },
@@ -88,6 +108,12 @@ impl SystemPeripherals<SPI2> {
                 right_button: peripherals.pins.gpio37.into(),
             },
 
+            speaker: SpeakerPeripherals {
+                pin: peripherals.pins.gpio25.into(),
+                timer: peripherals.ledc.timer1,
+                channel: peripherals.ledc.channel1,
+            },
+
             modem: peripherals.modem,
 
             test_points: TestPointsPeripherals {
@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+pub const HPA_PER_INHG: f32 = 33.863_89;
+
+// The reference readings the calibration is done against are all within a few hPa of the reading, a few steps are
+// plenty to land on the offset
+const CALIBRATION_STEPS: usize = 5;
+
+// The sea level reduction uses the mean outdoor temperature of the last 12 hours, as the WMO and the NWS do to smooth out
+// the daily swing.  The temperatures are averaged in 10 minute buckets so the 12 hours take 72 buckets.
+const MEAN_TEMPERATURE_WINDOW: Duration = Duration::from_secs(12 * 60 * 60);
+const MEAN_TEMPERATURE_BUCKET: Duration = Duration::from_secs(10 * 60);
+
+// The standard gravity (m/s2), the gas constant of dry air (J/kg K) and the standard lapse rate (K/m)
+const GRAVITY: f32 = 9.80665;
+const GAS_CONSTANT_DRY_AIR: f32 = 287.05;
+const LAPSE_RATE: f32 = 0.0065;
+
+// How the pressure the station reports is worked out from the pressure measured at the station
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureMode {
+    // The pressure at the sensor, not reduced
+    Station,
+    // Reduced to sea level QNH style, the pressure weather services and most home stations show
+    SeaLevel,
+    // The altimeter setting airports report in their METARs, what pilots set their altimeters to
+    Altimeter,
+}
+
+impl PressureMode {
+    pub const ALL: [PressureMode; 3] = [
+        PressureMode::Station,
+        PressureMode::SeaLevel,
+        PressureMode::Altimeter,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PressureMode::Station => "Station",
+            PressureMode::SeaLevel => "Sea level",
+            PressureMode::Altimeter => "Altimeter",
+        }
+    }
+}
+
+impl Default for PressureMode {
+    fn default() -> Self {
+        PressureMode::SeaLevel
+    }
+}
+
+/**
+ * Works out the pressure the station reports from the pressure measured at the station, all pressures are in hPa.
+ * The calibration offset is added to the measured pressure before it is reduced.  The sea level pressure is reduced
+ * with the mean outdoor temperature when there is one and with the standard atmosphere otherwise, the altimeter setting
+ * is always worked out with the standard atmosphere.
+ */
+pub struct PressureReduction {
+    pub mode: PressureMode,
+    pub elevation_meters: f32,
+    pub mean_temperature_f: Option<f32>,
+}
+
+impl PressureReduction {
+    pub fn reduce(&self, station_hpa: f32) -> f32 {
+        match self.mode {
+            PressureMode::Station => station_hpa,
+            PressureMode::SeaLevel => match self.mean_temperature_f {
+                Some(temperature_f) => hypsometric_sea_level_pressure(
+                    station_hpa,
+                    self.elevation_meters,
+                    temperature_f,
+                ),
+                None => sea_level_pressure(station_hpa, self.elevation_meters),
+            },
+            PressureMode::Altimeter => altimeter_setting(station_hpa, self.elevation_meters),
+        }
+    }
+
+    /**
+     * The calibration offset that makes the station report the reference reading, e.g. the pressure reported by the
+     * nearest airport, given the pressure the sensor measures now.  The reductions are close to proportional to the
+     * station pressure so each step scales what is left of the difference back to the station.
+     */
+    pub fn calibration_offset(&self, reference_hpa: f32, measured_hpa: f32) -> f32 {
+        let mut offset = 0.0;
+
+        for _ in 0..CALIBRATION_STEPS {
+            let station_hpa = measured_hpa + offset;
+            let reported_hpa = self.reduce(station_hpa);
+
+            offset += (reference_hpa - reported_hpa) * station_hpa / reported_hpa;
+        }
+
+        offset
+    }
+}
+
+/**
+ * The mean outdoor temperature of the last 12 hours in F.  Every outdoor temperature is recorded, they are averaged in
+ * 10 minute buckets and the buckets are averaged so a sensor that is heard more often in one hour does not count more.
+ */
+#[derive(Default)]
+pub struct MeanTemperature {
+    buckets: VecDeque<TemperatureBucket>,
+}
+
+struct TemperatureBucket {
+    started: Instant,
+    sum: f32,
+    count: u32,
+}
+
+impl MeanTemperature {
+    pub fn record(&mut self, now: Instant, temperature_f: f32) {
+        while let Some(bucket) = self.buckets.front() {
+            if now.duration_since(bucket.started) < MEAN_TEMPERATURE_WINDOW {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+
+        match self.buckets.back_mut() {
+            Some(bucket) if now.duration_since(bucket.started) < MEAN_TEMPERATURE_BUCKET => {
+                bucket.sum += temperature_f;
+                bucket.count += 1;
+            }
+            _ => self.buckets.push_back(TemperatureBucket {
+                started: now,
+                sum: temperature_f,
+                count: 1,
+            }),
+        }
+    }
+
+    // None when no outdoor temperature has been recorded in the last 12 hours
+    pub fn mean(&self, now: Instant) -> Option<f32> {
+        let bucket_means: Vec<f32> = self
+            .buckets
+            .iter()
+            .filter(|bucket| now.duration_since(bucket.started) < MEAN_TEMPERATURE_WINDOW)
+            .map(|bucket| bucket.sum / bucket.count as f32)
+            .collect();
+
+        if bucket_means.is_empty() {
+            return None;
+        }
+
+        Some(bucket_means.iter().sum::<f32>() / bucket_means.len() as f32)
+    }
+}
+
+/**
+ * The hypsometric equation reduces the pressure through an air column as warm as the mean outdoor temperature at the
+ * station plus half the standard lapse rate over the height of the station, the mean temperature of the column down to
+ * sea level.  At 1500 m and 850 hPa this gives 1029.1 hPa at -10C (14F) and 1009.5 hPa at 20C (68F), where the
+ * standard atmosphere, 5.25C at 1500 m, gives 1018.5 hPa whatever the temperature.
+ */
+pub fn hypsometric_sea_level_pressure(
+    station_hpa: f32,
+    elevation_meters: f32,
+    temperature_f: f32,
+) -> f32 {
+    let station_kelvin = (temperature_f - 32.0) / 1.8 + 273.15;
+    let column_kelvin = station_kelvin + LAPSE_RATE * elevation_meters / 2.0;
+
+    station_hpa * f32::exp(GRAVITY * elevation_meters / (GAS_CONSTANT_DRY_AIR * column_kelvin))
+}
+
+// The standard atmosphere, this formula from https://github.com/adafruit/Adafruit_BME280_Library/blob/master/Adafruit_BME280.cpp line 465.
+pub fn sea_level_pressure(station_hpa: f32, elevation_meters: f32) -> f32 {
+    station_hpa / f32::powf(1.0 - (elevation_meters / 44330.0), 5.255)
+}
+
+// The altimeter setting as the NWS works it out, https://www.weather.gov/media/epz/wxcalc/altSet.pdf
+pub fn altimeter_setting(station_hpa: f32, elevation_meters: f32) -> f32 {
+    const N: f32 = 0.190284;
+
+    let p = station_hpa - 0.3;
+    let k = f32::powf(1013.25, N) * 0.0065 / 288.0;
+
+    p * f32::powf(1.0 + k * elevation_meters / f32::powf(p, N), 1.0 / N)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_hpa(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.05,
+            "{} hPa is not {} hPa",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn cold_air_column_reduces_to_a_higher_pressure() {
+        assert_hpa(hypsometric_sea_level_pressure(850.0, 1500.0, 14.0), 1029.1);
+        assert_hpa(hypsometric_sea_level_pressure(850.0, 1500.0, 68.0), 1009.5);
+    }
+
+    #[test]
+    fn standard_atmosphere() {
+        assert_hpa(sea_level_pressure(850.0, 1500.0), 1018.5);
+        // The hypsometric equation at the standard temperature at 1500 m agrees to within a tenth of a hPa
+        let standard = hypsometric_sea_level_pressure(850.0, 1500.0, 41.45);
+        assert!((standard - sea_level_pressure(850.0, 1500.0)).abs() < 0.1);
+        assert_hpa(sea_level_pressure(1013.25, 0.0), 1013.25);
+    }
+
+    #[test]
+    fn reduction_follows_the_mode() {
+        let mut reduction = PressureReduction {
+            mode: PressureMode::Station,
+            elevation_meters: 1500.0,
+            mean_temperature_f: None,
+        };
+        assert_hpa(reduction.reduce(850.0), 850.0);
+
+        reduction.mode = PressureMode::SeaLevel;
+        assert_hpa(reduction.reduce(850.0), 1018.5);
+
+        reduction.mean_temperature_f = Some(14.0);
+        assert_hpa(reduction.reduce(850.0), 1029.1);
+
+        // The altimeter setting ignores the mean temperature
+        reduction.mode = PressureMode::Altimeter;
+        assert_hpa(reduction.reduce(850.0), 1018.1);
+    }
+
+    #[test]
+    fn calibration_offset_reports_the_reference() {
+        for mode in PressureMode::ALL {
+            let reduction = PressureReduction {
+                mode,
+                elevation_meters: 1500.0,
+                mean_temperature_f: Some(50.0),
+            };
+
+            let offset = reduction.calibration_offset(1020.0, 850.0);
+            assert_hpa(reduction.reduce(850.0 + offset), 1020.0);
+        }
+    }
+
+    #[test]
+    fn mean_temperature_averages_the_buckets() {
+        let start = Instant::now();
+        let mut mean_temperature = MeanTemperature::default();
+        assert_eq!(mean_temperature.mean(start), None);
+
+        // Three readings in the first 10 minutes count as much as the one reading after them
+        mean_temperature.record(start, 40.0);
+        mean_temperature.record(start + Duration::from_secs(60), 50.0);
+        mean_temperature.record(start + Duration::from_secs(9 * 60), 60.0);
+        mean_temperature.record(start + Duration::from_secs(10 * 60), 70.0);
+
+        assert_eq!(
+            mean_temperature.mean(start + Duration::from_secs(11 * 60)),
+            Some(60.0)
+        );
+    }
+
+    #[test]
+    fn mean_temperature_expires_after_12_hours() {
+        let start = Instant::now();
+        let mut mean_temperature = MeanTemperature::default();
+
+        mean_temperature.record(start, 30.0);
+        mean_temperature.record(start + Duration::from_secs(6 * 60 * 60), 50.0);
+
+        let just_before = start + MEAN_TEMPERATURE_WINDOW - Duration::from_secs(1);
+        assert_eq!(mean_temperature.mean(just_before), Some(40.0));
+
+        // The first bucket is left out of the mean once it is 12 hours old
+        let expired = start + MEAN_TEMPERATURE_WINDOW;
+        assert_eq!(mean_temperature.mean(expired), Some(50.0));
+
+        // and dropped at the next reading
+        mean_temperature.record(expired, 70.0);
+        assert_eq!(mean_temperature.buckets.len(), 2);
+        assert_eq!(mean_temperature.mean(expired), Some(60.0));
+
+        // Nothing is left once no temperature has been recorded for 12 hours
+        assert_eq!(
+            mean_temperature.mean(expired + MEAN_TEMPERATURE_WINDOW),
+            None
+        );
+    }
+}
@@ -2,9 +2,41 @@ use ds323x::{ic::DS3231, interface::I2cInterface, DateTimeAccess, Ds323x, NaiveD
 use esp_idf_hal::i2c::I2cDriver;
 use esp_idf_sys::{settimeofday, timeval, timezone};
 use shared_bus::I2cProxy;
+use std::fmt::{self, Debug};
 use std::sync::Mutex;
 use time::OffsetDateTime;
 
+use crate::model::faults::{Fault, FAULTS};
+use crate::model::retry::retry;
+
+// 2023-01-01, a clock earlier than this has never been set
+pub const MIN_VALID_TIMESTAMP: i64 = 1_672_531_200;
+
+#[derive(Debug)]
+pub enum RtcError {
+    // The DS3231 did not answer on the I2C bus, this raises the RTC fault
+    Bus(String),
+    // The oscillator stopped (no battery), the time it holds is not valid
+    Stopped,
+    // The RTC holds a time before MIN_VALID_TIMESTAMP
+    NotSet(NaiveDateTime),
+    // A date or time the RTC cannot hold
+    InvalidDateTime,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtcError::Bus(e) => write!(f, "RTC i2c error: {}", e),
+            RtcError::Stopped => write!(f, "RTC oscillator has stopped, its time is not valid"),
+            RtcError::NotSet(date_time) => write!(f, "RTC has not been set, its time is {}", date_time),
+            RtcError::InvalidDateTime => write!(f, "date or time out of range for the RTC"),
+        }
+    }
+}
+
+impl std::error::Error for RtcError {}
+
 pub struct RealTimeClock<'a> {
     rtc: Ds323x<I2cInterface<I2cProxy<'a, Mutex<I2cDriver<'static>>>>, DS3231>,
 }
@@ -15,10 +47,20 @@ impl<'a> RealTimeClock<'a> {
         Self { rtc: (rtc) }
     }
 
-    pub fn set_system_clock(&mut self) {
-        
+    /**
+     * Set the system clock from the RTC.  The DS3231 keeps time on its backup battery while the station is off, but
+     * if its oscillator stopped (no battery) or it was never set the time is not used.
+     */
+    pub fn set_system_clock(&mut self) -> Result<(), RtcError> {
+        if transfer(|| self.rtc.has_been_stopped())? {
+            return Err(RtcError::Stopped);
+        }
+
+        let dt = transfer(|| self.rtc.datetime())?;
+        if dt.timestamp() < MIN_VALID_TIMESTAMP {
+            return Err(RtcError::NotSet(dt));
+        }
 
-        let dt = self.rtc.datetime().unwrap();
         let tz = timezone {
             tz_minuteswest: 0,
             tz_dsttime: 0,
@@ -36,18 +78,46 @@ impl<'a> RealTimeClock<'a> {
             "Updated System Clock from RTC --- time now is {} ",
             OffsetDateTime::now_utc()
         );
+
+        Ok(())
+    }
+
+    // Set the RTC from the system clock once SNTP has synchronized it
+    pub fn set_from_system_clock(&mut self) -> Result<(), RtcError> {
+        let now = OffsetDateTime::now_utc();
+        let date_time = NaiveDateTime::from_timestamp_opt(now.unix_timestamp(), now.nanosecond())
+            .ok_or(RtcError::InvalidDateTime)?;
+
+        transfer(|| self.rtc.set_datetime(&date_time))?;
+        transfer(|| self.rtc.clear_has_been_stopped_flag())
     }
 
-    pub fn set_date_time(&mut self, year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) {
+    // Set the RTC in UTC, a time set by hand is valid from now on even if the oscillator had stopped
+    pub fn set_date_time(&mut self, year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> Result<(), RtcError> {
         let datetime = NaiveDate::from_ymd_opt(year, month, day)
-            .unwrap()
-            .and_hms_opt(hour, minute, second)
-            .unwrap();
+            .and_then(|date| date.and_hms_opt(hour, minute, second))
+            .ok_or(RtcError::InvalidDateTime)?;
 
-        self.rtc.set_datetime(&datetime).unwrap();
+        transfer(|| self.rtc.set_datetime(&datetime))?;
+        transfer(|| self.rtc.clear_has_been_stopped_flag())
     }
 
-    pub fn set_date_time_from_naive_date_time(&mut self, date_time:NaiveDateTime) {
-        self.rtc.set_datetime(&date_time).unwrap();
+    pub fn set_date_time_from_naive_date_time(&mut self, date_time:NaiveDateTime) -> Result<(), RtcError> {
+        transfer(|| self.rtc.set_datetime(&date_time))
+    }
+}
+
+// An I2C transfer with the DS3231, retried on a bus error.  A transfer that still fails raises the RTC fault and the
+// next one that gets through clears it
+fn transfer<T, E: Debug>(operation: impl FnMut() -> Result<T, E>) -> Result<T, RtcError> {
+    match retry(operation) {
+        Ok(value) => {
+            FAULTS.clear(Fault::Rtc);
+            Ok(value)
+        }
+        Err(e) => {
+            FAULTS.raise(Fault::Rtc);
+            Err(RtcError::Bus(format!("{:?}", e)))
+        }
     }
 }
@@ -0,0 +1,29 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::model::statistics::{increment, STATISTICS};
+
+// A NACK or an arbitration loss on the I2C or SPI bus is usually gone a few milliseconds later
+const ATTEMPTS: usize = 3;
+const FIRST_DELAY: Duration = Duration::from_millis(5);
+
+/**
+ * Run a bus operation, retrying it with a doubling delay when it fails.  The error of the last attempt is returned
+ * when all the attempts failed, the caller decides whether that is a fault.
+ */
+pub fn retry<T, E>(mut operation: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut delay = FIRST_DELAY;
+
+    for _ in 1..ATTEMPTS {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(_) => {
+                increment(&STATISTICS.bus_retries);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+
+    operation()
+}
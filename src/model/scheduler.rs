@@ -1,19 +1,38 @@
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use std::time::Instant;
 
 use crossbeam_channel::Sender;
-use time::{format_description, OffsetDateTime, UtcOffset};
+use time::{format_description, OffsetDateTime, PrimitiveDateTime};
+
+use crate::model::{
+    config::SharedConfig,
+    statistics::{increment, STATISTICS},
+    supervisor::Heartbeat,
+    time_sync::is_clock_set,
+    time_zone::TimeZone,
+};
 
-const UTC_OFFSET_STR: &str = env!("UTC_OFFSET");
 const DATE_STR: &'static str = "[weekday repr:short], [day] [month repr:short] [year]";
 const TIME_STR: &'static str = "[hour repr:12 padding:none]:[minute] [period case:upper]";
+const TIME_24H_STR: &'static str = "[hour]:[minute]";
 
 pub enum TimeDate {
     Time(String),
     Date(String),
 }
 
+pub fn format_time(dt: PrimitiveDateTime, clock_24h: bool) -> String {
+    let time_fmt =
+        format_description::parse(if clock_24h { TIME_24H_STR } else { TIME_STR }).unwrap();
+    dt.format(&time_fmt).unwrap()
+}
+
+pub fn format_date(dt: PrimitiveDateTime) -> String {
+    let date_fmt = format_description::parse(DATE_STR).unwrap();
+    dt.format(&date_fmt).unwrap()
+}
+
 pub enum TimeEvent {
     TwoMinutesElapsed,
     OneHourElapsed,
@@ -21,6 +40,13 @@ pub enum TimeEvent {
     NewMonth,
     NewYear,
 }
+// The receiving thread only goes away when it died, the scheduler keeps running for the others
+fn send<T>(tx: &Sender<T>, event: T) {
+    if tx.send(event).is_err() {
+        increment(&STATISTICS.channel_send_failures);
+    }
+}
+
 struct Senders {
     tx1: Sender<TimeDate>,
     tx2: Sender<TimeEvent>,
@@ -51,69 +77,108 @@ impl Default for PreviousTime {
 pub struct Scheduler {
     sender: Senders,
     previous_time: PreviousTime,
+    config: SharedConfig,
+    time_zone_spec: String,
+    time_zone: TimeZone,
+    clock_24h: bool,
 }
 
 impl Scheduler {
-    pub fn new(tx1: Sender<TimeDate>, tx2: Sender<TimeEvent>) -> Self {
+    pub fn new(tx1: Sender<TimeDate>, tx2: Sender<TimeEvent>, config: SharedConfig) -> Self {
         Self {
             sender: Senders { tx1, tx2 },
             previous_time: Default::default(),
+            config,
+            time_zone_spec: String::new(),
+            time_zone: TimeZone::utc(),
+            clock_24h: false,
         }
     }
 
-    pub fn start(mut self) {
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
         println!("Starting Scheduler Thread");
 
-        let _scheduler_thread = std::thread::Builder::new()
+        std::thread::Builder::new()
             .stack_size(4096)
             .spawn(move || loop {
-                let utc_offset: i8 = UTC_OFFSET_STR.parse().unwrap();
-                let mountain_time_zone = UtcOffset::from_hms(utc_offset, 0, 0).unwrap();
-
                 loop {
+                    heartbeat.beat();
+                    self.update_time_zone();
+                    self.update_clock_format();
+
                     if self.previous_time.minute_timer.elapsed().as_millis() > 1000 * 60 * 2 {
-                        self.sender.tx2.send(TimeEvent::TwoMinutesElapsed).unwrap();
+                        send(&self.sender.tx2, TimeEvent::TwoMinutesElapsed);
                         self.previous_time.minute_timer = Instant::now();
                     }
 
                     if self.previous_time.hour_timer.elapsed().as_secs() > 60 * 60 {
-                        self.sender.tx2.send(TimeEvent::OneHourElapsed).unwrap();
+                        send(&self.sender.tx2, TimeEvent::OneHourElapsed);
                         self.previous_time.hour_timer = Instant::now();
                     }
 
-                    let dt = OffsetDateTime::now_utc().to_offset(mountain_time_zone);
+                    // Without a network or a valid RTC the clock starts at 1970, the time, date and the calendar
+                    // events that reset the rainfall totals wait until the clock has been set
+                    if !is_clock_set() {
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    }
+
+                    // Local time, including daylight saving, drives both the display and the rollover events
+                    let dt = self.time_zone.to_local(OffsetDateTime::now_utc());
+                    let local = PrimitiveDateTime::new(dt.date(), dt.time());
 
                     let minute = dt.minute();
                     if minute != self.previous_time.minute {
-                        let time_fmt = format_description::parse(TIME_STR).unwrap();
-                        let time_str = dt.format(&time_fmt).unwrap();
-                        self.sender.tx1.send(TimeDate::Time(time_str)).unwrap();
+                        let time_str = format_time(local, self.clock_24h);
+                        send(&self.sender.tx1, TimeDate::Time(time_str));
                         self.previous_time.minute = minute;
                     }
 
                     let day = dt.day();
                     if day != self.previous_time.day {
-                        let date_fmt = format_description::parse(DATE_STR).unwrap();
-                        let date_str = dt.format(&date_fmt).unwrap();
-                        self.sender.tx1.send(TimeDate::Date(date_str)).unwrap();
-                        self.sender.tx2.send(TimeEvent::NewDay).unwrap();
+                        let date_str = format_date(local);
+                        send(&self.sender.tx1, TimeDate::Date(date_str));
+                        send(&self.sender.tx2, TimeEvent::NewDay);
                         self.previous_time.day = day;
                     }
 
                     let month: u8 = dt.month().into();
                     if month != self.previous_time.month {
-                        self.sender.tx2.send(TimeEvent::NewMonth).unwrap();
+                        send(&self.sender.tx2, TimeEvent::NewMonth);
                         self.previous_time.month = month;
                     }
 
                     let year = dt.year();
                     if year != self.previous_time.year {
-                        self.sender.tx2.send(TimeEvent::NewYear).unwrap();
+                        send(&self.sender.tx2, TimeEvent::NewYear);
                         self.previous_time.year = year;
                     }
 
                     thread::sleep(Duration::from_secs(1));
                 }
-            });
+            })
+            .unwrap()
+    }
+
+    // The time zone is read from the config each pass so a change applies without a restart, it is only parsed again
+    // when it changed
+    fn update_time_zone(&mut self) {
+        let config = self.config.lock().unwrap();
+        let time_zone_spec = &config.config().station.time_zone;
+
+        if *time_zone_spec != self.time_zone_spec {
+            self.time_zone = TimeZone::parse(time_zone_spec).unwrap_or_else(TimeZone::utc);
+            self.time_zone_spec = time_zone_spec.clone();
+        }
+    }
+
+    // A change between the 12 and 24 hour clock shows straight away rather than at the next minute
+    fn update_clock_format(&mut self) {
+        let clock_24h = self.config.lock().unwrap().config().station.clock_24h;
+
+        if clock_24h != self.clock_24h {
+            self.clock_24h = clock_24h;
+            self.previous_time.minute = u8::MAX;
+        }
     }
 }
@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/**
+ * Internal counters of the station.  The counters are plain atomics in a static so that any thread can update them
+ * without a channel or a lock.  They are exported by the /metrics endpoint of the http server.
+ */
+pub struct Statistics {
+    pub frames_decoded: AtomicU32,
+    pub crc_failures: AtomicU32,
+    pub parity_failures: AtomicU32,
+    pub invalid_message_types: AtomicU32,
+    pub rmt_overflows: AtomicU32,
+    pub rmt_timeouts: AtomicU32,
+    pub channel_send_failures: AtomicU32,
+    pub bus_retries: AtomicU32,
+    pub thread_restarts: AtomicU32,
+    pub unpaired_sensor_messages: AtomicU32,
+    pub weather_station_loop: LoopLatency,
+    pub gui_loop: LoopLatency,
+}
+
+pub static STATISTICS: Statistics = Statistics::new();
+
+impl Statistics {
+    pub const fn new() -> Self {
+        Self {
+            frames_decoded: AtomicU32::new(0),
+            crc_failures: AtomicU32::new(0),
+            parity_failures: AtomicU32::new(0),
+            invalid_message_types: AtomicU32::new(0),
+            rmt_overflows: AtomicU32::new(0),
+            rmt_timeouts: AtomicU32::new(0),
+            channel_send_failures: AtomicU32::new(0),
+            bus_retries: AtomicU32::new(0),
+            thread_restarts: AtomicU32::new(0),
+            unpaired_sensor_messages: AtomicU32::new(0),
+            weather_station_loop: LoopLatency::new(),
+            gui_loop: LoopLatency::new(),
+        }
+    }
+}
+
+pub fn increment(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn read(counter: &AtomicU32) -> u32 {
+    counter.load(Ordering::Relaxed)
+}
+
+// The time a thread spent doing work in one pass of its loop, not counting the time it sleeps
+pub struct LoopLatency {
+    pub last_us: AtomicU32,
+    pub max_us: AtomicU32,
+}
+
+impl LoopLatency {
+    pub const fn new() -> Self {
+        Self {
+            last_us: AtomicU32::new(0),
+            max_us: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let elapsed_us = elapsed.as_micros().min(u32::MAX as u128) as u32;
+
+        self.last_us.store(elapsed_us, Ordering::Relaxed);
+        self.max_us.fetch_max(elapsed_us, Ordering::Relaxed);
+    }
+}
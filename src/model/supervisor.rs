@@ -0,0 +1,321 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use embedded_svc::storage::RawStorage;
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_sys::{
+    esp, esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT,
+    esp_reset_reason_t_ESP_RST_DEEPSLEEP, esp_reset_reason_t_ESP_RST_EXT,
+    esp_reset_reason_t_ESP_RST_INT_WDT, esp_reset_reason_t_ESP_RST_PANIC,
+    esp_reset_reason_t_ESP_RST_POWERON, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT, esp_restart,
+    esp_task_wdt_add, esp_task_wdt_init, esp_task_wdt_reset, esp_timer_get_time, EspError,
+};
+
+use crate::model::statistics::{increment, STATISTICS};
+
+const NVS_NAMESPACE: &str = "supervisor";
+const NVS_KEY: &str = "reboot_reason";
+const NVS_MAX_REASON_SIZE: usize = 128;
+
+// The supervisor checks the threads this often and feeds the task watchdog while they are all healthy
+const CHECK_INTERVAL_MS: u32 = 1000;
+
+// The task watchdog reboots the station when the supervisor itself stops feeding it
+const WATCHDOG_TIMEOUT_SECS: u32 = 30;
+
+// A thread that sleeps beats this often so a long sleep is not taken for a stuck thread
+const SLEEP_BEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// A thread that can be restarted is restarted this many times, the station reboots when it stops once more
+const MAX_RESTARTS: u32 = 3;
+
+// Give the reason time to go out on the console before rebooting
+const REBOOT_DELAY_MS: u32 = 500;
+
+fn uptime_secs() -> u32 {
+    (unsafe { esp_timer_get_time() } / 1_000_000) as u32
+}
+
+/**
+ * A thread beats its heartbeat once every pass of its loop.  The supervisor reads how long ago the last beat was, a
+ * thread that has not beaten for longer than it is allowed is stuck.  The beat is the uptime in seconds in an atomic,
+ * the ESP32 has no 64 bit atomics.
+ */
+#[derive(Clone)]
+pub struct Heartbeat {
+    last_beat_secs: Arc<AtomicU32>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last_beat_secs: Arc::new(AtomicU32::new(uptime_secs())),
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_secs.store(uptime_secs(), Ordering::Relaxed);
+    }
+
+    // Sleep and keep beating, a thread waiting for its next upload or reconnect is not stuck
+    pub fn sleep(&self, duration: Duration) {
+        let mut remaining = duration;
+
+        while !remaining.is_zero() {
+            let step = remaining.min(SLEEP_BEAT_INTERVAL);
+            thread::sleep(step);
+            remaining -= step;
+            self.beat();
+        }
+    }
+
+    fn silence(&self) -> Duration {
+        let last_beat_secs = self.last_beat_secs.load(Ordering::Relaxed);
+        Duration::from_secs(uptime_secs().saturating_sub(last_beat_secs) as u64)
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * The reason the supervisor rebooted the station, kept in nvs so it can be reported after the reboot.  A reboot the
+ * supervisor did not ask for, e.g. a panic or the task watchdog, is reported with the reset reason of ESP-IDF.
+ */
+pub struct RebootLog {
+    nvs: EspDefaultNvs,
+}
+
+impl RebootLog {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        Ok(Self { nvs })
+    }
+
+    // The reason of the last reboot, it is removed from nvs so the next reboot is not reported with the same reason
+    pub fn take_last_reason(&mut self) -> String {
+        let mut buf = [0u8; NVS_MAX_REASON_SIZE];
+
+        let recorded = match self.nvs.get_raw(NVS_KEY, &mut buf) {
+            Ok(Some(bytes)) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            Ok(None) => None,
+            Err(e) => {
+                println!("Failed to read the reboot reason {:?}", e);
+                None
+            }
+        };
+
+        match recorded {
+            Some(reason) => {
+                if let Err(e) = self.nvs.remove(NVS_KEY) {
+                    println!("Failed to remove the reboot reason {:?}", e);
+                }
+                reason
+            }
+            None => reset_reason().to_string(),
+        }
+    }
+
+    fn record(&mut self, reason: &str) {
+        let reason = &reason.as_bytes()[..reason.len().min(NVS_MAX_REASON_SIZE)];
+
+        if let Err(e) = self.nvs.set_raw(NVS_KEY, reason) {
+            println!("Failed to record the reboot reason {:?}", e);
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power on",
+        esp_reset_reason_t_ESP_RST_EXT => "reset pin",
+        esp_reset_reason_t_ESP_RST_SW => "restarted",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "woke from deep sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
+
+struct SupervisedThread {
+    name: &'static str,
+    heartbeat: Heartbeat,
+    // The longest the thread may go without a beat, it covers the longest blocking call the thread makes
+    max_silence: Duration,
+    handle: JoinHandle<()>,
+    // Only threads that can be built again from what main kept, e.g. the network services, can be restarted.  A thread
+    // that owns a peripheral can not, the peripheral went with it.
+    restart: Option<Box<dyn FnMut(Heartbeat) -> JoinHandle<()>>>,
+    restarts: u32,
+}
+
+/**
+ * The supervisor owns the handles of the threads started by main.  Every second it checks that each thread is running
+ * and has beaten its heartbeat recently, and feeds the ESP-IDF task watchdog when they all have.
+ *
+ * A thread that stopped (it returned or panicked) is restarted if it can be, a thread that is stuck can not be stopped
+ * so the station is rebooted, as it is when a thread that can not be restarted stops.  The reason is recorded in nvs
+ * before rebooting.  If the supervisor itself hangs the task watchdog reboots the station.
+ */
+pub struct Supervisor {
+    threads: Vec<SupervisedThread>,
+    reboot_log: RebootLog,
+    watchdog_subscribed: bool,
+}
+
+impl Supervisor {
+    pub fn new(reboot_log: RebootLog) -> Self {
+        Self {
+            threads: Vec::new(),
+            reboot_log,
+            watchdog_subscribed: false,
+        }
+    }
+
+    // The heartbeat is the one given to the thread when it was started
+    pub fn supervise(
+        &mut self,
+        name: &'static str,
+        max_silence: Duration,
+        heartbeat: Heartbeat,
+        handle: JoinHandle<()>,
+    ) {
+        self.threads.push(SupervisedThread {
+            name,
+            heartbeat,
+            max_silence,
+            handle,
+            restart: None,
+            restarts: 0,
+        });
+    }
+
+    // start is called again to restart the thread when it stops
+    pub fn supervise_restartable<F>(
+        &mut self,
+        name: &'static str,
+        max_silence: Duration,
+        mut start: F,
+    ) where
+        F: FnMut(Heartbeat) -> JoinHandle<()> + 'static,
+    {
+        let heartbeat = Heartbeat::new();
+        let handle = start(heartbeat.clone());
+
+        self.threads.push(SupervisedThread {
+            name,
+            heartbeat,
+            max_silence,
+            handle,
+            restart: Some(Box::new(start)),
+            restarts: 0,
+        });
+    }
+
+    // Runs on the main task for as long as the station runs
+    pub fn run(mut self) -> ! {
+        println!("Supervising {} threads", self.threads.len());
+
+        loop {
+            self.check();
+        }
+    }
+
+    // Runs on the main task until done returns true, main then starts the threads that wait for it
+    pub fn run_until<F>(&mut self, mut done: F)
+    where
+        F: FnMut() -> bool,
+    {
+        println!("Supervising {} threads", self.threads.len());
+
+        while !done() {
+            self.check();
+        }
+    }
+
+    fn check(&mut self) {
+        if !self.watchdog_subscribed {
+            if let Err(e) = self.subscribe_watchdog() {
+                println!("Failed to subscribe to the task watchdog {:?}", e);
+            }
+            self.watchdog_subscribed = true;
+        }
+
+        if let Some(reason) = self.check_threads() {
+            self.reboot(&reason);
+        }
+
+        unsafe {
+            esp_task_wdt_reset();
+        }
+
+        FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+    }
+
+    /**
+     * The task watchdog is started by ESP-IDF, init sets its timeout and makes it reboot instead of only logging.
+     *
+     * Only the main task is added.  The network threads block for longer than the watchdog timeout in normal
+     * operation, an http request or a reconnect can take minutes, so each thread would need its own timeout.  The
+     * heartbeats give every thread its own limit instead, and the main task checks them and is itself on the watchdog,
+     * so a stuck thread or a stuck supervisor both end in a reboot.
+     */
+    fn subscribe_watchdog(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_task_wdt_init(WATCHDOG_TIMEOUT_SECS, true) })?;
+        esp!(unsafe { esp_task_wdt_add(std::ptr::null_mut()) })
+    }
+
+    // Restart the threads that stopped and can be, returns why the station must reboot
+    fn check_threads(&mut self) -> Option<String> {
+        for thread in self.threads.iter_mut() {
+            if thread.handle.is_finished() {
+                let restart = match thread.restart.as_mut() {
+                    Some(restart) if thread.restarts < MAX_RESTARTS => restart,
+                    Some(_) => {
+                        return Some(format!(
+                            "{} stopped {} times",
+                            thread.name,
+                            MAX_RESTARTS + 1
+                        ))
+                    }
+                    None => return Some(format!("{} stopped", thread.name)),
+                };
+
+                println!("{} stopped, restarting it", thread.name);
+                increment(&STATISTICS.thread_restarts);
+
+                thread.heartbeat.beat();
+                thread.handle = restart(thread.heartbeat.clone());
+                thread.restarts += 1;
+            } else if thread.heartbeat.silence() > thread.max_silence {
+                return Some(format!(
+                    "{} stuck for {} secs",
+                    thread.name,
+                    thread.heartbeat.silence().as_secs()
+                ));
+            }
+        }
+
+        None
+    }
+
+    fn reboot(&mut self, reason: &str) -> ! {
+        println!("Rebooting, {}", reason);
+        self.reboot_log.record(reason);
+
+        FreeRtos::delay_ms(REBOOT_DELAY_MS);
+        unsafe { esp_restart() }
+    }
+}
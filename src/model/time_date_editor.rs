@@ -0,0 +1,309 @@
+use time::{util::days_in_year_month, Month, PrimitiveDateTime};
+
+// Years offered when setting the date, an earlier clock would be taken as never set
+const MIN_YEAR: i32 = 2023;
+const MAX_YEAR: i32 = 2099;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditField {
+    Hour,
+    Minute,
+    Day,
+    Month,
+    Year,
+    ClockFormat,
+}
+
+impl EditField {
+    fn next(&self) -> Self {
+        match self {
+            EditField::Hour => EditField::Minute,
+            EditField::Minute => EditField::Day,
+            EditField::Day => EditField::Month,
+            EditField::Month => EditField::Year,
+            EditField::Year => EditField::ClockFormat,
+            EditField::ClockFormat => EditField::Hour,
+        }
+    }
+}
+
+/**
+ * The local time and date being set from the buttons.  One field at a time is highlighted and stepped up or down,
+ * each field wraps around within its range and the day is kept valid for the month and year.  The seconds are zero
+ * so the clock starts on the minute when the edit is confirmed.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeDateEditor {
+    date_time: PrimitiveDateTime,
+    clock_24h: bool,
+    field: EditField,
+}
+
+impl TimeDateEditor {
+    pub fn new(local: PrimitiveDateTime, clock_24h: bool) -> Self {
+        let year = local.year().clamp(MIN_YEAR, MAX_YEAR);
+        let date_time = local
+            .replace_second(0)
+            .unwrap()
+            .replace_nanosecond(0)
+            .unwrap();
+
+        Self {
+            date_time: with_date(date_time, year, date_time.month(), date_time.day()),
+            clock_24h,
+            field: EditField::Hour,
+        }
+    }
+
+    pub fn date_time(&self) -> PrimitiveDateTime {
+        self.date_time
+    }
+
+    pub fn clock_24h(&self) -> bool {
+        self.clock_24h
+    }
+
+    pub fn next_field(&mut self) {
+        self.field = self.field.next();
+    }
+
+    pub fn increment(&mut self) {
+        self.step(1);
+    }
+
+    pub fn decrement(&mut self) {
+        self.step(-1);
+    }
+
+    fn step(&mut self, delta: i32) {
+        let dt = self.date_time;
+
+        match self.field {
+            EditField::Hour => {
+                let hour = wrap(dt.hour() as i32 + delta, 0, 23) as u8;
+                self.date_time = dt.replace_hour(hour).unwrap();
+            }
+
+            EditField::Minute => {
+                let minute = wrap(dt.minute() as i32 + delta, 0, 59) as u8;
+                self.date_time = dt.replace_minute(minute).unwrap();
+            }
+
+            EditField::Day => {
+                let days = days_in_year_month(dt.year(), dt.month()) as i32;
+                let day = wrap(dt.day() as i32 + delta, 1, days) as u8;
+                self.date_time = dt.replace_day(day).unwrap();
+            }
+
+            EditField::Month => {
+                let month = if delta > 0 {
+                    dt.month().next()
+                } else {
+                    dt.month().previous()
+                };
+                self.date_time = with_date(dt, dt.year(), month, dt.day());
+            }
+
+            EditField::Year => {
+                let year = wrap(dt.year() + delta, MIN_YEAR, MAX_YEAR);
+                self.date_time = with_date(dt, year, dt.month(), dt.day());
+            }
+
+            EditField::ClockFormat => self.clock_24h = !self.clock_24h,
+        }
+    }
+
+    // The time with the field being edited in brackets, "[7]:45 PM" or "19:[45]"
+    pub fn time_text(&self) -> String {
+        let dt = self.date_time;
+
+        let hour = if self.clock_24h {
+            format!("{:02}", dt.hour())
+        } else {
+            format!("{}", hour_12(dt.hour()))
+        };
+
+        let mut text = format!(
+            "{}:{}",
+            self.highlight(EditField::Hour, &hour),
+            self.highlight(EditField::Minute, &format!("{:02}", dt.minute()))
+        );
+
+        if !self.clock_24h {
+            text.push_str(if dt.hour() < 12 { " AM" } else { " PM" });
+        }
+
+        text
+    }
+
+    // The date with the field being edited in brackets, "Tue, [15] Oct 2024"
+    pub fn date_text(&self) -> String {
+        let dt = self.date_time;
+        let weekday = dt.weekday().to_string();
+        let month = dt.month().to_string();
+
+        format!(
+            "{}, {} {} {}",
+            &weekday[..3],
+            self.highlight(EditField::Day, &format!("{:02}", dt.day())),
+            self.highlight(EditField::Month, &month[..3]),
+            self.highlight(EditField::Year, &format!("{}", dt.year()))
+        )
+    }
+
+    // The clock format, "Clock [12h]" while it is being edited
+    pub fn format_text(&self) -> String {
+        let format = if self.clock_24h { "24h" } else { "12h" };
+        format!("Clock {}", self.highlight(EditField::ClockFormat, format))
+    }
+
+    fn highlight(&self, field: EditField, text: &str) -> String {
+        if self.field == field {
+            format!("[{}]", text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// Move to another month or year keeping the day, the 31st becomes the last day of a shorter month
+fn with_date(dt: PrimitiveDateTime, year: i32, month: Month, day: u8) -> PrimitiveDateTime {
+    let day = day.min(days_in_year_month(year, month));
+
+    dt.replace_day(1)
+        .unwrap()
+        .replace_year(year)
+        .unwrap()
+        .replace_month(month)
+        .unwrap()
+        .replace_day(day)
+        .unwrap()
+}
+
+fn wrap(value: i32, min: i32, max: i32) -> i32 {
+    min + (value - min).rem_euclid(max - min + 1)
+}
+
+fn hour_12(hour: u8) -> u8 {
+    match hour % 12 {
+        0 => 12,
+        h => h,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn editor_on(field: EditField, local: PrimitiveDateTime) -> TimeDateEditor {
+        let mut editor = TimeDateEditor::new(local, true);
+        while editor.field != field {
+            editor.next_field();
+        }
+        editor
+    }
+
+    #[test]
+    fn seconds_are_cleared_and_the_year_is_kept_in_range() {
+        let editor = TimeDateEditor::new(datetime!(2024-10-15 19:45:31.5), true);
+        assert_eq!(editor.date_time(), datetime!(2024-10-15 19:45:00));
+
+        let editor = TimeDateEditor::new(datetime!(1970-01-01 00:00:10), true);
+        assert_eq!(editor.date_time(), datetime!(2023-01-01 00:00:00));
+    }
+
+    #[test]
+    fn hour_and_minute_wrap_around() {
+        let mut editor = editor_on(EditField::Hour, datetime!(2024-10-15 23:59));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2024-10-15 00:59));
+        editor.decrement();
+        assert_eq!(editor.date_time(), datetime!(2024-10-15 23:59));
+
+        // The hour does not carry into the date or the minute into the hour
+        editor.next_field();
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2024-10-15 23:00));
+        editor.decrement();
+        assert_eq!(editor.date_time(), datetime!(2024-10-15 23:59));
+    }
+
+    #[test]
+    fn day_is_clamped_to_the_month() {
+        let mut editor = editor_on(EditField::Month, datetime!(2024-01-31 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2024-02-29 12:00));
+
+        let mut editor = editor_on(EditField::Month, datetime!(2023-01-31 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2023-02-28 12:00));
+
+        // A leap day becomes the 28th in the year after
+        let mut editor = editor_on(EditField::Year, datetime!(2024-02-29 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2025-02-28 12:00));
+    }
+
+    #[test]
+    fn day_wraps_within_the_month() {
+        let mut editor = editor_on(EditField::Day, datetime!(2024-02-29 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2024-02-01 12:00));
+        editor.decrement();
+        assert_eq!(editor.date_time(), datetime!(2024-02-29 12:00));
+    }
+
+    #[test]
+    fn month_wraps_without_changing_the_year() {
+        let mut editor = editor_on(EditField::Month, datetime!(2024-12-15 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2024-01-15 12:00));
+        editor.decrement();
+        assert_eq!(editor.date_time(), datetime!(2024-12-15 12:00));
+    }
+
+    #[test]
+    fn year_wraps_within_its_bounds() {
+        let mut editor = editor_on(EditField::Year, datetime!(2099-06-15 12:00));
+        editor.increment();
+        assert_eq!(editor.date_time(), datetime!(2023-06-15 12:00));
+        editor.decrement();
+        assert_eq!(editor.date_time(), datetime!(2099-06-15 12:00));
+    }
+
+    #[test]
+    fn the_field_being_edited_is_highlighted() {
+        let mut editor = TimeDateEditor::new(datetime!(2024-10-15 19:45), false);
+        assert_eq!(editor.time_text(), "[7]:45 PM");
+        assert_eq!(editor.date_text(), "Tue, 15 Oct 2024");
+
+        editor.next_field();
+        assert_eq!(editor.time_text(), "7:[45] PM");
+
+        editor.next_field();
+        assert_eq!(editor.time_text(), "7:45 PM");
+        assert_eq!(editor.date_text(), "Tue, [15] Oct 2024");
+
+        editor.next_field();
+        assert_eq!(editor.date_text(), "Tue, 15 [Oct] 2024");
+
+        editor.next_field();
+        assert_eq!(editor.date_text(), "Tue, 15 Oct [2024]");
+
+        editor.next_field();
+        assert_eq!(editor.format_text(), "Clock [12h]");
+        editor.increment();
+        assert_eq!(editor.format_text(), "Clock [24h]");
+        assert_eq!(editor.time_text(), "19:45");
+    }
+
+    #[test]
+    fn midnight_and_noon_on_the_12_hour_clock() {
+        let editor = TimeDateEditor::new(datetime!(2024-10-15 00:05), false);
+        assert_eq!(editor.time_text(), "[12]:05 AM");
+
+        let editor = TimeDateEditor::new(datetime!(2024-10-15 12:05), false);
+        assert_eq!(editor.time_text(), "[12]:05 PM");
+    }
+}
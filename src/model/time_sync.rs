@@ -0,0 +1,139 @@
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Receiver;
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+use time::OffsetDateTime;
+
+use crate::{
+    model::{
+        real_time_clock::{RealTimeClock, MIN_VALID_TIMESTAMP},
+        supervisor::Heartbeat,
+    },
+    net::wifi,
+};
+
+// SNTP resynchronizes about once an hour, the RTC is written back at most this often to discipline its drift
+const RTC_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// True once the system clock has been set from the RTC or by SNTP
+pub fn is_clock_set() -> bool {
+    OffsetDateTime::now_utc().unix_timestamp() >= MIN_VALID_TIMESTAMP
+}
+
+/**
+ * Keeps the system clock in step.  SNTP runs in the background and synchronizes the clock whenever the network is
+ * up, each synchronized time is written back into the DS3231 so the next boot without a network still starts with
+ * the right time.  A time set by hand on the TimeDate view arrives here as UTC and is written to both clocks.
+ *
+ * SNTP is started again whenever the WifiSupervisor reports the link up, e.g. when the station booted without a
+ * network, so the clock is synchronized as soon as the network appears instead of at the next SNTP retry.
+ */
+pub struct TimeSync {
+    rtc: RealTimeClock<'static>,
+    cbc_rx_set_time: Receiver<OffsetDateTime>,
+}
+
+impl TimeSync {
+    pub fn new(rtc: RealTimeClock<'static>, rx: Receiver<OffsetDateTime>) -> Self {
+        Self {
+            rtc,
+            cbc_rx_set_time: rx,
+        }
+    }
+
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        println!("Starting TimeSync Thread");
+
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || {
+                // Without SNTP the clocks can still be set by hand
+                let mut sntp = start_sntp();
+                let mut link_up = wifi::is_link_up();
+
+                let mut last_rtc_update: Option<Instant> = None;
+
+                loop {
+                    heartbeat.beat();
+
+                    if wifi::is_link_up() != link_up {
+                        link_up = !link_up;
+
+                        if link_up {
+                            println!("Network is up, restarting SNTP");
+                            // Only one EspSntp can exist, the old one is stopped first
+                            drop(sntp.take());
+                            sntp = start_sntp();
+                        }
+                    }
+
+                    if let Ok(utc) = self.cbc_rx_set_time.recv_timeout(POLL_INTERVAL) {
+                        self.set_time(utc);
+                        continue;
+                    }
+
+                    let synchronized = match &sntp {
+                        Some(sntp) => sntp.get_sync_status() == SyncStatus::Completed,
+                        None => false,
+                    };
+
+                    if synchronized {
+                        let rtc_update_due = match last_rtc_update {
+                            Some(updated) => updated.elapsed() >= RTC_UPDATE_INTERVAL,
+                            None => true,
+                        };
+
+                        if rtc_update_due {
+                            match self.rtc.set_from_system_clock() {
+                                Ok(()) => {
+                                    println!(
+                                        "Updated RTC from SNTP --- time now is {}",
+                                        OffsetDateTime::now_utc()
+                                    );
+                                    last_rtc_update = Some(Instant::now());
+                                }
+                                Err(e) => println!("Failed to update RTC {}", e),
+                            }
+                        }
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    // The RTC is set first, the system clock is then read back from it so both hold the same time
+    fn set_time(&mut self, utc: OffsetDateTime) {
+        if let Err(e) = self.rtc.set_date_time(
+            utc.year(),
+            u8::from(utc.month()) as u32,
+            utc.day() as u32,
+            utc.hour() as u32,
+            utc.minute() as u32,
+            utc.second() as u32,
+        ) {
+            println!("Failed to set the RTC {}", e);
+            return;
+        }
+
+        match self.rtc.set_system_clock() {
+            Ok(()) => println!(
+                "Clock set by hand --- time now is {}",
+                OffsetDateTime::now_utc()
+            ),
+            Err(e) => println!("Failed to set the system clock {}", e),
+        }
+    }
+}
+
+fn start_sntp() -> Option<EspSntp> {
+    match EspSntp::new_default() {
+        Ok(sntp) => Some(sntp),
+        Err(e) => {
+            println!("SNTP failed to start {:?}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+const SECS_PER_HOUR: i32 = 60 * 60;
+
+// The transition time of a rule that does not give one, 02:00 local time
+const DEFAULT_TRANSITION_SECS: i32 = 2 * SECS_PER_HOUR;
+
+/**
+ * IANA zone names the station knows, with the POSIX TZ string of the rules in force.  A zone that is not in the table
+ * can be given as a POSIX TZ string, e.g. "CET-1CEST,M3.5.0,M10.5.0/3".
+ */
+const IANA_ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("Etc/UTC", "UTC0"),
+    ("Pacific/Honolulu", "HST10"),
+    ("America/Anchorage", "AKST9AKDT,M3.2.0,M11.1.0"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Vancouver", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Boise", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Edmonton", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Winnipeg", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Regina", "CST6"),
+    ("America/Mexico_City", "CST6"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Detroit", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Toronto", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Bogota", "<-05>5"),
+    ("America/Lima", "<-05>5"),
+    ("America/Halifax", "AST4ADT,M3.2.0,M11.1.0"),
+    ("America/Santiago", "<-04>4<-03>,M9.1.6/24,M4.1.6/24"),
+    ("America/St_Johns", "NST3:30NDT,M3.2.0,M11.1.0"),
+    ("America/Sao_Paulo", "<-03>3"),
+    ("America/Argentina/Buenos_Aires", "<-03>3"),
+    ("Atlantic/Reykjavik", "GMT0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "GMT0IST,M3.5.0/1,M10.5.0"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Brussels", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Copenhagen", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Prague", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Vienna", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Warsaw", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Zurich", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Kyiv", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Istanbul", "<+03>-3"),
+    ("Europe/Moscow", "MSK-3"),
+    ("Africa/Lagos", "WAT-1"),
+    ("Africa/Johannesburg", "SAST-2"),
+    ("Africa/Cairo", "EET-2EEST,M4.5.5/0,M10.5.4/24"),
+    ("Africa/Nairobi", "EAT-3"),
+    ("Asia/Jerusalem", "IST-2IDT,M3.4.4/26,M10.5.0"),
+    ("Asia/Dubai", "<+04>-4"),
+    ("Asia/Tehran", "<+0330>-3:30"),
+    ("Asia/Karachi", "PKT-5"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Kathmandu", "<+0545>-5:45"),
+    ("Asia/Dhaka", "<+06>-6"),
+    ("Asia/Bangkok", "<+07>-7"),
+    ("Asia/Jakarta", "WIB-7"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Hong_Kong", "HKT-8"),
+    ("Asia/Singapore", "<+08>-8"),
+    ("Asia/Manila", "PST-8"),
+    ("Australia/Perth", "AWST-8"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Asia/Seoul", "KST-9"),
+    ("Australia/Darwin", "ACST-9:30"),
+    ("Australia/Adelaide", "ACST-9:30ACDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Brisbane", "AEST-10"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Melbourne", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Hobart", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+];
+
+// The day of the year a daylight saving transition falls on
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RuleDate {
+    // Mm.w.d - day d (0 is Sunday) of week w (5 is the last) of month m
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+    // Jn - day 1 to 365, February 29 is never counted
+    Julian(u16),
+    // n - day 0 to 365, February 29 is counted in leap years
+    ZeroBased(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rule {
+    date: RuleDate,
+    // Local time of the transition, may be negative or past 24 hours
+    time_secs: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct DaylightSaving {
+    name: String,
+    offset_secs: i32,
+    start: Rule,
+    end: Rule,
+}
+
+// The IANA zone names in the table, in the order they are listed
+pub fn zone_names() -> impl Iterator<Item = &'static str> {
+    IANA_ZONES.iter().map(|(name, _)| *name)
+}
+
+/**
+ * A time zone described by a POSIX TZ string, e.g. "MST7MDT,M3.2.0,M11.1.0".  The offsets in the string are west of
+ * UTC, the offsets kept here are east of UTC like UtcOffset.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeZone {
+    name: String,
+    offset_secs: i32,
+    daylight_saving: Option<DaylightSaving>,
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            name: "UTC".to_string(),
+            offset_secs: 0,
+            daylight_saving: None,
+        }
+    }
+
+    // Parse an IANA zone name from the table or a POSIX TZ string
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let posix = IANA_ZONES
+            .iter()
+            .find(|(name, _)| *name == spec)
+            .map(|(_, posix)| *posix)
+            .unwrap_or(spec);
+
+        parse_posix(posix)
+    }
+
+    // The offset from UTC in force at a unix timestamp
+    pub fn offset_at(&self, timestamp: i64) -> UtcOffset {
+        UtcOffset::from_whole_seconds(self.offset_secs_at(timestamp)).unwrap_or(UtcOffset::UTC)
+    }
+
+    // The zone abbreviation in force at a unix timestamp, e.g. MST or MDT
+    pub fn abbreviation_at(&self, timestamp: i64) -> &str {
+        match &self.daylight_saving {
+            Some(dst) if self.is_daylight_saving(dst, timestamp) => &dst.name,
+            _ => &self.name,
+        }
+    }
+
+    pub fn to_local(&self, date_time: OffsetDateTime) -> OffsetDateTime {
+        date_time.to_offset(self.offset_at(date_time.unix_timestamp()))
+    }
+
+    /**
+     * The instant a local time names.  A time repeated when daylight saving ends is taken as standard time, a time
+     * skipped when it starts is moved on by the size of the jump.
+     */
+    pub fn from_local(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        let as_utc = local.assume_utc().unix_timestamp();
+
+        let mut offset_secs = self.offset_secs_at(as_utc - self.offset_secs as i64);
+        offset_secs = self.offset_secs_at(as_utc - offset_secs as i64);
+
+        OffsetDateTime::from_unix_timestamp(as_utc - offset_secs as i64).unwrap()
+    }
+
+    fn offset_secs_at(&self, timestamp: i64) -> i32 {
+        match &self.daylight_saving {
+            Some(dst) if self.is_daylight_saving(dst, timestamp) => dst.offset_secs,
+            _ => self.offset_secs,
+        }
+    }
+
+    fn is_daylight_saving(&self, dst: &DaylightSaving, timestamp: i64) -> bool {
+        // The rules are for the local year, which may not be the UTC year around new year
+        let year = match OffsetDateTime::from_unix_timestamp(timestamp + self.offset_secs as i64) {
+            Ok(date_time) => date_time.year(),
+            Err(_) => return false,
+        };
+
+        // The start is given in standard time and the end in daylight saving time
+        let start = transition_timestamp(year, &dst.start) - self.offset_secs as i64;
+        let end = transition_timestamp(year, &dst.end) - dst.offset_secs as i64;
+
+        if start < end {
+            timestamp >= start && timestamp < end
+        } else {
+            // Southern hemisphere, daylight saving runs over new year
+            timestamp >= start || timestamp < end
+        }
+    }
+}
+
+// The unix timestamp of a rule in a year, in the local time the rule is given in
+fn transition_timestamp(year: i32, rule: &Rule) -> i64 {
+    let date = match rule.date {
+        RuleDate::MonthWeekDay {
+            month,
+            week,
+            weekday,
+        } => {
+            let month = Month::try_from(month).unwrap_or(Month::January);
+            let first = Date::from_calendar_date(year, month, 1).unwrap();
+            let first_weekday = first.weekday().number_days_from_sunday();
+            let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+
+            // Week 5 means the last such day of the month
+            let days_in_month = time::util::days_in_year_month(year, month);
+            while day > days_in_month {
+                day -= 7;
+            }
+
+            Date::from_calendar_date(year, month, day).unwrap()
+        }
+
+        RuleDate::Julian(day) => {
+            let leap_day = time::util::is_leap_year(year) && day >= 60;
+            Date::from_ordinal_date(year, day + leap_day as u16).unwrap()
+        }
+
+        RuleDate::ZeroBased(day) => {
+            let days = time::util::days_in_year(year);
+            Date::from_ordinal_date(year, (day + 1).min(days)).unwrap()
+        }
+    };
+
+    date.midnight().assume_utc().unix_timestamp() + rule.time_secs as i64
+}
+
+/**
+ * Parse a POSIX TZ string
+ *
+ *  std offset [dst [offset] [,start[/time],end[/time]]]
+ *
+ *  MST7                                fixed offset, 7 hours west of UTC
+ *  MST7MDT,M3.2.0,M11.1.0              daylight saving from the 2nd Sunday of March to the 1st Sunday of November
+ *  <+0530>-5:30                        quoted names for zones without an abbreviation
+ *
+ * A daylight saving zone without an offset is one hour ahead of standard time, one without rules uses the US rules.
+ */
+fn parse_posix(posix: &str) -> Option<TimeZone> {
+    let mut parser = Parser { rest: posix };
+
+    let name = parser.name()?;
+    let offset_secs = -parser.offset()?;
+
+    if parser.rest.is_empty() {
+        return Some(TimeZone {
+            name,
+            offset_secs,
+            daylight_saving: None,
+        });
+    }
+
+    let dst_name = parser.name()?;
+    let dst_offset_secs = if parser.rest.is_empty() || parser.rest.starts_with(',') {
+        offset_secs + SECS_PER_HOUR
+    } else {
+        -parser.offset()?
+    };
+
+    let (start, end) = if parser.rest.is_empty() {
+        (
+            Rule {
+                date: RuleDate::MonthWeekDay {
+                    month: 3,
+                    week: 2,
+                    weekday: 0,
+                },
+                time_secs: DEFAULT_TRANSITION_SECS,
+            },
+            Rule {
+                date: RuleDate::MonthWeekDay {
+                    month: 11,
+                    week: 1,
+                    weekday: 0,
+                },
+                time_secs: DEFAULT_TRANSITION_SECS,
+            },
+        )
+    } else {
+        parser.expect(',')?;
+        let start = parser.rule()?;
+        parser.expect(',')?;
+        let end = parser.rule()?;
+        (start, end)
+    };
+
+    if !parser.rest.is_empty() {
+        return None;
+    }
+
+    Some(TimeZone {
+        name,
+        offset_secs,
+        daylight_saving: Some(DaylightSaving {
+            name: dst_name,
+            offset_secs: dst_offset_secs,
+            start,
+            end,
+        }),
+    })
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.rest = self.rest.strip_prefix(c)?;
+        Some(())
+    }
+
+    // Three or more letters, or anything between < and >
+    fn name(&mut self) -> Option<String> {
+        let (name, rest) = if let Some(quoted) = self.rest.strip_prefix('<') {
+            let end = quoted.find('>')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len());
+            (&self.rest[..end], &self.rest[end..])
+        };
+
+        if name.len() < 3 {
+            return None;
+        }
+
+        self.rest = rest;
+        Some(name.to_string())
+    }
+
+    fn number(&mut self, max: u32) -> Option<u32> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let number = self.rest[..end].parse::<u32>().ok()?;
+
+        if number > max {
+            return None;
+        }
+
+        self.rest = &self.rest[end..];
+        Some(number)
+    }
+
+    // [+-]hh[:mm[:ss]] as seconds, hours up to 24 for an offset and 167 for a transition time
+    fn hms(&mut self, max_hours: u32) -> Option<i32> {
+        let sign = if self.expect('-').is_some() {
+            -1
+        } else {
+            let _ = self.expect('+');
+            1
+        };
+
+        let mut secs = self.number(max_hours)? * 3600;
+        if self.expect(':').is_some() {
+            secs += self.number(59)? * 60;
+            if self.expect(':').is_some() {
+                secs += self.number(59)?;
+            }
+        }
+
+        Some(sign * secs as i32)
+    }
+
+    fn offset(&mut self) -> Option<i32> {
+        self.hms(24)
+    }
+
+    fn rule(&mut self) -> Option<Rule> {
+        let date = if self.expect('M').is_some() {
+            let month = self.number(12)? as u8;
+            self.expect('.')?;
+            let week = self.number(5)? as u8;
+            self.expect('.')?;
+            let weekday = self.number(6)? as u8;
+
+            if month == 0 || week == 0 {
+                return None;
+            }
+
+            RuleDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else if self.expect('J').is_some() {
+            match self.number(365)? as u16 {
+                0 => return None,
+                day => RuleDate::Julian(day),
+            }
+        } else {
+            RuleDate::ZeroBased(self.number(365)? as u16)
+        };
+
+        let time_secs = if self.expect('/').is_some() {
+            self.hms(167)?
+        } else {
+            DEFAULT_TRANSITION_SECS
+        };
+
+        Some(Rule { date, time_secs })
+    }
+}
+
+// A fixed offset zone for a whole hour utc offset, used to migrate the former utc_offset_hours setting
+pub fn posix_for_utc_offset(hours: i64) -> String {
+    match hours {
+        0 => "UTC0".to_string(),
+        _ => format!("<{:+03}>{}", hours, -hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    // The offset in hours just before and at a transition given in UTC
+    fn offsets_around(zone: &str, transition: OffsetDateTime) -> (f32, f32) {
+        let time_zone = TimeZone::parse(zone).unwrap();
+        let timestamp = transition.unix_timestamp();
+
+        (
+            time_zone.offset_at(timestamp - 1).whole_seconds() as f32 / 3600.0,
+            time_zone.offset_at(timestamp).whole_seconds() as f32 / 3600.0,
+        )
+    }
+
+    #[test]
+    fn northern_hemisphere_transitions() {
+        // 02:00 MST and 02:00 MDT
+        assert_eq!(
+            offsets_around("America/Denver", datetime!(2024-03-10 09:00 UTC)),
+            (-7.0, -6.0)
+        );
+        assert_eq!(
+            offsets_around("America/Denver", datetime!(2024-11-03 08:00 UTC)),
+            (-6.0, -7.0)
+        );
+
+        // The European Union changes at 01:00 UTC
+        assert_eq!(
+            offsets_around("Europe/Berlin", datetime!(2024-03-31 01:00 UTC)),
+            (1.0, 2.0)
+        );
+        assert_eq!(
+            offsets_around("Europe/Berlin", datetime!(2024-10-27 01:00 UTC)),
+            (2.0, 1.0)
+        );
+        assert_eq!(
+            offsets_around("Europe/London", datetime!(2024-03-31 01:00 UTC)),
+            (0.0, 1.0)
+        );
+        assert_eq!(
+            offsets_around("Europe/London", datetime!(2024-10-27 01:00 UTC)),
+            (1.0, 0.0)
+        );
+
+        // Half hour standard offset
+        assert_eq!(
+            offsets_around("America/St_Johns", datetime!(2024-03-10 05:30 UTC)),
+            (-3.5, -2.5)
+        );
+    }
+
+    #[test]
+    fn southern_hemisphere_transitions() {
+        // Daylight saving ends at 03:00 AEDT on the first Sunday of April and starts at 02:00 AEST on the first
+        // Sunday of October
+        assert_eq!(
+            offsets_around("Australia/Sydney", datetime!(2024-04-06 16:00 UTC)),
+            (11.0, 10.0)
+        );
+        assert_eq!(
+            offsets_around("Australia/Sydney", datetime!(2024-10-05 16:00 UTC)),
+            (10.0, 11.0)
+        );
+        assert_eq!(
+            offsets_around("Australia/Adelaide", datetime!(2024-04-06 16:30 UTC)),
+            (10.5, 9.5)
+        );
+        assert_eq!(
+            offsets_around("Pacific/Auckland", datetime!(2024-04-06 14:00 UTC)),
+            (13.0, 12.0)
+        );
+        assert_eq!(
+            offsets_around("Pacific/Auckland", datetime!(2024-09-28 14:00 UTC)),
+            (12.0, 13.0)
+        );
+
+        // The transitions at 24:00 on a Saturday are on Sunday in UTC
+        assert_eq!(
+            offsets_around("America/Santiago", datetime!(2024-04-07 03:00 UTC)),
+            (-3.0, -4.0)
+        );
+        assert_eq!(
+            offsets_around("America/Santiago", datetime!(2024-09-08 04:00 UTC)),
+            (-4.0, -3.0)
+        );
+    }
+
+    #[test]
+    fn southern_hemisphere_summer_spans_new_year() {
+        let sydney = TimeZone::parse("Australia/Sydney").unwrap();
+
+        for date_time in [
+            datetime!(2023-12-31 12:59 UTC),
+            datetime!(2023-12-31 13:00 UTC),
+            datetime!(2024-01-15 00:00 UTC),
+        ] {
+            let timestamp = date_time.unix_timestamp();
+            assert_eq!(sydney.offset_at(timestamp).whole_hours(), 11);
+            assert_eq!(sydney.abbreviation_at(timestamp), "AEDT");
+        }
+
+        let winter = datetime!(2024-07-01 00:00 UTC).unix_timestamp();
+        assert_eq!(sydney.offset_at(winter).whole_hours(), 10);
+        assert_eq!(sydney.abbreviation_at(winter), "AEST");
+    }
+
+    #[test]
+    fn fixed_offsets() {
+        let kolkata = TimeZone::parse("Asia/Kolkata").unwrap();
+        let tokyo = TimeZone::parse("Asia/Tokyo").unwrap();
+        let quoted = TimeZone::parse("<+0545>-5:45").unwrap();
+
+        for date_time in [
+            datetime!(2024-01-01 00:00 UTC),
+            datetime!(2024-07-01 00:00 UTC),
+        ] {
+            let timestamp = date_time.unix_timestamp();
+            assert_eq!(kolkata.offset_at(timestamp).whole_minutes(), 5 * 60 + 30);
+            assert_eq!(tokyo.offset_at(timestamp).whole_hours(), 9);
+            assert_eq!(quoted.offset_at(timestamp).whole_minutes(), 5 * 60 + 45);
+            assert_eq!(quoted.abbreviation_at(timestamp), "+0545");
+        }
+    }
+
+    #[test]
+    fn local_times_around_transitions() {
+        let denver = TimeZone::parse("America/Denver").unwrap();
+
+        // 02:30 does not exist on the day daylight saving starts, it is moved on by an hour
+        assert_eq!(
+            denver.from_local(datetime!(2024-03-10 02:30)),
+            datetime!(2024-03-10 09:30 UTC)
+        );
+        // 01:30 happens twice on the day it ends, the standard time one is taken
+        assert_eq!(
+            denver.from_local(datetime!(2024-11-03 01:30)),
+            datetime!(2024-11-03 08:30 UTC)
+        );
+        assert_eq!(
+            denver.to_local(datetime!(2024-07-01 18:00 UTC)),
+            datetime!(2024-07-01 12:00 -6)
+        );
+    }
+
+    #[test]
+    fn invalid_specs() {
+        assert_eq!(TimeZone::parse("Mars/Olympus_Mons"), None);
+        assert_eq!(TimeZone::parse("MST7MDT,M3.2.0"), None);
+        assert_eq!(TimeZone::parse("MST7MDT,M13.2.0,M11.1.0"), None);
+        assert_eq!(posix_for_utc_offset(-7), "<-07>7");
+        assert_eq!(
+            TimeZone::parse(&posix_for_utc_offset(-7))
+                .unwrap()
+                .offset_at(0)
+                .whole_hours(),
+            -7
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+// The units measurements are shown in.  The station measures and exports in imperial units, only the display converts.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    Imperial,
+    Metric,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    Temperature,
+    WindSpeed,
+    Rainfall,
+    Pressure,
+}
+
+impl Units {
+    pub const ALL: [Units; 2] = [Units::Imperial, Units::Metric];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Units::Imperial => "Imperial",
+            Units::Metric => "Metric",
+        }
+    }
+
+    // Convert a value measured in imperial units to these units
+    pub fn from_imperial(&self, quantity: Quantity, value: f32) -> f32 {
+        match (self, quantity) {
+            (Units::Imperial, _) => value,
+            (Units::Metric, Quantity::Temperature) => (value - 32.0) * 5.0 / 9.0,
+            (Units::Metric, Quantity::WindSpeed) => value * 1.609344,
+            (Units::Metric, Quantity::Rainfall) => value * 25.4,
+            (Units::Metric, Quantity::Pressure) => value * 33.863_89,
+        }
+    }
+
+    // Convert a value in these units back to imperial units
+    pub fn to_imperial(&self, quantity: Quantity, value: f32) -> f32 {
+        match (self, quantity) {
+            (Units::Imperial, _) => value,
+            (Units::Metric, Quantity::Temperature) => value * 9.0 / 5.0 + 32.0,
+            (Units::Metric, Quantity::WindSpeed) => value / 1.609344,
+            (Units::Metric, Quantity::Rainfall) => value / 25.4,
+            (Units::Metric, Quantity::Pressure) => value / 33.863_89,
+        }
+    }
+
+    pub fn symbol(&self, quantity: Quantity) -> &'static str {
+        match (self, quantity) {
+            (Units::Imperial, Quantity::Temperature) => "F",
+            (Units::Imperial, Quantity::WindSpeed) => "mph",
+            (Units::Imperial, Quantity::Rainfall) => "in",
+            (Units::Imperial, Quantity::Pressure) => " inHg",
+            (Units::Metric, Quantity::Temperature) => "C",
+            (Units::Metric, Quantity::WindSpeed) => "km/h",
+            (Units::Metric, Quantity::Rainfall) => "mm",
+            (Units::Metric, Quantity::Pressure) => " hPa",
+        }
+    }
+
+    // Digits after the decimal point shown for a quantity
+    pub fn decimals(&self, quantity: Quantity) -> usize {
+        match (self, quantity) {
+            (_, Quantity::Temperature) | (_, Quantity::WindSpeed) => 0,
+            (Units::Imperial, _) => 2,
+            (Units::Metric, _) => 1,
+        }
+    }
+
+    // A value measured in imperial units formatted in these units, e.g. "72F" or "22C"
+    pub fn format(&self, quantity: Quantity, value: f32) -> String {
+        format!(
+            "{:.*}{}",
+            self.decimals(quantity),
+            self.from_imperial(quantity, value),
+            self.symbol(quantity)
+        )
+    }
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units::Imperial
+    }
+}
@@ -1,3 +1,6 @@
+use std::thread::JoinHandle;
+use std::time::Instant;
+
 use crossbeam_channel::Sender;
 use debouncr::{debounce_4, Debouncer, Edge, Repeat4};
 use esp_idf_hal::{
@@ -5,34 +8,12 @@ use esp_idf_hal::{
     gpio::{AnyInputPin, Input, PinDriver},
 };
 
-use crate::model::peripherals::ButtonsPeripherals;
-
-#[derive(Debug, Copy, Clone)]
-pub enum BtnId {
-    Btn1,
-    Btn2,
-    Btn3,
-}
-
-pub enum BtnState {
-    Pressed,
-    Released,
-    Held,
-}
-
-#[allow(dead_code)]
-#[derive(PartialEq, PartialOrd)]
-pub enum UserBtnState {
-    Btn1Pressed,
-    Btn1Released,
-    Btn1Held,
-    Btn2Pressed,
-    Btn2Released,
-    Btn2Held,
-    Btn3Pressed,
-    Btn3Released,
-    Btn3Held,
-}
+use crate::model::{
+    button_events::{BtnId, ButtonEvents, ButtonTiming, UserBtnState},
+    peripherals::ButtonsPeripherals,
+    statistics::{increment, STATISTICS},
+    supervisor::Heartbeat,
+};
 
 pub struct UserButton {
     btn: PinDriver<'static, AnyInputPin, Input>,
@@ -41,84 +22,85 @@ pub struct UserButton {
 }
 
 impl UserButton {
-    pub fn button_status(&mut self) -> (BtnId, BtnState) {
-        let button_edge = self.btn_state.update(self.btn.is_low());
-
-        if button_edge == Some(Edge::Rising) {
-            (self.btn_id, BtnState::Pressed)
-        } else if button_edge == Some(Edge::Falling) {
-            (self.btn_id, BtnState::Released)
-        } else {
-            (self.btn_id, BtnState::Held)
+    fn new(btn: PinDriver<'static, AnyInputPin, Input>, btn_id: BtnId) -> Self {
+        Self {
+            btn,
+            btn_state: debounce_4(false),
+            btn_id,
         }
     }
+
+    // The debounced press or release of the button, if it changed
+    pub fn button_edge(&mut self) -> Option<Edge> {
+        self.btn_state.update(self.btn.is_low())
+    }
 }
 
 pub struct UserButtons {
     buttons: [UserButton; 3],
+    button_events: ButtonEvents,
     cbc_tx: Sender<UserBtnState>,
 }
 
 impl UserButtons {
-    pub fn new(buttons_peripherals: ButtonsPeripherals, cbc_tx: Sender<UserBtnState>) -> Self {
-        let btn1 = UserButton {
-            btn: PinDriver::input(buttons_peripherals.left_button).unwrap(),
-            btn_state: debounce_4(false),
-            btn_id: BtnId::Btn1,
-        };
-        let btn2 = UserButton {
-            btn: PinDriver::input(buttons_peripherals.middle_button).unwrap(),
-            btn_state: debounce_4(false),
-            btn_id: BtnId::Btn2,
-        };
-
-        let btn3 = UserButton {
-            btn: PinDriver::input(buttons_peripherals.right_button).unwrap(),
-            btn_state: debounce_4(false),
-            btn_id: BtnId::Btn3,
-        };
+    pub fn new(
+        buttons_peripherals: ButtonsPeripherals,
+        timing: ButtonTiming,
+        cbc_tx: Sender<UserBtnState>,
+    ) -> Self {
+        let btn1 = UserButton::new(
+            PinDriver::input(buttons_peripherals.left_button).unwrap(),
+            BtnId::Btn1,
+        );
+        let btn2 = UserButton::new(
+            PinDriver::input(buttons_peripherals.middle_button).unwrap(),
+            BtnId::Btn2,
+        );
+        let btn3 = UserButton::new(
+            PinDriver::input(buttons_peripherals.right_button).unwrap(),
+            BtnId::Btn3,
+        );
 
         Self {
             buttons: [btn1, btn2, btn3],
+            button_events: ButtonEvents::new(timing, Instant::now()),
             cbc_tx: (cbc_tx),
         }
     }
 
-    pub fn start(mut self) {
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
         // Start user button debounce thread
-        let _user_buttons_debounce_thread = std::thread::spawn(move || loop {
+        std::thread::spawn(move || loop {
             println!("User buttons debounce thread started");
 
             loop {
+                heartbeat.beat();
+                let now = Instant::now();
+
                 for btn in 0..self.buttons.len() {
-                    match self.buttons[btn].button_status() {
-                        (BtnId::Btn1, BtnState::Pressed) => {
-                            self.cbc_tx.send(UserBtnState::Btn1Pressed).unwrap()
-                        }
-                        (BtnId::Btn2, BtnState::Pressed) => {
-                            self.cbc_tx.send(UserBtnState::Btn2Pressed).unwrap()
-                        }
-                        (BtnId::Btn3, BtnState::Pressed) => {
-                            self.cbc_tx.send(UserBtnState::Btn3Pressed).unwrap()
-                        }
+                    let btn_id = self.buttons[btn].btn_id;
 
-                        (BtnId::Btn1, BtnState::Released) => {
-                            self.cbc_tx.send(UserBtnState::Btn1Released).unwrap()
-                        }
-                        (BtnId::Btn2, BtnState::Released) => {
-                            self.cbc_tx.send(UserBtnState::Btn2Released).unwrap()
-                        }
-                        (BtnId::Btn3, BtnState::Released) => {
-                            self.cbc_tx.send(UserBtnState::Btn3Released).unwrap()
+                    let events = match self.buttons[btn].button_edge() {
+                        Some(Edge::Rising) => self.button_events.press(btn_id, now),
+                        Some(Edge::Falling) => self.button_events.release(btn_id, now),
+                        None => continue,
+                    };
+
+                    for event in events {
+                        if self.cbc_tx.send(event).is_err() {
+                            increment(&STATISTICS.channel_send_failures);
                         }
+                    }
+                }
 
-                        // Don't care about held state - not implemented
-                        _ => (),
+                for event in self.button_events.tick(now) {
+                    if self.cbc_tx.send(event).is_err() {
+                        increment(&STATISTICS.channel_send_failures);
                     }
                 }
 
                 FreeRtos::delay_ms(20);
             }
-        });
+        })
     }
 }
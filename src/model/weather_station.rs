@@ -1,20 +1,22 @@
 use crate::model::{
     acurite5n1::{Acurite5n1Message, MessageHeader, MessageType1, MessageType8},
+    alerts::{AlertEvent, AlertsEngine},
+    config::{Config, SharedConfig},
+    history::{Sensor, SharedHistory},
+    indoor_sensors::{DetectedSensor, IndoorSample},
+    pressure::{MeanTemperature, PressureMode, PressureReduction},
     scheduler::TimeEvent,
+    statistics::{increment, STATISTICS},
+    supervisor::Heartbeat,
 };
 
-use crossbeam_channel::{Receiver, Sender};
-use esp_idf_hal::delay;
-use esp_idf_hal::i2c::I2cDriver;
-use shared_bus::I2cProxy;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 
-use bme280_rs::{Bme280, Configuration, Oversampling, SensorMode};
-use std::sync::Mutex;
-use std::thread;
-use std::time::Duration;
-
-const MY_ALTITUDE_METERS: &str = env!("MY_ALTITUDE_METERS");
+use std::collections::VecDeque;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+#[derive(Clone, Debug)]
 pub enum Measurement {
     BatteryStatus(String),
     ChannelNumber(u8),
@@ -22,6 +24,7 @@ pub enum Measurement {
     CurrentWindSpeedMph(u8),
     AverageWindSpeedMph(u8),
     PeakWindSpeedMph(u8),
+    WindGustMph(u8),
     WindDirection(String),
     DailyRainfall(f32),
     MonthlyRainfall(f32),
@@ -34,6 +37,220 @@ pub enum Measurement {
     IndoorTemperature(i16),
     IndoorHumidity(u8),
     IndoorPressure(f32),
+    SeaLevelPressure(f32),
+    IndoorCo2(u16),
+    IndoorGasResistance(f32),
+    IndoorAirQuality(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeasurementField {
+    BatteryStatus,
+    ChannelNumber,
+    ProductId,
+    CurrentWindSpeed,
+    AverageWindSpeed,
+    PeakWindSpeed,
+    WindGust,
+    WindDirection,
+    DailyRainfall,
+    MonthlyRainfall,
+    YearlyRainfall,
+    OutdoorTemperature,
+    OutdoorHumidity,
+    OutdoorHeatIndex,
+    OutdoorWindchill,
+    OutdoorDewpoint,
+    IndoorTemperature,
+    IndoorHumidity,
+    IndoorPressure,
+    SeaLevelPressure,
+    IndoorCo2,
+    IndoorGasResistance,
+    IndoorAirQuality,
+}
+
+impl MeasurementField {
+    pub const ALL: [MeasurementField; 23] = [
+        MeasurementField::BatteryStatus,
+        MeasurementField::ChannelNumber,
+        MeasurementField::ProductId,
+        MeasurementField::CurrentWindSpeed,
+        MeasurementField::AverageWindSpeed,
+        MeasurementField::PeakWindSpeed,
+        MeasurementField::WindGust,
+        MeasurementField::WindDirection,
+        MeasurementField::DailyRainfall,
+        MeasurementField::MonthlyRainfall,
+        MeasurementField::YearlyRainfall,
+        MeasurementField::OutdoorTemperature,
+        MeasurementField::OutdoorHumidity,
+        MeasurementField::OutdoorHeatIndex,
+        MeasurementField::OutdoorWindchill,
+        MeasurementField::OutdoorDewpoint,
+        MeasurementField::IndoorTemperature,
+        MeasurementField::IndoorHumidity,
+        MeasurementField::IndoorPressure,
+        MeasurementField::SeaLevelPressure,
+        MeasurementField::IndoorCo2,
+        MeasurementField::IndoorGasResistance,
+        MeasurementField::IndoorAirQuality,
+    ];
+
+    // The name used for this field in the json api and any other exporters
+    pub fn name(&self) -> &'static str {
+        match self {
+            MeasurementField::BatteryStatus => "battery_status",
+            MeasurementField::ChannelNumber => "channel_number",
+            MeasurementField::ProductId => "product_id",
+            MeasurementField::CurrentWindSpeed => "current_wind_speed",
+            MeasurementField::AverageWindSpeed => "average_wind_speed",
+            MeasurementField::PeakWindSpeed => "peak_wind_speed",
+            MeasurementField::WindGust => "wind_gust",
+            MeasurementField::WindDirection => "wind_direction",
+            MeasurementField::DailyRainfall => "daily_rainfall",
+            MeasurementField::MonthlyRainfall => "monthly_rainfall",
+            MeasurementField::YearlyRainfall => "yearly_rainfall",
+            MeasurementField::OutdoorTemperature => "outdoor_temperature",
+            MeasurementField::OutdoorHumidity => "outdoor_humidity",
+            MeasurementField::OutdoorHeatIndex => "outdoor_heat_index",
+            MeasurementField::OutdoorWindchill => "outdoor_windchill",
+            MeasurementField::OutdoorDewpoint => "outdoor_dewpoint",
+            MeasurementField::IndoorTemperature => "indoor_temperature",
+            MeasurementField::IndoorHumidity => "indoor_humidity",
+            MeasurementField::IndoorPressure => "indoor_pressure",
+            MeasurementField::SeaLevelPressure => "sea_level_pressure",
+            MeasurementField::IndoorCo2 => "indoor_co2",
+            MeasurementField::IndoorGasResistance => "indoor_gas_resistance",
+            MeasurementField::IndoorAirQuality => "indoor_air_quality",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            MeasurementField::CurrentWindSpeed
+            | MeasurementField::AverageWindSpeed
+            | MeasurementField::PeakWindSpeed
+            | MeasurementField::WindGust => "mph",
+
+            MeasurementField::DailyRainfall
+            | MeasurementField::MonthlyRainfall
+            | MeasurementField::YearlyRainfall => "in",
+
+            MeasurementField::OutdoorTemperature
+            | MeasurementField::OutdoorHeatIndex
+            | MeasurementField::OutdoorWindchill
+            | MeasurementField::OutdoorDewpoint
+            | MeasurementField::IndoorTemperature => "F",
+
+            MeasurementField::OutdoorHumidity
+            | MeasurementField::IndoorHumidity
+            | MeasurementField::IndoorAirQuality => "%",
+
+            MeasurementField::IndoorPressure | MeasurementField::SeaLevelPressure => "inHg",
+
+            MeasurementField::IndoorCo2 => "ppm",
+
+            MeasurementField::IndoorGasResistance => "kOhm",
+
+            MeasurementField::BatteryStatus
+            | MeasurementField::ChannelNumber
+            | MeasurementField::ProductId
+            | MeasurementField::WindDirection => "",
+        }
+    }
+
+    // The fields measured by the indoor sensors on the I2C bus, the others come from the Acurite5n1
+    pub fn is_indoor(&self) -> bool {
+        matches!(
+            self,
+            MeasurementField::IndoorTemperature
+                | MeasurementField::IndoorHumidity
+                | MeasurementField::IndoorPressure
+                | MeasurementField::SeaLevelPressure
+                | MeasurementField::IndoorCo2
+                | MeasurementField::IndoorGasResistance
+                | MeasurementField::IndoorAirQuality
+        )
+    }
+
+    pub fn from_name(name: &str) -> Option<MeasurementField> {
+        MeasurementField::ALL
+            .iter()
+            .find(|field| field.name() == name)
+            .copied()
+    }
+}
+
+impl Measurement {
+    pub fn field(&self) -> MeasurementField {
+        match self {
+            Measurement::BatteryStatus(_) => MeasurementField::BatteryStatus,
+            Measurement::ChannelNumber(_) => MeasurementField::ChannelNumber,
+            Measurement::ProductId(_) => MeasurementField::ProductId,
+            Measurement::CurrentWindSpeedMph(_) => MeasurementField::CurrentWindSpeed,
+            Measurement::AverageWindSpeedMph(_) => MeasurementField::AverageWindSpeed,
+            Measurement::PeakWindSpeedMph(_) => MeasurementField::PeakWindSpeed,
+            Measurement::WindGustMph(_) => MeasurementField::WindGust,
+            Measurement::WindDirection(_) => MeasurementField::WindDirection,
+            Measurement::DailyRainfall(_) => MeasurementField::DailyRainfall,
+            Measurement::MonthlyRainfall(_) => MeasurementField::MonthlyRainfall,
+            Measurement::YearlyRainfall(_) => MeasurementField::YearlyRainfall,
+            Measurement::OutdoorTemperature(_) => MeasurementField::OutdoorTemperature,
+            Measurement::OutdoorHumidity(_) => MeasurementField::OutdoorHumidity,
+            Measurement::OutdoorHeatIndex(_) => MeasurementField::OutdoorHeatIndex,
+            Measurement::OutdoorWindchill(_) => MeasurementField::OutdoorWindchill,
+            Measurement::OutdoorDewpoint(_) => MeasurementField::OutdoorDewpoint,
+            Measurement::IndoorTemperature(_) => MeasurementField::IndoorTemperature,
+            Measurement::IndoorHumidity(_) => MeasurementField::IndoorHumidity,
+            Measurement::IndoorPressure(_) => MeasurementField::IndoorPressure,
+            Measurement::SeaLevelPressure(_) => MeasurementField::SeaLevelPressure,
+            Measurement::IndoorCo2(_) => MeasurementField::IndoorCo2,
+            Measurement::IndoorGasResistance(_) => MeasurementField::IndoorGasResistance,
+            Measurement::IndoorAirQuality(_) => MeasurementField::IndoorAirQuality,
+        }
+    }
+
+    // Returns the value as a number, text measurements such as the wind direction return None
+    pub fn numeric_value(&self) -> Option<f32> {
+        match self {
+            Measurement::BatteryStatus(_) | Measurement::WindDirection(_) => None,
+            Measurement::ChannelNumber(value) => Some(*value as f32),
+            Measurement::ProductId(value) => Some(*value as f32),
+            Measurement::CurrentWindSpeedMph(value)
+            | Measurement::AverageWindSpeedMph(value)
+            | Measurement::PeakWindSpeedMph(value)
+            | Measurement::WindGustMph(value)
+            | Measurement::OutdoorHumidity(value)
+            | Measurement::IndoorHumidity(value)
+            | Measurement::IndoorAirQuality(value) => Some(*value as f32),
+            Measurement::IndoorCo2(value) => Some(*value as f32),
+            Measurement::DailyRainfall(value)
+            | Measurement::MonthlyRainfall(value)
+            | Measurement::YearlyRainfall(value)
+            | Measurement::IndoorPressure(value)
+            | Measurement::SeaLevelPressure(value)
+            | Measurement::IndoorGasResistance(value) => Some(*value),
+            Measurement::OutdoorTemperature(value)
+            | Measurement::OutdoorHeatIndex(value)
+            | Measurement::OutdoorWindchill(value)
+            | Measurement::OutdoorDewpoint(value)
+            | Measurement::IndoorTemperature(value) => Some(*value as f32),
+        }
+    }
+}
+
+// Converts a compass point such as "NNE" to degrees, returns None for an unknown compass point
+pub fn compass_point_to_degrees(compass_point: &str) -> Option<f32> {
+    const COMPASS_POINTS: [&'static str; 16] = [
+        "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW",
+        "NW", "NNW",
+    ];
+
+    COMPASS_POINTS
+        .iter()
+        .position(|point| *point == compass_point)
+        .map(|index| index as f32 * 22.5)
 }
 
 #[derive(Default)]
@@ -43,11 +260,46 @@ pub struct LastRainfall {
     pub yearly: f32,
 }
 
+/**
+ * The gust is the highest wind speed of the last 5 minutes, the period APRS asks for, Weather Underground leaves the
+ * period to the station.  Every wind speed the Acurite5n1 sends is kept for the period, not only the changes, so a
+ * steady wind is not dropped from it.
+ */
+#[derive(Default)]
+pub struct WindGust {
+    samples: VecDeque<(Instant, u8)>,
+}
+
+impl WindGust {
+    pub const WINDOW: Duration = Duration::from_secs(5 * 60);
+
+    // Returns the gust including the new wind speed
+    pub fn record(&mut self, now: Instant, wind_speed_mph: u8) -> u8 {
+        while let Some((received, _)) = self.samples.front() {
+            if now.duration_since(*received) <= Self::WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back((now, wind_speed_mph));
+
+        self.samples
+            .iter()
+            .map(|(_, mph)| *mph)
+            .max()
+            .unwrap_or(wind_speed_mph)
+    }
+}
+
 #[derive(Default)]
 pub struct LastIndoorSample {
     pub temperaturex10: i16,
     pub humidityx10: u16,
     pub pressurex10: u32,
+    pub co2_ppm: u16,
+    pub gas_resistance_ohms: u32,
+    pub air_quality: u8,
 }
 
 pub struct HeatIndex {
@@ -80,7 +332,7 @@ pub struct IndoorTemperature {
 pub struct LastRawMeasurement {
     channel: u8,
     prodcut_id: u16,
-    status: u8,
+    battery_low: Option<bool>,
     wind_speed: u8,
     wind_direction: u8,
     rain_bucket_tips: u16,
@@ -93,7 +345,7 @@ impl Default for LastRawMeasurement {
         Self {
             channel: u8::MAX,
             prodcut_id: 0,
-            status: u8::MAX,
+            battery_low: None,
             wind_speed: u8::MAX,
             wind_direction: 0,
             rain_bucket_tips: u16::MAX,
@@ -104,101 +356,190 @@ impl Default for LastRawMeasurement {
 }
 
 pub struct WeatherStation {
-    //i2c_proxy: I2cProxy<'a, Mutex<I2cDriver<'static>>>,
-    bme280: Bme280<I2cProxy<'static, Mutex<I2cDriver<'static>>>, delay::Ets>,
+    indoor_sensors: Vec<DetectedSensor>,
     rx1: Receiver<Acurite5n1Message>, // Receive from Acurite5n1
     rx2: Receiver<TimeEvent>,         // Receive from Scheduler
-    tx1: Sender<Measurement>,
+    rx3: Receiver<f32>,               // Receive pressure references in hPa from Gui
+    tx1: Sender<Measurement>,         // Send to Gui
+    tx2: Sender<Measurement>,         // Send to MqttPublisher
+    tx3: Sender<AlertEvent>,          // Send to Gui
+    history: SharedHistory,
+    config: SharedConfig,
     last_raw_measurement: LastRawMeasurement,
     last_peak_wind_speed_mph: u8,
+    wind_gust: WindGust,
+    last_wind_gust_mph: Option<u8>,
     last_rainfall: LastRainfall,
     collected_wind_speeds_mph: Vec<u8>,
     last_indoor_sample: LastIndoorSample,
+    // The pressure the indoor sensor measured last in hPa, before the calibration offset is added
+    last_station_hpa: Option<f32>,
+    // The sea level pressure is reduced with the mean outdoor temperature of the last 12 hours
+    outdoor_temperature_mean: MeanTemperature,
+    alerts: AlertsEngine,
 }
 
 impl WeatherStation {
     pub fn new(
-        i2c_proxy: I2cProxy<'static, Mutex<I2cDriver<'static>>>,
+        indoor_sensors: Vec<DetectedSensor>,
         rx1: Receiver<Acurite5n1Message>,
         rx2: Receiver<TimeEvent>,
+        rx3: Receiver<f32>,
         tx1: Sender<Measurement>,
+        tx2: Sender<Measurement>,
+        tx3: Sender<AlertEvent>,
+        history: SharedHistory,
+        config: SharedConfig,
     ) -> Self {
         Self {
-            //i2c_proxy,
-            bme280: Bme280::new(i2c_proxy, delay::Ets),
+            indoor_sensors,
             rx1,
             rx2,
+            rx3,
             tx1,
+            tx2,
+            tx3,
+            history,
+            config,
             last_raw_measurement: LastRawMeasurement::default(),
             last_peak_wind_speed_mph: 0,
+            wind_gust: WindGust::default(),
+            last_wind_gust_mph: None,
             last_rainfall: LastRainfall::default(),
             collected_wind_speeds_mph: Vec::new(),
             last_indoor_sample: LastIndoorSample::default(),
+            last_station_hpa: None,
+            outdoor_temperature_mean: MeanTemperature::default(),
+            alerts: AlertsEngine::new(Instant::now()),
         }
     }
 
-    pub fn start(mut self) {
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
         println!("Starting WeatherStation Thread");
 
-        self.bme280.init().expect("Bme280 not connected?????");
-        self.bme280
-            .set_sampling_configuration(
-                Configuration::default()
-                    .with_temperature_oversampling(Oversampling::Oversample1)
-                    .with_pressure_oversampling(Oversampling::Oversample8)
-                    .with_humidity_oversampling(Oversampling::Oversample1)
-                    .with_sensor_mode(SensorMode::Normal),
-            )
-            .expect("Failed to configure bme280");
-
-        // my_elevation value was determined from using "My Elevation" app on android phone at my location.
-        let my_elevation: f32 = MY_ALTITUDE_METERS.parse().unwrap();
-
-        let _weather_station_thread =
-            std::thread::Builder::new()
-                .stack_size(5000)
-                .spawn(move || loop {
-                    while let Ok(time_event) = self.rx2.try_recv() {
-                        match time_event {
-                            TimeEvent::TwoMinutesElapsed => {
-                                self.update_average_wind_speed();
-                                self.process_bme280(my_elevation);
-                            }
-
-                            TimeEvent::OneHourElapsed => self.last_peak_wind_speed_mph = 0,
-
-                            TimeEvent::NewDay => {
-                                self.last_rainfall.daily = 0.0;
-                                self.tx1
-                                    .send(Measurement::DailyRainfall(0.0 as f32))
-                                    .unwrap();
-                            }
-
-                            TimeEvent::NewMonth => {
-                                self.last_rainfall.monthly = 0.0;
-                                self.tx1
-                                    .send(Measurement::MonthlyRainfall(0.0 as f32))
-                                    .unwrap();
-                            }
-
-                            TimeEvent::NewYear => {
-                                self.last_rainfall.yearly = 0.0;
-                                self.tx1
-                                    .send(Measurement::YearlyRainfall(0.0 as f32))
-                                    .unwrap();
-                            }
+        {
+            let mut history = self.history.lock().unwrap();
+            for indoor_sensor in self.indoor_sensors.iter() {
+                history.sensor_detected(indoor_sensor.driver.sensor(), &indoor_sensor.fields);
+            }
+        }
+
+        if self.indoor_sensors.is_empty() {
+            println!("No indoor sensors found, the indoor measurements will not be reported");
+        }
+
+        std::thread::Builder::new()
+            .stack_size(5000)
+            .spawn(move || loop {
+                heartbeat.beat();
+                let loop_started = Instant::now();
+
+                while let Ok(time_event) = self.rx2.try_recv() {
+                    match time_event {
+                        TimeEvent::TwoMinutesElapsed => {
+                            self.update_average_wind_speed();
+                            self.process_indoor_sensors();
                         }
-                    }
 
-                    if let Ok(message) = self.rx1.try_recv() {
-                        self.process_message(message);
+                        TimeEvent::OneHourElapsed => self.last_peak_wind_speed_mph = 0,
+
+                        TimeEvent::NewDay => {
+                            self.last_rainfall.daily = 0.0;
+                            self.send_measurement(Measurement::DailyRainfall(0.0 as f32));
+                        }
+
+                        TimeEvent::NewMonth => {
+                            self.last_rainfall.monthly = 0.0;
+                            self.send_measurement(Measurement::MonthlyRainfall(0.0 as f32));
+                        }
+
+                        TimeEvent::NewYear => {
+                            self.last_rainfall.yearly = 0.0;
+                            self.send_measurement(Measurement::YearlyRainfall(0.0 as f32));
+                        }
                     }
+                }
+
+                if let Ok(message) = self.rx1.try_recv() {
+                    self.process_message(message);
+                }
+
+                if let Ok(reference_hpa) = self.rx3.try_recv() {
+                    self.calibrate_pressure(reference_hpa);
+                }
+
+                let alerts_config = self.config.lock().unwrap().config().alerts.clone();
+                let events = self.alerts.check(&alerts_config, Instant::now());
+                self.send_alert_events(events);
+
+                STATISTICS
+                    .weather_station_loop
+                    .record(loop_started.elapsed());
+
+                thread::sleep(Duration::from_secs(1));
+            })
+            .unwrap()
+    }
+
+    // Record the measurement in the history store, check it against the alerts and forward it to the gui and the mqtt
+    // publisher
+    fn send_measurement(&mut self, measurement: Measurement) {
+        self.history.lock().unwrap().record(&measurement);
+
+        let alerts_config = self.config.lock().unwrap().config().alerts.clone();
+        let events = self
+            .alerts
+            .measurement(&alerts_config, &measurement, Instant::now());
+        self.send_alert_events(events);
+
+        // The mqtt publisher may be disconnected or not configured, it republishes the latest values from
+        // the history store when it connects so it is ok to drop measurements when its channel is full
+        if let Err(TrySendError::Full(_)) = self.tx2.try_send(measurement.clone()) {
+            increment(&STATISTICS.channel_send_failures);
+        }
+
+        // The gui only goes away when its thread died, the weather station keeps recording without it
+        if self.tx1.send(measurement).is_err() {
+            increment(&STATISTICS.channel_send_failures);
+        }
+    }
+
+    // The exporters read the active alerts from the history store, the gui shows them in a banner
+    fn send_alert_events(&self, events: Vec<AlertEvent>) {
+        for event in events {
+            match event {
+                AlertEvent::Raised(alert) => {
+                    println!("Alert {} raised at {}", alert.kind.name(), alert.value);
+                    self.history.lock().unwrap().alert_raised(alert);
+                }
+                AlertEvent::Cleared(kind) => {
+                    println!("Alert {} cleared", kind.name());
+                    self.history.lock().unwrap().alert_cleared(kind);
+                }
+            }
 
-                    thread::sleep(Duration::from_secs(1));
-                });
+            if self.tx3.send(event).is_err() {
+                increment(&STATISTICS.channel_send_failures);
+            }
+        }
     }
 
     fn process_message(&mut self, message: Acurite5n1Message) {
+        // Once a sensor is paired the messages of any other sensor in range are dropped
+        let product_id = match &message {
+            Acurite5n1Message::Type1(message) => message.header.product_id,
+            Acurite5n1Message::Type8(message) => message.header.product_id,
+        };
+        let paired_product_id = self.config.lock().unwrap().config().sensor.product_id;
+
+        if paired_product_id.map_or(false, |paired| paired != product_id) {
+            increment(&STATISTICS.unpaired_sensor_messages);
+            return;
+        }
+
+        self.history.lock().unwrap().sensor_seen(Sensor::Acurite5n1);
+        self.alerts.sensor_seen(Instant::now());
+
         match message {
             Acurite5n1Message::Type1(MessageType1 {
                 header,
@@ -207,6 +548,7 @@ impl WeatherStation {
                 rain_bucket_tips,
             }) => {
                 self.process_header(header);
+                self.process_wind_gust(wind_speed);
                 self.process_wind_speed(wind_speed);
                 self.process_wind_direction(wind_direction);
                 self.process_rain_bucket_tips(rain_bucket_tips);
@@ -219,6 +561,7 @@ impl WeatherStation {
                 temperature,
             }) => {
                 self.process_header(header);
+                self.process_wind_gust(wind_speed);
                 self.process_wind_speed(wind_speed);
                 self.process_temperature_humidity_wind_speed(temperature, humidity, wind_speed);
             }
@@ -228,32 +571,30 @@ impl WeatherStation {
     fn process_header(&mut self, header: MessageHeader) {
         if header.channel_number != self.last_raw_measurement.channel {
             self.last_raw_measurement.channel = header.channel_number;
-            self.tx1
-                .send(Measurement::ChannelNumber(header.channel_number))
-                .unwrap();
+            self.send_measurement(Measurement::ChannelNumber(header.channel_number));
         }
 
         if header.product_id != self.last_raw_measurement.prodcut_id {
             self.last_raw_measurement.prodcut_id = header.product_id;
-            self.tx1
-                .send(Measurement::ProductId(header.product_id))
-                .unwrap();
+            self.send_measurement(Measurement::ProductId(header.product_id));
         }
 
-        if header.status != self.last_raw_measurement.status {
-            self.last_raw_measurement.status = header.status;
-
-            let battery_status: &str;
+        // The parity bit changes with the rest of the byte, only a change of the battery flag is a new status
+        if Some(header.status.battery_low) != self.last_raw_measurement.battery_low {
+            self.last_raw_measurement.battery_low = Some(header.status.battery_low);
 
-            if header.status == 7 {
-                battery_status = "OK";
+            let battery_status = if header.status.battery_low {
+                "REPLACE"
             } else {
-                battery_status = "REPLACE";
-            }
+                "OK"
+            };
+
+            self.history
+                .lock()
+                .unwrap()
+                .battery_status(header.status.battery_low);
 
-            self.tx1
-                .send(Measurement::BatteryStatus(battery_status.to_string()))
-                .unwrap();
+            self.send_measurement(Measurement::BatteryStatus(battery_status.to_string()));
         }
     }
 
@@ -269,25 +610,30 @@ impl WeatherStation {
             // Check if peak wind speed need to be updated
             if current_wind_speed_mph > self.last_peak_wind_speed_mph {
                 self.last_peak_wind_speed_mph = current_wind_speed_mph;
-                self.tx1
-                    .send(Measurement::PeakWindSpeedMph(current_wind_speed_mph))
-                    .unwrap();
+                self.send_measurement(Measurement::PeakWindSpeedMph(current_wind_speed_mph));
             }
 
-            self.tx1
-                .send(Measurement::CurrentWindSpeedMph(current_wind_speed_mph))
-                .unwrap();
+            self.send_measurement(Measurement::CurrentWindSpeedMph(current_wind_speed_mph));
+        }
+    }
+
+    // The peak wind speed is the highest of the hour for the display, the exporters send the gust
+    fn process_wind_gust(&mut self, wind_speed: u8) {
+        let wind_speed_mph = self.convert_raw_wind_speed(wind_speed);
+        let wind_gust_mph = self.wind_gust.record(Instant::now(), wind_speed_mph);
+
+        if self.last_wind_gust_mph != Some(wind_gust_mph) {
+            self.last_wind_gust_mph = Some(wind_gust_mph);
+            self.send_measurement(Measurement::WindGustMph(wind_gust_mph));
         }
     }
 
     fn process_wind_direction(&mut self, wind_direction: u8) {
         if self.last_raw_measurement.wind_direction != wind_direction {
             self.last_raw_measurement.wind_direction = wind_direction;
-            self.tx1
-                .send(Measurement::WindDirection(
-                    self.convert_raw_wind_direction(wind_direction),
-                ))
-                .unwrap();
+            self.send_measurement(Measurement::WindDirection(
+                self.convert_raw_wind_direction(wind_direction),
+            ));
         }
     }
 
@@ -306,19 +652,13 @@ impl WeatherStation {
             self.last_raw_measurement.rain_bucket_tips = rain_bucket_tips;
 
             self.last_rainfall.daily += rainfall;
-            self.tx1
-                .send(Measurement::DailyRainfall(self.last_rainfall.daily))
-                .unwrap();
+            self.send_measurement(Measurement::DailyRainfall(self.last_rainfall.daily));
 
             self.last_rainfall.monthly += rainfall;
-            self.tx1
-                .send(Measurement::MonthlyRainfall(self.last_rainfall.monthly))
-                .unwrap();
+            self.send_measurement(Measurement::MonthlyRainfall(self.last_rainfall.monthly));
 
             self.last_rainfall.yearly += rainfall;
-            self.tx1
-                .send(Measurement::YearlyRainfall(self.last_rainfall.yearly))
-                .unwrap();
+            self.send_measurement(Measurement::YearlyRainfall(self.last_rainfall.yearly));
         }
     }
 
@@ -328,10 +668,20 @@ impl WeatherStation {
         current_humidity: u8,
         raw_wind_speed: u8,
     ) {
+        let calibration = self.config.lock().unwrap().config().calibration.clone();
         let current_wind_speed_mph = self.convert_raw_wind_speed(raw_wind_speed);
-        let current_temperature_deg_f = self.convert_raw_temperature(raw_temperature);
+        let current_temperature_deg_f = self.convert_raw_temperature(raw_temperature)
+            + calibration.outdoor_temperature_offset_f;
+        // The dew point takes the log of the humidity so it is kept above 0
+        let current_humidity = (current_humidity as f32 + calibration.outdoor_humidity_offset)
+            .round()
+            .clamp(1.0, 100.0) as u8;
         let mut humidity_changed = false;
         let mut temperature_changed = false;
+
+        self.outdoor_temperature_mean
+            .record(Instant::now(), current_temperature_deg_f);
+
         let mut wind_speed_changed = false;
 
         // Check if temperature changed
@@ -339,12 +689,10 @@ impl WeatherStation {
             temperature_changed = true;
             self.last_raw_measurement.temperature = raw_temperature;
 
-            self.tx1
-                .send(Measurement::OutdoorTemperature(
-                    self.calculate_outdoor_temperature(raw_temperature)
-                        .fahrenheit,
-                ))
-                .unwrap();
+            self.send_measurement(Measurement::OutdoorTemperature(
+                self.calculate_outdoor_temperature(current_temperature_deg_f)
+                    .fahrenheit,
+            ));
         }
 
         // Check if humidity changed
@@ -352,9 +700,7 @@ impl WeatherStation {
             humidity_changed = true;
             self.last_raw_measurement.humidity = current_humidity;
 
-            self.tx1
-                .send(Measurement::OutdoorHumidity(current_humidity))
-                .unwrap();
+            self.send_measurement(Measurement::OutdoorHumidity(current_humidity));
         }
 
         // Check if wind speed changed
@@ -367,96 +713,211 @@ impl WeatherStation {
 
             if current_wind_speed_mph > self.last_peak_wind_speed_mph {
                 self.last_peak_wind_speed_mph = current_wind_speed_mph;
-                self.tx1
-                    .send(Measurement::PeakWindSpeedMph(current_wind_speed_mph))
-                    .unwrap();
+                self.send_measurement(Measurement::PeakWindSpeedMph(current_wind_speed_mph));
             }
 
-            self.tx1
-                .send(Measurement::CurrentWindSpeedMph(current_wind_speed_mph))
-                .unwrap();
+            self.send_measurement(Measurement::CurrentWindSpeedMph(current_wind_speed_mph));
         }
 
         // Check if heat index and dew point need to be updated
         if temperature_changed || humidity_changed {
-            self.tx1
-                .send(Measurement::OutdoorHeatIndex(
-                    self.calculate_heat_index(current_temperature_deg_f, current_humidity)
-                        .fahrenheit,
-                ))
-                .unwrap();
-
-            self.tx1
-                .send(Measurement::OutdoorDewpoint(
-                    self.calculate_dew_point(current_temperature_deg_f, current_humidity)
-                        .fahrenheit,
-                ))
-                .unwrap();
+            self.send_measurement(Measurement::OutdoorHeatIndex(
+                self.calculate_heat_index(current_temperature_deg_f, current_humidity)
+                    .fahrenheit,
+            ));
+
+            self.send_measurement(Measurement::OutdoorDewpoint(
+                self.calculate_dew_point(current_temperature_deg_f, current_humidity)
+                    .fahrenheit,
+            ));
         }
 
         // Check if wind chill needs to be updated
         if wind_speed_changed || temperature_changed {
             if current_wind_speed_mph > 3 && current_temperature_deg_f < 40.0 {
-                self.tx1
-                    .send(Measurement::OutdoorWindchill(
-                        self.calculate_wind_chill(
-                            current_wind_speed_mph,
-                            current_temperature_deg_f,
-                        )
+                self.send_measurement(Measurement::OutdoorWindchill(
+                    self.calculate_wind_chill(current_wind_speed_mph, current_temperature_deg_f)
                         .fahrenheit,
-                    ))
-                    .unwrap();
+                ));
             } else {
-                self.tx1
-                    .send(Measurement::OutdoorWindchill(
-                        current_temperature_deg_f as i16,
-                    ))
-                    .unwrap();
+                self.send_measurement(Measurement::OutdoorWindchill(
+                    current_temperature_deg_f as i16,
+                ));
             }
         }
     }
 
-    fn process_bme280(&mut self, my_elevation: f32) {
-        if let (Some(t), Some(p), Some(h)) = self.bme280.read_sample().unwrap() {
-            //println!("T= {:.2}  H = {:.2}, P = {:.2}", t, h, p);
-
-            if self.last_indoor_sample.temperaturex10 != (t * 10.0) as i16 {
-                self.last_indoor_sample.temperaturex10 = (t * 10.0) as i16;
-                self.tx1
-                    .send(Measurement::IndoorTemperature(self.convert_c_to_f(t) as i16))
-                    .unwrap();
+    // Read every indoor sensor, each reports the fields it was given when the sensors were detected.  A sensor that
+    // fails is skipped, it raises a fault and is re-initialised by its next reads
+    fn process_indoor_sensors(&mut self) {
+        let mut samples: Vec<(Sensor, Vec<MeasurementField>, IndoorSample)> = Vec::new();
+
+        for indoor_sensor in self.indoor_sensors.iter_mut() {
+            if let Some(sample) = indoor_sensor.read() {
+                samples.push((
+                    indoor_sensor.driver.sensor(),
+                    indoor_sensor.fields.clone(),
+                    sample,
+                ));
             }
+        }
+
+        for (sensor, fields, sample) in samples {
+            self.history.lock().unwrap().sensor_seen(sensor);
+            self.process_indoor_sample(&fields, sample);
+        }
+    }
+
+    fn process_indoor_sample(&mut self, fields: &[MeasurementField], sample: IndoorSample) {
+        //println!("{:?}", sample);
+
+        let calibration = self.config.lock().unwrap().config().calibration.clone();
+
+        for field in fields {
+            match field {
+                MeasurementField::IndoorTemperature => {
+                    if let Some(t) = sample.temperature_c {
+                        let t = t + calibration.indoor_temperature_offset_f / 1.8;
+
+                        if self.last_indoor_sample.temperaturex10 != (t * 10.0) as i16 {
+                            self.last_indoor_sample.temperaturex10 = (t * 10.0) as i16;
+                            self.send_measurement(Measurement::IndoorTemperature(
+                                self.convert_c_to_f(t) as i16,
+                            ));
+                        }
+                    }
+                }
+
+                MeasurementField::IndoorHumidity => {
+                    if let Some(h) = sample.humidity {
+                        let h = (h + calibration.indoor_humidity_offset).clamp(0.0, 100.0);
 
-            if self.last_indoor_sample.humidityx10 != (h * 10.0) as u16 {
-                self.last_indoor_sample.humidityx10 = (h * 10.0) as u16;
-                self.tx1.send(Measurement::IndoorHumidity(h as u8)).unwrap();
+                        if self.last_indoor_sample.humidityx10 != (h * 10.0) as u16 {
+                            self.last_indoor_sample.humidityx10 = (h * 10.0) as u16;
+                            self.send_measurement(Measurement::IndoorHumidity(h as u8));
+                        }
+                    }
+                }
+
+                MeasurementField::IndoorPressure => {
+                    if let Some(p) = sample.pressure_pa {
+                        if self.last_indoor_sample.pressurex10 != (p * 10.0) as u32 {
+                            self.last_indoor_sample.pressurex10 = (p * 10.0) as u32;
+
+                            self.last_station_hpa = Some(p / 100.0);
+                            self.report_pressure();
+                        }
+                    }
+                }
+
+                MeasurementField::IndoorCo2 => {
+                    if let Some(co2_ppm) = sample.co2_ppm {
+                        if self.last_indoor_sample.co2_ppm != co2_ppm {
+                            self.last_indoor_sample.co2_ppm = co2_ppm;
+                            self.send_measurement(Measurement::IndoorCo2(co2_ppm));
+                        }
+                    }
+                }
+
+                // The gas resistance is reported in kOhm, it is compared to the ohm to skip unchanged readings
+                MeasurementField::IndoorGasResistance => {
+                    if let Some(ohms) = sample.gas_resistance_ohms {
+                        if self.last_indoor_sample.gas_resistance_ohms != ohms as u32 {
+                            self.last_indoor_sample.gas_resistance_ohms = ohms as u32;
+                            self.send_measurement(Measurement::IndoorGasResistance(ohms / 1000.0));
+                        }
+                    }
+                }
+
+                MeasurementField::IndoorAirQuality => {
+                    if let Some(air_quality) = sample.air_quality {
+                        if self.last_indoor_sample.air_quality != air_quality as u8 {
+                            self.last_indoor_sample.air_quality = air_quality as u8;
+                            self.send_measurement(Measurement::IndoorAirQuality(air_quality as u8));
+                        }
+                    }
+                }
+
+                _ => (),
             }
+        }
+    }
 
-            if self.last_indoor_sample.pressurex10 != (p * 10.0) as u32 {
-                self.last_indoor_sample.pressurex10 = (p * 10.0) as u32;
+    /**
+     * Report the pressure last measured with the calibration offset added, reduced as the config asks.  The sea level
+     * pressure is reported as well whatever the pressure mode is, it is what the PWS and CWOP uploads expect.
+     */
+    fn report_pressure(&mut self) {
+        let station_hpa = match self.last_station_hpa {
+            Some(station_hpa) => station_hpa,
+            None => return,
+        };
+
+        let (reduction, pressure_offset_hpa) = {
+            let config_store = self.config.lock().unwrap();
+            let config = config_store.config();
+
+            (
+                self.pressure_reduction(config),
+                config.calibration.pressure_offset_hpa,
+            )
+        };
 
-                let atmospheric = p / 100.0;
+        let reported_hpa = reduction.reduce(station_hpa + pressure_offset_hpa);
+        let sea_level_hpa = PressureReduction {
+            mode: PressureMode::SeaLevel,
+            ..reduction
+        }
+        .reduce(station_hpa + pressure_offset_hpa);
 
-                // This formula from https://github.com/adafruit/Adafruit_BME280_Library/blob/master/Adafruit_BME280.cpp line 465.
-                let sea_level_hpa = atmospheric / f32::powf(1.0 - (my_elevation / 44330.0), 5.255);
+        //println!("station hpa = {:.2}", station_hpa);
+        //println!("reported hpa = {:.2}", reported_hpa);
 
-                // The hpa_offset is a correction factor to obtain the correct sea level value.  The barometer reading at the
-                // airport was used as a gold standard. Converted the airport inHg reading to hPa from
-                // https://www.justintools.com/unit-conversion/pressure.php?k1=hectopascals&k2=inch-of-mercury
-                // Took my sea_level_hpa reading and subtracted from the airport hpa reading to determine the offset needed.
-                let hpa_offset = 1.0;
-                let sea_level_hpa_compensated = sea_level_hpa - hpa_offset;
+        self.send_measurement(Measurement::IndoorPressure(
+            self.convert_hpa_to_inHg(reported_hpa),
+        ));
+        self.send_measurement(Measurement::SeaLevelPressure(
+            self.convert_hpa_to_inHg(sea_level_hpa),
+        ));
+    }
 
-                //println!("atmospheric = {:.2}", atmospheric);
-                //println!("sea level hpa = {}", sea_level_hpa);
-                //println!("sea level hpa compensated = {}", sea_level_hpa_compensated);
+    // The altitude is read from the config each time so a change applies without a restart
+    fn pressure_reduction(&self, config: &Config) -> PressureReduction {
+        PressureReduction {
+            mode: config.calibration.pressure_mode,
+            elevation_meters: config.station.altitude_meters,
+            mean_temperature_f: self.outdoor_temperature_mean.mean(Instant::now()),
+        }
+    }
 
-                self.tx1
-                    .send(Measurement::IndoorPressure(
-                        self.convert_hpa_to_inHg(sea_level_hpa_compensated),
-                    ))
-                    .unwrap();
+    /**
+     * The calibration wizard of the settings menu sends the reading of a reference barometer, e.g. the pressure the
+     * nearest airport reports, in the pressure mode the station reports in.  The offset that makes the station report
+     * the same reading is saved to the config.
+     */
+    fn calibrate_pressure(&mut self, reference_hpa: f32) {
+        let station_hpa = match self.last_station_hpa {
+            Some(station_hpa) => station_hpa,
+            None => {
+                println!("No pressure has been measured yet, the calibration is skipped");
+                return;
             }
+        };
+
+        let result = {
+            let mut config_store = self.config.lock().unwrap();
+            let mut config = config_store.config().clone();
+
+            let reduction = self.pressure_reduction(&config);
+            let offset = reduction.calibration_offset(reference_hpa, station_hpa);
+            config.calibration.pressure_offset_hpa = (offset * 100.0).round() / 100.0;
+
+            config_store.update(config)
+        };
+
+        match result {
+            Ok(_) => self.report_pressure(),
+            Err(e) => println!("Failed to save the pressure calibration {}", e),
         }
     }
 
@@ -501,9 +962,7 @@ impl WeatherStation {
         String::from(DIRECTION[wind_direction as usize])
     }
 
-    fn calculate_outdoor_temperature(&self, raw_temperature: u16) -> OutdoorTemperature {
-        let current_temperature_deg_f = self.convert_raw_temperature(raw_temperature);
-
+    fn calculate_outdoor_temperature(&self, current_temperature_deg_f: f32) -> OutdoorTemperature {
         OutdoorTemperature {
             fahrenheit: (current_temperature_deg_f as i16),
             celsius: (self.convert_f_to_c(current_temperature_deg_f) as i16),
@@ -583,8 +1042,6 @@ impl WeatherStation {
             avg_wind_speed = wind_speeds_mph_sum / items_collected;
         }
 
-        self.tx1
-            .send(Measurement::AverageWindSpeedMph((avg_wind_speed) as u8))
-            .unwrap();
+        self.send_measurement(Measurement::AverageWindSpeedMph((avg_wind_speed) as u8));
     }
 }
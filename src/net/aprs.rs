@@ -0,0 +1,432 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use time::OffsetDateTime;
+
+use crate::model::{
+    config::AprsConfig,
+    history::{now_timestamp, HistoryStore, SharedHistory},
+    supervisor::Heartbeat,
+    weather_station::{compass_point_to_degrees, Measurement, MeasurementField},
+};
+
+const SOFTWARE_NAME: &str = "M5StackWeatherStation";
+const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(15);
+
+const HPA_PER_INHG: f32 = 33.8639;
+
+/**
+ * The values of an APRS weather report in the units of the APRS spec.  Values the station has not received yet are
+ * sent as dots.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct AprsWeather {
+    pub timestamp: i64,
+    pub wind_direction: Option<f32>,      // degrees
+    pub wind_speed: Option<f32>,          // mph, sustained one minute
+    pub wind_gust: Option<f32>,           // mph, peak in the last 5 minutes
+    pub temperature: Option<f32>,         // F
+    pub rain_last_hour: Option<f32>,      // inches
+    pub rain_last_24h: Option<f32>,       // inches
+    pub rain_since_midnight: Option<f32>, // inches
+    pub humidity: Option<f32>,            // %
+    pub barometer: Option<f32>,           // inHg, sea level
+}
+
+impl AprsWeather {
+    // Build a weather report from the latest values the weather station has computed
+    pub fn from_history(history: &HistoryStore, now: i64) -> Self {
+        let value = |field: MeasurementField| {
+            history
+                .latest(field)
+                .and_then(|latest| latest.measurement.numeric_value())
+        };
+
+        let wind_direction = history
+            .latest(MeasurementField::WindDirection)
+            .and_then(|latest| match &latest.measurement {
+                Measurement::WindDirection(compass_point) => {
+                    compass_point_to_degrees(compass_point)
+                }
+                _ => None,
+            });
+
+        let rain_known = history.latest(MeasurementField::YearlyRainfall).is_some();
+
+        Self {
+            timestamp: now,
+            wind_direction,
+            wind_speed: value(MeasurementField::AverageWindSpeed),
+            wind_gust: value(MeasurementField::WindGust),
+            temperature: value(MeasurementField::OutdoorTemperature),
+            rain_last_hour: rain_known.then(|| history.rainfall_since(now - 60 * 60)),
+            rain_last_24h: rain_known.then(|| history.rainfall_since(now - 24 * 60 * 60)),
+            rain_since_midnight: value(MeasurementField::DailyRainfall),
+            humidity: value(MeasurementField::OutdoorHumidity),
+            barometer: value(MeasurementField::SeaLevelPressure),
+        }
+    }
+}
+
+// A station position in decimal degrees, north and east are positive
+#[derive(Clone, Copy, Debug)]
+pub struct AprsPosition {
+    pub latitude: f32,
+    pub longitude: f32,
+}
+
+/**
+ * Format the weather report part of an APRS packet
+ *
+ *  with a position:     @DDHHMMzDDMM.hhN/DDDMM.hhW_DDD/SSSgGGGtTTTrRRRpRRRPRRRhHHbBBBBB
+ *  positionless:        _MMDDHHMMcDDDsSSSgGGGtTTTrRRRpRRRPRRRhHHbBBBBB
+ *
+ * c/_ wind direction (degrees), s/ wind speed (mph), g gust (mph), t temperature (F), r rain last hour,
+ * p rain last 24 hours, P rain since midnight (hundredths of an inch), h humidity (%, 100% is 00),
+ * b barometer (tenths of hPa).
+ */
+pub fn format_weather(weather: &AprsWeather, position: Option<AprsPosition>) -> String {
+    let date_time = OffsetDateTime::from_unix_timestamp(weather.timestamp)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    let direction = field(
+        weather
+            .wind_direction
+            .map(|degrees| degrees.round() as i32 % 360),
+        3,
+    );
+    let speed = field(weather.wind_speed.map(|mph| mph.round() as i32), 3);
+
+    let mut payload = String::with_capacity(96);
+
+    match position {
+        Some(position) => write!(
+            payload,
+            "@{:02}{:02}{:02}z{}/{}_{}/{}",
+            date_time.day(),
+            date_time.hour(),
+            date_time.minute(),
+            format_latitude(position.latitude),
+            format_longitude(position.longitude),
+            direction,
+            speed
+        )
+        .unwrap(),
+        None => write!(
+            payload,
+            "_{:02}{:02}{:02}{:02}c{}s{}",
+            date_time.month() as u8,
+            date_time.day(),
+            date_time.hour(),
+            date_time.minute(),
+            direction,
+            speed
+        )
+        .unwrap(),
+    }
+
+    let hundredths = |inches: f32| (inches * 100.0).round() as i32;
+
+    write!(
+        payload,
+        "g{}t{}r{}p{}P{}h{}b{}",
+        field(weather.wind_gust.map(|mph| mph.round() as i32), 3),
+        field(weather.temperature.map(|f| f.round() as i32), 3),
+        field(weather.rain_last_hour.map(hundredths), 3),
+        field(weather.rain_last_24h.map(hundredths), 3),
+        field(weather.rain_since_midnight.map(hundredths), 3),
+        field(weather.humidity.map(|h| h.round() as i32 % 100), 2),
+        field(
+            weather
+                .barometer
+                .map(|inhg| (inhg * HPA_PER_INHG * 10.0).round() as i32),
+            5
+        ),
+    )
+    .unwrap();
+
+    payload
+}
+
+// A complete packet as sent over APRS-IS, e.g. "CW1234>APRS,TCPIP*:@011200z..."
+pub fn format_packet(callsign: &str, payload: &str) -> String {
+    format!("{}>APRS,TCPIP*:{}", callsign.to_uppercase(), payload)
+}
+
+// A fixed width field, zero padded, values that are unknown or do not fit are sent as dots
+fn field(value: Option<i32>, width: usize) -> String {
+    let max = 10i32.pow(width as u32) - 1;
+    let min = -(10i32.pow(width as u32 - 1) - 1);
+
+    match value {
+        Some(value) if value >= 0 && value <= max => format!("{:0width$}", value, width = width),
+        Some(value) if value < 0 && value >= min => {
+            format!("-{:0width$}", -value, width = width - 1)
+        }
+        _ => ".".repeat(width),
+    }
+}
+
+// 49.0583 -> 4903.50N
+pub fn format_latitude(latitude: f32) -> String {
+    let (degrees, minutes) = degrees_minutes(latitude.abs());
+    let hemisphere = if latitude < 0.0 { 'S' } else { 'N' };
+
+    format!("{:02}{:05.2}{}", degrees, minutes, hemisphere)
+}
+
+// -72.0292 -> 07201.75W
+pub fn format_longitude(longitude: f32) -> String {
+    let (degrees, minutes) = degrees_minutes(longitude.abs());
+    let hemisphere = if longitude < 0.0 { 'W' } else { 'E' };
+
+    format!("{:03}{:05.2}{}", degrees, minutes, hemisphere)
+}
+
+// Split decimal degrees into whole degrees and minutes rounded to hundredths, carrying 60.00 minutes into degrees
+fn degrees_minutes(decimal_degrees: f32) -> (u32, f32) {
+    let mut degrees = decimal_degrees.trunc() as u32;
+    let mut hundredths = ((decimal_degrees - degrees as f32) * 6000.0).round() as u32;
+
+    if hundredths >= 6000 {
+        degrees += 1;
+        hundredths -= 6000;
+    }
+
+    (degrees, hundredths as f32 / 100.0)
+}
+
+/**
+ * The APRS-IS passcode of a callsign.  The SSID is ignored.  It is only used to check the passcode a licensed amateur
+ * configured, CWOP stations without an amateur radio license log in with a passcode of -1.
+ */
+pub fn passcode(callsign: &str) -> u16 {
+    let base = callsign
+        .split('-')
+        .next()
+        .unwrap_or_default()
+        .to_uppercase();
+    let bytes = base.as_bytes();
+
+    let mut hash: u16 = 0x73e2;
+    for pair in bytes.chunks(2) {
+        hash ^= (pair[0] as u16) << 8;
+        if let Some(low) = pair.get(1) {
+            hash ^= *low as u16;
+        }
+    }
+
+    hash & 0x7fff
+}
+
+pub struct AprsReporter {
+    history: SharedHistory,
+    config: AprsConfig,
+    position: Option<AprsPosition>,
+}
+
+impl AprsReporter {
+    pub fn new(history: SharedHistory, config: AprsConfig) -> Self {
+        let position = match (config.latitude, config.longitude) {
+            (Some(latitude), Some(longitude)) => Some(AprsPosition {
+                latitude,
+                longitude,
+            }),
+            _ => None,
+        };
+
+        Self {
+            history,
+            config,
+            position,
+        }
+    }
+
+    pub fn is_configured(config: &AprsConfig) -> bool {
+        !config.callsign.is_empty()
+    }
+
+    pub fn start(self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        println!("Starting AprsReporter Thread");
+
+        if !self.config.passcode.is_empty()
+            && self.config.passcode != passcode(&self.config.callsign).to_string()
+        {
+            println!(
+                "APRS passcode does not match the callsign, the server will not verify the reports"
+            );
+        }
+
+        std::thread::Builder::new()
+            .stack_size(6144)
+            .spawn(move || {
+                loop {
+                    heartbeat.sleep(Duration::from_secs(self.config.interval_secs as u64));
+
+                    let weather = {
+                        let history = self.history.lock().unwrap();
+                        AprsWeather::from_history(&history, now_timestamp())
+                    };
+
+                    if weather.temperature.is_none() && weather.wind_speed.is_none() {
+                        continue;
+                    }
+
+                    let packet = format_packet(
+                        &self.config.callsign,
+                        &format_weather(&weather, self.position),
+                    );
+
+                    // CWOP asks stations to connect, send one packet and disconnect, so a failed report is not retried
+                    if let Err(e) = send_packet(&self.config, &packet) {
+                        println!("APRS report failed {:?}", e);
+                    }
+                }
+            })
+            .unwrap()
+    }
+}
+
+fn send_packet(config: &AprsConfig, packet: &str) -> Result<()> {
+    // CWOP stations log in with their CW or DW id and -1, a licensed amateur supplies the passcode of their callsign
+    let passcode = if config.passcode.is_empty() {
+        "-1"
+    } else {
+        config.passcode.as_str()
+    };
+
+    let stream = TcpStream::connect(config.server.as_str())?;
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+
+    // The server greets with a comment line before accepting the login
+    reader.read_line(&mut line)?;
+
+    write!(
+        writer,
+        "user {} pass {} vers {} {}\r\n",
+        config.callsign.to_uppercase(),
+        passcode,
+        SOFTWARE_NAME,
+        SOFTWARE_VERSION
+    )?;
+
+    line.clear();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("# logresp") {
+        bail!("unexpected login response {}", line.trim());
+    }
+
+    write!(writer, "{}\r\n", packet)?;
+    writer.flush()?;
+
+    println!("APRS sent {}", packet);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weather(timestamp: i64) -> AprsWeather {
+        AprsWeather {
+            timestamp,
+            wind_direction: Some(220.0),
+            wind_speed: Some(4.0),
+            wind_gust: Some(5.0),
+            temperature: Some(77.0),
+            rain_last_hour: Some(0.0),
+            rain_last_24h: Some(0.0),
+            rain_since_midnight: Some(0.0),
+            humidity: Some(50.0),
+            barometer: Some(990.0 / HPA_PER_INHG),
+        }
+    }
+
+    // The positionless weather report of the APRS spec, 9 October 05:56 UTC
+    #[test]
+    fn positionless_report() {
+        let payload = format_weather(&weather(1696830960), None);
+
+        assert_eq!(payload, "_10090556c220s004g005t077r000p000P000h50b09900");
+    }
+
+    // The weather report with a position of the APRS spec, 9 October 23:45 UTC
+    #[test]
+    fn report_with_position() {
+        let position = AprsPosition {
+            latitude: 49.058333,
+            longitude: -72.029167,
+        };
+
+        let payload = format_weather(&weather(1696895100), Some(position));
+
+        assert_eq!(
+            payload,
+            "@092345z4903.50N/07201.75W_220/004g005t077r000p000P000h50b09900"
+        );
+        assert_eq!(
+            format_packet("cw1234", &payload),
+            "CW1234>APRS,TCPIP*:@092345z4903.50N/07201.75W_220/004g005t077r000p000P000h50b09900"
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_dots() {
+        let weather = AprsWeather {
+            timestamp: 1696830960,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_weather(&weather, None),
+            "_10090556c...s...g...t...r...p...P...h..b....."
+        );
+    }
+
+    #[test]
+    fn fields_are_padded_to_their_width() {
+        let weather = AprsWeather {
+            wind_direction: Some(360.0),
+            wind_speed: Some(12.4),
+            wind_gust: Some(1000.0),
+            temperature: Some(-5.0),
+            rain_last_hour: Some(0.125),
+            rain_last_24h: Some(1.5),
+            rain_since_midnight: Some(12.0),
+            humidity: Some(100.0),
+            barometer: Some(29.92),
+            ..weather(1696830960)
+        };
+
+        assert_eq!(
+            format_weather(&weather, None),
+            "_10090556c000s012g...t-05r013p150P...h00b10132"
+        );
+    }
+
+    #[test]
+    fn latitude_and_longitude() {
+        assert_eq!(format_latitude(-33.8688), "3352.13S");
+        assert_eq!(format_longitude(151.2093), "15112.56E");
+        // 59.9999 minutes round up into the next degree
+        assert_eq!(format_latitude(10.999999), "1100.00N");
+    }
+
+    #[test]
+    fn passcode_of_callsign() {
+        assert_eq!(passcode("N0CALL"), 13023);
+        assert_eq!(passcode("n0call-13"), 13023);
+    }
+}
@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+// Exponential backoff used when reconnecting or retrying network requests
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            next: min,
+        }
+    }
+
+    // Returns the delay to use now and doubles the next delay up to max
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
@@ -0,0 +1,638 @@
+use anyhow::Result;
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerError, Request},
+        Headers, Method, Query,
+    },
+    io::{Read, Write},
+};
+use esp_idf_hal::{delay::FreeRtos, reset};
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use esp_idf_sys::{esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time};
+use serde_json::json;
+
+use crate::{
+    model::{
+        config::{merge_config, to_json, Config, ConfigError, ConfigStore, SharedConfig},
+        faults::{Fault, FAULTS},
+        history::{now_timestamp, HistoryStore, SharedHistory},
+        statistics::STATISTICS,
+        weather_station::MeasurementField,
+    },
+    net::{json, prometheus, provisioning::url_decode, wifi::read_rssi},
+};
+
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+// Default range returned by /api/history when from is not given
+const DEFAULT_HISTORY_SECS: i64 = 24 * 60 * 60;
+
+// The largest config a client may post, the stored config is limited to the same size
+const MAX_CONFIG_BODY: usize = 4096;
+
+pub struct SystemStatus {
+    pub uptime_secs: i64,
+    pub free_heap: u32,
+    pub minimum_free_heap: u32,
+    pub wifi_rssi: Option<i8>,
+    pub faults: Vec<Fault>,
+    // Why the station last rebooted, e.g. "power on" or "gui stuck for 12 secs"
+    pub last_reboot: String,
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn text(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    fn html(body: &str) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/html",
+            body: body.to_string(),
+        }
+    }
+}
+
+/**
+ * Route a GET request to its handler.  This function does not touch any hardware so the routing and
+ * rendering can be exercised on the host.
+ *
+ *      /                   dashboard
+ *      /api/current        latest value of every measurement
+ *      /api/history        ?field=outdoor_temperature[&from=unix secs][&to=unix secs]
+ *      /api/status         sensor health, uptime, heap and wifi rssi
+ *      /api/alerts         the alerts that are active
+ *      /api/config         the config with its secrets masked
+ *      /metrics            prometheus text exposition format
+ */
+pub fn handle_request(
+    uri: &str,
+    history: &HistoryStore,
+    config: &Config,
+    system_status: &SystemStatus,
+    now: i64,
+) -> HttpResponse {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (uri, ""),
+    };
+
+    match path {
+        "/" | "/index.html" => HttpResponse::html(DASHBOARD_HTML),
+
+        "/api/current" => HttpResponse::json(200, json::render_current(history, now)),
+
+        "/api/history" => handle_history(query, history, now),
+
+        "/api/status" => HttpResponse::json(200, json::render_status(history, system_status, now)),
+
+        "/api/alerts" => HttpResponse::json(200, json::render_alerts(history.active_alerts())),
+
+        "/api/config" => HttpResponse::json(200, to_json(&config.redacted())),
+
+        "/metrics" => HttpResponse::text(
+            "text/plain; version=0.0.4",
+            prometheus::render_metrics(history, system_status, &STATISTICS, now),
+        ),
+
+        "/api/restart" => method_not_allowed(),
+
+        _ => not_found(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PostAction {
+    UpdateConfig,
+    Restart,
+}
+
+/**
+ * Route a POST request and check its admin token, before any of the body is read.
+ *
+ *      /api/config         merge the posted settings over the stored config
+ *      /api/restart        restart the station
+ */
+pub fn route_post(
+    uri: &str,
+    config: &Config,
+    authorization: Option<&str>,
+) -> Result<PostAction, HttpResponse> {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+
+    let action = match path {
+        "/api/config" => PostAction::UpdateConfig,
+        "/api/restart" => PostAction::Restart,
+        "/" | "/index.html" | "/api/current" | "/api/history" | "/api/status" | "/api/alerts"
+        | "/metrics" => return Err(method_not_allowed()),
+        _ => return Err(not_found()),
+    };
+
+    authorize(config, authorization)?;
+
+    Ok(action)
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::json(404, json::render_error("not found"))
+}
+
+fn method_not_allowed() -> HttpResponse {
+    HttpResponse::json(405, json::render_error("method not allowed"))
+}
+
+fn handle_history(query: &str, history: &HistoryStore, now: i64) -> HttpResponse {
+    let field = match query_param(query, "field")
+        .as_deref()
+        .and_then(MeasurementField::from_name)
+    {
+        Some(field) => field,
+        None => return HttpResponse::json(400, json::render_error("unknown or missing field")),
+    };
+
+    let to = match query_param(query, "to").map(|to| to.parse::<i64>()) {
+        Some(Ok(to)) => to,
+        Some(Err(_)) => return HttpResponse::json(400, json::render_error("invalid to")),
+        None => now,
+    };
+
+    let from = match query_param(query, "from").map(|from| from.parse::<i64>()) {
+        Some(Ok(from)) => from,
+        Some(Err(_)) => return HttpResponse::json(400, json::render_error("invalid from")),
+        None => to - DEFAULT_HISTORY_SECS,
+    };
+
+    let points = history.range(field, from, to);
+
+    HttpResponse::json(200, json::render_history(field, from, to, &points))
+}
+
+/**
+ * Handle a POST of a new config to /api/config.  The posted settings are merged over the stored config so a client can
+ * post only the sections it changes, and secrets posted back as the mask keep their stored value.  The station and
+ * time settings apply straight away, the network settings apply after a restart.
+ *
+ * {"config":{...},"restart_required":true}
+ */
+pub fn handle_config_update(body: &str, store: &mut ConfigStore) -> HttpResponse {
+    let mut config = match merge_config(store.config(), body) {
+        Ok(config) => config,
+        Err(e) => return HttpResponse::json(400, json::render_error(&e.to_string())),
+    };
+
+    config.keep_masked_secrets(store.config());
+
+    let previous = store.config().clone();
+
+    match store.update(config) {
+        Ok(()) => {
+            let updated = store.config();
+            let restart_required = updated.wifi != previous.wifi
+                || updated.mqtt != previous.mqtt
+                || updated.pws != previous.pws
+                || updated.aprs != previous.aprs
+                || updated.influxdb != previous.influxdb;
+
+            HttpResponse::json(
+                200,
+                json!({ "config": updated.redacted(), "restart_required": restart_required })
+                    .to_string(),
+            )
+        }
+        Err(e @ ConfigError::Storage(_)) => {
+            HttpResponse::json(500, json::render_error(&e.to_string()))
+        }
+        Err(e) => HttpResponse::json(400, json::render_error(&e.to_string())),
+    }
+}
+
+/**
+ * Check the admin token of a request that changes the station, it is sent as "Authorization: Bearer <token>".  While
+ * no admin token is configured every such request is refused.
+ */
+pub fn authorize(config: &Config, authorization: Option<&str>) -> Result<(), HttpResponse> {
+    let admin_token = config.http.admin_token.as_bytes();
+    let token = authorization
+        .and_then(|authorization| authorization.trim().strip_prefix("Bearer "))
+        .map(|token| token.trim().as_bytes());
+
+    match token {
+        Some(token) if !admin_token.is_empty() && tokens_match(token, admin_token) => Ok(()),
+        _ => Err(HttpResponse::json(
+            401,
+            json::render_error("admin token required"),
+        )),
+    }
+}
+
+// Compare every byte so the time taken does not tell how much of the token was right
+fn tokens_match(token: &[u8], admin_token: &[u8]) -> bool {
+    token.len() == admin_token.len()
+        && token
+            .iter()
+            .zip(admin_token)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+// The percent decoded value of a query parameter, from=1700000000&field=outdoor%5Ftemperature
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| url_decode(key) == name)
+        .map(|(_, value)| url_decode(value))
+}
+
+pub struct HttpServer {
+    history: SharedHistory,
+    config: SharedConfig,
+    last_reboot: String,
+}
+
+impl HttpServer {
+    pub fn new(history: SharedHistory, config: SharedConfig, last_reboot: String) -> Self {
+        Self {
+            history,
+            config,
+            last_reboot,
+        }
+    }
+
+    // The returned server must be kept alive, dropping it stops the server
+    pub fn start(self) -> Result<EspHttpServer> {
+        println!("Starting Http Server");
+
+        let mut server = EspHttpServer::new(&Configuration {
+            uri_match_wildcard: true,
+            ..Default::default()
+        })?;
+
+        let config = self.config.clone();
+        server.fn_handler("/*", Method::Post, move |mut request| {
+            let action = route_post(
+                request.uri(),
+                config.lock().unwrap().config(),
+                request.header("Authorization"),
+            );
+
+            let response = match action {
+                Ok(PostAction::UpdateConfig) => {
+                    let mut body = Vec::new();
+                    let mut buf = [0u8; 512];
+
+                    loop {
+                        let length = request.read(&mut buf)?;
+                        if length == 0 {
+                            break;
+                        }
+
+                        body.extend_from_slice(&buf[..length]);
+                        if body.len() > MAX_CONFIG_BODY {
+                            break;
+                        }
+                    }
+
+                    if body.len() > MAX_CONFIG_BODY {
+                        HttpResponse::json(413, json::render_error("config too large"))
+                    } else {
+                        let body = String::from_utf8_lossy(&body);
+                        handle_config_update(&body, &mut config.lock().unwrap())
+                    }
+                }
+
+                Ok(PostAction::Restart) => {
+                    request
+                        .into_response(200, None, &[("Content-Type", "application/json")])?
+                        .write_all(b"{\"restarting\":true}")?;
+
+                    // Give the response time to go out before restarting
+                    FreeRtos::delay_ms(500);
+                    reset::restart();
+
+                    return Ok(());
+                }
+
+                Err(response) => response,
+            };
+
+            send_response(request, response)
+        })?;
+
+        server.fn_handler("/*", Method::Get, move |request| {
+            let system_status = read_system_status(&self.last_reboot);
+            let config = self.config.lock().unwrap().config().clone();
+            let response = {
+                let history = self.history.lock().unwrap();
+                handle_request(
+                    request.uri(),
+                    &history,
+                    &config,
+                    &system_status,
+                    now_timestamp(),
+                )
+            };
+
+            send_response(request, response)
+        })?;
+
+        Ok(server)
+    }
+}
+
+// A refused admin token also tells the client which scheme to use
+fn send_response<C: Connection>(
+    request: Request<C>,
+    response: HttpResponse,
+) -> Result<(), HandlerError> {
+    let headers = [
+        ("Content-Type", response.content_type),
+        ("WWW-Authenticate", "Bearer"),
+    ];
+    let headers = if response.status == 401 {
+        &headers[..]
+    } else {
+        &headers[..1]
+    };
+
+    request
+        .into_response(response.status, None, headers)?
+        .write_all(response.body.as_bytes())?;
+
+    Ok(())
+}
+
+fn read_system_status(last_reboot: &str) -> SystemStatus {
+    SystemStatus {
+        uptime_secs: unsafe { esp_timer_get_time() } / 1_000_000,
+        free_heap: unsafe { esp_get_free_heap_size() },
+        minimum_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        wifi_rssi: read_rssi(),
+        faults: FAULTS.active(),
+        last_reboot: last_reboot.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{config::MemoryConfigBackend, weather_station::Measurement};
+    use serde_json::Value;
+
+    const NOW: i64 = 1690000000;
+
+    fn system_status() -> SystemStatus {
+        SystemStatus {
+            uptime_secs: 3600,
+            free_heap: 120000,
+            minimum_free_heap: 90000,
+            wifi_rssi: Some(-61),
+            faults: Vec::new(),
+            last_reboot: "power on".to_string(),
+        }
+    }
+
+    fn get(uri: &str, history: &HistoryStore) -> HttpResponse {
+        handle_request(uri, history, &Config::new(), &system_status(), NOW)
+    }
+
+    fn body(response: &HttpResponse) -> Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let response = get(
+            "/api/nothing?field=outdoor_temperature",
+            &HistoryStore::default(),
+        );
+
+        assert_eq!(response.status, 404);
+        assert_eq!(response.content_type, "application/json");
+        assert_eq!(body(&response)["error"], "not found");
+    }
+
+    #[test]
+    fn history_defaults_to_the_last_day() {
+        let mut history = HistoryStore::default();
+        history.record_at(
+            NOW - DEFAULT_HISTORY_SECS - 1,
+            &Measurement::OutdoorTemperature(60),
+        );
+        history.record_at(
+            NOW - DEFAULT_HISTORY_SECS,
+            &Measurement::OutdoorTemperature(65),
+        );
+        history.record_at(NOW, &Measurement::OutdoorTemperature(72));
+
+        let response = get("/api/history?field=outdoor_temperature", &history);
+
+        assert_eq!(response.status, 200);
+        let body = body(&response);
+        assert_eq!(body["from"], NOW - DEFAULT_HISTORY_SECS);
+        assert_eq!(body["to"], NOW);
+        assert_eq!(
+            body["points"],
+            serde_json::json!([[NOW - DEFAULT_HISTORY_SECS, 65.0], [NOW, 72.0]])
+        );
+    }
+
+    #[test]
+    fn history_range_is_taken_from_the_query() {
+        let mut history = HistoryStore::default();
+        history.record_at(NOW - 120, &Measurement::OutdoorTemperature(60));
+        history.record_at(NOW - 60, &Measurement::OutdoorTemperature(65));
+        history.record_at(NOW, &Measurement::OutdoorTemperature(72));
+
+        let uri = format!(
+            "/api/history?to={}&from={}&field=outdoor%5Ftemperature",
+            NOW - 60,
+            NOW - 120
+        );
+        let body = body(&get(&uri, &history));
+
+        assert_eq!(body["field"], "outdoor_temperature");
+        assert_eq!(
+            body["points"],
+            serde_json::json!([[NOW - 120, 60.0], [NOW - 60, 65.0]])
+        );
+    }
+
+    #[test]
+    fn bad_history_queries_are_rejected() {
+        let history = HistoryStore::default();
+
+        for (uri, error) in [
+            ("/api/history", "unknown or missing field"),
+            (
+                "/api/history?field=dew_point_on_mars",
+                "unknown or missing field",
+            ),
+            (
+                "/api/history?field=outdoor_temperature&from=yesterday",
+                "invalid from",
+            ),
+            (
+                "/api/history?field=outdoor_temperature&to=1.5",
+                "invalid to",
+            ),
+        ] {
+            let response = get(uri, &history);
+            assert_eq!(response.status, 400, "{}", uri);
+            assert_eq!(body(&response)["error"], error, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn metrics_are_plain_text() {
+        let response = get("/metrics", &HistoryStore::default());
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "text/plain; version=0.0.4");
+        assert!(response
+            .body
+            .contains("weather_station_uptime_seconds 3600"));
+    }
+
+    #[test]
+    fn config_is_served_with_its_secrets_masked() {
+        let config = config_with_token("correct horse");
+        let response = handle_request(
+            "/api/config",
+            &HistoryStore::default(),
+            &config,
+            &system_status(),
+            NOW,
+        );
+
+        assert_eq!(response.status, 200);
+        assert!(!response.body.contains("correct horse"));
+    }
+
+    #[test]
+    fn posts_are_routed_once_the_token_is_checked() {
+        let config = config_with_token("correct horse");
+        let token = Some("Bearer correct horse");
+
+        assert_eq!(
+            route_post("/api/config", &config, token).ok(),
+            Some(PostAction::UpdateConfig)
+        );
+        assert_eq!(
+            route_post("/api/restart", &config, token).ok(),
+            Some(PostAction::Restart)
+        );
+
+        let refused = route_post("/api/restart", &config, None).unwrap_err();
+        assert_eq!(refused.status, 401);
+        assert_eq!(
+            route_post("/api/config", &config, Some("Bearer wrong horse"))
+                .unwrap_err()
+                .status,
+            401
+        );
+
+        // The path is checked before the token
+        assert_eq!(
+            route_post("/api/status", &config, None).unwrap_err().status,
+            405
+        );
+        assert_eq!(
+            route_post("/api/nothing", &config, token)
+                .unwrap_err()
+                .status,
+            404
+        );
+    }
+
+    #[test]
+    fn restart_is_not_a_get() {
+        assert_eq!(get("/api/restart", &HistoryStore::default()).status, 405);
+    }
+
+    fn config_with_token(admin_token: &str) -> Config {
+        let mut config = Config::new();
+        config.http.admin_token = admin_token.to_string();
+        config
+    }
+
+    #[test]
+    fn admin_token_is_required() {
+        let config = config_with_token("correct horse");
+
+        assert!(authorize(&config, Some("Bearer correct horse")).is_ok());
+        assert_eq!(
+            authorize(&config, Some("Bearer wrong horse"))
+                .unwrap_err()
+                .status,
+            401
+        );
+        assert_eq!(
+            authorize(&config, Some("correct horse"))
+                .unwrap_err()
+                .status,
+            401
+        );
+        assert_eq!(authorize(&config, None).unwrap_err().status, 401);
+    }
+
+    fn config_store(config: &Config) -> ConfigStore {
+        ConfigStore::load(Box::new(MemoryConfigBackend(Some(to_json(config)))))
+    }
+
+    #[test]
+    fn posting_one_section_keeps_the_rest_of_the_config() {
+        let mut stored = config_with_token("correct horse");
+        stored.wifi.ssid = "home".to_string();
+        stored.wifi.password = "wifi password".to_string();
+        stored.mqtt.broker_url = "mqtt://broker:1883".to_string();
+        let mut store = config_store(&stored);
+
+        let response = handle_config_update(r#"{"station":{"altitude_meters":1500}}"#, &mut store);
+
+        assert_eq!(response.status, 200);
+        assert!(response.body.ends_with(r#""restart_required":false}"#));
+        assert_eq!(store.config().station.altitude_meters, 1500.0);
+        assert_eq!(store.config().wifi, stored.wifi);
+        assert_eq!(store.config().mqtt, stored.mqtt);
+        assert_eq!(store.config().http.admin_token, "correct horse");
+    }
+
+    #[test]
+    fn invalid_config_is_rejected_and_the_stored_one_kept() {
+        let stored = config_with_token("correct horse");
+        let mut store = config_store(&stored);
+
+        let response = handle_config_update(r#"{"station":{"altitude_meters":20000}}"#, &mut store);
+
+        assert_eq!(response.status, 400);
+        assert_eq!(store.config(), &stored);
+    }
+
+    #[test]
+    fn nothing_is_authorized_without_an_admin_token() {
+        let config = config_with_token("");
+
+        assert!(authorize(&config, Some("Bearer ")).is_err());
+        assert!(authorize(&config, None).is_err());
+    }
+}
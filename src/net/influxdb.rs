@@ -0,0 +1,445 @@
+use std::fmt::Write as _;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::{
+    http::{client::Client, Status},
+    io::Write,
+};
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+
+use crate::{
+    model::{
+        config::InfluxDbConfig,
+        history::{HistoryPoint, HistoryStore, Sensor, SharedHistory},
+        real_time_clock::MIN_VALID_TIMESTAMP,
+        supervisor::Heartbeat,
+        weather_station::{Measurement, MeasurementField},
+    },
+    net::{backoff::Backoff, url::url_encode},
+};
+
+const MEASUREMENT_NAME: &str = "weather";
+
+// Points are pushed in batches so that a backfill after a long outage does not need one huge request body
+const MAX_BATCH_POINTS: usize = 200;
+
+const NANOSECONDS_PER_SECOND: i64 = 1_000_000_000;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug)]
+pub enum InfluxDbEndpoint {
+    V1 {
+        url: String,
+        database: String,
+        username: String,
+        password: String,
+    },
+    V2 {
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+impl InfluxDbEndpoint {
+    // A token selects the v2 api, without one the v1 api is used
+    pub fn from_config(config: &InfluxDbConfig) -> Self {
+        if config.token.is_empty() {
+            InfluxDbEndpoint::V1 {
+                url: config.url.clone(),
+                database: config.database.clone(),
+                username: config.username.clone(),
+                password: config.password.clone(),
+            }
+        } else {
+            InfluxDbEndpoint::V2 {
+                url: config.url.clone(),
+                org: config.org.clone(),
+                bucket: config.bucket.clone(),
+                token: config.token.clone(),
+            }
+        }
+    }
+
+    /**
+     * The url to POST line protocol to
+     *
+     *  v1:  http://influxdb:8086/write?db=weather[&u=user&p=password]
+     *  v2:  http://influxdb:8086/api/v2/write?org=home&bucket=weather
+     *
+     * No precision is given so InfluxDB takes the timestamps as nanoseconds, its default
+     */
+    pub fn write_url(&self) -> String {
+        match self {
+            InfluxDbEndpoint::V1 {
+                url,
+                database,
+                username,
+                password,
+            } => {
+                let mut write_url = format!(
+                    "{}/write?db={}",
+                    url.trim_end_matches('/'),
+                    url_encode(database)
+                );
+
+                if !username.is_empty() {
+                    write!(
+                        write_url,
+                        "&u={}&p={}",
+                        url_encode(username),
+                        url_encode(password)
+                    )
+                    .unwrap();
+                }
+
+                write_url
+            }
+
+            InfluxDbEndpoint::V2 {
+                url, org, bucket, ..
+            } => format!(
+                "{}/api/v2/write?org={}&bucket={}",
+                url.trim_end_matches('/'),
+                url_encode(org),
+                url_encode(bucket)
+            ),
+        }
+    }
+
+    // The v2 api authenticates with a token header, v1 credentials are part of the url
+    pub fn authorization(&self) -> Option<String> {
+        match self {
+            InfluxDbEndpoint::V1 { .. } => None,
+            InfluxDbEndpoint::V2 { token, .. } => Some(format!("Token {}", token)),
+        }
+    }
+}
+
+// The tags written with every point
+#[derive(Clone, Debug, Default)]
+pub struct InfluxDbTags {
+    pub product_id: Option<u16>,
+    pub location: String,
+    // The sensor each field is measured by, indexed by field
+    pub field_sensors: Vec<Option<Sensor>>,
+}
+
+impl InfluxDbTags {
+    pub fn from_history(history: &HistoryStore, location: &str) -> Self {
+        let product_id =
+            history
+                .latest(MeasurementField::ProductId)
+                .and_then(|latest| match latest.measurement {
+                    Measurement::ProductId(product_id) => Some(product_id),
+                    _ => None,
+                });
+
+        Self {
+            product_id,
+            location: location.to_string(),
+            field_sensors: MeasurementField::ALL
+                .iter()
+                .map(|field| history.field_sensor(*field))
+                .collect(),
+        }
+    }
+
+    fn field_sensor(&self, field: MeasurementField) -> Option<Sensor> {
+        self.field_sensors.get(field as usize).copied().flatten()
+    }
+}
+
+/**
+ * Format history points as InfluxDB line protocol, one line per point
+ *
+ * weather,sensor_id=acurite5n1,product_id=1234,location=back\ yard outdoor_temperature=72 1690000000000000000
+ *
+ * The product id tag is only written for the Acurite5n1 and the location tag only when one is configured.  Points
+ * recorded before the clock was set have no real time and are left out.
+ */
+pub fn format_lines(points: &[HistoryPoint], tags: &InfluxDbTags) -> String {
+    let location = escape_tag(&tags.location);
+    let mut lines = String::with_capacity(points.len() * 96);
+
+    for point in points {
+        if point.timestamp < MIN_VALID_TIMESTAMP {
+            continue;
+        }
+
+        lines.push_str(MEASUREMENT_NAME);
+
+        match tags.field_sensor(point.field) {
+            Some(Sensor::Acurite5n1) => {
+                lines.push_str(",sensor_id=acurite5n1");
+                if let Some(product_id) = tags.product_id {
+                    write!(lines, ",product_id={}", product_id).unwrap();
+                }
+            }
+            Some(sensor) => write!(lines, ",sensor_id={}", sensor.id()).unwrap(),
+            None => (),
+        }
+
+        if !location.is_empty() {
+            write!(lines, ",location={}", location).unwrap();
+        }
+
+        writeln!(
+            lines,
+            " {}={} {}",
+            point.field.name(),
+            point.value,
+            point.timestamp * NANOSECONDS_PER_SECOND
+        )
+        .unwrap();
+    }
+
+    lines
+}
+
+// Commas, equal signs and spaces in tag values are escaped with a backslash
+pub fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/**
+ * Pushes the history points to InfluxDB.  The exporter remembers the sequence number of the next point to push so
+ * points recorded while InfluxDB is unreachable stay in the history store and are backfilled when it comes back.
+ */
+pub struct InfluxDbExporter {
+    history: SharedHistory,
+    endpoint: InfluxDbEndpoint,
+    location: String,
+    interval: Duration,
+    next_sequence: u64,
+}
+
+impl InfluxDbExporter {
+    pub fn new(history: SharedHistory, config: &InfluxDbConfig) -> Self {
+        Self {
+            history,
+            endpoint: InfluxDbEndpoint::from_config(config),
+            location: config.location.clone(),
+            interval: Duration::from_secs(config.interval_secs as u64),
+            next_sequence: 0,
+        }
+    }
+
+    pub fn is_configured(config: &InfluxDbConfig) -> bool {
+        !config.url.is_empty()
+    }
+
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        println!("Starting InfluxDbExporter Thread");
+
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || {
+                let mut backoff = Backoff::new(MIN_RETRY_DELAY, MAX_RETRY_DELAY);
+
+                loop {
+                    match self.push_pending(&heartbeat) {
+                        Ok(()) => {
+                            backoff.reset();
+                            heartbeat.sleep(self.interval);
+                        }
+                        Err(e) => {
+                            let delay = backoff.next_delay();
+                            println!(
+                                "InfluxDB write failed {:?}, retrying in {} s",
+                                e,
+                                delay.as_secs()
+                            );
+                            heartbeat.sleep(delay);
+                        }
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    // Push batches until every recorded point has been written
+    fn push_pending(&mut self, heartbeat: &Heartbeat) -> Result<()> {
+        loop {
+            heartbeat.beat();
+
+            let (points, next_sequence, tags, oldest_sequence) = {
+                let history = self.history.lock().unwrap();
+                let (points, next_sequence) =
+                    history.points_from(self.next_sequence, MAX_BATCH_POINTS);
+                let oldest_sequence = next_sequence - points.len() as u64;
+
+                (
+                    points,
+                    next_sequence,
+                    InfluxDbTags::from_history(&history, &self.location),
+                    oldest_sequence,
+                )
+            };
+
+            if oldest_sequence > self.next_sequence {
+                println!(
+                    "InfluxDB backfill lost {} points",
+                    oldest_sequence - self.next_sequence
+                );
+            }
+
+            if points.is_empty() {
+                self.next_sequence = next_sequence;
+                return Ok(());
+            }
+
+            let lines = format_lines(&points, &tags);
+            if !lines.is_empty() {
+                http_post(&self.endpoint, &lines)?;
+            }
+
+            self.next_sequence = next_sequence;
+        }
+    }
+}
+
+fn http_post(endpoint: &InfluxDbEndpoint, body: &str) -> Result<()> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+
+    let url = endpoint.write_url();
+    let content_length = body.len().to_string();
+    let authorization = endpoint.authorization();
+
+    let mut headers = vec![
+        ("Content-Type", "text/plain; charset=utf-8"),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if let Some(authorization) = authorization.as_deref() {
+        headers.push(("Authorization", authorization));
+    }
+
+    let mut client = Client::wrap(connection);
+    let mut request = client.post(&url, &headers)?;
+    request.write_all(body.as_bytes())?;
+    let response = request.submit()?;
+
+    match response.status() {
+        200..=299 => Ok(()),
+        status => bail!("http status {}", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(field: MeasurementField, value: f32) -> HistoryPoint {
+        HistoryPoint {
+            timestamp: 1690000000,
+            field,
+            value,
+        }
+    }
+
+    #[test]
+    fn tag_values_are_escaped() {
+        assert_eq!(escape_tag("back yard"), "back\\ yard");
+        assert_eq!(escape_tag("north,east"), "north\\,east");
+        assert_eq!(escape_tag("a=b"), "a\\=b");
+        assert_eq!(escape_tag("roof"), "roof");
+    }
+
+    #[test]
+    fn lines_carry_the_sensor_product_id_and_location() {
+        let mut field_sensors = vec![None; MeasurementField::ALL.len()];
+        field_sensors[MeasurementField::OutdoorTemperature as usize] = Some(Sensor::Acurite5n1);
+        field_sensors[MeasurementField::IndoorHumidity as usize] = Some(Sensor::Sht4x);
+
+        let tags = InfluxDbTags {
+            product_id: Some(1234),
+            location: "back yard, north=1".to_string(),
+            field_sensors,
+        };
+
+        let lines = format_lines(
+            &[
+                point(MeasurementField::OutdoorTemperature, 72.0),
+                point(MeasurementField::IndoorHumidity, 41.5),
+            ],
+            &tags,
+        );
+
+        assert_eq!(
+            lines,
+            "weather,sensor_id=acurite5n1,product_id=1234,location=back\\ yard\\,\\ north\\=1 outdoor_temperature=72 1690000000000000000\n\
+             weather,sensor_id=sht4x,location=back\\ yard\\,\\ north\\=1 indoor_humidity=41.5 1690000000000000000\n"
+        );
+    }
+
+    #[test]
+    fn lines_without_tags() {
+        let lines = format_lines(
+            &[point(MeasurementField::IndoorCo2, 600.0)],
+            &InfluxDbTags::default(),
+        );
+
+        assert_eq!(lines, "weather indoor_co2=600 1690000000000000000\n");
+    }
+
+    #[test]
+    fn points_before_the_clock_was_set_are_left_out() {
+        let early = HistoryPoint {
+            timestamp: 120,
+            ..point(MeasurementField::IndoorCo2, 450.0)
+        };
+
+        let lines = format_lines(
+            &[early, point(MeasurementField::IndoorCo2, 600.0)],
+            &InfluxDbTags::default(),
+        );
+
+        assert_eq!(lines, "weather indoor_co2=600 1690000000000000000\n");
+        assert_eq!(format_lines(&[early], &InfluxDbTags::default()), "");
+    }
+
+    #[test]
+    fn write_urls() {
+        let v1 = InfluxDbEndpoint::V1 {
+            url: "http://influxdb:8086/".to_string(),
+            database: "weather".to_string(),
+            username: "station".to_string(),
+            password: "p&ss word".to_string(),
+        };
+        let v2 = InfluxDbEndpoint::V2 {
+            url: "http://influxdb:8086".to_string(),
+            org: "my home".to_string(),
+            bucket: "weather".to_string(),
+            token: "secret".to_string(),
+        };
+
+        assert_eq!(
+            v1.write_url(),
+            "http://influxdb:8086/write?db=weather&u=station&p=p%26ss%20word"
+        );
+        assert_eq!(
+            v2.write_url(),
+            "http://influxdb:8086/api/v2/write?org=my%20home&bucket=weather"
+        );
+        assert_eq!(v2.authorization().as_deref(), Some("Token secret"));
+    }
+}
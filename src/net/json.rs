@@ -0,0 +1,248 @@
+use serde_json::{json, Map, Value};
+
+use crate::{
+    model::{
+        history::{ActiveAlert, BatteryLowPeriod, HistoryPoint, HistoryStore},
+        weather_station::{Measurement, MeasurementField},
+    },
+    net::http_server::SystemStatus,
+};
+
+// A sensor that has not been heard from in this many seconds is reported as stale.  The Acurite5n1 sends a packet
+// every 18 seconds and the indoor sensors are read every 2 minutes.
+const SENSOR_STALE_SECS: i64 = 5 * 60;
+
+// Render the value of a measurement as a json value, text measurements are rendered as json strings
+pub fn measurement_value(measurement: &Measurement) -> Value {
+    match measurement {
+        Measurement::BatteryStatus(text) | Measurement::WindDirection(text) => json!(text),
+        Measurement::DailyRainfall(value)
+        | Measurement::MonthlyRainfall(value)
+        | Measurement::YearlyRainfall(value)
+        | Measurement::IndoorPressure(value)
+        | Measurement::SeaLevelPressure(value)
+        | Measurement::IndoorGasResistance(value) => number((value * 100.0).round() / 100.0),
+        // The other measurements are whole numbers
+        _ => json!(measurement.numeric_value().unwrap_or_default() as i64),
+    }
+}
+
+/**
+ * Render the latest value of every measurement
+ *
+ * {"timestamp":1690000000,"measurements":{"outdoor_temperature":{"value":72,"unit":"F","timestamp":1689999990},...}}
+ *
+ * Measurements that have not been received yet are rendered as null.
+ */
+pub fn render_current(history: &HistoryStore, now: i64) -> String {
+    let mut measurements = Map::new();
+
+    for field in MeasurementField::ALL {
+        let value = match history.latest(field) {
+            Some(latest) => json!({
+                "value": measurement_value(&latest.measurement),
+                "unit": field.unit(),
+                "timestamp": latest.timestamp,
+            }),
+            None => Value::Null,
+        };
+
+        measurements.insert(field.name().to_string(), value);
+    }
+
+    json!({ "timestamp": now, "measurements": measurements }).to_string()
+}
+
+/**
+ * Render a range of history points for a field
+ *
+ * {"field":"outdoor_temperature","unit":"F","from":1690000000,"to":1690086400,"points":[[1690000010,72],...]}
+ */
+pub fn render_history(
+    field: MeasurementField,
+    from: i64,
+    to: i64,
+    points: &[HistoryPoint],
+) -> String {
+    let points: Vec<Value> = points
+        .iter()
+        .map(|point| json!([point.timestamp, number(point.value)]))
+        .collect();
+
+    json!({
+        "field": field.name(),
+        "unit": field.unit(),
+        "from": from,
+        "to": to,
+        "points": points,
+    })
+    .to_string()
+}
+
+/**
+ * Render the station status
+ *
+ * {"uptime":3600,"free_heap":81234,"minimum_free_heap":70000,"wifi_rssi":-61,"last_reboot":"power on",
+ *  "sensors":{"acurite5n1":{"status":"ok","last_seen":1690000000},"sht4x":{"status":"missing","last_seen":null}},
+ *  "battery":{"low_since":1690000000,"history":[{"low_since":1650000000,"replaced":1651000000},...]},
+ *  "faults":["receiver","sensor_bme280"]}
+ *
+ * The sensors are the Acurite5n1 and the indoor sensors found on the I2C bus at boot.  The faults are the peripherals
+ * that stopped answering and have not recovered.
+ */
+pub fn render_status(history: &HistoryStore, system_status: &SystemStatus, now: i64) -> String {
+    let mut sensors = Map::new();
+
+    for sensor in history.sensors() {
+        sensors.insert(
+            sensor.id().to_string(),
+            render_sensor_health(history.last_seen(sensor), now),
+        );
+    }
+
+    let faults: Vec<String> = system_status
+        .faults
+        .iter()
+        .map(|fault| fault.id())
+        .collect();
+
+    json!({
+        "uptime": system_status.uptime_secs,
+        "free_heap": system_status.free_heap,
+        "minimum_free_heap": system_status.minimum_free_heap,
+        "wifi_rssi": system_status.wifi_rssi,
+        "last_reboot": system_status.last_reboot,
+        "sensors": sensors,
+        "battery": render_battery(history.battery_low_since(), &history.battery_low_periods()),
+        "faults": faults,
+    })
+    .to_string()
+}
+
+/**
+ * Render the active alerts, the value is in the unit of the field it was raised on and the rain rate is in inches per
+ * hour
+ *
+ * {"alerts":[{"alert":"frost","value":30,"since":1690000000},...]}
+ */
+pub fn render_alerts(active_alerts: &[ActiveAlert]) -> String {
+    let alerts: Vec<Value> = active_alerts
+        .iter()
+        .map(|active_alert| {
+            json!({
+                "alert": active_alert.alert.kind.name(),
+                "value": number(active_alert.alert.value),
+                "since": active_alert.since,
+            })
+        })
+        .collect();
+
+    json!({ "alerts": alerts }).to_string()
+}
+
+pub fn render_error(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+// A json number with the shortest decimal that reads back as the f32, json! would widen 0.12 to 0.11999999731779099
+fn number(value: f32) -> Value {
+    json!(value.to_string().parse::<f64>().unwrap_or_default())
+}
+
+fn render_sensor_health(last_seen: Option<i64>, now: i64) -> Value {
+    let status = match last_seen {
+        Some(timestamp) if now - timestamp <= SENSOR_STALE_SECS => "ok",
+        Some(_) => "stale",
+        None => "missing",
+    };
+
+    json!({ "status": status, "last_seen": last_seen })
+}
+
+fn render_battery(low_since: Option<i64>, periods: &[BatteryLowPeriod]) -> Value {
+    let history: Vec<Value> = periods
+        .iter()
+        .map(|period| json!({ "low_since": period.low_since, "replaced": period.replaced }))
+        .collect();
+
+    json!({ "low_since": low_since, "history": history })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_message_is_escaped() {
+        let json = render_error("bad \"field\"\\\n\tend\u{1}");
+
+        assert_eq!(json, r#"{"error":"bad \"field\"\\\n\tend\u0001"}"#);
+        assert_eq!(
+            serde_json::from_str::<Value>(&json).unwrap()["error"],
+            "bad \"field\"\\\n\tend\u{1}"
+        );
+    }
+
+    #[test]
+    fn text_measurements_are_strings() {
+        let value = measurement_value(&Measurement::WindDirection("NNE".to_string()));
+
+        assert_eq!(value, json!("NNE"));
+    }
+
+    #[test]
+    fn rainfall_is_rounded_to_hundredths() {
+        assert_eq!(
+            measurement_value(&Measurement::DailyRainfall(0.123)),
+            json!(0.12)
+        );
+        assert_eq!(
+            measurement_value(&Measurement::OutdoorTemperature(-4)),
+            json!(-4)
+        );
+    }
+
+    #[test]
+    fn history_shape() {
+        let points = [
+            HistoryPoint {
+                timestamp: 1690000010,
+                field: MeasurementField::OutdoorTemperature,
+                value: 72.0,
+            },
+            HistoryPoint {
+                timestamp: 1690000028,
+                field: MeasurementField::OutdoorTemperature,
+                value: 72.5,
+            },
+        ];
+
+        let json = render_history(
+            MeasurementField::OutdoorTemperature,
+            1690000000,
+            1690086400,
+            &points,
+        );
+        let value: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "field": "outdoor_temperature",
+                "unit": "F",
+                "from": 1690000000,
+                "to": 1690086400,
+                "points": [[1690000010, 72.0], [1690000028, 72.5]],
+            })
+        );
+    }
+
+    #[test]
+    fn empty_history_has_no_points() {
+        let json = render_history(MeasurementField::OutdoorHumidity, 0, 60, &[]);
+        let value: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["points"], json!([]));
+        assert_eq!(value["unit"], "%");
+    }
+}
@@ -0,0 +1,11 @@
+pub mod aprs;
+pub mod backoff;
+pub mod http_server;
+pub mod influxdb;
+pub mod json;
+pub mod mqtt;
+pub mod prometheus;
+pub mod provisioning;
+pub mod pws;
+pub mod url;
+pub mod wifi;
@@ -0,0 +1,620 @@
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError};
+use embedded_svc::mqtt::client::{Event, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+use esp_idf_sys::esp_efuse_mac_get_default;
+use serde_json::{json, Value};
+
+use crate::{
+    model::{
+        alerts::AlertKind,
+        config::MqttConfig,
+        history::SharedHistory,
+        supervisor::Heartbeat,
+        weather_station::{Measurement, MeasurementField},
+    },
+    net::{backoff::Backoff, json},
+};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+const ALERT_ON: &str = "ON";
+const ALERT_OFF: &str = "OFF";
+
+// How long to wait for the broker to accept the connection before giving up and backing off
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+enum ConnectionEvent {
+    Connected,
+    Disconnected,
+}
+
+pub struct HomeAssistantClass {
+    pub device_class: Option<&'static str>,
+    pub state_class: Option<&'static str>,
+    pub unit: Option<&'static str>,
+    pub entity_category: Option<&'static str>,
+}
+
+pub fn home_assistant_class(field: MeasurementField) -> HomeAssistantClass {
+    let (device_class, state_class, unit, entity_category) = match field {
+        MeasurementField::OutdoorTemperature
+        | MeasurementField::OutdoorHeatIndex
+        | MeasurementField::OutdoorWindchill
+        | MeasurementField::OutdoorDewpoint
+        | MeasurementField::IndoorTemperature => {
+            (Some("temperature"), Some("measurement"), Some("°F"), None)
+        }
+
+        MeasurementField::OutdoorHumidity | MeasurementField::IndoorHumidity => {
+            (Some("humidity"), Some("measurement"), Some("%"), None)
+        }
+
+        MeasurementField::IndoorPressure | MeasurementField::SeaLevelPressure => (
+            Some("atmospheric_pressure"),
+            Some("measurement"),
+            Some("inHg"),
+            None,
+        ),
+
+        MeasurementField::IndoorCo2 => (
+            Some("carbon_dioxide"),
+            Some("measurement"),
+            Some("ppm"),
+            None,
+        ),
+
+        // Home Assistant has no device class for the gas resistance or an air quality score
+        MeasurementField::IndoorGasResistance => (None, Some("measurement"), Some("kΩ"), None),
+
+        MeasurementField::IndoorAirQuality => (None, Some("measurement"), Some("%"), None),
+
+        MeasurementField::CurrentWindSpeed
+        | MeasurementField::AverageWindSpeed
+        | MeasurementField::PeakWindSpeed
+        | MeasurementField::WindGust => {
+            (Some("wind_speed"), Some("measurement"), Some("mph"), None)
+        }
+
+        // The rainfall totals are reset at the start of each day, month and year which total_increasing handles
+        MeasurementField::DailyRainfall
+        | MeasurementField::MonthlyRainfall
+        | MeasurementField::YearlyRainfall => (
+            Some("precipitation"),
+            Some("total_increasing"),
+            Some("in"),
+            None,
+        ),
+
+        MeasurementField::WindDirection => (None, None, None, None),
+
+        MeasurementField::BatteryStatus
+        | MeasurementField::ChannelNumber
+        | MeasurementField::ProductId => (None, None, None, Some("diagnostic")),
+    };
+
+    HomeAssistantClass {
+        device_class,
+        state_class,
+        unit,
+        entity_category,
+    }
+}
+
+pub fn availability_topic(device_id: &str) -> String {
+    format!("weather-station/{}/availability", device_id)
+}
+
+pub fn state_topic(device_id: &str, field: MeasurementField) -> String {
+    format!("weather-station/{}/{}", device_id, field.name())
+}
+
+pub fn discovery_topic(device_id: &str, field: MeasurementField) -> String {
+    format!(
+        "{}/sensor/{}/{}/config",
+        DISCOVERY_PREFIX,
+        device_id,
+        field.name()
+    )
+}
+
+pub fn alert_state_topic(device_id: &str, kind: AlertKind) -> String {
+    format!("weather-station/{}/alert/{}", device_id, kind.name())
+}
+
+pub fn alert_discovery_topic(device_id: &str, kind: AlertKind) -> String {
+    format!(
+        "{}/binary_sensor/{}/alert_{}/config",
+        DISCOVERY_PREFIX,
+        device_id,
+        kind.name()
+    )
+}
+
+// Text measurements are published as is, numbers are published without a unit
+pub fn state_payload(measurement: &Measurement) -> String {
+    match measurement {
+        Measurement::BatteryStatus(text) | Measurement::WindDirection(text) => text.clone(),
+        _ => json::measurement_value(measurement).to_string(),
+    }
+}
+
+/**
+ * Build the Home Assistant discovery config for a field
+ *
+ * {"name":"Outdoor Temperature","unique_id":"ws_a1b2c3_outdoor_temperature","state_topic":"...",
+ *  "availability_topic":"...","device_class":"temperature","state_class":"measurement","unit_of_measurement":"°F",
+ *  "device":{"identifiers":["ws_a1b2c3"],"name":"Weather Station","model":"M5Stack Acurite5n1","manufacturer":"enelson1001"}}
+ */
+pub fn discovery_payload(device_id: &str, field: MeasurementField) -> String {
+    let class = home_assistant_class(field);
+
+    let mut payload = json!({
+        "name": title(field.name()),
+        "unique_id": format!("{}_{}", device_id, field.name()),
+        "state_topic": state_topic(device_id, field),
+        "availability_topic": availability_topic(device_id),
+    });
+
+    let optional_keys = [
+        ("device_class", class.device_class),
+        ("state_class", class.state_class),
+        ("unit_of_measurement", class.unit),
+        ("entity_category", class.entity_category),
+    ];
+
+    for (key, value) in optional_keys {
+        if let Some(value) = value {
+            payload[key] = json!(value);
+        }
+    }
+
+    payload["device"] = device(device_id);
+
+    payload.to_string()
+}
+
+/**
+ * Build the Home Assistant discovery config for an alert, each alert is a binary sensor that is on while it is active
+ *
+ * {"name":"Frost Alert","unique_id":"ws_a1b2c3_alert_frost","state_topic":"...","availability_topic":"...",
+ *  "device_class":"problem","payload_on":"ON","payload_off":"OFF","device":{...}}
+ */
+pub fn alert_discovery_payload(device_id: &str, kind: AlertKind) -> String {
+    json!({
+        "name": format!("{} Alert", title(kind.name())),
+        "unique_id": format!("{}_alert_{}", device_id, kind.name()),
+        "state_topic": alert_state_topic(device_id, kind),
+        "availability_topic": availability_topic(device_id),
+        "device_class": "problem",
+        "payload_on": ALERT_ON,
+        "payload_off": ALERT_OFF,
+        "device": device(device_id),
+    })
+    .to_string()
+}
+
+// The station as a Home Assistant device, every sensor and alert of the station is grouped under it
+fn device(device_id: &str) -> Value {
+    json!({
+        "identifiers": [device_id],
+        "name": "Weather Station",
+        "model": "M5Stack Acurite5n1",
+        "manufacturer": "enelson1001",
+    })
+}
+
+// outdoor_temperature -> Outdoor Temperature
+fn title(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// A device id built from the wifi mac address so that each station gets unique topics and entities
+pub fn device_id() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+
+    format!("ws_{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+pub struct MqttPublisher {
+    rx: Receiver<Measurement>, // Receive from WeatherStation
+    history: SharedHistory,
+    config: MqttConfig,
+    device_id: String,
+}
+
+impl MqttPublisher {
+    pub fn new(rx: Receiver<Measurement>, history: SharedHistory, config: MqttConfig) -> Self {
+        Self {
+            rx,
+            history,
+            config,
+            device_id: device_id(),
+        }
+    }
+
+    pub fn is_configured(config: &MqttConfig) -> bool {
+        !config.broker_url.is_empty()
+    }
+
+    pub fn start(self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        println!("Starting MqttPublisher Thread");
+
+        std::thread::Builder::new()
+            .stack_size(6144)
+            .spawn(move || {
+                let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+
+                loop {
+                    self.run_session(&mut backoff, &heartbeat);
+
+                    let delay = backoff.next_delay();
+                    println!("MQTT disconnected, reconnecting in {} s", delay.as_secs());
+                    self.wait_to_reconnect(delay, &heartbeat);
+                }
+            })
+            .unwrap()
+    }
+
+    /**
+     * Wait before connecting again, dropping the measurements that arrive meanwhile so the channel does not fill up
+     * while the broker or the network is down.  announce publishes the latest values once connected.
+     */
+    fn wait_to_reconnect(&self, delay: Duration, heartbeat: &Heartbeat) {
+        let reconnect_at = Instant::now() + delay;
+
+        while let Some(remaining) = reconnect_at.checked_duration_since(Instant::now()) {
+            heartbeat.beat();
+
+            match self.rx.recv_timeout(remaining.min(Duration::from_secs(1))) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    heartbeat.sleep(remaining);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Connect to the broker and publish measurements until the connection is lost
+    fn run_session(&self, backoff: &mut Backoff, heartbeat: &Heartbeat) {
+        let availability_topic = availability_topic(&self.device_id);
+        let (event_tx, event_rx) = bounded(5);
+
+        let conf = MqttClientConfiguration {
+            client_id: Some(&self.device_id),
+            username: (!self.config.username.is_empty()).then_some(self.config.username.as_str()),
+            password: (!self.config.password.is_empty()).then_some(self.config.password.as_str()),
+            lwt: Some(LwtConfiguration {
+                topic: &availability_topic,
+                payload: OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        };
+
+        let mut client = match EspMqttClient::new(&self.config.broker_url, &conf, move |event| {
+            let connection_event = match event {
+                Ok(Event::Connected(_)) => Some(ConnectionEvent::Connected),
+                Ok(Event::Disconnected) => Some(ConnectionEvent::Disconnected),
+                Err(e) => {
+                    println!("MQTT error {:?}", e);
+                    None
+                }
+                _ => None,
+            };
+
+            if let Some(connection_event) = connection_event {
+                let _ = event_tx.try_send(connection_event);
+            }
+        }) {
+            Ok(client) => client,
+            Err(e) => {
+                println!("Failed to create MQTT client {:?}", e);
+                return;
+            }
+        };
+
+        // Wait for the broker to accept the connection
+        let connect_started = Instant::now();
+        loop {
+            heartbeat.beat();
+            while self.rx.try_recv().is_ok() {}
+
+            match event_rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(ConnectionEvent::Connected) => break,
+                Ok(ConnectionEvent::Disconnected) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => {
+                    if connect_started.elapsed() > CONNECT_TIMEOUT {
+                        return;
+                    }
+                }
+            }
+        }
+
+        println!("MQTT connected to {}", self.config.broker_url);
+        backoff.reset();
+
+        // Anything queued while disconnected is older than the latest values published by announce
+        while self.rx.try_recv().is_ok() {}
+
+        if self.announce(&mut client, &availability_topic).is_err() {
+            return;
+        }
+
+        // The alerts are read from the history store, every alert is published by announce
+        let mut published_alerts = match self.publish_alerts(&mut client, None) {
+            Ok(active_alerts) => active_alerts,
+            Err(_) => return,
+        };
+
+        loop {
+            heartbeat.beat();
+
+            if let Ok(ConnectionEvent::Disconnected) = event_rx.try_recv() {
+                return;
+            }
+
+            match self.rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(measurement) => {
+                    if self.publish_state(&mut client, &measurement).is_err() {
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            published_alerts = match self.publish_alerts(&mut client, Some(&published_alerts)) {
+                Ok(active_alerts) => active_alerts,
+                Err(_) => return,
+            };
+        }
+    }
+
+    /**
+     * Publish availability, the discovery config of every sensor and the latest known state of every sensor.  Indoor
+     * fields that none of the sensors found on the I2C bus measure are not announced.
+     */
+    fn announce(
+        &self,
+        client: &mut EspMqttClient,
+        availability_topic: &str,
+    ) -> Result<(), esp_idf_sys::EspError> {
+        client.publish(
+            availability_topic,
+            QoS::AtLeastOnce,
+            true,
+            ONLINE.as_bytes(),
+        )?;
+
+        let fields: Vec<MeasurementField> = {
+            let history = self.history.lock().unwrap();
+            MeasurementField::ALL
+                .iter()
+                .filter(|field| history.field_sensor(**field).is_some())
+                .copied()
+                .collect()
+        };
+
+        for field in fields {
+            client.publish(
+                &discovery_topic(&self.device_id, field),
+                QoS::AtLeastOnce,
+                true,
+                discovery_payload(&self.device_id, field).as_bytes(),
+            )?;
+        }
+
+        for kind in AlertKind::ALL {
+            client.publish(
+                &alert_discovery_topic(&self.device_id, kind),
+                QoS::AtLeastOnce,
+                true,
+                alert_discovery_payload(&self.device_id, kind).as_bytes(),
+            )?;
+        }
+
+        let latest: Vec<Measurement> = {
+            let history = self.history.lock().unwrap();
+            MeasurementField::ALL
+                .iter()
+                .filter_map(|field| history.latest(*field))
+                .map(|latest| latest.measurement.clone())
+                .collect()
+        };
+
+        for measurement in latest.iter() {
+            self.publish_state(client, measurement)?;
+        }
+
+        Ok(())
+    }
+
+    fn publish_state(
+        &self,
+        client: &mut EspMqttClient,
+        measurement: &Measurement,
+    ) -> Result<(), esp_idf_sys::EspError> {
+        client.publish(
+            &state_topic(&self.device_id, measurement.field()),
+            QoS::AtLeastOnce,
+            true,
+            state_payload(measurement).as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /**
+     * Publish the state of the alerts that changed since the published ones, or of every alert when none have been
+     * published yet.  Returns the alerts that are active.
+     */
+    fn publish_alerts(
+        &self,
+        client: &mut EspMqttClient,
+        published: Option<&[AlertKind]>,
+    ) -> Result<Vec<AlertKind>, esp_idf_sys::EspError> {
+        let active_alerts: Vec<AlertKind> = self
+            .history
+            .lock()
+            .unwrap()
+            .active_alerts()
+            .iter()
+            .map(|active_alert| active_alert.alert.kind)
+            .collect();
+
+        for kind in AlertKind::ALL {
+            let is_active = active_alerts.contains(&kind);
+
+            if published.map_or(true, |published| published.contains(&kind) != is_active) {
+                client.publish(
+                    &alert_state_topic(&self.device_id, kind),
+                    QoS::AtLeastOnce,
+                    true,
+                    if is_active { ALERT_ON } else { ALERT_OFF }.as_bytes(),
+                )?;
+            }
+        }
+
+        Ok(active_alerts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(payload: &str) -> Value {
+        serde_json::from_str(payload).unwrap()
+    }
+
+    #[test]
+    fn topics() {
+        assert_eq!(
+            state_topic("ws_a1b2c3", MeasurementField::OutdoorTemperature),
+            "weather-station/ws_a1b2c3/outdoor_temperature"
+        );
+        assert_eq!(
+            availability_topic("ws_a1b2c3"),
+            "weather-station/ws_a1b2c3/availability"
+        );
+        assert_eq!(
+            discovery_topic("ws_a1b2c3", MeasurementField::DailyRainfall),
+            "homeassistant/sensor/ws_a1b2c3/daily_rainfall/config"
+        );
+        assert_eq!(
+            alert_state_topic("ws_a1b2c3", AlertKind::Frost),
+            "weather-station/ws_a1b2c3/alert/frost"
+        );
+        assert_eq!(
+            alert_discovery_topic("ws_a1b2c3", AlertKind::Frost),
+            "homeassistant/binary_sensor/ws_a1b2c3/alert_frost/config"
+        );
+    }
+
+    #[test]
+    fn state_payloads() {
+        assert_eq!(state_payload(&Measurement::OutdoorTemperature(72)), "72");
+        assert_eq!(
+            state_payload(&Measurement::SeaLevelPressure(29.921)),
+            "29.92"
+        );
+        assert_eq!(
+            state_payload(&Measurement::WindDirection("NNE".to_string())),
+            "NNE"
+        );
+        assert_eq!(
+            state_payload(&Measurement::BatteryStatus("OK".to_string())),
+            "OK"
+        );
+    }
+
+    #[test]
+    fn home_assistant_classes() {
+        let class = home_assistant_class(MeasurementField::SeaLevelPressure);
+        assert_eq!(class.device_class, Some("atmospheric_pressure"));
+        assert_eq!(class.unit, Some("inHg"));
+
+        let class = home_assistant_class(MeasurementField::YearlyRainfall);
+        assert_eq!(class.state_class, Some("total_increasing"));
+
+        let class = home_assistant_class(MeasurementField::ProductId);
+        assert_eq!(class.device_class, None);
+        assert_eq!(class.entity_category, Some("diagnostic"));
+    }
+
+    #[test]
+    fn sensor_discovery_payload() {
+        let payload = parse(&discovery_payload(
+            "ws_a1b2c3",
+            MeasurementField::OutdoorTemperature,
+        ));
+
+        assert_eq!(payload["name"], "Outdoor Temperature");
+        assert_eq!(payload["unique_id"], "ws_a1b2c3_outdoor_temperature");
+        assert_eq!(
+            payload["state_topic"],
+            "weather-station/ws_a1b2c3/outdoor_temperature"
+        );
+        assert_eq!(
+            payload["availability_topic"],
+            "weather-station/ws_a1b2c3/availability"
+        );
+        assert_eq!(payload["device_class"], "temperature");
+        assert_eq!(payload["state_class"], "measurement");
+        assert_eq!(payload["unit_of_measurement"], "°F");
+        assert_eq!(payload["device"]["identifiers"][0], "ws_a1b2c3");
+        assert!(payload.get("entity_category").is_none());
+    }
+
+    #[test]
+    fn fields_without_a_class_leave_the_keys_out() {
+        let payload = parse(&discovery_payload(
+            "ws_a1b2c3",
+            MeasurementField::WindDirection,
+        ));
+
+        assert!(payload.get("device_class").is_none());
+        assert!(payload.get("state_class").is_none());
+        assert!(payload.get("unit_of_measurement").is_none());
+    }
+
+    #[test]
+    fn alert_discovery() {
+        let payload = parse(&alert_discovery_payload("ws_a1b2c3", AlertKind::HighWind));
+
+        assert_eq!(payload["name"], "High Wind Alert");
+        assert_eq!(payload["unique_id"], "ws_a1b2c3_alert_high_wind");
+        assert_eq!(
+            payload["state_topic"],
+            "weather-station/ws_a1b2c3/alert/high_wind"
+        );
+        assert_eq!(
+            payload["availability_topic"],
+            "weather-station/ws_a1b2c3/availability"
+        );
+        assert_eq!(payload["device_class"], "problem");
+        assert_eq!(payload["payload_on"], "ON");
+        assert_eq!(payload["payload_off"], "OFF");
+        assert_eq!(payload["device"]["name"], "Weather Station");
+    }
+}
@@ -0,0 +1,442 @@
+use std::fmt::Write;
+
+use crate::{
+    model::{
+        alerts::AlertKind,
+        faults::Fault,
+        history::HistoryStore,
+        statistics::{read, LoopLatency, Statistics},
+        weather_station::{compass_point_to_degrees, Measurement, MeasurementField},
+    },
+    net::http_server::SystemStatus,
+};
+
+/**
+ * Render the metrics in the Prometheus text exposition format
+ *
+ * # HELP weather_outdoor_temperature_fahrenheit Outdoor temperature
+ * # TYPE weather_outdoor_temperature_fahrenheit gauge
+ * weather_outdoor_temperature_fahrenheit 72
+ *
+ * Every measurement the station has received is exported as a gauge named after its field and unit.  The rainfall
+ * totals only go up until they are reset at the start of a day, month or year so they are exported as counters.
+ */
+pub fn render_metrics(
+    history: &HistoryStore,
+    system_status: &SystemStatus,
+    statistics: &Statistics,
+    now: i64,
+) -> String {
+    let mut text = String::with_capacity(4096);
+
+    for field in MeasurementField::ALL {
+        if let Some(latest) = history.latest(field) {
+            if let Some(value) = metric_value(&latest.measurement) {
+                let kind = metric_kind(field);
+                write_metric(&mut text, &metric_name(field), kind, &help(field), value);
+            }
+        }
+    }
+
+    let sensor_ages: Vec<_> = history
+        .sensors()
+        .into_iter()
+        .filter_map(|sensor| Some((sensor, now - history.last_seen(sensor)?)))
+        .collect();
+
+    if !sensor_ages.is_empty() {
+        write_header(
+            &mut text,
+            "weather_sensor_age_seconds",
+            "gauge",
+            "Seconds since the sensor was last heard from",
+        );
+        for (sensor, age) in sensor_ages {
+            write_sample(
+                &mut text,
+                &format!("weather_sensor_age_seconds{{sensor=\"{}\"}}", sensor.id()),
+                age as f32,
+            );
+        }
+    }
+
+    // Every alert is exported, 1 while it is active
+    write_header(
+        &mut text,
+        "weather_alert_active",
+        "gauge",
+        "1 while the alert is active",
+    );
+    for kind in AlertKind::ALL {
+        let active = history
+            .active_alerts()
+            .iter()
+            .any(|active_alert| active_alert.alert.kind == kind);
+
+        write_sample(
+            &mut text,
+            &format!("weather_alert_active{{alert=\"{}\"}}", kind.name()),
+            if active { 1.0 } else { 0.0 },
+        );
+    }
+
+    // The faults of the receiver, the RTC, the display and the indoor sensors that were found, 1 while it is active
+    write_header(
+        &mut text,
+        "weather_station_fault_active",
+        "gauge",
+        "1 while the peripheral has stopped answering",
+    );
+    for fault in Fault::all() {
+        if let Fault::IndoorSensor(sensor) = fault {
+            if !history.sensors().contains(&sensor) {
+                continue;
+            }
+        }
+
+        write_sample(
+            &mut text,
+            &format!("weather_station_fault_active{{fault=\"{}\"}}", fault.id()),
+            if system_status.faults.contains(&fault) {
+                1.0
+            } else {
+                0.0
+            },
+        );
+    }
+
+    write_metric(
+        &mut text,
+        "weather_station_uptime_seconds",
+        "gauge",
+        "Seconds since the station booted",
+        system_status.uptime_secs as f32,
+    );
+    write_metric(
+        &mut text,
+        "weather_station_free_heap_bytes",
+        "gauge",
+        "Free heap",
+        system_status.free_heap as f32,
+    );
+    write_metric(
+        &mut text,
+        "weather_station_minimum_free_heap_bytes",
+        "gauge",
+        "Lowest free heap since boot",
+        system_status.minimum_free_heap as f32,
+    );
+    if let Some(rssi) = system_status.wifi_rssi {
+        write_metric(
+            &mut text,
+            "weather_station_wifi_rssi_dbm",
+            "gauge",
+            "Signal strength of the wifi access point",
+            rssi as f32,
+        );
+    }
+
+    let counters = [
+        (
+            "weather_station_frames_decoded_total",
+            "Acurite5n1 messages that passed the integrity checks",
+            &statistics.frames_decoded,
+        ),
+        (
+            "weather_station_crc_failures_total",
+            "Acurite5n1 messages with a bad checksum",
+            &statistics.crc_failures,
+        ),
+        (
+            "weather_station_parity_failures_total",
+            "Acurite5n1 messages with bad parity",
+            &statistics.parity_failures,
+        ),
+        (
+            "weather_station_invalid_message_types_total",
+            "Acurite5n1 messages with an unknown message type",
+            &statistics.invalid_message_types,
+        ),
+        (
+            "weather_station_rmt_overflows_total",
+            "RMT receive buffer overflows",
+            &statistics.rmt_overflows,
+        ),
+        (
+            "weather_station_rmt_timeouts_total",
+            "RMT receive timeouts",
+            &statistics.rmt_timeouts,
+        ),
+        (
+            "weather_station_channel_send_failures_total",
+            "Messages dropped because a channel was full or its receiving thread stopped",
+            &statistics.channel_send_failures,
+        ),
+        (
+            "weather_station_bus_retries_total",
+            "I2C and SPI transfers that failed and were retried",
+            &statistics.bus_retries,
+        ),
+        (
+            "weather_station_thread_restarts_total",
+            "Threads the supervisor restarted after they stopped",
+            &statistics.thread_restarts,
+        ),
+        (
+            "weather_station_unpaired_sensor_messages_total",
+            "Acurite5n1 messages dropped because they came from a sensor that is not paired",
+            &statistics.unpaired_sensor_messages,
+        ),
+    ];
+
+    for (name, help, counter) in counters {
+        write_metric(&mut text, name, "counter", help, read(counter) as f32);
+    }
+
+    write_loop_latency(
+        &mut text,
+        &[
+            ("weather_station", &statistics.weather_station_loop),
+            ("gui", &statistics.gui_loop),
+        ],
+    );
+
+    text
+}
+
+// weather_outdoor_temperature_fahrenheit, weather_daily_rainfall_inches_total
+pub fn metric_name(field: MeasurementField) -> String {
+    let mut name = format!("weather_{}", field.name());
+
+    let suffix = match field.unit() {
+        "F" => Some("fahrenheit"),
+        "%" => Some("percent"),
+        "inHg" => Some("inches_of_mercury"),
+        "mph" => Some("miles_per_hour"),
+        "in" => Some("inches"),
+        "ppm" => Some("ppm"),
+        "kOhm" => Some("kiloohms"),
+        _ => None,
+    };
+
+    if let Some(suffix) = suffix {
+        name.push('_');
+        name.push_str(suffix);
+    }
+
+    if field == MeasurementField::WindDirection {
+        name.push_str("_degrees");
+    }
+
+    if metric_kind(field) == "counter" {
+        name.push_str("_total");
+    }
+
+    name
+}
+
+fn metric_kind(field: MeasurementField) -> &'static str {
+    match field {
+        MeasurementField::DailyRainfall
+        | MeasurementField::MonthlyRainfall
+        | MeasurementField::YearlyRainfall => "counter",
+        _ => "gauge",
+    }
+}
+
+// The wind direction is exported in degrees and the battery status as 1 for ok and 0 for replace
+fn metric_value(measurement: &Measurement) -> Option<f32> {
+    match measurement {
+        Measurement::WindDirection(compass_point) => compass_point_to_degrees(compass_point),
+        Measurement::BatteryStatus(status) => Some(if status == "OK" { 1.0 } else { 0.0 }),
+        _ => measurement.numeric_value(),
+    }
+}
+
+// outdoor_temperature -> Outdoor temperature
+fn help(field: MeasurementField) -> String {
+    let text = field.name().replace('_', " ");
+    let mut chars = text.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => text,
+    }
+}
+
+fn write_loop_latency(text: &mut String, threads: &[(&str, &LoopLatency)]) {
+    write_header(
+        text,
+        "weather_station_loop_latency_seconds",
+        "gauge",
+        "Time the thread spent in its last loop pass",
+    );
+    for (thread, latency) in threads {
+        write_sample(
+            text,
+            &format!(
+                "weather_station_loop_latency_seconds{{thread=\"{}\"}}",
+                thread
+            ),
+            read(&latency.last_us) as f32 / 1_000_000.0,
+        );
+    }
+
+    write_header(
+        text,
+        "weather_station_loop_latency_max_seconds",
+        "gauge",
+        "Longest loop pass of the thread since boot",
+    );
+    for (thread, latency) in threads {
+        write_sample(
+            text,
+            &format!(
+                "weather_station_loop_latency_max_seconds{{thread=\"{}\"}}",
+                thread
+            ),
+            read(&latency.max_us) as f32 / 1_000_000.0,
+        );
+    }
+}
+
+// A metric without labels, its HELP and TYPE followed by its one sample
+fn write_metric(text: &mut String, name: &str, kind: &str, help: &str, value: f32) {
+    write_header(text, name, kind, help);
+    write_sample(text, name, value);
+}
+
+// The HELP and TYPE lines are written once for a metric, before all of its samples
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_sample(text: &mut String, name: &str, value: f32) {
+    writeln!(text, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::statistics::increment;
+    use std::time::Duration;
+
+    fn system_status() -> SystemStatus {
+        SystemStatus {
+            uptime_secs: 3600,
+            free_heap: 120000,
+            minimum_free_heap: 90000,
+            wifi_rssi: None,
+            faults: vec![Fault::Rtc],
+            last_reboot: "power on".to_string(),
+        }
+    }
+
+    fn render(history: &HistoryStore, statistics: &Statistics) -> String {
+        render_metrics(history, &system_status(), statistics, 1690000000)
+    }
+
+    #[test]
+    fn names_carry_the_unit() {
+        assert_eq!(
+            metric_name(MeasurementField::OutdoorTemperature),
+            "weather_outdoor_temperature_fahrenheit"
+        );
+        assert_eq!(
+            metric_name(MeasurementField::SeaLevelPressure),
+            "weather_sea_level_pressure_inches_of_mercury"
+        );
+        assert_eq!(
+            metric_name(MeasurementField::WindGust),
+            "weather_wind_gust_miles_per_hour"
+        );
+        assert_eq!(
+            metric_name(MeasurementField::WindDirection),
+            "weather_wind_direction_degrees"
+        );
+        assert_eq!(
+            metric_name(MeasurementField::DailyRainfall),
+            "weather_daily_rainfall_inches_total"
+        );
+    }
+
+    #[test]
+    fn measurements_are_gauges_and_rainfall_totals_are_counters() {
+        let mut history = HistoryStore::default();
+        history.record_at(1690000000, &Measurement::OutdoorTemperature(72));
+        history.record_at(1690000000, &Measurement::WindDirection("SW".to_string()));
+        history.record_at(1690000000, &Measurement::DailyRainfall(0.5));
+
+        let text = render(&history, &Statistics::new());
+
+        assert!(text.contains(
+            "# HELP weather_outdoor_temperature_fahrenheit Outdoor temperature\n\
+             # TYPE weather_outdoor_temperature_fahrenheit gauge\n\
+             weather_outdoor_temperature_fahrenheit 72\n"
+        ));
+        assert!(text.contains(
+            "# TYPE weather_wind_direction_degrees gauge\nweather_wind_direction_degrees 225\n"
+        ));
+        assert!(text.contains(
+            "# HELP weather_daily_rainfall_inches_total Daily rainfall\n\
+             # TYPE weather_daily_rainfall_inches_total counter\n\
+             weather_daily_rainfall_inches_total 0.5\n"
+        ));
+        // Only the measurements that were received are exported
+        assert!(!text.contains("weather_outdoor_humidity"));
+        assert!(!text.contains("weather_station_wifi_rssi_dbm"));
+    }
+
+    #[test]
+    fn station_counters_and_faults() {
+        let statistics = Statistics::new();
+        increment(&statistics.frames_decoded);
+        increment(&statistics.frames_decoded);
+
+        let text = render(&HistoryStore::default(), &statistics);
+
+        assert!(text.contains(
+            "# TYPE weather_station_frames_decoded_total counter\n\
+             weather_station_frames_decoded_total 2\n"
+        ));
+        assert!(text.contains("weather_station_crc_failures_total 0\n"));
+        assert!(text.contains("weather_station_uptime_seconds 3600\n"));
+        assert!(text.contains("weather_station_fault_active{fault=\"rtc\"} 1\n"));
+        assert!(text.contains("weather_station_fault_active{fault=\"receiver\"} 0\n"));
+    }
+
+    #[test]
+    fn labelled_samples_share_one_header() {
+        let statistics = Statistics::new();
+        statistics
+            .weather_station_loop
+            .record(Duration::from_millis(250));
+
+        let text = render(&HistoryStore::default(), &statistics);
+
+        assert!(text.contains(
+            "# HELP weather_station_loop_latency_seconds Time the thread spent in its last loop pass\n\
+             # TYPE weather_station_loop_latency_seconds gauge\n\
+             weather_station_loop_latency_seconds{thread=\"weather_station\"} 0.25\n\
+             weather_station_loop_latency_seconds{thread=\"gui\"} 0\n"
+        ));
+        assert!(text.contains(
+            "weather_station_loop_latency_max_seconds{thread=\"weather_station\"} 0.25\n"
+        ));
+
+        let type_lines: Vec<_> = text
+            .lines()
+            .filter(|line| line.starts_with("# TYPE"))
+            .collect();
+        for line in type_lines.iter() {
+            assert_eq!(
+                type_lines.iter().filter(|other| *other == line).count(),
+                1,
+                "{}",
+                line
+            );
+        }
+        assert_eq!(text.matches("# TYPE weather_alert_active gauge").count(), 1);
+    }
+}
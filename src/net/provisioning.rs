@@ -0,0 +1,654 @@
+use std::fmt::Write as _;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use embedded_svc::{
+    http::{Method, Query},
+    io::{Read, Write},
+    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, Wifi},
+};
+use esp_idf_hal::{delay::FreeRtos, reset};
+use esp_idf_svc::{
+    http::server::{self, EspHttpServer},
+    wifi::EspWifi,
+};
+
+use crate::{
+    model::config::{ConfigError, ConfigStore, SharedConfig, WifiConfig},
+    net::wifi::NetworkEvent,
+};
+
+const AP_SSID_PREFIX: &str = "WeatherStation-";
+
+// The credentials form is small, anything larger is not from the portal page
+const MAX_FORM_BODY: usize = 1024;
+
+const DNS_PORT: u16 = 53;
+const DNS_TTL_SECS: u32 = 60;
+// How often the DNS responder checks whether the portal was stopped
+const DNS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// A network found by the scan before the access point was started
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedNetwork {
+    pub ssid: String,
+    pub signal_strength: i8,
+    pub secured: bool,
+}
+
+// The access point is named after the last two bytes of the mac so that two stations can be told apart
+pub fn ap_ssid(mac: [u8; 6]) -> String {
+    format!("{}{:02X}{:02X}", AP_SSID_PREFIX, mac[4], mac[5])
+}
+
+// Drop hidden networks and repeated access points of the same network, strongest signal first
+pub fn sort_networks(networks: Vec<ScannedNetwork>) -> Vec<ScannedNetwork> {
+    let mut sorted: Vec<ScannedNetwork> = Vec::with_capacity(networks.len());
+
+    for network in networks {
+        if network.ssid.is_empty() {
+            continue;
+        }
+
+        match sorted.iter_mut().find(|known| known.ssid == network.ssid) {
+            Some(known) if known.signal_strength < network.signal_strength => *known = network,
+            Some(_) => (),
+            None => sorted.push(network),
+        }
+    }
+
+    sorted.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    sorted
+}
+
+/**
+ * Render the portal page.  The scanned networks are offered in a list, a network that was not found can be typed in.
+ * An error from a previous post is shown above the form.
+ */
+pub fn render_portal_page(networks: &[ScannedNetwork], error: Option<&str>) -> String {
+    let mut page = String::with_capacity(2048);
+
+    page.push_str(PAGE_HEAD);
+    page.push_str("<h1>Weather Station Wi-Fi Setup</h1>\n");
+
+    if let Some(error) = error {
+        writeln!(page, "<p class=\"error\">{}</p>", html_escape(error)).unwrap();
+    }
+
+    page.push_str("<form method=\"post\" action=\"/save\">\n");
+    page.push_str("<label for=\"ssid\">Network</label>\n<select id=\"ssid\" name=\"ssid\">\n");
+
+    for network in networks {
+        writeln!(
+            page,
+            "<option value=\"{}\">{} ({} dBm{})</option>",
+            html_escape(&network.ssid),
+            html_escape(&network.ssid),
+            network.signal_strength,
+            if network.secured { ", secured" } else { "" }
+        )
+        .unwrap();
+    }
+
+    page.push_str("<option value=\"\">Other network...</option>\n</select>\n");
+    page.push_str(
+        "<label for=\"other_ssid\">Other network</label>\n\
+         <input id=\"other_ssid\" name=\"other_ssid\" maxlength=\"32\">\n\
+         <label for=\"password\">Password</label>\n\
+         <input id=\"password\" name=\"password\" type=\"password\" maxlength=\"64\">\n\
+         <label for=\"admin_token\">Admin token for changing settings over the network (optional)</label>\n\
+         <input id=\"admin_token\" name=\"admin_token\" type=\"password\" maxlength=\"64\">\n\
+         <button type=\"submit\">Save and restart</button>\n</form>\n</body>\n</html>\n",
+    );
+
+    page
+}
+
+pub fn render_saved_page(ssid: &str) -> String {
+    format!(
+        "{}<h1>Weather Station Wi-Fi Setup</h1>\n<p>Saved, the station is restarting and will join {}.</p>\n\
+         </body>\n</html>\n",
+        PAGE_HEAD,
+        html_escape(ssid)
+    )
+}
+
+const PAGE_HEAD: &str = "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>Weather Station Wi-Fi Setup</title>\n\
+<style>body{font-family:sans-serif;margin:1em auto;max-width:24em}label,input,select,button{display:block;\
+width:100%;box-sizing:border-box;margin-top:.5em}button{margin-top:1em;padding:.5em}.error{color:#b00}</style>\n\
+</head>\n<body>\n";
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// Decode an application/x-www-form-urlencoded value, a bad escape is kept as it is
+pub fn url_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let high = (bytes[i + 1] as char).to_digit(16);
+                let low = (bytes[i + 2] as char).to_digit(16);
+
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// ssid=Home&password=secret -> [("ssid", "Home"), ("password", "secret")]
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(name), url_decode(value))
+        })
+        .collect()
+}
+
+/**
+ * Read the credentials from a posted portal form.  A typed in network name wins over the one picked from the list.
+ * The lengths are checked by the config validation when the credentials are saved.
+ */
+pub fn parse_credentials(body: &str) -> Result<WifiConfig, ConfigError> {
+    let form = parse_form(body);
+    let value = |name: &str| {
+        form.iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+            .unwrap_or_default()
+    };
+
+    let ssid = match value("other_ssid").trim() {
+        "" => value("ssid"),
+        other_ssid => other_ssid,
+    };
+
+    if ssid.is_empty() {
+        return Err(ConfigError::Invalid {
+            setting: "wifi.ssid",
+            reason: "must not be empty",
+        });
+    }
+
+    Ok(WifiConfig {
+        ssid: ssid.to_string(),
+        password: value("password").to_string(),
+    })
+}
+
+// The admin token typed into the portal form, empty when it was left blank
+pub fn parse_admin_token(body: &str) -> String {
+    parse_form(body)
+        .into_iter()
+        .find(|(name, _)| name == "admin_token")
+        .map(|(_, value)| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+/**
+ * Store new credentials, the rest of the config is kept as it is.  The portal is an open access point, so an admin
+ * token given on it is only taken while none is stored, anyone in range could otherwise replace it.
+ */
+pub fn save_credentials(
+    store: &mut ConfigStore,
+    wifi: WifiConfig,
+    admin_token: String,
+) -> Result<(), ConfigError> {
+    let mut config = store.config().clone();
+    config.wifi = wifi;
+    if !admin_token.is_empty() && config.http.admin_token.is_empty() {
+        config.http.admin_token = admin_token;
+    }
+
+    store.update(config)
+}
+
+/**
+ * Answer a DNS query with the address of the portal so that any name the phone looks up leads to the portal.  Only
+ * A queries get an answer, other types get an empty answer so the client falls back to IPv4.  Anything that is not a
+ * standard query is ignored.
+ */
+pub fn dns_response(query: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+
+    // Must be a query (QR clear) with the standard opcode and at least one question
+    if flags & 0xf800 != 0 || question_count == 0 {
+        return None;
+    }
+
+    // Walk the labels of the first question name
+    let mut end = HEADER_LEN;
+    loop {
+        let length = *query.get(end)? as usize;
+        end += 1;
+        if length == 0 {
+            break;
+        }
+        if length & 0xc0 != 0 {
+            return None;
+        }
+        end += length;
+    }
+
+    let question_type = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let answer = question_type == 1 || question_type == 255;
+
+    let mut response = Vec::with_capacity(end + 16);
+    response.extend_from_slice(&query[0..2]);
+    // QR, recursion desired copied from the query, recursion available
+    response.extend_from_slice(&(0x8080 | (flags & 0x0100)).to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&(answer as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(&query[HEADER_LEN..end]);
+
+    if answer {
+        // Name is a pointer to the question, type A, class IN
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        response.extend_from_slice(&DNS_TTL_SECS.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&address.octets());
+    }
+
+    Some(response)
+}
+
+/**
+ * The captive portal used to enter the wifi credentials when the station can not connect.  The station starts an
+ * open access point, answers every DNS query with its own address and serves the portal page for every url so that
+ * phones show the page as soon as they join.  Saved credentials are stored in the config and the station restarts.
+ *
+ * The station interface keeps the stored credentials so the WifiSupervisor can retry the stored network while the
+ * portal is up, main stops the portal when it connects.
+ */
+pub struct CaptivePortal {
+    config: SharedConfig,
+    cbc_tx_network_event: Sender<NetworkEvent>,
+}
+
+impl CaptivePortal {
+    pub fn new(config: SharedConfig, tx: Sender<NetworkEvent>) -> Self {
+        Self {
+            config,
+            cbc_tx_network_event: tx,
+        }
+    }
+
+    // The portal runs until the returned RunningPortal is dropped
+    pub fn start(
+        self,
+        esp_wifi: &mut EspWifi<'static>,
+        wifi_config: &WifiConfig,
+    ) -> Result<RunningPortal> {
+        println!("Starting CaptivePortal");
+
+        let ap_ssid = ap_ssid(esp_wifi.ap_netif().get_mac()?);
+
+        // The station interface stays up in mixed mode so the networks around can be scanned and the stored network
+        // can be retried
+        let _ = esp_wifi.stop();
+        esp_wifi.set_configuration(&Configuration::Mixed(
+            ClientConfiguration {
+                ssid: wifi_config.ssid.as_str().into(),
+                password: wifi_config.password.as_str().into(),
+                ..Default::default()
+            },
+            AccessPointConfiguration {
+                ssid: ap_ssid.as_str().into(),
+                auth_method: AuthMethod::None,
+                channel: 1,
+                ..Default::default()
+            },
+        ))?;
+        esp_wifi.start()?;
+
+        let networks = sort_networks(
+            esp_wifi
+                .scan()?
+                .into_iter()
+                .map(|access_point| ScannedNetwork {
+                    ssid: access_point.ssid.to_string(),
+                    signal_strength: access_point.signal_strength,
+                    secured: access_point.auth_method != AuthMethod::None,
+                })
+                .collect(),
+        );
+        println!("Found {} wifi networks", networks.len());
+
+        let address = esp_wifi.ap_netif().get_ip_info()?.ip;
+
+        let dns_running = Arc::new(AtomicBool::new(true));
+        start_dns_responder(address, dns_running.clone())?;
+
+        let mut server = EspHttpServer::new(&server::Configuration {
+            uri_match_wildcard: true,
+            ..Default::default()
+        })?;
+
+        let portal_networks = networks.clone();
+        let config = self.config;
+        let tx = self.cbc_tx_network_event.clone();
+        server.fn_handler("/save", Method::Post, move |mut request| {
+            let mut body = Vec::new();
+            let mut buf = [0u8; 256];
+
+            loop {
+                let length = request.read(&mut buf)?;
+                if length == 0 || body.len() + length > MAX_FORM_BODY {
+                    break;
+                }
+
+                body.extend_from_slice(&buf[..length]);
+            }
+
+            let body = String::from_utf8_lossy(&body);
+            let result = parse_credentials(&body).and_then(|wifi| {
+                save_credentials(
+                    &mut config.lock().unwrap(),
+                    wifi.clone(),
+                    parse_admin_token(&body),
+                )?;
+                Ok(wifi)
+            });
+
+            match result {
+                Ok(wifi) => {
+                    request
+                        .into_response(200, None, &[("Content-Type", "text/html")])?
+                        .write_all(render_saved_page(&wifi.ssid).as_bytes())?;
+
+                    println!("Wifi credentials saved for {}, restarting", wifi.ssid);
+                    tx.send(NetworkEvent::CredentialsSaved { ssid: wifi.ssid })
+                        .unwrap();
+
+                    // Give the page time to go out before restarting
+                    FreeRtos::delay_ms(1000);
+                    reset::restart();
+                }
+                Err(e) => {
+                    request
+                        .into_response(400, None, &[("Content-Type", "text/html")])?
+                        .write_all(
+                            render_portal_page(&portal_networks, Some(&e.to_string())).as_bytes(),
+                        )?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        // Phones probe a url of their own to detect a captive portal, anything but the page is sent to the page
+        let location = format!("http://{}/", address);
+        server.fn_handler("/*", Method::Get, move |request| {
+            if request.uri().split('?').next().unwrap_or_default() == "/" {
+                request
+                    .into_response(200, None, &[("Content-Type", "text/html")])?
+                    .write_all(render_portal_page(&networks, None).as_bytes())?;
+            } else {
+                request.into_response(302, None, &[("Location", location.as_str())])?;
+            }
+
+            Ok(())
+        })?;
+
+        self.cbc_tx_network_event
+            .send(NetworkEvent::Provisioning { ap_ssid, address })
+            .unwrap();
+
+        Ok(RunningPortal {
+            _server: server,
+            dns_running,
+        })
+    }
+}
+
+// Dropping the running portal stops the page server and the DNS responder
+pub struct RunningPortal {
+    _server: EspHttpServer,
+    dns_running: Arc<AtomicBool>,
+}
+
+impl Drop for RunningPortal {
+    fn drop(&mut self) {
+        println!("Stopping CaptivePortal");
+        self.dns_running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn start_dns_responder(address: Ipv4Addr, running: Arc<AtomicBool>) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+    socket.set_read_timeout(Some(DNS_POLL_INTERVAL))?;
+
+    let _dns_responder_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buf = [0u8; 512];
+
+            while running.load(Ordering::Relaxed) {
+                match socket.recv_from(&mut buf) {
+                    Ok((length, client)) => {
+                        if let Some(response) = dns_response(&buf[..length], address) {
+                            let _ = socket.send_to(&response, client);
+                        }
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                    Err(e) => {
+                        println!("DNS receive failed {:?}", e);
+                        FreeRtos::delay_ms(1000);
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::config::{to_json, Config, MemoryConfigBackend};
+
+    const PORTAL: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    fn config_store(config: &Config) -> ConfigStore {
+        ConfigStore::load(Box::new(MemoryConfigBackend(Some(to_json(config)))))
+    }
+
+    // A standard query with recursion desired for example.com
+    fn dns_query(question_type: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&question_type.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    #[test]
+    fn form_values_are_url_decoded() {
+        assert_eq!(url_decode("my+home"), "my home");
+        assert_eq!(url_decode("p%40ss%26word%3D1"), "p@ss&word=1");
+        assert_eq!(url_decode("caf%C3%A9"), "café");
+        // A bad escape is kept as it is
+        assert_eq!(url_decode("100%zz"), "100%zz");
+        assert_eq!(url_decode("50%4"), "50%4");
+        assert_eq!(url_decode("%"), "%");
+    }
+
+    #[test]
+    fn form_is_split_into_decoded_pairs() {
+        assert_eq!(
+            parse_form("ssid=My+Home&password=p%26ss&&flag"),
+            vec![
+                ("ssid".to_string(), "My Home".to_string()),
+                ("password".to_string(), "p&ss".to_string()),
+                ("flag".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_in_network_wins_over_the_picked_one() {
+        let wifi = parse_credentials("ssid=Home&other_ssid=Cabin+Wifi&password=secret123").unwrap();
+        assert_eq!(wifi.ssid, "Cabin Wifi");
+        assert_eq!(wifi.password, "secret123");
+
+        let wifi = parse_credentials("ssid=Home&other_ssid=+&password=secret123").unwrap();
+        assert_eq!(wifi.ssid, "Home");
+    }
+
+    #[test]
+    fn empty_network_is_rejected() {
+        assert!(matches!(
+            parse_credentials("ssid=&other_ssid=&password=secret123"),
+            Err(ConfigError::Invalid {
+                setting: "wifi.ssid",
+                ..
+            })
+        ));
+        assert!(parse_credentials("").is_err());
+    }
+
+    #[test]
+    fn admin_token_is_trimmed() {
+        assert_eq!(
+            parse_admin_token("ssid=Home&admin_token=+correct+horse+"),
+            "correct horse"
+        );
+        assert_eq!(parse_admin_token("ssid=Home"), "");
+    }
+
+    #[test]
+    fn saving_credentials_keeps_the_rest_of_the_config() {
+        let mut stored = Config::new();
+        stored.station.altitude_meters = 780.0;
+        stored.mqtt.broker_url = "mqtt://broker:1883".to_string();
+        let mut store = config_store(&stored);
+
+        let wifi = WifiConfig {
+            ssid: "Home".to_string(),
+            password: "secret123".to_string(),
+        };
+        save_credentials(&mut store, wifi.clone(), String::new()).unwrap();
+
+        assert_eq!(store.config().wifi, wifi);
+        assert_eq!(store.config().station, stored.station);
+        assert_eq!(store.config().mqtt, stored.mqtt);
+        // A blank token leaves the config without one
+        assert_eq!(store.config().http.admin_token, "");
+    }
+
+    #[test]
+    fn portal_sets_the_admin_token_only_while_none_is_stored() {
+        let mut store = config_store(&Config::new());
+        let wifi = WifiConfig {
+            ssid: "Home".to_string(),
+            password: "secret123".to_string(),
+        };
+
+        save_credentials(&mut store, wifi.clone(), "correct horse".to_string()).unwrap();
+        assert_eq!(store.config().http.admin_token, "correct horse");
+
+        save_credentials(&mut store, wifi.clone(), "wrong horse".to_string()).unwrap();
+        assert_eq!(store.config().http.admin_token, "correct horse");
+
+        save_credentials(&mut store, wifi, String::new()).unwrap();
+        assert_eq!(store.config().http.admin_token, "correct horse");
+    }
+
+    #[test]
+    fn a_query_gets_the_portal_address() {
+        let query = dns_query(1);
+        let response = dns_response(&query, PORTAL).unwrap();
+
+        // The id, a response with recursion desired and available, one question and one answer
+        assert_eq!(
+            &response[..12],
+            &[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(&response[12..query.len()], &query[12..]);
+        assert_eq!(
+            &response[query.len()..],
+            &[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn an_ipv6_query_gets_an_empty_answer() {
+        let query = dns_query(28);
+        let response = dns_response(&query, PORTAL).unwrap();
+
+        assert_eq!(
+            &response[..12],
+            &[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn compressed_names_and_truncated_queries_are_ignored() {
+        let mut compressed = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        compressed.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        assert_eq!(dns_response(&compressed, PORTAL), None);
+
+        let query = dns_query(1);
+        assert_eq!(dns_response(&query[..8], PORTAL), None);
+        assert_eq!(dns_response(&query[..18], PORTAL), None);
+        assert_eq!(dns_response(&query[..query.len() - 1], PORTAL), None);
+    }
+
+    #[test]
+    fn responses_are_not_answered() {
+        let mut query = dns_query(1);
+        query[2] |= 0x80;
+
+        assert_eq!(dns_response(&query, PORTAL), None);
+    }
+}
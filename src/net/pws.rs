@@ -0,0 +1,482 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::http::{client::Client, Status};
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use time::OffsetDateTime;
+
+use crate::{
+    model::{
+        config::PwsConfig,
+        history::{now_timestamp, HistoryStore, SharedHistory},
+        supervisor::Heartbeat,
+        weather_station::{compass_point_to_degrees, Measurement, MeasurementField},
+    },
+    net::url::url_encode,
+};
+
+const SOFTWARE_TYPE: &str = "M5StackWeatherStation";
+
+// Uploads that failed are kept and retried at the next interval.  An upload carries its own dateutc so the services
+// record it at the time it was measured.  Uploads older than an hour are dropped as the services ignore them.
+const MAX_QUEUED_UPLOADS: usize = 24;
+const MAX_UPLOAD_AGE_SECS: i64 = 60 * 60;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PwsService {
+    WeatherUnderground,
+    PwsWeather,
+    Wow,
+}
+
+impl PwsService {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PwsService::WeatherUnderground => "Weather Underground",
+            PwsService::PwsWeather => "PWSweather",
+            PwsService::Wow => "WOW",
+        }
+    }
+
+    pub fn base_url(&self) -> &'static str {
+        match self {
+            PwsService::WeatherUnderground => {
+                "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php"
+            }
+            PwsService::PwsWeather => "https://pwsupdate.pwsweather.com/api/v1/submitwx",
+            PwsService::Wow => "https://wow.metoffice.gov.uk/automaticreading",
+        }
+    }
+
+    // The name of the station id and station key query parameters
+    fn credential_params(&self) -> (&'static str, &'static str) {
+        match self {
+            PwsService::WeatherUnderground | PwsService::PwsWeather => ("ID", "PASSWORD"),
+            PwsService::Wow => ("siteid", "siteAuthenticationKey"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PwsCredentials {
+    pub service: PwsService,
+    pub station_id: String,
+    pub station_key: String,
+}
+
+/**
+ * A single observation in the units of the PWS protocol.  Fields that the station has not received yet are left
+ * out of the upload.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct PwsObservation {
+    pub timestamp: i64,
+    pub tempf: Option<f32>,
+    pub humidity: Option<f32>,
+    pub dewptf: Option<f32>,
+    pub windspeedmph: Option<f32>,
+    pub windgustmph: Option<f32>,
+    pub winddir: Option<f32>,
+    pub rainin: Option<f32>,
+    pub dailyrainin: Option<f32>,
+    pub baromin: Option<f32>,
+    pub indoortempf: Option<f32>,
+    pub indoorhumidity: Option<f32>,
+}
+
+impl PwsObservation {
+    // Build an observation from the latest values the weather station has computed
+    pub fn from_history(history: &HistoryStore, now: i64) -> Self {
+        let value = |field: MeasurementField| {
+            history
+                .latest(field)
+                .and_then(|latest| latest.measurement.numeric_value())
+        };
+
+        let winddir = history
+            .latest(MeasurementField::WindDirection)
+            .and_then(|latest| match &latest.measurement {
+                Measurement::WindDirection(compass_point) => {
+                    compass_point_to_degrees(compass_point)
+                }
+                _ => None,
+            });
+
+        // Rain over the past hour is only known once the rain gauge has reported
+        let rainin = history
+            .latest(MeasurementField::YearlyRainfall)
+            .map(|_| history.rainfall_since(now - 60 * 60));
+
+        Self {
+            timestamp: now,
+            tempf: value(MeasurementField::OutdoorTemperature),
+            humidity: value(MeasurementField::OutdoorHumidity),
+            dewptf: value(MeasurementField::OutdoorDewpoint),
+            windspeedmph: value(MeasurementField::AverageWindSpeed),
+            windgustmph: value(MeasurementField::WindGust),
+            winddir,
+            rainin,
+            dailyrainin: value(MeasurementField::DailyRainfall),
+            baromin: value(MeasurementField::SeaLevelPressure),
+            indoortempf: value(MeasurementField::IndoorTemperature),
+            indoorhumidity: value(MeasurementField::IndoorHumidity),
+        }
+    }
+
+    pub fn has_outdoor_values(&self) -> bool {
+        self.tempf.is_some() || self.windspeedmph.is_some() || self.baromin.is_some()
+    }
+}
+
+/**
+ * Build the upload url for a service
+ *
+ * https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php?ID=KXXX1&PASSWORD=key
+ *      &dateutc=2023-07-01%2012%3A00%3A00&tempf=72&humidity=40&...&softwaretype=M5StackWeatherStation&action=updateraw
+ */
+pub fn upload_url(
+    base_url: &str,
+    credentials: &PwsCredentials,
+    observation: &PwsObservation,
+) -> String {
+    let (id_param, key_param) = credentials.service.credential_params();
+
+    let mut url = String::with_capacity(512);
+    write!(
+        url,
+        "{}?{}={}&{}={}&dateutc={}",
+        base_url,
+        id_param,
+        url_encode(&credentials.station_id),
+        key_param,
+        url_encode(&credentials.station_key),
+        url_encode(&format_dateutc(observation.timestamp)),
+    )
+    .unwrap();
+
+    let params = [
+        ("tempf", observation.tempf, 1),
+        ("humidity", observation.humidity, 0),
+        ("dewptf", observation.dewptf, 1),
+        ("windspeedmph", observation.windspeedmph, 1),
+        ("windgustmph", observation.windgustmph, 1),
+        ("winddir", observation.winddir, 0),
+        ("rainin", observation.rainin, 2),
+        ("dailyrainin", observation.dailyrainin, 2),
+        ("baromin", observation.baromin, 2),
+        ("indoortempf", observation.indoortempf, 1),
+        ("indoorhumidity", observation.indoorhumidity, 0),
+    ];
+
+    for (name, value, decimals) in params {
+        if let Some(value) = value {
+            write!(url, "&{}={:.*}", name, decimals, value).unwrap();
+        }
+    }
+
+    write!(url, "&softwaretype={}", SOFTWARE_TYPE).unwrap();
+
+    if credentials.service == PwsService::WeatherUnderground {
+        url.push_str("&action=updateraw");
+    }
+
+    url
+}
+
+// The PWS protocol wants the time as "YYYY-MM-DD HH:MM:SS" in UTC
+pub fn format_dateutc(timestamp: i64) -> String {
+    let date_time =
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        date_time.year(),
+        date_time.month() as u8,
+        date_time.day(),
+        date_time.hour(),
+        date_time.minute(),
+        date_time.second()
+    )
+}
+
+struct PendingUpload {
+    service: PwsService,
+    timestamp: i64,
+    url: String,
+}
+
+pub struct PwsUploader {
+    history: SharedHistory,
+    services: Vec<PwsCredentials>,
+    interval: Duration,
+    url_override: String,
+    queue: VecDeque<PendingUpload>,
+}
+
+impl PwsUploader {
+    pub fn new(history: SharedHistory, config: &PwsConfig) -> Self {
+        Self {
+            history,
+            services: configured_services(config),
+            interval: Duration::from_secs(config.interval_secs as u64),
+            url_override: config.url_override.clone(),
+            queue: VecDeque::with_capacity(MAX_QUEUED_UPLOADS),
+        }
+    }
+
+    pub fn is_configured(config: &PwsConfig) -> bool {
+        !configured_services(config).is_empty()
+    }
+
+    pub fn start(mut self, heartbeat: Heartbeat) -> JoinHandle<()> {
+        println!("Starting PwsUploader Thread");
+
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || loop {
+                heartbeat.sleep(self.interval);
+
+                let observation = {
+                    let history = self.history.lock().unwrap();
+                    PwsObservation::from_history(&history, now_timestamp())
+                };
+
+                if observation.has_outdoor_values() {
+                    self.queue_observation(&observation);
+                }
+
+                self.upload_queued(&heartbeat);
+            })
+            .unwrap()
+    }
+
+    fn queue_observation(&mut self, observation: &PwsObservation) {
+        for credentials in self.services.iter() {
+            let base_url = if self.url_override.is_empty() {
+                credentials.service.base_url()
+            } else {
+                self.url_override.as_str()
+            };
+
+            if self.queue.len() == MAX_QUEUED_UPLOADS {
+                self.queue.pop_front();
+            }
+
+            self.queue.push_back(PendingUpload {
+                service: credentials.service,
+                timestamp: observation.timestamp,
+                url: upload_url(base_url, credentials, observation),
+            });
+        }
+    }
+
+    // Upload the queued observations oldest first.  When an upload to a service fails the remaining uploads to
+    // that service are kept for the next interval.
+    fn upload_queued(&mut self, heartbeat: &Heartbeat) {
+        let now = now_timestamp();
+        let mut failed_services: Vec<PwsService> = Vec::new();
+        let mut remaining = VecDeque::with_capacity(MAX_QUEUED_UPLOADS);
+
+        while let Some(upload) = self.queue.pop_front() {
+            heartbeat.beat();
+
+            if now - upload.timestamp > MAX_UPLOAD_AGE_SECS {
+                println!("{} upload dropped, too old", upload.service.name());
+                continue;
+            }
+
+            if failed_services.contains(&upload.service) {
+                remaining.push_back(upload);
+                continue;
+            }
+
+            match http_get(&upload.url) {
+                Ok(()) => (),
+                Err(e) => {
+                    println!("{} upload failed {:?}", upload.service.name(), e);
+                    failed_services.push(upload.service);
+                    remaining.push_back(upload);
+                }
+            }
+        }
+
+        self.queue = remaining;
+    }
+}
+
+fn configured_services(config: &PwsConfig) -> Vec<PwsCredentials> {
+    let all = [
+        (
+            PwsService::WeatherUnderground,
+            &config.wu_station_id,
+            &config.wu_station_key,
+        ),
+        (
+            PwsService::PwsWeather,
+            &config.pwsweather_station_id,
+            &config.pwsweather_station_key,
+        ),
+        (PwsService::Wow, &config.wow_site_id, &config.wow_site_key),
+    ];
+
+    all.iter()
+        .filter(|(_, station_id, _)| !station_id.is_empty())
+        .map(|(service, station_id, station_key)| PwsCredentials {
+            service: *service,
+            station_id: station_id.to_string(),
+            station_key: station_key.to_string(),
+        })
+        .collect()
+}
+
+fn http_get(url: &str) -> Result<()> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+
+    let mut client = Client::wrap(connection);
+    let response = client.get(url)?.submit()?;
+
+    match response.status() {
+        200..=299 => Ok(()),
+        status => bail!("http status {}", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-07-01 12:00:00 UTC
+    const NOON: i64 = 1688212800;
+
+    fn credentials(service: PwsService) -> PwsCredentials {
+        PwsCredentials {
+            service,
+            station_id: "KXXX1".to_string(),
+            station_key: "key&1".to_string(),
+        }
+    }
+
+    fn observation() -> PwsObservation {
+        PwsObservation {
+            timestamp: NOON,
+            tempf: Some(72.0),
+            humidity: Some(40.0),
+            dewptf: Some(46.5),
+            windspeedmph: Some(5.0),
+            windgustmph: Some(12.0),
+            winddir: Some(225.0),
+            rainin: Some(0.25),
+            dailyrainin: Some(1.5),
+            baromin: Some(29.92),
+            indoortempf: Some(70.0),
+            indoorhumidity: Some(45.0),
+        }
+    }
+
+    #[test]
+    fn dateutc_is_the_utc_time() {
+        assert_eq!(format_dateutc(NOON), "2023-07-01 12:00:00");
+        assert_eq!(format_dateutc(NOON + 3661), "2023-07-01 13:01:01");
+    }
+
+    #[test]
+    fn weather_underground_url() {
+        let url = upload_url(
+            PwsService::WeatherUnderground.base_url(),
+            &credentials(PwsService::WeatherUnderground),
+            &observation(),
+        );
+
+        assert_eq!(
+            url,
+            "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php\
+             ?ID=KXXX1&PASSWORD=key%261&dateutc=2023-07-01%2012%3A00%3A00\
+             &tempf=72.0&humidity=40&dewptf=46.5&windspeedmph=5.0&windgustmph=12.0&winddir=225\
+             &rainin=0.25&dailyrainin=1.50&baromin=29.92&indoortempf=70.0&indoorhumidity=45\
+             &softwaretype=M5StackWeatherStation&action=updateraw"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_left_out() {
+        let observation = PwsObservation {
+            timestamp: NOON,
+            tempf: Some(72.0),
+            baromin: Some(29.92),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            upload_url(
+                "https://pwsupdate.pwsweather.com/api/v1/submitwx",
+                &credentials(PwsService::PwsWeather),
+                &observation
+            ),
+            "https://pwsupdate.pwsweather.com/api/v1/submitwx?ID=KXXX1&PASSWORD=key%261\
+             &dateutc=2023-07-01%2012%3A00%3A00&tempf=72.0&baromin=29.92&softwaretype=M5StackWeatherStation"
+        );
+    }
+
+    #[test]
+    fn wow_uses_its_own_credential_names() {
+        let observation = PwsObservation {
+            timestamp: NOON,
+            tempf: Some(72.0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            upload_url(
+                PwsService::Wow.base_url(),
+                &credentials(PwsService::Wow),
+                &observation
+            ),
+            "https://wow.metoffice.gov.uk/automaticreading?siteid=KXXX1&siteAuthenticationKey=key%261\
+             &dateutc=2023-07-01%2012%3A00%3A00&tempf=72.0&softwaretype=M5StackWeatherStation"
+        );
+    }
+
+    #[test]
+    fn observation_from_history() {
+        let mut history = HistoryStore::default();
+        history.record_at(NOON - 7200, &Measurement::YearlyRainfall(10.0));
+        history.record_at(NOON, &Measurement::YearlyRainfall(10.25));
+        history.record_at(NOON, &Measurement::OutdoorTemperature(72));
+        history.record_at(NOON, &Measurement::AverageWindSpeedMph(5));
+        history.record_at(NOON, &Measurement::WindDirection("SW".to_string()));
+        history.record_at(NOON, &Measurement::SeaLevelPressure(29.92));
+        history.record_at(NOON, &Measurement::IndoorPressure(29.5));
+
+        let observation = PwsObservation::from_history(&history, NOON);
+
+        assert_eq!(observation.timestamp, NOON);
+        assert_eq!(observation.tempf, Some(72.0));
+        assert_eq!(observation.windspeedmph, Some(5.0));
+        assert_eq!(observation.winddir, Some(225.0));
+        assert_eq!(observation.rainin, Some(0.25));
+        assert_eq!(observation.baromin, Some(29.92));
+        assert_eq!(observation.humidity, None);
+        assert!(observation.has_outdoor_values());
+    }
+
+    #[test]
+    fn rain_is_unknown_until_the_gauge_reports() {
+        let mut history = HistoryStore::default();
+        history.record_at(NOON, &Measurement::IndoorTemperature(70));
+
+        let observation = PwsObservation::from_history(&history, NOON);
+
+        assert_eq!(observation.rainin, None);
+        assert_eq!(observation.indoortempf, Some(70.0));
+        assert!(!observation.has_outdoor_values());
+    }
+}
@@ -0,0 +1,17 @@
+use std::fmt::Write;
+
+// Percent encode a query string value, everything but the unreserved characters of RFC 3986 is encoded
+pub fn url_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len() * 3);
+
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => write!(encoded, "%{:02X}", byte).unwrap(),
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,323 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use crossbeam_channel::{bounded, Sender};
+use embedded_svc::wifi::{ClientConfiguration, Configuration, Wifi};
+use esp_idf_hal::{modem::Modem, peripheral};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    handle::RawHandle,
+    netif::{EspNetif, EspNetifWait, IpEvent},
+    nvs::EspDefaultNvsPartition,
+    wifi::{EspWifi, WifiEvent, WifiWait},
+};
+use esp_idf_sys::{
+    esp, esp_wifi_set_mode, esp_wifi_sta_get_ap_info, wifi_ap_record_t, wifi_mode_t_WIFI_MODE_STA,
+};
+use log::*;
+
+use crate::{
+    model::{config::WifiConfig, supervisor::Heartbeat},
+    net::backoff::Backoff,
+};
+
+const START_TIMEOUT: Duration = Duration::from_secs(20);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(45);
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+const RSSI_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Set by the WifiSupervisor while the station interface has an address
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+
+pub fn is_link_up() -> bool {
+    LINK_UP.load(Ordering::Relaxed)
+}
+
+// Network events shown on the display
+pub enum NetworkEvent {
+    // The setup access point is up, the user joins ap_ssid and opens the portal at address
+    Provisioning { ap_ssid: String, address: Ipv4Addr },
+    // New credentials were saved from the portal and the station is about to restart
+    CredentialsSaved { ssid: String },
+    // The link state, address or signal strength of the station interface changed
+    Status(NetworkStatus),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkState {
+    Connected,
+    Reconnecting,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkStatus {
+    pub state: LinkState,
+    pub ip: Option<Ipv4Addr>,
+    pub rssi: Option<i8>,
+}
+
+// Signal strength as 0 to 4 bars
+pub fn signal_bars(rssi: i8) -> u8 {
+    match rssi {
+        -55..=0 => 4,
+        -65..=-56 => 3,
+        -75..=-66 => 2,
+        -85..=-76 => 1,
+        _ => 0,
+    }
+}
+
+pub fn create(
+    modem: impl peripheral::Peripheral<P = Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+) -> Result<Box<EspWifi<'static>>> {
+    Ok(Box::new(EspWifi::new(modem, sysloop, Some(nvs))?))
+}
+
+// Connect to the configured access point and wait for a DHCP lease
+pub fn connect(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: &EspSystemEventLoop,
+    wifi_config: &WifiConfig,
+) -> Result<()> {
+    if wifi_config.ssid.is_empty() {
+        bail!("No wifi network is configured");
+    }
+
+    esp_wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: wifi_config.ssid.as_str().into(),
+        password: wifi_config.password.as_str().into(),
+        ..Default::default()
+    }))?;
+
+    esp_wifi.start()?;
+    info!("Starting wifi");
+
+    if !WifiWait::new(sysloop)?.wait_with_timeout(START_TIMEOUT, || esp_wifi.is_started().unwrap())
+    {
+        bail!("Wifi did not start");
+    }
+
+    info!("Connecting wifi ...");
+
+    esp_wifi.connect()?;
+
+    if !EspNetifWait::new::<EspNetif>(esp_wifi.sta_netif(), sysloop)?.wait_with_timeout(
+        CONNECT_TIMEOUT,
+        || {
+            esp_wifi.is_connected().unwrap()
+                && esp_wifi.sta_netif().get_ip_info().unwrap().ip != Ipv4Addr::new(0, 0, 0, 0)
+        },
+    ) {
+        bail!("Wifi did not connect or did not receive a DHCP lease");
+    }
+
+    let ip_info = esp_wifi.sta_netif().get_ip_info()?;
+
+    info!("Wifi DHCP info: {:?}", ip_info);
+
+    Ok(())
+}
+
+// Events from the system event loop handed to the supervisor thread
+enum LinkEvent {
+    Disconnected,
+    GotIp(Ipv4Addr),
+}
+
+/**
+ * Watches the wifi link.  A lost connection, or one that could not be made at boot, is retried with an exponential
+ * backoff until the access point is back, and the link state, address and signal strength are sent to the Gui.
+ *
+ * When the station booted into the setup portal the wifi runs in mixed mode with the stored credentials, so the
+ * stored network is retried while the portal is up.  Once it connects the setup access point is switched off.
+ */
+pub struct WifiSupervisor {
+    esp_wifi: Box<EspWifi<'static>>,
+    sysloop: EspSystemEventLoop,
+    cbc_tx_network_event: Sender<NetworkEvent>,
+}
+
+impl WifiSupervisor {
+    pub fn new(
+        esp_wifi: Box<EspWifi<'static>>,
+        sysloop: EspSystemEventLoop,
+        tx: Sender<NetworkEvent>,
+    ) -> Self {
+        Self {
+            esp_wifi,
+            sysloop,
+            cbc_tx_network_event: tx,
+        }
+    }
+
+    pub fn start(mut self, heartbeat: Heartbeat) -> Result<JoinHandle<()>> {
+        println!("Starting WifiSupervisor Thread");
+
+        let (tx, rx) = bounded(10);
+
+        // The event loop callbacks only pass the events on, the work is done on the supervisor thread
+        let wifi_tx = tx.clone();
+        let wifi_subscription = self.sysloop.subscribe(move |event: &WifiEvent| {
+            if let WifiEvent::StaDisconnected = event {
+                let _ = wifi_tx.try_send(LinkEvent::Disconnected);
+            }
+        })?;
+
+        // The netif handle is kept as an address so the callback stays Send
+        let sta_netif = self.esp_wifi.sta_netif().handle() as usize;
+        let ip_subscription = self.sysloop.subscribe(move |event: &IpEvent| {
+            if let IpEvent::DhcpIpAssigned(assignment) = event {
+                if assignment.netif_handle as usize == sta_netif {
+                    let _ = tx.try_send(LinkEvent::GotIp(assignment.ip_settings.ip));
+                }
+            }
+        })?;
+
+        // Nothing is retried until the portal has saved a network
+        let (configured, mut access_point) = match self.esp_wifi.get_configuration()? {
+            Configuration::Client(client) => (!client.ssid.is_empty(), false),
+            Configuration::Mixed(client, _) => (!client.ssid.is_empty(), true),
+            _ => (false, false),
+        };
+
+        let wifi_supervisor_thread =
+            std::thread::Builder::new()
+                .stack_size(4096)
+                .spawn(move || {
+                    // Dropping the subscriptions would stop the events
+                    let _subscriptions = (wifi_subscription, ip_subscription);
+
+                    let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+                    let mut reconnect_at: Option<Instant> = None;
+                    let mut rssi_polled = Instant::now();
+
+                    let ip = self
+                        .esp_wifi
+                        .sta_netif()
+                        .get_ip_info()
+                        .ok()
+                        .map(|info| info.ip)
+                        .filter(|ip| *ip != Ipv4Addr::new(0, 0, 0, 0));
+
+                    let mut status = match ip {
+                        Some(ip) if self.esp_wifi.is_connected().unwrap_or(false) => {
+                            LINK_UP.store(true, Ordering::Relaxed);
+                            let status = NetworkStatus {
+                                state: LinkState::Connected,
+                                ip: Some(ip),
+                                rssi: read_rssi(),
+                            };
+                            self.send_status(&status);
+                            status
+                        }
+                        // The Gui keeps showing the setup portal until the first connect
+                        _ => {
+                            if configured {
+                                reconnect_at = Some(Instant::now() + backoff.next_delay());
+                            }
+                            NetworkStatus {
+                                state: LinkState::Reconnecting,
+                                ip: None,
+                                rssi: None,
+                            }
+                        }
+                    };
+
+                    loop {
+                        heartbeat.beat();
+                        let previous = status.clone();
+
+                        match rx.recv_timeout(Duration::from_secs(1)) {
+                            Ok(LinkEvent::Disconnected) => {
+                                // A failed attempt also ends in a disconnect, the next attempt waits a little longer
+                                if reconnect_at.is_none() {
+                                    let delay = backoff.next_delay();
+                                    println!(
+                                        "Wifi disconnected, reconnecting in {} s",
+                                        delay.as_secs()
+                                    );
+                                    reconnect_at = Some(Instant::now() + delay);
+                                }
+
+                                LINK_UP.store(false, Ordering::Relaxed);
+                                status.state = LinkState::Reconnecting;
+                                status.ip = None;
+                                status.rssi = None;
+                            }
+
+                            Ok(LinkEvent::GotIp(ip)) => {
+                                println!("Wifi connected, ip {}", ip);
+                                backoff.reset();
+                                reconnect_at = None;
+
+                                // The setup access point is not needed once the stored network is back
+                                if access_point {
+                                    println!("Stopping the wifi setup access point");
+                                    if let Err(e) = esp!(unsafe {
+                                        esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA)
+                                    }) {
+                                        println!("Failed to stop the access point {:?}", e);
+                                    }
+                                    access_point = false;
+                                }
+
+                                LINK_UP.store(true, Ordering::Relaxed);
+
+                                status.state = LinkState::Connected;
+                                status.ip = Some(ip);
+                                status.rssi = read_rssi();
+                            }
+
+                            Err(_) => (),
+                        }
+
+                        if let Some(at) = reconnect_at {
+                            if Instant::now() >= at {
+                                reconnect_at = None;
+                                if let Err(e) = self.esp_wifi.connect() {
+                                    println!("Wifi reconnect failed {:?}", e);
+                                    reconnect_at = Some(Instant::now() + backoff.next_delay());
+                                }
+                            }
+                        }
+
+                        if status.state == LinkState::Connected
+                            && rssi_polled.elapsed() >= RSSI_POLL_INTERVAL
+                        {
+                            status.rssi = read_rssi();
+                            rssi_polled = Instant::now();
+                        }
+
+                        if status != previous {
+                            self.send_status(&status);
+                        }
+                    }
+                })?;
+
+        Ok(wifi_supervisor_thread)
+    }
+
+    fn send_status(&self, status: &NetworkStatus) {
+        // The Gui drains its channels every 30 ms, a status dropped on a full channel is replaced by the next one
+        let _ = self
+            .cbc_tx_network_event
+            .try_send(NetworkEvent::Status(status.clone()));
+    }
+}
+
+// The signal strength of the access point the station is connected to
+pub fn read_rssi() -> Option<i8> {
+    let mut ap_info = wifi_ap_record_t::default();
+
+    match esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }) {
+        Ok(()) => Some(ap_info.rssi),
+        Err(_) => None,
+    }
+}